@@ -49,7 +49,7 @@ fn build_frontend() {
 // render readme
 fn render_readme() {
     process::Command::new("cargo")
-        .args(["readme", "-o", "README.md"])
+        .args(["readme", "-i", "src/main.rs", "-o", "README.md"])
         .output()
         .expect("Failed to execute cargo readme");
 }
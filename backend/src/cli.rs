@@ -6,9 +6,10 @@ use clap::Args;
 use clap::{
     crate_authors, crate_description, crate_name, crate_version, Parser, Subcommand, ValueHint,
 };
+use colony_rs::H160;
 use once_cell::sync::Lazy;
 use secrecy::SecretString;
-use serde::Deserialize;
+use serde::{Deserialize, Serialize};
 use std::path::PathBuf;
 
 /// The long description used for the help subcommand and man page.
@@ -55,8 +56,17 @@ pub enum Commands {
     #[clap(subcommand)]
     Config(ConfigCmd),
     /// Interact with the presistent storage and encryption
-    #[clap(subcommand)]
-    Storage(StorageCmd),
+    Storage {
+        /// Copy the database to this path first and run the command on the
+        /// copy. This works while the bot is running and holds the lock on
+        /// the database, but only for read only commands like `list`. Writes
+        /// still require exclusive access, i.e. a stopped bot
+        #[clap(long, global(true), value_name = "PATH", value_hint = ValueHint::DirPath)]
+        snapshot: Option<PathBuf>,
+        /// The storage sub command to run
+        #[clap(subcommand)]
+        cmd: StorageCmd,
+    },
     /// Interact with the discord slash commands
     #[clap(subcommand)]
     Slash(SlashCommands),
@@ -72,9 +82,33 @@ pub enum Commands {
     /// would have used the `/gate enforce` slash command
     Batch {
         /// The guild id in which the users should be checked
-        guild_id: u64,
+        #[clap(required_unless_present = "all-guilds")]
+        guild_id: Option<u64>,
         /// The discord user ids to check
         user_ids: Vec<u64>,
+        /// Check all registered users against the gates of every guild
+        /// instead. This works offline, i.e. without asking discord for the
+        /// members, so users are reported for guilds they might not be in
+        #[clap(long, conflicts_with = "guild-id")]
+        all_guilds: bool,
+        /// Check all users, even if they were checked recently
+        #[clap(long)]
+        force: bool,
+    },
+    /// Check whether a signature of a message recovers to an address, the
+    /// same way registrations are verified. This works offline, e.g. to
+    /// debug a failed registration
+    VerifySignature {
+        /// The wallet address that should have signed the message
+        #[clap(long)]
+        address: String,
+        /// The signed message, e.g. the registration message with the
+        /// username and session filled in
+        #[clap(long)]
+        message: String,
+        /// The personal_sign signature as hex
+        #[clap(long)]
+        signature: String,
     },
 }
 
@@ -83,7 +117,11 @@ pub enum Commands {
 #[clap()]
 pub enum ConfigCmd {
     /// Print the configuration sources and merged config
-    Show,
+    Show {
+        /// Only print the merged config as JSON, with secrets redacted
+        #[clap(long)]
+        json: bool,
+    },
     /// Prints an example configuration template
     Template,
 }
@@ -132,7 +170,8 @@ pub enum DeleteCmd {
 
 /// Represents the storage sub command, used to interact with the stored data
 /// and encryption. Commands that use the data on disk, only work if the
-/// bot is not running, otherwise the data is locked.
+/// bot is not running, otherwise the data is locked. Read only commands can
+/// be run on a snapshot with `--snapshot` instead.
 /// Be careful, these commands are able to alter data in the storage_type
 /// and also expose secretes to the console, especially the user commands
 #[derive(Debug, Subcommand)]
@@ -149,6 +188,30 @@ pub enum StorageCmd {
     #[clap(subcommand)]
     /// List, add or delete discord role gates in the db
     Gate(GateCmd),
+    /// Rewrites all stored gates in the latest storage layout, run this after
+    /// an upgrade while the bot is stopped
+    Migrate,
+    /// Shows the cumulative registrations and unregistrations of all users
+    Stats,
+    /// Checks whether all gates and users can be read by this version of the
+    /// bot, without changing the database. Run this before an upgrade with
+    /// the new binary, it exits with an error if anything is unreadable
+    Verify,
+    /// Stops granting and revoking gated roles in a guild, or in all guilds
+    /// without a guild id. Run this while the bot is stopped, in a running
+    /// bot use the freeze subcommand of the gate command instead
+    Freeze {
+        /// The discord guild id to freeze
+        #[clap(value_hint = ValueHint::Other)]
+        guild_id: Option<u64>,
+    },
+    /// Lifts a freeze of a guild, or the freeze of all guilds without a
+    /// guild id
+    Unfreeze {
+        /// The discord guild id to unfreeze
+        #[clap(value_hint = ValueHint::Other)]
+        guild_id: Option<u64>,
+    },
 }
 
 /// Represents the user sub command, used to interact with the user storage
@@ -195,11 +258,25 @@ pub enum UserCmd {
         #[clap(value_hint = ValueHint::Other)]
         wallet_address: String,
     },
-    /// Remove a user
+    /// Add users from a CSV file with a `user_id,wallet` line per user,
+    /// invalid lines are reported and skipped
+    Import {
+        /// The file with the users and their wallet addresses
+        #[clap(long, value_hint = ValueHint::FilePath)]
+        file: PathBuf,
+        /// Replace the wallets of users that are registered already
+        #[clap(long)]
+        overwrite: bool,
+    },
+    /// Remove a user, keeping a tombstone if soft deletion is configured
     Remove {
         /// The discord user id to delete
         #[clap(value_hint = ValueHint::Other)]
         user_id: u64,
+        /// Delete the user without a tombstone, even if soft deletion is
+        /// configured
+        #[clap(long)]
+        hard: bool,
     },
 }
 
@@ -228,6 +305,52 @@ pub enum GateCmd {
         #[clap(value_hint = ValueHint::Other)]
         identifier: u128,
     },
+    /// Add an allowlist gate from a CSV file or a file with one wallet
+    /// address per line, invalid entries are reported and skipped
+    AddAllowlist {
+        /// The discord guild id
+        #[clap(long)]
+        guild: u64,
+        /// The discord role id granted by the gate
+        #[clap(long)]
+        role: u64,
+        /// The file with the wallet addresses
+        #[clap(long, value_hint = ValueHint::FilePath)]
+        file: PathBuf,
+    },
+    /// Add a gate that applies to all guilds, the role is resolved by name in
+    /// each guild
+    AddGlobal {
+        /// The name of the gated role, guilds without a role of this name
+        /// are not affected
+        #[clap(long)]
+        role_name: String,
+        /// The gate type, e.g. token or reputation
+        #[clap(value_hint = ValueHint::Other)]
+        gate_type: String,
+        /// The gate options as `name=value`
+        #[clap(value_hint = ValueHint::Other)]
+        options: Vec<String>,
+    },
+    /// Point a gate at another role, keeping its condition and settings.
+    /// The role is part of the identifier, so the gate gets a new one
+    Move {
+        /// The discord guild id
+        #[clap(long)]
+        guild: u64,
+        /// The identifier of the gate to move
+        #[clap(long)]
+        identifier: u128,
+        /// The discord role id the gate grants from now on
+        #[clap(long)]
+        new_role: u64,
+    },
+    /// Remove a global gate
+    RemoveGlobal {
+        /// The identifier of the global gate to delete
+        #[clap(value_hint = ValueHint::Other)]
+        identifier: u128,
+    },
 }
 
 /// This structs contains the configuration for the application from command
@@ -240,14 +363,78 @@ pub struct CliConfig {
     /// Sets a custom config file
     #[clap(short, long,  value_name = "FILE", value_hint = ValueHint::FilePath, global(true))]
     pub config_file: Option<PathBuf>,
-    /// The time it takes for a session to expire in seconds
+    /// The time it takes for a session to expire in seconds. Deprecated in
+    /// favor of the lifetimes of the registration and unregistration sessions
     #[clap(long, short, global(true))]
     pub session_expiration: Option<u64>,
+    /// The time it takes for a registration link to expire in seconds
+    #[clap(long, global(true))]
+    pub register_session_secs: Option<u64>,
+    /// The time it takes for an unregistration link to expire in seconds
+    #[clap(long, global(true))]
+    pub unregister_session_secs: Option<u64>,
     /// Start the bot in maintenance mode, this will do nothing except telling
     /// discord users that the bot is in maintenance mode. This allows
     /// manipulating the storage in the meantime
     #[clap(long, short)]
     pub maintenance: Option<bool>,
+    /// A scheduled maintenance window as `start-end` unix timestamps, during
+    /// which the bot answers all commands with a maintenance notice. Can be
+    /// given multiple times
+    #[clap(
+        long = "maintenance-window",
+        value_name = "START-END",
+        value_parser = parse_maintenance_window,
+        global(true)
+    )]
+    pub maintenance_windows: Option<Vec<(u64, u64)>>,
+    /// The maximum number of concurrent calls to the chain
+    #[clap(long, global(true))]
+    pub rpc_concurrency: Option<usize>,
+    /// Skip users in the enforcement that were checked less than this many
+    /// seconds ago, 0 always checks all users
+    #[clap(long, global(true))]
+    pub check_freshness: Option<u64>,
+    /// Answer `/get in` with the last result for this many seconds after a
+    /// check, 0 always checks the gates
+    #[clap(long, global(true))]
+    pub check_cooldown: Option<u64>,
+    /// Only revoke a role once the user failed its gate for this many
+    /// seconds, 0 revokes right away
+    #[clap(long, global(true))]
+    pub revoke_grace_period: Option<u64>,
+    /// The maximum number of wallets a user can link, 0 allows any number
+    #[clap(long, global(true))]
+    pub max_wallets_per_user: Option<usize>,
+    /// Start even if the stored data was written by a newer version of the
+    /// bot, which may drop data this version can not decode
+    #[clap(long)]
+    pub force: bool,
+    /// A colony address reputation gates may be created for, all colonies
+    /// are allowed if none is given. Can be given multiple times
+    #[clap(
+        long = "allowed-colony",
+        value_name = "ADDRESS",
+        value_parser = parse_address,
+        global(true)
+    )]
+    pub allowed_colonies: Option<Vec<H160>>,
+    /// A token address token gates may be created for, all tokens are
+    /// allowed if none is given. Can be given multiple times
+    #[clap(
+        long = "allowed-token",
+        value_name = "ADDRESS",
+        value_parser = parse_address,
+        global(true)
+    )]
+    pub allowed_tokens: Option<Vec<H160>>,
+    /// A colony whose domain count is looked up at startup before reporting
+    /// ready on /readyz
+    #[clap(long, value_name = "ADDRESS", value_parser = parse_address, global(true))]
+    pub readiness_probe_colony: Option<H160>,
+    /// How many seconds to wait for the readiness probe at startup
+    #[clap(long, global(true))]
+    pub readiness_probe_timeout: Option<u64>,
     #[clap(flatten)]
     pub observability: CliObservabilityConfig,
     #[clap(flatten)]
@@ -283,6 +470,57 @@ pub struct CliDiscordConfig {
     /// The discord bot token
     #[clap(short, long, global(true))]
     pub token: Option<SecretString>,
+    /// The color of embeds in bot messages as hex string, e.g. "#289BDC"
+    #[clap(long, global(true))]
+    pub embed_color: Option<String>,
+    /// The member permission required by default to use the `/gate` command,
+    /// e.g. MANAGE_GUILD or MANAGE_ROLES. With NONE, every member can use it
+    /// unless restricted by discord's per command permission overrides
+    #[clap(long, global(true))]
+    pub gate_permission: Option<String>,
+    /// The url behind the invite button on the index page. If left empty, it
+    /// is generated from the application id of the bot
+    #[clap(long, global(true))]
+    pub invite_url: Option<String>,
+    /// Enforce the role of a gate right after the gate is deleted in discord
+    #[clap(long, global(true))]
+    pub revoke_on_delete: Option<bool>,
+    /// Remove the gated roles of a user right after they unregistered,
+    /// otherwise the roles are kept
+    #[clap(long, global(true))]
+    pub unregister_revokes_roles: Option<bool>,
+    /// How often fetching a page of guild members is retried
+    #[clap(long, global(true))]
+    pub member_fetch_retries: Option<u32>,
+    /// How many seconds fetching a page of guild members may take
+    #[clap(long, global(true))]
+    pub member_fetch_timeout: Option<u64>,
+    /// Leave bot accounts out of `/gate enforce`
+    #[clap(long, global(true))]
+    pub enforce_skip_bots: Option<bool>,
+    /// A user id that `/gate enforce` leaves out, can be given multiple times
+    #[clap(long = "enforce-excluded-user", value_name = "USER_ID", global(true))]
+    pub enforce_excluded_users: Option<Vec<u64>>,
+    /// After how many seconds without a result `/get in` tells the user that
+    /// the check is still running
+    #[clap(long, global(true))]
+    pub check_notice_timeout: Option<u64>,
+    /// How many follow up messages are sent per second at most
+    #[clap(long, global(true))]
+    pub messages_per_second: Option<u32>,
+    /// Who sees the result of `/get in` when a role could not be granted,
+    /// one of Public, AdminPing or Ephemeral
+    #[clap(long, global(true))]
+    pub grant_failure_visibility: Option<FailureVisibility>,
+    /// What `/get in` says when no role was granted and no check failed
+    #[clap(long, global(true))]
+    pub no_roles_message: Option<String>,
+    /// A JSON file with translations of the commands by discord locale
+    #[clap(long, value_hint = ValueHint::FilePath, global(true))]
+    pub command_localizations: Option<PathBuf>,
+    /// The channel told when the bot lacks the permission to manage a role
+    #[clap(long, global(true))]
+    pub permission_alert_channel: Option<u64>,
 }
 
 /// This structs contains the sub configuration for the http server options.
@@ -293,12 +531,38 @@ pub struct CliServerConfig {
     /// The address to listen on
     #[clap(short = 'H', long, global(true))]
     pub host: Option<String>,
-    /// The base url under which the server is reachable
+    /// The public base url under which the server is reachable
     #[clap(short, long, global(true))]
     pub url: Option<String>,
     /// The port to listen on
     #[clap(short = 'P', long, global(true))]
     pub port: Option<u16>,
+    /// An address to listen on as `host:port` instead of host and port, can
+    /// be given multiple times, e.g. for IPv4 and IPv6
+    #[clap(
+        long = "bind",
+        value_name = "HOST:PORT",
+        value_parser = parse_bind,
+        global(true)
+    )]
+    pub binds: Option<Vec<String>>,
+    /// Listen on a unix domain socket at this path instead of host and port
+    #[clap(long, value_hint = ValueHint::FilePath, global(true))]
+    pub unix_socket: Option<PathBuf>,
+    /// Offer a deep link on the registration page, that opens the page in
+    /// the browser of a mobile wallet app
+    #[clap(long, global(true))]
+    pub mobile_deep_link: Option<bool>,
+    /// The message users sign with their wallet to register, must contain
+    /// the `{username}` and `{session}` placeholders
+    #[clap(long, global(true))]
+    pub registration_message: Option<String>,
+    /// The Content-Security-Policy sent with every page, empty sends none
+    #[clap(long, global(true))]
+    pub content_security_policy: Option<String>,
+    /// The Referrer-Policy sent with every page, empty sends none
+    #[clap(long, global(true))]
+    pub referrer_policy: Option<String>,
 }
 
 /// This structs contains the sub configuration for the storage options.
@@ -309,16 +573,30 @@ pub struct CliStorageConfig {
     /// The path where the persistent data is stored
     #[clap(short, long, global(true))]
     pub directory: Option<PathBuf>,
+    /// The path where the users are stored, if not in the directory above
+    #[clap(long, global(true))]
+    pub user_directory: Option<PathBuf>,
+    /// Sets the data of this bot apart from other bots storing their data in
+    /// the same directory
+    #[clap(long = "storage-namespace", global(true))]
+    pub namespace: Option<String>,
     /// How to store data, on disk or in memory
     #[clap(short = 'S', long, global(true))]
     pub storage_type: Option<StorageType>,
     /// The encryption_key used to encrypt the stored data
     #[clap(short, long, global(true))]
     pub key: Option<SecretString>,
+    /// A previous encryption key, only used to decrypt data that was not yet
+    /// written again with the current key. Can be given multiple times
+    #[clap(long = "legacy-key", value_name = "KEY", global(true))]
+    pub legacy_keys: Option<Vec<SecretString>>,
+    /// Keep a tombstone of users that unregister instead of forgetting them
+    #[clap(long, global(true))]
+    pub soft_delete_users: Option<bool>,
 }
 
 /// The storage type enum, used to select the storage type
-#[derive(Clone, Debug, Deserialize)]
+#[derive(Clone, Debug, Deserialize, Serialize)]
 pub enum StorageType {
     /// Store data peristent and encrypted on disk, this is the default
     Encrypted,
@@ -340,3 +618,77 @@ impl std::str::FromStr for StorageType {
         }
     }
 }
+
+/// Who sees the result of `/get in` when a role could not be granted
+#[derive(Clone, Debug, Deserialize, Serialize, PartialEq, Eq)]
+pub enum FailureVisibility {
+    /// The result is posted to the channel, so admins notice problems with
+    /// the role hierarchy, this is the default
+    Public,
+    /// Only the user sees the result, the owner of the server is pinged in
+    /// the channel about the roles, without naming the user
+    AdminPing,
+    /// Only the user sees the result
+    Ephemeral,
+}
+
+impl std::str::FromStr for FailureVisibility {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "Public" => Ok(FailureVisibility::Public),
+            "AdminPing" => Ok(FailureVisibility::AdminPing),
+            "Ephemeral" => Ok(FailureVisibility::Ephemeral),
+            _ => Err(format!("Invalid failure visibility: {}", s)),
+        }
+    }
+}
+
+/// Parses a hex encoded address like `0xCFD3aa1EbC6119D80Ed47955a87A9d9C281A97B3`
+pub fn parse_address(s: &str) -> Result<H160, String> {
+    s.trim()
+        .parse::<H160>()
+        .map_err(|why| format!("Invalid address {:?}: {}", s, why))
+}
+
+/// Checks that a listen address is given as `host:port`, IPv6 hosts are put
+/// in brackets, e.g. `[::1]:8080`
+pub fn parse_bind(s: &str) -> Result<String, String> {
+    let s = s.trim();
+    if s.parse::<std::net::SocketAddr>().is_ok() {
+        return Ok(s.to_string());
+    }
+    let (_, port) = s
+        .rsplit_once(':')
+        .filter(|(host, _)| !host.is_empty() && !host.contains(':'))
+        .ok_or(format!(
+            "Invalid listen address {:?}, expected host:port",
+            s
+        ))?;
+    port.parse::<u16>()
+        .map_err(|why| format!("Invalid port in listen address {:?}: {}", s, why))?;
+    Ok(s.to_string())
+}
+
+/// Parses a maintenance window given as `start-end` unix timestamps
+pub fn parse_maintenance_window(s: &str) -> Result<(u64, u64), String> {
+    let (start, end) = s
+        .split_once('-')
+        .ok_or(format!("Invalid maintenance window {:?}, expected start-end", s))?;
+    let start = start
+        .trim()
+        .parse::<u64>()
+        .map_err(|why| format!("Invalid start of maintenance window {:?}: {}", s, why))?;
+    let end = end
+        .trim()
+        .parse::<u64>()
+        .map_err(|why| format!("Invalid end of maintenance window {:?}: {}", s, why))?;
+    if end <= start {
+        return Err(format!(
+            "Invalid maintenance window {:?}, the end must be after the start",
+            s
+        ));
+    }
+    Ok((start, end))
+}
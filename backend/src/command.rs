@@ -6,34 +6,52 @@ use crate::cli::*;
 
 use crate::config;
 use crate::config::CONFIG;
-use crate::controller::{self, BatchResponse, Controller, Message};
+use crate::controller::{
+    self, BatchResponse, Controller, EnforcementSummary, GuildBatchResponse, Message,
+};
 use crate::discord;
+use crate::gate::{self, AllowlistGate, Gate, GlobalGate};
+use crate::maintenance;
 use crate::server;
-use crate::storage::{InMemoryStorage, SledEncryptedStorage, SledUnencryptedStorage, Storage};
+use crate::storage::{
+    self, InMemoryStorage, SledEncryptedStorage, SledUnencryptedStorage, Storage,
+};
 use chacha20poly1305::{
     aead::{KeyInit, OsRng},
     ChaCha20Poly1305,
 };
 use secrecy::ExposeSecret;
+use std::collections::HashMap;
+use std::path::PathBuf;
 
 use tracing::{info, warn};
 
 /// Chooses the appropriate actions based on the Commands enum
 pub fn execute(cli: &Cli) {
     match &cli.cmd {
-        Some(Commands::Config(ConfigCmd::Show)) => config::print_config(&cli.cfg),
+        Some(Commands::Config(ConfigCmd::Show { json: false })) => config::print_config(&cli.cfg),
+        Some(Commands::Config(ConfigCmd::Show { json: true })) => {
+            config::print_config_json(&cli.cfg)
+        }
 
         Some(Commands::Config(ConfigCmd::Template)) => config::print_template(),
 
-        Some(Commands::Storage(StorageCmd::Generate)) => {
+        Some(Commands::Storage {
+            cmd: StorageCmd::Generate,
+            ..
+        }) => {
             let key = ChaCha20Poly1305::generate_key(&mut OsRng);
             println!("{}", hex::encode(key));
         }
 
-        Some(Commands::Storage(StorageCmd::Guild(GuildCmd::List { start, end }))) => {
+        Some(Commands::Storage {
+            cmd: StorageCmd::Guild(GuildCmd::List { start, end }),
+            snapshot,
+        }) => {
+            let (path, user_path) = storage_paths(snapshot);
             match CONFIG.wait().storage.storage_type {
                 StorageType::Unencrypted => {
-                    let storage = SledUnencryptedStorage::new();
+                    let storage = SledUnencryptedStorage::open_with_users(&path, &user_path);
                     storage
                         .list_guilds()
                         .skip(*start as usize)
@@ -43,7 +61,7 @@ pub fn execute(cli: &Cli) {
                         });
                 }
                 StorageType::Encrypted => {
-                    let storage = SledEncryptedStorage::new();
+                    let storage = SledEncryptedStorage::open_with_users(&path, &user_path);
                     storage
                         .list_guilds()
                         .skip(*start as usize)
@@ -58,7 +76,11 @@ pub fn execute(cli: &Cli) {
             };
         }
 
-        Some(Commands::Storage(StorageCmd::Guild(GuildCmd::Remove { guild_id }))) => {
+        Some(Commands::Storage {
+            cmd: StorageCmd::Guild(GuildCmd::Remove { guild_id }),
+            snapshot,
+        }) => {
+            deny_snapshot(snapshot);
             match CONFIG.wait().storage.storage_type {
                 StorageType::Unencrypted => {
                     let mut storage = SledUnencryptedStorage::new();
@@ -78,10 +100,39 @@ pub fn execute(cli: &Cli) {
             };
         }
 
-        Some(Commands::Storage(StorageCmd::User(UserCmd::List { start, end }))) => {
+        Some(Commands::Storage {
+            cmd: cmd @ (StorageCmd::Freeze { guild_id } | StorageCmd::Unfreeze { guild_id }),
+            snapshot,
+        }) => {
+            deny_snapshot(snapshot);
+            let frozen = matches!(cmd, StorageCmd::Freeze { .. });
+            match CONFIG.wait().storage.storage_type {
+                StorageType::Unencrypted => {
+                    let mut storage = SledUnencryptedStorage::new();
+                    storage
+                        .set_frozen(*guild_id, frozen)
+                        .expect("Failed to set the gating freeze");
+                }
+                StorageType::Encrypted => {
+                    let mut storage = SledEncryptedStorage::new();
+                    storage
+                        .set_frozen(*guild_id, frozen)
+                        .expect("Failed to set the gating freeze");
+                }
+                StorageType::InMemory => {
+                    panic!("InMemory storage does not make sense for this command")
+                }
+            };
+        }
+
+        Some(Commands::Storage {
+            cmd: StorageCmd::User(UserCmd::List { start, end }),
+            snapshot,
+        }) => {
+            let (path, user_path) = storage_paths(snapshot);
             match CONFIG.wait().storage.storage_type {
                 StorageType::Unencrypted => {
-                    let storage = SledUnencryptedStorage::new();
+                    let storage = SledUnencryptedStorage::open_with_users(&path, &user_path);
                     storage
                         .list_users()
                         .expect("Failed to list users")
@@ -99,7 +150,7 @@ pub fn execute(cli: &Cli) {
                         });
                 }
                 StorageType::Encrypted => {
-                    let storage = SledEncryptedStorage::new();
+                    let storage = SledEncryptedStorage::open_with_users(&path, &user_path);
                     storage
                         .list_users()
                         .expect("Failed to list users")
@@ -122,10 +173,15 @@ pub fn execute(cli: &Cli) {
             };
         }
 
-        Some(Commands::Storage(StorageCmd::User(UserCmd::Add {
-            user_id,
-            wallet_address,
-        }))) => {
+        Some(Commands::Storage {
+            cmd:
+                StorageCmd::User(UserCmd::Add {
+                    user_id,
+                    wallet_address,
+                }),
+            snapshot,
+        }) => {
+            deny_snapshot(snapshot);
             match CONFIG.wait().storage.storage_type {
                 StorageType::Unencrypted => {
                     let mut storage = SledUnencryptedStorage::new();
@@ -145,15 +201,53 @@ pub fn execute(cli: &Cli) {
             };
         }
 
-        Some(Commands::Storage(StorageCmd::User(UserCmd::Remove { user_id }))) => {
+        Some(Commands::Storage {
+            cmd: StorageCmd::User(UserCmd::Import { file, overwrite }),
+            snapshot,
+        }) => {
+            deny_snapshot(snapshot);
+            let content = std::fs::read_to_string(file)
+                .unwrap_or_else(|why| panic!("Failed to read {:?}: {:?}", file, why));
+            let import = match CONFIG.wait().storage.storage_type {
+                StorageType::Unencrypted => {
+                    storage::import_users(&mut SledUnencryptedStorage::new(), &content, *overwrite)
+                }
+                StorageType::Encrypted => {
+                    storage::import_users(&mut SledEncryptedStorage::new(), &content, *overwrite)
+                }
+                StorageType::InMemory => {
+                    panic!("InMemory storage does not make sense for this command")
+                }
+            };
+            for error in &import.errors {
+                println!("Skipped line {}: {}", error.line, error.reason);
+            }
+            println!(
+                "Imported {} users, {} registered already, {} invalid",
+                import.imported,
+                import.existing,
+                import.errors.len()
+            );
+        }
+
+        Some(Commands::Storage {
+            cmd: StorageCmd::User(UserCmd::Remove { user_id, hard }),
+            snapshot,
+        }) => {
+            deny_snapshot(snapshot);
+            let soft = CONFIG.wait().storage.soft_delete_users && !hard;
             match CONFIG.wait().storage.storage_type {
                 StorageType::Unencrypted => {
                     let mut storage = SledUnencryptedStorage::new();
-                    storage.remove_user(user_id).expect("Failed to remove user");
+                    storage
+                        .remove_user(user_id, soft)
+                        .expect("Failed to remove user");
                 }
                 StorageType::Encrypted => {
                     let mut storage = SledEncryptedStorage::new();
-                    storage.remove_user(user_id).expect("Failed to remove user");
+                    storage
+                        .remove_user(user_id, soft)
+                        .expect("Failed to remove user");
                 }
                 StorageType::InMemory => {
                     panic!("InMemory storage does not make sense for this command")
@@ -161,10 +255,14 @@ pub fn execute(cli: &Cli) {
             };
         }
 
-        Some(Commands::Storage(StorageCmd::Gate(GateCmd::List { guild, start, end }))) => {
+        Some(Commands::Storage {
+            cmd: StorageCmd::Gate(GateCmd::List { guild, start, end }),
+            snapshot,
+        }) => {
+            let (path, user_path) = storage_paths(snapshot);
             match CONFIG.wait().storage.storage_type {
                 StorageType::Unencrypted => {
-                    let storage = SledUnencryptedStorage::new();
+                    let storage = SledUnencryptedStorage::open_with_users(&path, &user_path);
                     let guilds = if let Some(guild) = guild {
                         vec![*guild]
                     } else {
@@ -181,9 +279,12 @@ pub fn execute(cli: &Cli) {
                                 println!("{}:{:?}", gate.identifier(), gate);
                             });
                     }
+                    if guild.is_none() {
+                        print_global_gates(&storage);
+                    }
                 }
                 StorageType::Encrypted => {
-                    let storage = SledEncryptedStorage::new();
+                    let storage = SledEncryptedStorage::open_with_users(&path, &user_path);
                     let guilds = if let Some(guild) = guild {
                         vec![*guild]
                     } else {
@@ -200,6 +301,9 @@ pub fn execute(cli: &Cli) {
                                 println!("{}:{:?}", gate.identifier(), gate);
                             });
                     }
+                    if guild.is_none() {
+                        print_global_gates(&storage);
+                    }
                 }
                 StorageType::InMemory => {
                     panic!("InMemory storage does not make sense for this command")
@@ -207,10 +311,15 @@ pub fn execute(cli: &Cli) {
             };
         }
 
-        Some(Commands::Storage(StorageCmd::Gate(GateCmd::Remove {
-            guild_id,
-            identifier,
-        }))) => {
+        Some(Commands::Storage {
+            cmd:
+                StorageCmd::Gate(GateCmd::Remove {
+                    guild_id,
+                    identifier,
+                }),
+            snapshot,
+        }) => {
+            deny_snapshot(snapshot);
             match CONFIG.wait().storage.storage_type {
                 StorageType::Unencrypted => {
                     let mut storage = SledUnencryptedStorage::new();
@@ -230,6 +339,203 @@ pub fn execute(cli: &Cli) {
             };
         }
 
+        Some(Commands::Storage {
+            cmd: StorageCmd::Gate(GateCmd::AddAllowlist { guild, role, file }),
+            snapshot,
+        }) => {
+            deny_snapshot(snapshot);
+            let content = std::fs::read_to_string(file)
+                .unwrap_or_else(|why| panic!("Failed to read {:?}: {:?}", file, why));
+            let (condition, invalid) = AllowlistGate::from_csv(&content)
+                .unwrap_or_else(|why| panic!("Invalid allowlist: {:?}", why));
+            for entry in &invalid {
+                println!("Skipped line {}: {:?}", entry.line, entry.value);
+            }
+            println!(
+                "Loaded {} addresses, {} invalid",
+                condition.wallets.len(),
+                invalid.len()
+            );
+            let gate = Gate {
+                role_id: *role,
+                condition: Box::new(condition),
+                enabled: true,
+                grant_message: None,
+            };
+            println!("{}:{:?}", gate.identifier(), gate);
+            match CONFIG.wait().storage.storage_type {
+                StorageType::Unencrypted => SledUnencryptedStorage::new()
+                    .add_gate(guild, gate)
+                    .expect("Failed to add gate"),
+                StorageType::Encrypted => SledEncryptedStorage::new()
+                    .add_gate(guild, gate)
+                    .expect("Failed to add gate"),
+                StorageType::InMemory => {
+                    panic!("InMemory storage does not make sense for this command")
+                }
+            };
+        }
+
+        Some(Commands::Storage {
+            cmd:
+                StorageCmd::Gate(GateCmd::AddGlobal {
+                    role_name,
+                    gate_type,
+                    options,
+                }),
+            snapshot,
+        }) => {
+            deny_snapshot(snapshot);
+            let options = gate::parse_gate_options(gate_type, options)
+                .unwrap_or_else(|why| panic!("Invalid gate options: {:?}", why));
+            let rt = tokio::runtime::Builder::new_current_thread()
+                .enable_all()
+                .build()
+                .expect("Failed to build tokio runtime");
+            let gate = rt
+                .block_on(Gate::new(0, gate_type, &options))
+                .expect("Failed to create gate");
+            let gate = GlobalGate::new(role_name.clone(), gate.condition);
+            println!("{}:{:?}", gate.identifier(), gate);
+            match CONFIG.wait().storage.storage_type {
+                StorageType::Unencrypted => SledUnencryptedStorage::new()
+                    .add_global_gate(gate)
+                    .expect("Failed to add global gate"),
+                StorageType::Encrypted => SledEncryptedStorage::new()
+                    .add_global_gate(gate)
+                    .expect("Failed to add global gate"),
+                StorageType::InMemory => {
+                    panic!("InMemory storage does not make sense for this command")
+                }
+            };
+        }
+
+        Some(Commands::Storage {
+            cmd:
+                StorageCmd::Gate(GateCmd::Move {
+                    guild,
+                    identifier,
+                    new_role,
+                }),
+            snapshot,
+        }) => {
+            deny_snapshot(snapshot);
+            let moved = match CONFIG.wait().storage.storage_type {
+                StorageType::Unencrypted => controller::move_gate(
+                    &mut SledUnencryptedStorage::new(),
+                    *guild,
+                    *identifier,
+                    *new_role,
+                ),
+                StorageType::Encrypted => controller::move_gate(
+                    &mut SledEncryptedStorage::new(),
+                    *guild,
+                    *identifier,
+                    *new_role,
+                ),
+                StorageType::InMemory => {
+                    panic!("InMemory storage does not make sense for this command")
+                }
+            }
+            .expect("Failed to move gate");
+            match moved {
+                Some(gate) => {
+                    println!("Moved gate {} to role {}", identifier, new_role);
+                    println!("{}:{:?}", gate.identifier(), gate);
+                }
+                None => {
+                    eprintln!("No gate {} found in guild {}", identifier, guild);
+                    std::process::exit(1);
+                }
+            }
+        }
+
+        Some(Commands::Storage {
+            cmd: StorageCmd::Gate(GateCmd::RemoveGlobal { identifier }),
+            snapshot,
+        }) => {
+            deny_snapshot(snapshot);
+            match CONFIG.wait().storage.storage_type {
+                StorageType::Unencrypted => SledUnencryptedStorage::new()
+                    .remove_global_gate(*identifier)
+                    .expect("Failed to remove global gate"),
+                StorageType::Encrypted => SledEncryptedStorage::new()
+                    .remove_global_gate(*identifier)
+                    .expect("Failed to remove global gate"),
+                StorageType::InMemory => {
+                    panic!("InMemory storage does not make sense for this command")
+                }
+            };
+        }
+
+        Some(Commands::Storage {
+            cmd: StorageCmd::Migrate,
+            snapshot,
+        }) => {
+            deny_snapshot(snapshot);
+            let migrated = match CONFIG.wait().storage.storage_type {
+                StorageType::Unencrypted => SledUnencryptedStorage::new()
+                    .migrate_gates()
+                    .expect("Failed to migrate gates"),
+                StorageType::Encrypted => SledEncryptedStorage::new()
+                    .migrate_gates()
+                    .expect("Failed to migrate gates"),
+                StorageType::InMemory => {
+                    panic!("InMemory storage does not make sense for this command")
+                }
+            };
+            println!("Migrated {} gates", migrated);
+        }
+
+        Some(Commands::Storage {
+            cmd: StorageCmd::Stats,
+            snapshot,
+        }) => {
+            let (path, user_path) = storage_paths(snapshot);
+            let stats = match CONFIG.wait().storage.storage_type {
+                StorageType::Unencrypted => {
+                    SledUnencryptedStorage::open_with_users(&path, &user_path)
+                        .registration_stats()
+                        .expect("Failed to get stats")
+                }
+                StorageType::Encrypted => SledEncryptedStorage::open_with_users(&path, &user_path)
+                    .registration_stats()
+                    .expect("Failed to get stats"),
+                StorageType::InMemory => {
+                    panic!("InMemory storage does not make sense for this command")
+                }
+            };
+            println!("Registrations: {}", stats.registrations);
+            println!("Unregistrations: {}", stats.unregistrations);
+        }
+
+        Some(Commands::Storage {
+            cmd: StorageCmd::Verify,
+            snapshot,
+        }) => {
+            let (path, user_path) = storage_paths(snapshot);
+            let encrypted = match CONFIG.wait().storage.storage_type {
+                StorageType::Unencrypted => false,
+                StorageType::Encrypted => true,
+                StorageType::InMemory => {
+                    panic!("InMemory storage does not make sense for this command")
+                }
+            };
+            let report =
+                storage::verify(&path, &user_path, encrypted).expect("Failed to verify storage");
+            println!(
+                "Gates: {} readable, {} unreadable",
+                report.gates, report.unreadable_gates
+            );
+            println!(
+                "Users: {} readable, {} unreadable",
+                report.users, report.unreadable_users
+            );
+            if !report.is_ok() {
+                std::process::exit(1);
+            }
+        }
+
         Some(Commands::Slash(SlashCommands::Register(RegisterCmd::Global))) => {
             let rt = tokio::runtime::Builder::new_current_thread()
                 .enable_all()
@@ -269,20 +575,104 @@ pub fn execute(cli: &Cli) {
                 .expect("Failed to build tokio runtime");
             let controller: Controller<SledEncryptedStorage> = Controller::new();
             let wallet = controller
-                .storage
+                .read_storage()
                 .get_user(user_id)
                 .expect("Failed to get user");
             let gates = controller
-                .storage
+                .read_storage()
                 .list_gates(guild_id)
                 .expect("Failed to list gates");
-            let roles = rt.block_on(controller::check_with_wallet(wallet[0].clone(), gates));
-            println!("Roles: {:?}", roles);
+            let outcome = rt.block_on(controller::check_with_wallets(wallet, gates));
+            println!("Roles: {:?}", outcome.granted);
+            for role_id in outcome.granted.iter() {
+                if let Some(wallet) = outcome.wallets.get(role_id) {
+                    println!("  {} met with {:?}", role_id, wallet);
+                }
+            }
+            if !outcome.errored.is_empty() {
+                println!("Could not check: {:?}", outcome.errored);
+            }
+            if !outcome.panicked.is_empty() {
+                println!("Gates panicked: {:?}", outcome.panicked);
+            }
+        }
+
+        Some(Commands::VerifySignature {
+            address,
+            message,
+            signature,
+        }) => {
+            let check = server::check_signature(address, message, signature)
+                .expect("Failed to parse the address or signature");
+            match check.recovered {
+                Some(recovered) => println!("Recovered address: {:?}", recovered),
+                None => println!("Recovered address: none"),
+            }
+            println!("Matches {}: {}", address, check.matches);
+            if !check.matches {
+                std::process::exit(1);
+            }
         }
 
-        Some(Commands::Batch { guild_id, user_ids }) => {
-            let guild_id = *guild_id;
+        Some(Commands::Batch {
+            all_guilds: true, ..
+        }) => {
+            let rt = tokio::runtime::Builder::new_multi_thread()
+                .enable_all()
+                .build()
+                .expect("Failed to build tokio runtime");
+            let controller: Controller<SledEncryptedStorage> = Controller::new();
+            let message_tx = controller.message_tx.clone();
+            rt.spawn(controller.spawn());
+            #[cfg(unix)]
+            rt.spawn(controller::pause_enforcement_on_signals());
+            let (response_tx, mut response_rx) = tokio::sync::mpsc::channel(100);
+            let span = tracing::info_span!("BatchAllGuilds");
+            rt.spawn(async move {
+                message_tx
+                    .send(Message::BatchAllGuilds { response_tx, span })
+                    .await
+                    .expect("Failed to send batch message to controller");
+            });
+            rt.block_on(async move {
+                let mut current_guild = None;
+                let mut summary = EnforcementSummary::default();
+                while let Some(response) = response_rx.recv().await {
+                    match response {
+                        GuildBatchResponse::Grant {
+                            guild_id,
+                            user_id,
+                            outcome,
+                        } => {
+                            if current_guild != Some(guild_id) {
+                                println!("Guild: {}", guild_id);
+                                current_guild = Some(guild_id);
+                            }
+                            println!(
+                                "  User: {}, Roles: {:?}, Could not check: {:?}",
+                                user_id, outcome.granted, outcome.errored
+                            );
+                            summary.add_outcome(&outcome);
+                        }
+                        GuildBatchResponse::Done => {
+                            print_summary(&summary);
+                            break;
+                        }
+                    }
+                }
+            });
+        }
+
+        Some(Commands::Batch {
+            guild_id,
+            user_ids,
+            force,
+            ..
+        }) => {
+            let guild_id = guild_id.expect("The guild id is required without --all-guilds");
             let user_ids = user_ids.clone();
+            let requested = user_ids.len();
+            let force = *force;
             let rt = tokio::runtime::Builder::new_multi_thread()
                 .enable_all()
                 .build()
@@ -297,6 +687,11 @@ pub fn execute(cli: &Cli) {
                     .send(Message::Batch {
                         guild_id,
                         user_ids,
+                        force,
+                        bypass_cache: false,
+                        held_roles: HashMap::new(),
+                        report_unregistered: false,
+                        role_id: None,
                         response_tx,
                         span,
                     })
@@ -304,13 +699,21 @@ pub fn execute(cli: &Cli) {
                     .expect("Failed to send batch message to controller");
             });
             rt.block_on(async move {
+                let mut summary = EnforcementSummary::default();
                 while let Some(response) = response_rx.recv().await {
                     match response {
-                        BatchResponse::Grant { user_id, roles } => {
-                            println!("User: {}, Roles: {:?}", user_id, roles);
+                        BatchResponse::Grant { user_id, outcome } => {
+                            println!(
+                                "User: {}, Roles: {:?}, Could not check: {:?}",
+                                user_id, outcome.granted, outcome.errored
+                            );
+                            summary.add_outcome(&outcome);
                         }
+                        BatchResponse::Unregistered { .. } => {}
+                        BatchResponse::Frozen => println!("Gating is frozen in this guild"),
                         BatchResponse::Done => {
-                            println!("Done");
+                            summary.skip(requested.saturating_sub(summary.processed));
+                            print_summary(&summary);
                             break;
                         }
                     }
@@ -341,10 +744,75 @@ pub fn execute(cli: &Cli) {
                     rt.spawn(Controller::<SledEncryptedStorage>::init())
                 }
             };
+            // switching the discord client live would need a restart, so
+            // during scheduled windows the handler answers in maintenance mode
+            rt.spawn(maintenance::watch_windows());
+            #[cfg(unix)]
+            rt.spawn(controller::rotate_session_key_on_hangup());
+            #[cfg(unix)]
+            rt.spawn(controller::pause_enforcement_on_signals());
             rt.spawn(discord::start());
+            rt.spawn(server::mark_ready());
             if let Err(err) = rt.block_on(server::start()) {
                 eprintln!("Error: {}", err);
             }
         }
     }
 }
+
+/// Returns the paths of the database and the user database to use for read
+/// only commands. If a snapshot path is given, the databases are copied there
+/// first, so the command does not need the locks held by a running bot. A
+/// separate user database is copied next to it, with a `-users` suffix
+fn storage_paths(snapshot: &Option<PathBuf>) -> (PathBuf, PathBuf) {
+    let storage = &CONFIG.wait().storage;
+    let directory = &storage.directory;
+    let user_directory = storage.user_directory();
+    match snapshot {
+        Some(snapshot) if user_directory == directory => {
+            info!(?snapshot, "Creating snapshot of the database");
+            storage::snapshot(directory, snapshot).expect("Failed to create snapshot");
+            (snapshot.clone(), snapshot.clone())
+        }
+        Some(snapshot) => {
+            info!(?snapshot, "Creating snapshot of the databases");
+            let mut user_snapshot = snapshot.clone().into_os_string();
+            user_snapshot.push("-users");
+            let user_snapshot = PathBuf::from(user_snapshot);
+            storage::snapshot(directory, snapshot).expect("Failed to create snapshot");
+            storage::snapshot(user_directory, &user_snapshot)
+                .expect("Failed to create snapshot of the user database");
+            (snapshot.clone(), user_snapshot)
+        }
+        None => (directory.clone(), user_directory.to_path_buf()),
+    }
+}
+
+/// Commands that write to the database must not run on a snapshot, since the
+/// changes would be lost
+fn deny_snapshot(snapshot: &Option<PathBuf>) {
+    if snapshot.is_some() {
+        panic!("Writes require exclusive access to the database, --snapshot only works with read only commands");
+    }
+}
+
+fn print_global_gates(storage: &impl Storage) {
+    let gates = storage
+        .list_global_gates()
+        .expect("Failed to list global gates");
+    if !gates.is_empty() {
+        println!("\nGlobal:");
+        for gate in gates {
+            println!("{}:{:?}", gate.identifier(), gate);
+        }
+    }
+}
+
+/// Prints the summary of a batch as a single JSON line, so scripts can pick
+/// it up after the per user lines
+fn print_summary(summary: &EnforcementSummary) {
+    println!(
+        "{}",
+        serde_json::to_string(summary).expect("Failed to serialize the summary")
+    );
+}
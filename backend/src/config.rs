@@ -2,13 +2,21 @@
 //! OnceCell
 //!
 
-use crate::cli::{CliConfig, StorageType};
+use crate::cli::{
+    parse_address, parse_bind, parse_maintenance_window, CliConfig, FailureVisibility, StorageType,
+};
 use crate::logging::LogLevel;
+use actix_web::http::Uri;
+use colony_rs::H160;
 use confique::{toml, toml::FormatOptions, Config, File, FileFormat, Partial};
 use once_cell::sync::OnceCell;
 use secrecy::SecretString;
-use serde::Deserialize;
-use std::{path::PathBuf, str::FromStr};
+use serde::{Deserialize, Serialize, Serializer};
+use serenity::model::permissions::Permissions;
+use std::{
+    path::{Path, PathBuf},
+    str::FromStr,
+};
 
 /// The global configuration is loaded into a global static OnceCell
 /// and can be accessed from there by all parts of the application
@@ -16,20 +24,88 @@ pub static CONFIG: OnceCell<GlobalConfig> = OnceCell::new();
 
 /// The main configuration struct used by the entire application
 /// it is constructed from the partial configurations from different sources
-#[derive(Clone, Config, Debug, Deserialize)]
+#[derive(Clone, Config, Debug, Deserialize, Serialize)]
 pub struct GlobalConfig {
     /// The path to the configuration file, specifiying this in the config
     /// file itself does not have any effect since the config file is loaded
     /// already
     #[config(env = "CLNY_CONFIG_FILE", default = "config.toml")]
     pub config_file: PathBuf,
-    /// The time it takes for a session to expire in seconds
+    /// The time it takes for a session to expire in seconds. Deprecated, only
+    /// used for the sessions whose own lifetime is not set, see
+    /// `register_session_secs` and `unregister_session_secs`
     #[config(env = "CLNY_SESSION_EXPIRATION", default = 60)]
     pub session_expiration: u64,
+    /// The time it takes for a registration link to expire in seconds,
+    /// users may take a while to set up their wallet
+    #[config(env = "CLNY_REGISTER_SESSION_SECS")]
+    pub register_session_secs: Option<u64>,
+    /// The time it takes for an unregistration link to expire in seconds,
+    /// it deletes the user so it should be short lived
+    #[config(env = "CLNY_UNREGISTER_SESSION_SECS")]
+    pub unregister_session_secs: Option<u64>,
     /// Start the bot in maintenance mode, this will do nothing except telling
     /// discord users that the bot is in maintenance mode
     #[config(env = "CLNY_MAINTENANCE", default = false)]
     pub maintenance: bool,
+    /// Scheduled maintenance windows as `[start, end]` unix timestamps.
+    /// During a window the bot answers all commands with a maintenance notice
+    #[config(
+        env = "CLNY_MAINTENANCE_WINDOWS",
+        parse_env = parse_maintenance_windows,
+        default = []
+    )]
+    pub maintenance_windows: Vec<(u64, u64)>,
+    /// The maximum number of colony client calls, e.g. balance or reputation
+    /// lookups, that are sent to the chain at the same time
+    #[config(env = "CLNY_RPC_CONCURRENCY", default = 32)]
+    pub rpc_concurrency: usize,
+    /// Users that were checked in a guild less than this many seconds ago are
+    /// skipped by the enforcement, unless it is forced. 0 always checks all
+    /// users
+    #[config(env = "CLNY_CHECK_FRESHNESS", default = 0)]
+    pub check_freshness: u64,
+    /// A user that used `/get in` in a guild less than this many seconds ago
+    /// gets the result of the last check again, without checking the gates.
+    /// Changes of the gates show once it is over. 0 always checks the gates
+    #[config(env = "CLNY_CHECK_COOLDOWN", default = 0)]
+    pub check_cooldown: u64,
+    /// A role is only revoked by the enforcement once the user failed its
+    /// gate for this many seconds, so a short dip in reputation or balance
+    /// does not take the role away. 0 revokes right away
+    #[config(env = "CLNY_REVOKE_GRACE_PERIOD", default = 0)]
+    pub revoke_grace_period: u64,
+    /// The maximum number of wallets a user can link, registering another
+    /// wallet beyond this is rejected. 0 allows any number of wallets
+    #[config(env = "CLNY_MAX_WALLETS_PER_USER", default = 0)]
+    pub max_wallets_per_user: usize,
+    /// Start even if the stored data was written by a newer version of the
+    /// bot. Data the running version can not decode may get lost this way
+    #[config(env = "CLNY_FORCE", default = false)]
+    pub force: bool,
+    /// The colony addresses reputation gates may be created for. Empty
+    /// allows all colonies
+    #[config(
+        env = "CLNY_ALLOWED_COLONIES",
+        parse_env = parse_addresses,
+        default = []
+    )]
+    pub allowed_colonies: Vec<H160>,
+    /// The token addresses token gates may be created for. Empty allows all
+    /// tokens
+    #[config(env = "CLNY_ALLOWED_TOKENS", parse_env = parse_addresses, default = [])]
+    pub allowed_tokens: Vec<H160>,
+    /// A colony whose domain count is looked up at startup, `/readyz` only
+    /// reports ready once the lookup succeeds, so the first checks after a
+    /// cold start do not fail while the rpc is unreachable. Without a colony
+    /// the bot is ready right away
+    #[config(env = "CLNY_READINESS_PROBE_COLONY")]
+    pub readiness_probe_colony: Option<H160>,
+    /// How many seconds the startup waits for the readiness probe to
+    /// succeed, afterwards the bot reports ready anyway
+    #[config(env = "CLNY_READINESS_PROBE_TIMEOUT", default = 120)]
+    pub readiness_probe_timeout: u64,
+    /// The configuration of logging and tracing
     #[config(nested)]
     pub observability: ObservabilityConfig,
     /// The discord configuration
@@ -43,7 +119,8 @@ pub struct GlobalConfig {
     pub storage: StorageConfig,
 }
 
-#[derive(Clone, Config, Debug, Deserialize)]
+/// The sub configuration for logging and tracing
+#[derive(Clone, Config, Debug, Deserialize, Serialize)]
 pub struct ObservabilityConfig {
     /// The log level, can be one of: Off, Error, Warn, Info, Debug, Trace
     #[config(env = "CLNY_VERBOSITY", parse_env = parse_from_env::<LogLevel>, default = "Error")]
@@ -55,9 +132,10 @@ pub struct ObservabilityConfig {
 }
 
 /// The sub configuration for the http server
-#[derive(Clone, Config, Debug, Deserialize)]
+#[derive(Clone, Config, Debug, Deserialize, Serialize)]
 pub struct ServerConfig {
-    /// The base url under which the server is reachable
+    /// The public base url under which the server is reachable, this can
+    /// differ from host and port e.g. when running behind a reverse proxy
     #[config(env = "CLNY_URL", default = "http://localhost:8080")]
     pub url: String,
     /// The address to listen on
@@ -66,28 +144,189 @@ pub struct ServerConfig {
     /// The port to listen on
     #[config(env = "CLNY_PORT", default = 8080)]
     pub port: u16,
+    /// The addresses to listen on as `host:port`, e.g. an internal and an
+    /// external interface. Host and port are used if this is empty
+    #[config(env = "CLNY_BINDS", parse_env = parse_binds, default = [])]
+    pub binds: Vec<String>,
+    /// Listen on a unix domain socket at this path instead of host and port,
+    /// e.g. behind a local reverse proxy. Only supported on unix
+    #[config(env = "CLNY_UNIX_SOCKET")]
+    pub unix_socket: Option<PathBuf>,
+    /// Offer a deep link on the registration page, that opens the page in
+    /// the browser of a mobile wallet app
+    #[config(env = "CLNY_MOBILE_DEEP_LINK", default = false)]
+    pub mobile_deep_link: bool,
+    /// The message users sign with their wallet to register. It must contain
+    /// the `{username}` and `{session}` placeholders
+    #[config(
+        env = "CLNY_REGISTRATION_MESSAGE",
+        default = "Please sign this message to connect your Discord username {username} with your wallet address. Session ID: {session}"
+    )]
+    pub registration_message: String,
+    /// The `Content-Security-Policy` sent with every page, empty sends none.
+    /// The default allows the bundled script, wallet extensions inject their
+    /// provider from their own scripts, which the policy does not restrict
+    #[config(
+        env = "CLNY_CONTENT_SECURITY_POLICY",
+        default = "default-src 'self'; script-src 'self'; style-src 'self' https://assets.website-files.com; img-src 'self' data: https://assets.website-files.com; font-src 'self' https://assets.website-files.com; connect-src 'self'; form-action 'self'; frame-ancestors 'none'; base-uri 'none'"
+    )]
+    pub content_security_policy: String,
+    /// The `Referrer-Policy` sent with every page, empty sends none. The
+    /// default keeps the session in the url from leaking to other sites
+    #[config(env = "CLNY_REFERRER_POLICY", default = "no-referrer")]
+    pub referrer_policy: String,
 }
 
 /// The sub configuration for storage and encryption
-#[derive(Clone, Config, Debug, Deserialize)]
+#[derive(Clone, Config, Debug, Deserialize, Serialize)]
 pub struct StorageConfig {
     /// The path where the persistent data is stored
     #[config(env = "CLNY_STORAGE_DIRECTORY", default = "./data")]
     pub directory: PathBuf,
+    /// The path where the users and their wallets are stored, e.g. on a
+    /// separate encrypted volume. Defaults to the directory above. Users that
+    /// were stored before this was set are not moved, the bot refuses to
+    /// start while they are still in the directory above
+    #[config(env = "CLNY_USER_STORAGE_DIRECTORY")]
+    pub user_directory: Option<PathBuf>,
+    /// Sets the data of this bot apart from other bots that store their data
+    /// in the same directory, e.g. for different discord applications. Empty
+    /// uses the data stored without a namespace
+    #[config(env = "CLNY_STORAGE_NAMESPACE", default = "")]
+    pub namespace: String,
     /// How to store data, on disk or in memory
     #[config(env = "CLNY_STORAGE_TYPE",parse_env = parse_from_env::<StorageType>,  default = "Encrypted")]
     pub storage_type: StorageType,
     /// The encryption_key used to encrypt the stored data
     #[config(env = "CLNY_ENCRYPTION_KEY")]
+    #[serde(serialize_with = "redact")]
     pub key: SecretString,
+    /// Previous encryption keys, which are only used to decrypt data that was
+    /// not yet written again with the current key, e.g. during a key rotation
+    #[config(
+        env = "CLNY_LEGACY_ENCRYPTION_KEYS",
+        parse_env = parse_legacy_keys,
+        default = []
+    )]
+    #[serde(serialize_with = "redact_all")]
+    pub legacy_keys: Vec<SecretString>,
+    /// Keep a tombstone with the time of the removal when a user unregisters,
+    /// so the bot remembers that the user opted out and `/get in` doesn't urge
+    /// them to register again. The wallets are removed either way
+    #[config(env = "CLNY_SOFT_DELETE_USERS", default = false)]
+    pub soft_delete_users: bool,
+}
+
+impl GlobalConfig {
+    /// The lifetime of registration sessions, `session_expiration` unless
+    /// it is set
+    pub fn register_session_secs(&self) -> u64 {
+        self.register_session_secs
+            .unwrap_or(self.session_expiration)
+    }
+
+    /// The lifetime of unregistration sessions, `session_expiration` unless
+    /// it is set
+    pub fn unregister_session_secs(&self) -> u64 {
+        self.unregister_session_secs
+            .unwrap_or(self.session_expiration)
+    }
+}
+
+impl StorageConfig {
+    /// The path of the user database, the storage directory unless a
+    /// separate one is configured
+    pub fn user_directory(&self) -> &Path {
+        self.user_directory.as_deref().unwrap_or(&self.directory)
+    }
 }
 
 /// The sub configuration for discord interaction
-#[derive(Clone, Config, Debug, Deserialize)]
+#[derive(Clone, Config, Debug, Deserialize, Serialize)]
 pub struct DiscordConfig {
     /// The discord bot token
     #[config(env = "CLNY_DISCORD_TOKEN")]
+    #[serde(serialize_with = "redact")]
     pub token: SecretString,
+    /// The color of embeds in bot messages as hex string, e.g. "#289BDC"
+    #[config(env = "CLNY_EMBED_COLOR", default = "#289BDC")]
+    pub embed_color: String,
+    /// The member permission required by default to use the `/gate` command,
+    /// e.g. MANAGE_GUILD or MANAGE_ROLES. With NONE, every member can use it
+    /// unless restricted by discord's per command permission overrides
+    #[config(env = "CLNY_GATE_PERMISSION", default = "MANAGE_GUILD")]
+    pub gate_permission: String,
+    /// The url behind the invite button on the index page. If left empty, it
+    /// is generated from the application id of the bot
+    #[config(env = "CLNY_INVITE_URL", default = "")]
+    pub invite_url: String,
+    /// Enforce the role of a gate right after the gate is deleted in discord,
+    /// so members that only qualified through the deleted gate lose the role
+    /// immediately instead of with the next enforcement
+    #[config(env = "CLNY_REVOKE_ON_DELETE", default = false)]
+    pub revoke_on_delete: bool,
+    /// Remove the gated roles of a user right after they unregistered with
+    /// `/get out`. Without it only the wallet is unlinked and the roles are
+    /// kept, e.g. during an event. The enforcement does not remove them
+    /// either, it only reports such members with `report_unregistered`
+    #[config(env = "CLNY_UNREGISTER_REVOKES_ROLES", default = true)]
+    pub unregister_revokes_roles: bool,
+    /// How often fetching a page of guild members is retried after it failed
+    /// or timed out, with a doubling backoff between the attempts
+    #[config(env = "CLNY_MEMBER_FETCH_RETRIES", default = 3)]
+    pub member_fetch_retries: u32,
+    /// How many seconds fetching a page of guild members may take before the
+    /// attempt is given up
+    #[config(env = "CLNY_MEMBER_FETCH_TIMEOUT", default = 30)]
+    pub member_fetch_timeout: u64,
+    /// Leave bot accounts out of `/gate enforce`, so their roles are not
+    /// checked or removed
+    #[config(env = "CLNY_ENFORCE_SKIP_BOTS", default = true)]
+    pub enforce_skip_bots: bool,
+    /// User ids that `/gate enforce` leaves out, e.g. service accounts that
+    /// hold gated roles without a wallet
+    #[config(
+        env = "CLNY_ENFORCE_EXCLUDED_USERS",
+        parse_env = parse_user_ids,
+        default = []
+    )]
+    pub enforce_excluded_users: Vec<u64>,
+    /// After how many seconds without a result `/get in` tells the user that
+    /// the check is still running. The result is still delivered afterwards
+    #[config(env = "CLNY_CHECK_NOTICE_TIMEOUT", default = 10)]
+    pub check_notice_timeout: u64,
+    /// How many follow up messages, e.g. the reports of an enforcement, are
+    /// sent per second at most across all guilds. 0 sends them unpaced
+    #[config(env = "CLNY_MESSAGES_PER_SECOND", default = 5)]
+    pub messages_per_second: u32,
+    /// Who sees the result of `/get in` when a role could not be granted:
+    /// Public posts it to the channel, AdminPing shows it only to the user
+    /// and pings the server owner about the roles, Ephemeral only shows it
+    /// to the user
+    #[config(
+        env = "CLNY_GRANT_FAILURE_VISIBILITY",
+        parse_env = parse_from_env::<FailureVisibility>,
+        default = "Public"
+    )]
+    pub grant_failure_visibility: FailureVisibility,
+    /// What `/get in` says after mentioning the user when no role was granted
+    /// and no check failed, e.g. with a link to what the gates require
+    #[config(
+        env = "CLNY_NO_ROLES_MESSAGE",
+        default = "used the `/get in` but sadly, didn't get any roles yet 😢"
+    )]
+    pub no_roles_message: String,
+    /// A JSON file with translations of the names and descriptions of the
+    /// `/gate` and `/get` commands and their options, keyed by discord
+    /// locale, e.g. "de", and the path of the command or option, e.g.
+    /// "get in". Without it the commands are only offered in English
+    #[config(env = "CLNY_COMMAND_LOCALIZATIONS")]
+    pub command_localizations: Option<PathBuf>,
+    /// The channel that is told when the bot lacks the permission to manage
+    /// a gated role in a guild, e.g. an ops channel of the bot operator.
+    /// Each role is reported at most once an hour, 0 disables the alerts
+    #[config(env = "CLNY_PERMISSION_ALERT_CHANNEL", default = 0)]
+    pub permission_alert_channel: u64,
 }
 
 /// Partial configuration used to construct the final configuration
@@ -153,8 +392,70 @@ impl std::fmt::Debug for PrintablePartialConf {
             "session_expiration", self.global.session_expiration
         ));
         s.push('\n');
+        s.push_str(&format!(
+            "{}: {:?}",
+            "register_session_secs", self.global.register_session_secs
+        ));
+        s.push('\n');
+        s.push_str(&format!(
+            "{}: {:?}",
+            "unregister_session_secs", self.global.unregister_session_secs
+        ));
+        s.push('\n');
         s.push_str(&format!("{}: {:?}", "maintenance", self.global.maintenance));
         s.push('\n');
+        s.push_str(&format!(
+            "{}: {:?}",
+            "maintenance_windows", self.global.maintenance_windows
+        ));
+        s.push('\n');
+        s.push_str(&format!(
+            "{}: {:?}",
+            "rpc_concurrency", self.global.rpc_concurrency
+        ));
+        s.push('\n');
+        s.push_str(&format!(
+            "{}: {:?}",
+            "check_freshness", self.global.check_freshness
+        ));
+        s.push('\n');
+        s.push_str(&format!(
+            "{}: {:?}",
+            "check_cooldown", self.global.check_cooldown
+        ));
+        s.push('\n');
+        s.push_str(&format!(
+            "{}: {:?}",
+            "revoke_grace_period", self.global.revoke_grace_period
+        ));
+        s.push('\n');
+        s.push_str(&format!(
+            "{}: {:?}",
+            "max_wallets_per_user", self.global.max_wallets_per_user
+        ));
+        s.push('\n');
+        s.push_str(&format!("{}: {:?}", "force", self.global.force));
+        s.push('\n');
+        s.push_str(&format!(
+            "{}: {:?}",
+            "allowed_colonies", self.global.allowed_colonies
+        ));
+        s.push('\n');
+        s.push_str(&format!(
+            "{}: {:?}",
+            "allowed_tokens", self.global.allowed_tokens
+        ));
+        s.push('\n');
+        s.push_str(&format!(
+            "{}: {:?}",
+            "readiness_probe_colony", self.global.readiness_probe_colony
+        ));
+        s.push('\n');
+        s.push_str(&format!(
+            "{}: {:?}",
+            "readiness_probe_timeout", self.global.readiness_probe_timeout
+        ));
+        s.push('\n');
         s.push_str(&format!("{}: {:?}", "observability", &self.observability));
         s.push('\n');
         s.push_str(&format!("{}: {:?}", "discord", &self.discord));
@@ -193,6 +494,30 @@ impl std::fmt::Debug for PrintablePartialServerConf {
         s.push_str(&format!(" {}: {:?}", "host", self.0.host));
         s.push('\n');
         s.push_str(&format!(" {}: {:?}", "port", self.0.port));
+        s.push('\n');
+        s.push_str(&format!(" {}: {:?}", "binds", self.0.binds));
+        s.push('\n');
+        s.push_str(&format!(" {}: {:?}", "unix_socket", self.0.unix_socket));
+        s.push('\n');
+        s.push_str(&format!(
+            " {}: {:?}",
+            "mobile_deep_link", self.0.mobile_deep_link
+        ));
+        s.push('\n');
+        s.push_str(&format!(
+            " {}: {:?}",
+            "registration_message", self.0.registration_message
+        ));
+        s.push('\n');
+        s.push_str(&format!(
+            " {}: {:?}",
+            "content_security_policy", self.0.content_security_policy
+        ));
+        s.push('\n');
+        s.push_str(&format!(
+            " {}: {:?}",
+            "referrer_policy", self.0.referrer_policy
+        ));
 
         write!(f, "{}", s)
     }
@@ -203,8 +528,18 @@ impl std::fmt::Debug for PrintablePartialStorageConf {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
         let mut s = String::new();
         s.push_str(&format!("\n {}: {:?}\n", "directory", self.0.directory));
+        s.push_str(&format!(
+            " {}: {:?}\n",
+            "user_directory", self.0.user_directory
+        ));
+        s.push_str(&format!(" {}: {:?}\n", "namespace", self.0.namespace));
         s.push_str(&format!(" {}: {:?}\n", "storage_type", self.0.storage_type));
         s.push_str(&format!(" {}: {:?}\n", "key", self.0.key));
+        s.push_str(&format!(" {}: {:?}\n", "legacy_keys", self.0.legacy_keys));
+        s.push_str(&format!(
+            " {}: {:?}\n",
+            "soft_delete_users", self.0.soft_delete_users
+        ));
 
         write!(f, "{}", s)
     }
@@ -215,6 +550,60 @@ impl std::fmt::Debug for PrintablePartialDiscordConf {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
         let mut s = String::new();
         s.push_str(&format!("\n {}: {:?}", "token", self.0.token));
+        s.push_str(&format!("\n {}: {:?}", "embed_color", self.0.embed_color));
+        s.push_str(&format!(
+            "\n {}: {:?}",
+            "gate_permission", self.0.gate_permission
+        ));
+        s.push_str(&format!("\n {}: {:?}", "invite_url", self.0.invite_url));
+        s.push_str(&format!(
+            "\n {}: {:?}",
+            "revoke_on_delete", self.0.revoke_on_delete
+        ));
+        s.push_str(&format!(
+            "\n {}: {:?}",
+            "unregister_revokes_roles", self.0.unregister_revokes_roles
+        ));
+        s.push_str(&format!(
+            "\n {}: {:?}",
+            "member_fetch_retries", self.0.member_fetch_retries
+        ));
+        s.push_str(&format!(
+            "\n {}: {:?}",
+            "member_fetch_timeout", self.0.member_fetch_timeout
+        ));
+        s.push_str(&format!(
+            "\n {}: {:?}",
+            "enforce_skip_bots", self.0.enforce_skip_bots
+        ));
+        s.push_str(&format!(
+            "\n {}: {:?}",
+            "enforce_excluded_users", self.0.enforce_excluded_users
+        ));
+        s.push_str(&format!(
+            "\n {}: {:?}",
+            "check_notice_timeout", self.0.check_notice_timeout
+        ));
+        s.push_str(&format!(
+            "\n {}: {:?}",
+            "messages_per_second", self.0.messages_per_second
+        ));
+        s.push_str(&format!(
+            "\n {}: {:?}",
+            "grant_failure_visibility", self.0.grant_failure_visibility
+        ));
+        s.push_str(&format!(
+            "\n {}: {:?}",
+            "no_roles_message", self.0.no_roles_message
+        ));
+        s.push_str(&format!(
+            "\n {}: {:?}",
+            "command_localizations", self.0.command_localizations
+        ));
+        s.push_str(&format!(
+            "\n {}: {:?}",
+            "permission_alert_channel", self.0.permission_alert_channel
+        ));
         write!(f, "{}", s)
     }
 }
@@ -234,10 +623,76 @@ pub fn setup_config(raw_cli_cfg: &CliConfig) -> Result<(), String> {
         .with_fallback(file)
         .with_fallback(default);
     let cfg = GlobalConfig::from_partial(merged).expect("Invalid configuration");
-    CONFIG.set(cfg).expect("Failed to set config");
+    set_config(cfg)
+}
+
+/// Validates the configuration and sets it globally, this can only be done
+/// once
+pub fn set_config(cfg: GlobalConfig) -> Result<(), String> {
+    validate_url(&cfg.server.url)?;
+    validate_registration_message(&cfg.server.registration_message)?;
+    parse_permission(&cfg.discord.gate_permission)?;
+    CONFIG
+        .set(cfg)
+        .map_err(|_| "The config is already set".to_string())
+}
+
+/// Makes sure the public server url is an absolute http(s) url, since all
+/// links sent to the users are built from it
+fn validate_url(url: &str) -> Result<(), String> {
+    let uri = url
+        .parse::<Uri>()
+        .map_err(|why| format!("Invalid server url {:?}: {}", url, why))?;
+    match (uri.scheme_str(), uri.host()) {
+        (Some("http") | Some("https"), Some(_)) => Ok(()),
+        _ => Err(format!(
+            "Invalid server url {:?}: expected an absolute http or https url",
+            url
+        )),
+    }
+}
+
+/// Makes sure the registration message contains the username and session,
+/// otherwise a signature could be replayed for other users or sessions
+fn validate_registration_message(template: &str) -> Result<(), String> {
+    for placeholder in ["{username}", "{session}"] {
+        if !template.contains(placeholder) {
+            return Err(format!(
+                "Invalid registration message {:?}: the {} placeholder is missing",
+                template, placeholder
+            ));
+        }
+    }
     Ok(())
 }
 
+/// Maps a permission name from the configuration to the discord permission,
+/// `NONE` means that no permission is required by default. Every permission
+/// serenity knows is accepted, either by its flag name like `MANAGE_GUILD` or
+/// by the name discord shows like `Manage Guilds`
+pub fn parse_permission(name: &str) -> Result<Option<Permissions>, String> {
+    let normalized = normalize_permission_name(name);
+    if normalized == "NONE" {
+        return Ok(None);
+    }
+    (0..u64::BITS)
+        .filter_map(|bit| Permissions::from_bits(1 << bit))
+        .find(|permission| {
+            // the debug output of the flags is the name of the constant
+            normalize_permission_name(&format!("{:?}", permission)) == normalized
+                || permission
+                    .get_permission_names()
+                    .iter()
+                    .any(|name| normalize_permission_name(name) == normalized)
+        })
+        .map(Some)
+        .ok_or_else(|| format!("Invalid gate permission {:?}", name))
+}
+
+fn normalize_permission_name(name: &str) -> String {
+    name.trim().to_uppercase().replace(' ', "_")
+}
+
 /// Prints the different sources and finally merged configuration to stdout
 pub fn print_config(raw_cli_cfg: &CliConfig) {
     let (cli_cfg, env, file, default, config_file) = get_config_hirarchy(raw_cli_cfg);
@@ -280,6 +735,36 @@ pub fn print_config(raw_cli_cfg: &CliConfig) {
     println!("\n\nMerged final config: {:#?}", cfg);
 }
 
+/// Prints only the merged final configuration as JSON to stdout, so it can be
+/// read by tooling. Secrets are replaced by `***`
+pub fn print_config_json(raw_cli_cfg: &CliConfig) {
+    let (cli_cfg, env, file, default, _) = get_config_hirarchy(raw_cli_cfg);
+    let merged = cli_cfg
+        .with_fallback(env)
+        .with_fallback(file)
+        .with_fallback(default);
+
+    let cfg = GlobalConfig::from_partial(merged).expect("Invalid configuration");
+
+    println!(
+        "{}",
+        serde_json::to_string_pretty(&cfg).expect("Could not serialize config")
+    );
+}
+
+/// What secrets are replaced with when the configuration is serialized
+const REDACTED: &str = "***";
+
+/// Serializes a secret without revealing it
+fn redact<S: Serializer>(_: &SecretString, serializer: S) -> Result<S::Ok, S::Error> {
+    serializer.serialize_str(REDACTED)
+}
+
+/// Serializes a list of secrets without revealing them, only their count
+fn redact_all<S: Serializer>(secrets: &[SecretString], serializer: S) -> Result<S::Ok, S::Error> {
+    serializer.collect_seq(secrets.iter().map(|_| REDACTED))
+}
+
 /// Gets all partial configurations from the different sources.
 /// It also does the special handling of verbose and quiet flags
 fn get_config_hirarchy(
@@ -288,6 +773,17 @@ fn get_config_hirarchy(
     let cli_cfg = PartialConf {
         config_file: raw_cli_cfg.config_file.clone(),
         maintenance: raw_cli_cfg.maintenance,
+        maintenance_windows: raw_cli_cfg.maintenance_windows.clone(),
+        rpc_concurrency: raw_cli_cfg.rpc_concurrency,
+        check_freshness: raw_cli_cfg.check_freshness,
+        check_cooldown: raw_cli_cfg.check_cooldown,
+        revoke_grace_period: raw_cli_cfg.revoke_grace_period,
+        max_wallets_per_user: raw_cli_cfg.max_wallets_per_user,
+        force: raw_cli_cfg.force.then_some(true),
+        allowed_colonies: raw_cli_cfg.allowed_colonies.clone(),
+        readiness_probe_colony: raw_cli_cfg.readiness_probe_colony,
+        readiness_probe_timeout: raw_cli_cfg.readiness_probe_timeout,
+        allowed_tokens: raw_cli_cfg.allowed_tokens.clone(),
         observability: PartialObservabilityConf {
             verbosity: match (
                 raw_cli_cfg.observability.verbose,
@@ -304,18 +800,45 @@ fn get_config_hirarchy(
             jaeger_endpoint: raw_cli_cfg.observability.jaeger_endpoint.clone(),
         },
         session_expiration: raw_cli_cfg.session_expiration,
+        register_session_secs: raw_cli_cfg.register_session_secs,
+        unregister_session_secs: raw_cli_cfg.unregister_session_secs,
         discord: PartialDiscordConf {
             token: raw_cli_cfg.discord.token.clone(),
+            embed_color: raw_cli_cfg.discord.embed_color.clone(),
+            gate_permission: raw_cli_cfg.discord.gate_permission.clone(),
+            invite_url: raw_cli_cfg.discord.invite_url.clone(),
+            revoke_on_delete: raw_cli_cfg.discord.revoke_on_delete,
+            unregister_revokes_roles: raw_cli_cfg.discord.unregister_revokes_roles,
+            member_fetch_retries: raw_cli_cfg.discord.member_fetch_retries,
+            member_fetch_timeout: raw_cli_cfg.discord.member_fetch_timeout,
+            enforce_skip_bots: raw_cli_cfg.discord.enforce_skip_bots,
+            enforce_excluded_users: raw_cli_cfg.discord.enforce_excluded_users.clone(),
+            check_notice_timeout: raw_cli_cfg.discord.check_notice_timeout,
+            messages_per_second: raw_cli_cfg.discord.messages_per_second,
+            grant_failure_visibility: raw_cli_cfg.discord.grant_failure_visibility.clone(),
+            no_roles_message: raw_cli_cfg.discord.no_roles_message.clone(),
+            command_localizations: raw_cli_cfg.discord.command_localizations.clone(),
+            permission_alert_channel: raw_cli_cfg.discord.permission_alert_channel,
         },
         server: PartialServerConf {
             url: raw_cli_cfg.server.url.clone(),
             host: raw_cli_cfg.server.host.clone(),
             port: raw_cli_cfg.server.port,
+            binds: raw_cli_cfg.server.binds.clone(),
+            mobile_deep_link: raw_cli_cfg.server.mobile_deep_link,
+            registration_message: raw_cli_cfg.server.registration_message.clone(),
+            unix_socket: raw_cli_cfg.server.unix_socket.clone(),
+            content_security_policy: raw_cli_cfg.server.content_security_policy.clone(),
+            referrer_policy: raw_cli_cfg.server.referrer_policy.clone(),
         },
         storage: PartialStorageConf {
             directory: raw_cli_cfg.storage.directory.clone(),
+            user_directory: raw_cli_cfg.storage.user_directory.clone(),
+            namespace: raw_cli_cfg.storage.namespace.clone(),
             storage_type: raw_cli_cfg.storage.storage_type.clone(),
             key: raw_cli_cfg.storage.key.clone(),
+            legacy_keys: raw_cli_cfg.storage.legacy_keys.clone(),
+            soft_delete_users: raw_cli_cfg.storage.soft_delete_users,
         },
     };
     let env = PartialConf::from_env().expect("Could not build config from env");
@@ -337,16 +860,75 @@ fn get_config_hirarchy(
 /// Prints a configuration file template to stdout, that can be used as a
 /// starting point for a custom configuration file
 pub fn print_template() {
-    println!(
-        "{}",
+    println!("{}", template());
+}
+
+/// The configuration file template with all fields and their defaults,
+/// headed by the version of the bot that generated it. Comparing it with
+/// the template of an older version shows the fields that were added since
+fn template() -> String {
+    format!(
+        "# Configuration template generated by {} {} at {}\n\n{}",
+        env!("CARGO_PKG_NAME"),
+        env!("CARGO_PKG_VERSION"),
+        serenity::model::Timestamp::now(),
         toml::template::<GlobalConfig>(FormatOptions::default())
-    );
+    )
 }
 
 fn parse_from_env<T: FromStr<Err = String>>(s: &str) -> Result<T, ConfigFromEnvError> {
     Ok(T::from_str(s)?)
 }
 
+/// Parses comma separated `start-end` maintenance windows from the environment
+fn parse_maintenance_windows(s: &str) -> Result<Vec<(u64, u64)>, ConfigFromEnvError> {
+    Ok(s.split(',')
+        .map(str::trim)
+        .filter(|window| !window.is_empty())
+        .map(parse_maintenance_window)
+        .collect::<Result<_, _>>()?)
+}
+
+/// Parses comma separated addresses from the environment
+fn parse_addresses(s: &str) -> Result<Vec<H160>, ConfigFromEnvError> {
+    Ok(s.split(',')
+        .map(str::trim)
+        .filter(|address| !address.is_empty())
+        .map(parse_address)
+        .collect::<Result<_, _>>()?)
+}
+
+/// Parses comma separated listen addresses from the environment
+fn parse_binds(s: &str) -> Result<Vec<String>, ConfigFromEnvError> {
+    Ok(s.split(',')
+        .map(str::trim)
+        .filter(|bind| !bind.is_empty())
+        .map(parse_bind)
+        .collect::<Result<_, _>>()?)
+}
+
+/// Parses comma separated user ids from the environment
+fn parse_user_ids(s: &str) -> Result<Vec<u64>, ConfigFromEnvError> {
+    Ok(s.split(',')
+        .map(str::trim)
+        .filter(|user_id| !user_id.is_empty())
+        .map(|user_id| {
+            user_id
+                .parse::<u64>()
+                .map_err(|why| format!("Invalid user id {:?}: {}", user_id, why))
+        })
+        .collect::<Result<_, _>>()?)
+}
+
+/// Parses comma separated legacy encryption keys from the environment
+fn parse_legacy_keys(s: &str) -> Result<Vec<SecretString>, ConfigFromEnvError> {
+    Ok(s.split(',')
+        .map(str::trim)
+        .filter(|key| !key.is_empty())
+        .map(|key| SecretString::new(key.to_string()))
+        .collect())
+}
+
 #[derive(Debug)]
 struct ConfigFromEnvError(String);
 
@@ -381,3 +963,142 @@ impl Default for StorageType {
         Self::Encrypted
     }
 }
+
+/// Makes sure a configured invite url is an https url pointing at discord
+pub fn validate_invite_url(url: &str) -> Result<(), String> {
+    let uri = url
+        .parse::<Uri>()
+        .map_err(|why| format!("Invalid invite url {:?}: {}", url, why))?;
+    let host = uri.host().unwrap_or_default();
+    if uri.scheme_str() != Some("https")
+        || !(host == "discord.com" || host.ends_with(".discord.com"))
+    {
+        return Err(format!(
+            "Invalid invite url {:?}: expected an https url on discord.com",
+            url
+        ));
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_template_contains_all_fields() {
+        let template = template();
+        assert!(template.starts_with("# Configuration template generated by"));
+        assert!(template.contains(env!("CARGO_PKG_VERSION")));
+        for field in GlobalConfig::META.fields {
+            assert!(!field.doc.is_empty(), "{} is not documented", field.name);
+            assert!(
+                template.contains(field.name),
+                "{} is missing in the template",
+                field.name
+            );
+        }
+    }
+
+    #[test]
+    fn test_validate_url() {
+        assert!(validate_url("http://localhost:8080").is_ok());
+        assert!(validate_url("https://gate.example.com/").is_ok());
+        assert!(validate_url("gate.example.com").is_err());
+        assert!(validate_url("ftp://gate.example.com").is_err());
+        assert!(validate_url("not a url").is_err());
+    }
+
+    #[test]
+    fn test_validate_registration_message() {
+        assert!(validate_registration_message("Sign in {username} with {session}").is_ok());
+        assert!(validate_registration_message("Sign in {username}").is_err());
+        assert!(validate_registration_message("Session: {session}").is_err());
+    }
+
+    #[test]
+    fn test_parse_permission() {
+        assert_eq!(
+            parse_permission(" manage_roles "),
+            Ok(Some(Permissions::MANAGE_ROLES))
+        );
+        assert_eq!(parse_permission("NONE"), Ok(None));
+        assert_eq!(
+            parse_permission("MANAGE_GUILD"),
+            Ok(Some(Permissions::MANAGE_GUILD))
+        );
+        assert_eq!(
+            parse_permission("Manage Guilds"),
+            Ok(Some(Permissions::MANAGE_GUILD))
+        );
+        assert_eq!(
+            parse_permission("view_audit_log"),
+            Ok(Some(Permissions::VIEW_AUDIT_LOG))
+        );
+        assert!(parse_permission("MANAGE_EVERYTHING").is_err());
+    }
+
+    #[test]
+    fn test_validate_invite_url() {
+        assert!(validate_invite_url(
+            "https://discord.com/api/oauth2/authorize?client_id=1125748696043225180&permissions=268435456&scope=bot"
+        )
+        .is_ok());
+        assert!(validate_invite_url("https://ptb.discord.com/oauth2/authorize").is_ok());
+        assert!(validate_invite_url("http://discord.com/oauth2/authorize").is_err());
+        assert!(validate_invite_url("https://notdiscord.com/oauth2/authorize").is_err());
+        assert!(validate_invite_url("garbage").is_err());
+        assert!(validate_invite_url("").is_err());
+    }
+
+    #[test]
+    fn test_parse_maintenance_windows() {
+        assert_eq!(
+            parse_maintenance_windows("100-200, 300-400").unwrap(),
+            vec![(100, 200), (300, 400)]
+        );
+        assert!(parse_maintenance_windows("").unwrap().is_empty());
+        assert!(parse_maintenance_windows("200-100").is_err());
+        assert!(parse_maintenance_windows("tomorrow").is_err());
+    }
+
+    #[test]
+    fn test_config_json_redacts_secrets() {
+        let mut partial = PartialConf::default_values();
+        partial.discord.token = Some(SecretString::new("discord-token".to_string()));
+        partial.storage.key = Some(SecretString::new("storage-key".to_string()));
+        partial.storage.legacy_keys = Some(vec![SecretString::new("old-key".to_string())]);
+        let cfg = GlobalConfig::from_partial(partial).unwrap();
+
+        let json = serde_json::to_value(&cfg).unwrap();
+        for key in [
+            "config_file",
+            "observability",
+            "discord",
+            "server",
+            "storage",
+        ] {
+            assert!(json.get(key).is_some(), "{} is missing", key);
+        }
+        assert_eq!(json["discord"]["token"], "***");
+        assert_eq!(json["storage"]["key"], "***");
+        assert_eq!(json["storage"]["legacy_keys"], serde_json::json!(["***"]));
+        assert_eq!(json["storage"]["storage_type"], "Encrypted");
+        assert_eq!(json["discord"]["gate_permission"], "MANAGE_GUILD");
+
+        let text = serde_json::to_string(&cfg).unwrap();
+        for secret in ["discord-token", "storage-key", "old-key"] {
+            assert!(!text.contains(secret), "{} is leaked", secret);
+        }
+    }
+
+    #[test]
+    fn test_parse_addresses() {
+        assert_eq!(
+            parse_addresses("0x0101010101010101010101010101010101010101, ").unwrap(),
+            vec![H160::repeat_byte(1)]
+        );
+        assert!(parse_addresses("").unwrap().is_empty());
+        assert!(parse_addresses("0x01").is_err());
+    }
+}
@@ -3,8 +3,12 @@
 //! the controller.
 //!
 
-use crate::gate::Gate;
-use crate::{config::CONFIG, storage::Storage};
+use crate::gate::{Gate, GlobalGate};
+use crate::{
+    config::CONFIG,
+    storage::{RegistrationStats, Storage, StorageErrorKind},
+    util::unix_timestamp,
+};
 use anyhow::{anyhow, bail, Error, Result};
 use chacha20poly1305::{
     aead::{
@@ -18,25 +22,84 @@ use futures::FutureExt;
 
 use once_cell::sync::OnceCell;
 use secrecy::{ExposeSecret, SecretString};
+use serde::Serialize;
 use std::{
     collections::{HashMap, HashSet},
+    panic::AssertUnwindSafe,
     str::FromStr,
-    sync::Arc,
-    time::{SystemTime, UNIX_EPOCH},
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc, PoisonError, RwLock, RwLockReadGuard, RwLockWriteGuard,
+    },
+    time::{Duration, Instant, SystemTime, UNIX_EPOCH},
 };
 use tokio::{
     sync::Mutex,
     sync::{mpsc, oneshot},
     task::JoinSet,
 };
-use tracing::{debug, error, info, info_span, instrument, Instrument, Span};
+use tracing::{debug, error, info, info_span, instrument, warn, Instrument, Span};
 
 /// The global channel on which the controller can be communicated with
 pub static CONTROLLER_CHANNEL: OnceCell<mpsc::Sender<Message>> = OnceCell::new();
-/// A session encryption key which is used to encrypt the session used for
-/// user registration. It is generated once at startup and never changes as
-/// long as the application is running.
-static SESSION_KEY: OnceCell<Vec<u8>> = OnceCell::new();
+/// The session encryption keys which are used to encrypt the session used
+/// for user registration. They are generated at startup and only change when
+/// the key is rotated with [`rotate_session_key`].
+static SESSION_KEYS: OnceCell<RwLock<SessionKeys>> = OnceCell::new();
+/// Whether the enforcement is paused, e.g. during an incident. This is not
+/// stored, so a restart resumes the enforcement
+static ENFORCEMENT_PAUSED: AtomicBool = AtomicBool::new(false);
+/// How often expired pending unregisters are cleaned up
+const REAPER_INTERVAL: Duration = Duration::from_secs(5);
+/// The length of the correlation id derived from a session
+const CORRELATION_ID_LEN: usize = 8;
+/// The maximum number of characters of the username in a session. Discord
+/// names are at most 32 characters, longer names are truncated, so they
+/// can't bloat the registration links
+const MAX_SESSION_USERNAME_CHARS: usize = 32;
+
+/// The unregisters waiting for the user to confirm on the web page, keyed by
+/// the encoded session
+type PendingUnregisters = Arc<Mutex<HashMap<String, PendingUnregister>>>;
+
+/// The last outcomes of `/get in` by guild and user, so a user that checks
+/// again within the cooldown gets the same outcome without checking the gates
+#[derive(Debug, Default)]
+struct RecentChecks(std::sync::Mutex<HashMap<(u64, u64), (Instant, CheckOutcome)>>);
+
+impl RecentChecks {
+    /// The outcome of the last check of the user, if it is younger than the
+    /// cooldown
+    fn get(&self, guild_id: u64, user_id: u64, cooldown: Duration) -> Option<CheckOutcome> {
+        let checks = self.0.lock().unwrap_or_else(PoisonError::into_inner);
+        checks
+            .get(&(guild_id, user_id))
+            .filter(|(checked_at, _)| checked_at.elapsed() < cooldown)
+            .map(|(_, outcome)| outcome.clone())
+    }
+
+    /// Remembers the outcome of a check and drops the ones that are past the
+    /// cooldown
+    fn insert(&self, guild_id: u64, user_id: u64, outcome: &CheckOutcome, cooldown: Duration) {
+        let mut checks = self.0.lock().unwrap_or_else(PoisonError::into_inner);
+        checks.retain(|_, (checked_at, _)| checked_at.elapsed() < cooldown);
+        checks.insert((guild_id, user_id), (Instant::now(), outcome.clone()));
+    }
+
+    /// Forgets the outcomes of the user in all guilds, e.g. after linking
+    /// another wallet
+    fn forget(&self, user_id: u64) {
+        let mut checks = self.0.lock().unwrap_or_else(PoisonError::into_inner);
+        checks.retain(|(_, checked_user_id), _| *checked_user_id != user_id);
+    }
+}
+
+/// An unregister request waiting for the confirmation of the user
+#[derive(Debug)]
+struct PendingUnregister {
+    removed_tx: oneshot::Sender<RemoveUserResponse>,
+    expires_at: Instant,
+}
 
 /// The message type is the main way for other parts of the application to
 /// communicate with the controller.
@@ -57,9 +120,25 @@ pub enum Message {
         gate: Gate,
         span: Span,
     },
+    /// Adds a gate to a guild, the response tells whether it was stored
     Gate {
         guild_id: u64,
         gate: Gate,
+        response_tx: oneshot::Sender<Result<()>>,
+        span: Span,
+    },
+    Toggle {
+        guild_id: u64,
+        identifier: u128,
+        response_tx: oneshot::Sender<ToggleResponse>,
+        span: Span,
+    },
+    /// Points a gate at another role, which changes its identifier
+    Move {
+        guild_id: u64,
+        identifier: u128,
+        new_role_id: u64,
+        response_tx: oneshot::Sender<MoveResponse>,
         span: Span,
     },
     Check {
@@ -69,12 +148,53 @@ pub enum Message {
         response_tx: oneshot::Sender<CheckResponse>,
         span: Span,
     },
+    /// A fresh registration link for the user, whether registered or not,
+    /// e.g. to link another wallet
+    Link {
+        user_id: u64,
+        username: String,
+        response_tx: oneshot::Sender<Result<String>>,
+        span: Span,
+    },
     Batch {
         guild_id: u64,
         user_ids: Vec<u64>,
+        /// Check all users, even if they were checked recently
+        force: bool,
+        /// Look up live data instead of cached results, e.g. for rechecks
+        bypass_cache: bool,
+        /// The gated roles the members currently hold. Only the failures of
+        /// held roles start the grace period
+        held_roles: HashMap<u64, Vec<u64>>,
+        /// Report the members that hold any of their roles without being
+        /// registered
+        report_unregistered: bool,
+        /// Only check the gates managing this role and only grant or revoke
+        /// it, all other roles are left out of the outcomes
+        role_id: Option<u64>,
         response_tx: mpsc::Sender<BatchResponse>,
         span: Span,
     },
+    Coverage {
+        guild_id: u64,
+        identifier: u128,
+        /// The members of the guild, unregistered ones are ignored
+        user_ids: Vec<u64>,
+        response_tx: oneshot::Sender<CoverageResponse>,
+        span: Span,
+    },
+    /// Checks a gate that is not stored for a single user, e.g. before an
+    /// admin adds it
+    Preview {
+        user_id: u64,
+        gate: Gate,
+        response_tx: oneshot::Sender<PreviewResponse>,
+        span: Span,
+    },
+    BatchAllGuilds {
+        response_tx: mpsc::Sender<GuildBatchResponse>,
+        span: Span,
+    },
     Register {
         user_id: u64,
         wallet: SecretString,
@@ -93,30 +213,302 @@ pub enum Message {
         response_tx: oneshot::Sender<RemoveUserResponse>,
         span: Span,
     },
+    GuildRoles {
+        guild_id: u64,
+        /// The role ids of the guild by name
+        roles: HashMap<String, u64>,
+        span: Span,
+    },
+    /// The number of guilds with stored gates
+    GuildCount {
+        response: oneshot::Sender<usize>,
+        span: Span,
+    },
+    /// The cumulative registrations and unregistrations of all users
+    Stats {
+        response: oneshot::Sender<RegistrationStats>,
+        span: Span,
+    },
+    /// Freezes or unfreezes gating in a guild, or in all guilds without a
+    /// guild id. While frozen, checks grant and revoke nothing
+    Freeze {
+        guild_id: Option<u64>,
+        frozen: bool,
+        response_tx: oneshot::Sender<Result<()>>,
+        span: Span,
+    },
+    /// Enables or disables granting the roles of registered members when
+    /// they join the guild
+    AutoGrant {
+        guild_id: u64,
+        enabled: bool,
+        response_tx: oneshot::Sender<Result<()>>,
+        span: Span,
+    },
+    /// A member joined the guild. With auto grant enabled in the guild a
+    /// registered member is checked like in a batch, otherwise only
+    /// [`BatchResponse::Done`] is sent
+    MemberJoined {
+        guild_id: u64,
+        user_id: u64,
+        response_tx: mpsc::Sender<BatchResponse>,
+        span: Span,
+    },
 }
 
 /// The response to a check message, sent back via the oneshot channel in the
 /// inbound message.
 #[derive(Debug)]
 pub enum CheckResponse {
-    Grant(Vec<u64>),
+    Grant(CheckOutcome),
     Register(String),
+    /// The registration link of a user that unregistered before and is
+    /// remembered by a tombstone, the user is not urged to register again
+    Reregister(String),
+    /// Gating is frozen in the guild, so no roles may change
+    Frozen,
+    Error(Error),
+}
+
+/// The response to a preview message, sent back via the oneshot channel in
+/// the inbound message. The outcome only holds the role of the previewed gate
+#[derive(Debug)]
+pub enum PreviewResponse {
+    Outcome(CheckOutcome),
+    Unregistered,
+    Error(Error),
+}
+
+/// The response to a toggle message, sent back via the oneshot channel in the
+/// inbound message.
+#[derive(Debug)]
+pub enum ToggleResponse {
+    Toggled(Gate),
+    NotFound,
+    Error(Error),
+}
+
+/// The response to a move message, sent back via the oneshot channel in the
+/// inbound message. The moved gate has the identifier it is now stored under
+#[derive(Debug)]
+pub enum MoveResponse {
+    Moved(Gate),
+    NotFound,
     Error(Error),
 }
 
+/// The response to a coverage message, sent back via the oneshot channel in
+/// the inbound message.
+#[derive(Debug)]
+pub enum CoverageResponse {
+    Coverage { gate: Gate, coverage: Coverage },
+    NotFound,
+    Error(Error),
+}
+
+/// How many of the registered members qualify for a gate
+#[derive(Debug, Default, PartialEq, Eq)]
+pub struct Coverage {
+    /// The registered members that were checked
+    pub checked: usize,
+    /// The members that meet the condition of the gate
+    pub qualified: usize,
+    /// The members whose condition could not be checked
+    pub errored: usize,
+}
+
 #[derive(Debug)]
 pub enum BatchResponse {
-    Grant { user_id: u64, roles: Vec<u64> },
+    Grant {
+        user_id: u64,
+        outcome: CheckOutcome,
+    },
+    /// A member holds gated roles without being registered, so they got the
+    /// roles some other way
+    Unregistered {
+        user_id: u64,
+        roles: Vec<u64>,
+    },
+    /// Gating is frozen in the guild, so nothing was checked and no roles
+    /// may change, followed by [`BatchResponse::Done`]
+    Frozen,
+    Done,
+}
+
+/// The response to a batch check over all guilds. The results are sent guild
+/// by guild, so all results of one guild arrive before the next guild starts
+#[derive(Debug)]
+pub enum GuildBatchResponse {
+    Grant {
+        guild_id: u64,
+        user_id: u64,
+        outcome: CheckOutcome,
+    },
     Done,
 }
 
+/// The counts of an enforcement, reported when it finished, e.g. for
+/// alerting on failures
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize)]
+pub struct EnforcementSummary {
+    /// The members that were checked
+    pub processed: usize,
+    /// The roles that were granted
+    pub granted: usize,
+    /// The roles that were revoked
+    pub revoked: usize,
+    /// The members that were not checked, e.g. because they are not
+    /// registered, were checked recently or are excluded
+    pub skipped: usize,
+    /// The checked members with roles that could not be checked or changed
+    pub failed: usize,
+    /// The checked members that qualify for each gated role, whether they
+    /// held it already or not
+    pub qualifying: HashMap<u64, usize>,
+}
+
+impl EnforcementSummary {
+    /// An empty summary of an enforcement of the roles, so roles no one
+    /// qualifies for are counted with zero members
+    pub fn for_roles(roles: impl IntoIterator<Item = u64>) -> Self {
+        EnforcementSummary {
+            qualifying: roles.into_iter().map(|role| (role, 0)).collect(),
+            ..Default::default()
+        }
+    }
+
+    /// Counts a checked member as qualifying for the granted roles
+    pub fn add_qualifying(&mut self, granted: &[u64]) {
+        for role in granted {
+            *self.qualifying.entry(*role).or_default() += 1;
+        }
+    }
+
+    /// Counts a checked member with the number of roles it gained and lost
+    pub fn add_member(&mut self, granted: usize, revoked: usize, failed: bool) {
+        self.processed += 1;
+        self.granted += granted;
+        self.revoked += revoked;
+        self.failed += usize::from(failed);
+    }
+
+    /// Counts a checked member without knowing the roles it holds, e.g.
+    /// without discord, so all roles it qualifies for count as granted
+    pub fn add_outcome(&mut self, outcome: &CheckOutcome) {
+        self.add_member(outcome.granted.len(), 0, !outcome.errored.is_empty());
+        self.add_qualifying(&outcome.granted);
+    }
+
+    /// Counts members that were not checked
+    pub fn skip(&mut self, members: usize) {
+        self.skipped += members;
+    }
+
+    /// Emits the qualifying members of each role as gauge events, labeled by
+    /// guild and role, that can be picked up by a metrics layer. Only
+    /// complete for runs that checked every member, runs that skip recently
+    /// checked members would undercount
+    #[cfg(feature = "metrics")]
+    pub fn record_qualifying(&self, guild_id: u64) {
+        for (role, members) in self.qualifying.iter() {
+            tracing::info!(
+                gauge.qualifying_members = *members as u64,
+                guild = guild_id,
+                role = *role,
+                "Qualifying members"
+            );
+        }
+    }
+}
+
+/// The outcome of checking a wallet against the gates of a guild
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct CheckOutcome {
+    /// The roles whose gate conditions are met
+    pub granted: Vec<u64>,
+    /// The roles whose gate conditions could not be checked, e.g. because of
+    /// network problems. These should neither be granted nor revoked
+    pub errored: Vec<u64>,
+    /// The errored roles whose gate panicked, which points to a bug in the
+    /// gate rather than a temporary problem
+    pub panicked: Vec<u64>,
+    /// The custom messages of the gates that granted a role, by role
+    pub grant_messages: HashMap<u64, String>,
+    /// Why the gates granted a role, e.g. the measured reputation, by role.
+    /// Only gates that explain themselves have a reason
+    pub reasons: HashMap<u64, String>,
+    /// The linked wallet that met the gate of a granted role, by role
+    pub wallets: HashMap<u64, H160>,
+    /// The roles whose gate conditions are not met, but only for less than
+    /// the grace period. These are not revoked yet
+    pub in_grace: Vec<u64>,
+}
+
+impl CheckOutcome {
+    /// Drops everything about other roles than the given one, e.g. the other
+    /// tiers of a tiered gate when enforcing a single role
+    fn scoped_to(mut self, role_id: Option<u64>) -> Self {
+        if let Some(role_id) = role_id {
+            self.granted.retain(|id| *id == role_id);
+            self.errored.retain(|id| *id == role_id);
+            self.panicked.retain(|id| *id == role_id);
+            self.grant_messages.retain(|id, _| *id == role_id);
+            self.reasons.retain(|id, _| *id == role_id);
+            self.wallets.retain(|id, _| *id == role_id);
+            self.in_grace.retain(|id| *id == role_id);
+        }
+        self
+    }
+
+    /// Adds the outcome of another wallet of the same user. Roles that are
+    /// granted already keep their wallet, message and reason, a role that
+    /// errored for one wallet but is granted for another counts as granted
+    fn merge(&mut self, mut other: CheckOutcome) {
+        for role_id in other.granted {
+            if self.granted.contains(&role_id) {
+                continue;
+            }
+            self.granted.push(role_id);
+            if let Some(wallet) = other.wallets.remove(&role_id) {
+                self.wallets.insert(role_id, wallet);
+            }
+            if let Some(message) = other.grant_messages.remove(&role_id) {
+                self.grant_messages.insert(role_id, message);
+            }
+            if let Some(reason) = other.reasons.remove(&role_id) {
+                self.reasons.insert(role_id, reason);
+            }
+        }
+        self.granted.sort();
+        self.errored.extend(other.errored);
+        self.errored
+            .retain(|role_id| !self.granted.contains(role_id));
+        self.errored.sort();
+        self.errored.dedup();
+        self.panicked.extend(other.panicked);
+        self.panicked
+            .retain(|role_id| self.errored.contains(role_id));
+        self.panicked.sort();
+        self.panicked.dedup();
+    }
+}
+
 /// The response to a register message, sent back via the oneshot channel in the
 /// inbound message.
 #[derive(Debug)]
 pub enum RegisterResponse {
     AlreadyRegistered,
+    /// The user has linked as many wallets as allowed already
+    TooManyWallets {
+        max: usize,
+    },
     Success,
-    Error(Error),
+    /// Storing the user failed, the kind tells whether an operator needs to
+    /// look into the storage
+    Error {
+        kind: StorageErrorKind,
+        why: Error,
+    },
 }
 
 /// The response to a unregister message, sent back via the oneshot channel in the
@@ -136,38 +528,124 @@ pub enum RemoveUserResponse {
 
 /// The main business logic instance. It holds a storage instance and a channel
 /// for communication with other parts of the application.
+///
+/// Messages that only read the storage are handled beside the loop on the
+/// blocking pool, so a slow read does not hold up the other messages. The
+/// messages that write are handled in the loop one after the other, so a
+/// read always sees the writes of the messages before it.
 #[derive(Debug)]
 pub struct Controller<S: Storage> {
-    pub storage: S,
+    storage: Arc<RwLock<S>>,
     pub message_tx: mpsc::Sender<Message>,
     message_rx: mpsc::Receiver<Message>,
+    /// The role names of the guilds, used to resolve the global gates
+    guild_roles: HashMap<u64, HashMap<String, u64>>,
+    /// The maximum number of wallets a user can link, 0 for no limit
+    max_wallets_per_user: usize,
+    /// How many seconds a user keeps a role after failing its gate, 0 to
+    /// revoke right away
+    revoke_grace_period: u64,
+    /// How long `/get in` answers with the last outcome of a user, zero to
+    /// always check the gates
+    check_cooldown: Duration,
+    /// The last outcomes of `/get in`, only kept with a cooldown
+    recent_checks: Arc<RecentChecks>,
+}
+
+impl<S: Storage + Send + 'static + std::marker::Sync> Default for Controller<S> {
+    fn default() -> Self {
+        Self::new()
+    }
 }
 
 impl<S: Storage + Send + 'static + std::marker::Sync> Controller<S> {
     pub fn new() -> Self {
+        Self::with_storage(S::new())
+    }
+
+    /// Creates a controller with a storage that is already set up, instead of
+    /// the one from the configuration
+    pub fn with_storage(storage: S) -> Self {
         let (message_tx, message_rx) = mpsc::channel(1024);
 
         Controller {
-            storage: S::new(),
+            storage: Arc::new(RwLock::new(storage)),
             message_tx,
             message_rx,
+            guild_roles: HashMap::new(),
+            max_wallets_per_user: CONFIG.get().map_or(0, |cfg| cfg.max_wallets_per_user),
+            revoke_grace_period: CONFIG.get().map_or(0, |cfg| cfg.revoke_grace_period),
+            check_cooldown: Duration::from_secs(CONFIG.get().map_or(0, |cfg| cfg.check_cooldown)),
+            recent_checks: Arc::new(RecentChecks::default()),
         }
     }
 
+    /// Locks the storage for reading, this waits for a write of the loop
+    pub fn read_storage(&self) -> RwLockReadGuard<'_, S> {
+        read(&self.storage)
+    }
+
+    /// Locks the storage for writing, this waits for the reads running
+    /// beside the loop
+    pub fn write_storage(&self) -> RwLockWriteGuard<'_, S> {
+        write(&self.storage)
+    }
+
+    /// Runs storage calls on the blocking pool and waits for them. The loop
+    /// still goes on only after the write, but a slow disk does not block
+    /// the runtime thread the other tasks run on
+    async fn offload_write<T, F>(&self, f: F) -> T
+    where
+        F: FnOnce(&mut S) -> T + Send + 'static,
+        T: Send + 'static,
+    {
+        let storage = self.storage.clone();
+        let span = Span::current();
+        tokio::task::spawn_blocking(move || {
+            let _enter = span.enter();
+            f(&mut write(&storage))
+        })
+        .await
+        .unwrap_or_else(|why| std::panic::resume_unwind(why.into_panic()))
+    }
+
+    /// Runs a handler that only reads the storage on the blocking pool
+    /// without waiting for it, so the loop can go on with the next message
+    fn spawn_read<F>(&self, span: Span, f: F)
+    where
+        F: FnOnce(&S) + Send + 'static,
+    {
+        let storage = self.storage.clone();
+        tokio::task::spawn_blocking(move || {
+            let _enter = span.enter();
+            f(&read(&storage))
+        });
+    }
+
     pub async fn init()
     where
         S: Storage + Send + 'static,
         <S as Storage>::GateIter: Send,
     {
-        let key = ChaCha20Poly1305::generate_key(&mut OsRng);
-        SESSION_KEY
-            .set(key.to_vec())
-            .expect("Failed to set session key");
-        let controller: Controller<S> = Controller::new();
+        Controller::<S>::new().start().await;
+    }
+
+    /// Generates the session key, sets the global channel and starts the
+    /// controller. This can only be done once, since both are global
+    pub async fn start(self) -> mpsc::Sender<Message>
+    where
+        S: Storage + Send + 'static,
+        <S as Storage>::GateIter: Send,
+    {
+        if SESSION_KEYS.set(RwLock::new(SessionKeys::new())).is_err() {
+            panic!("Failed to set session key");
+        }
+        let message_tx = self.message_tx.clone();
         CONTROLLER_CHANNEL
-            .set(controller.message_tx.clone())
+            .set(message_tx.clone())
             .expect("Failed to set controller channel");
-        controller.spawn().await;
+        self.spawn().await;
+        message_tx
     }
 
     /// Starts the controller and sets the global static channel for other
@@ -185,30 +663,74 @@ impl<S: Storage + Send + 'static + std::marker::Sync> Controller<S> {
         S: Storage + Send + 'static,
         <S as Storage>::GateIter: Send,
     {
-        let pending_unregisters: Arc<Mutex<HashMap<String, oneshot::Sender<RemoveUserResponse>>>> =
-            Arc::new(Mutex::new(HashMap::new()));
+        let pending_unregisters: PendingUnregisters = Arc::new(Mutex::new(HashMap::new()));
+        let reaper = tokio::spawn(reap_pending_unregisters(
+            pending_unregisters.clone(),
+            REAPER_INTERVAL,
+        ));
         while let Some(message) = self.message_rx.recv().await {
             match message {
                 Message::Gate {
                     guild_id,
                     gate,
+                    response_tx,
+                    span,
+                } => self.add_gate(guild_id, gate, response_tx, span).await,
+                Message::Toggle {
+                    guild_id,
+                    identifier,
+                    response_tx,
+                    span,
+                } => {
+                    self.toggle_gate(guild_id, identifier, response_tx, span)
+                        .await
+                }
+                Message::Move {
+                    guild_id,
+                    identifier,
+                    new_role_id,
+                    response_tx,
                     span,
-                } => self.add_gate(guild_id, gate, span).await,
+                } => {
+                    let _enter = span.enter();
+                    info!(identifier, new_role_id, "Moving gate");
+                    let response = match self
+                        .offload_write(move |storage| {
+                            move_gate(storage, guild_id, identifier, new_role_id)
+                        })
+                        .await
+                    {
+                        Ok(Some(gate)) => MoveResponse::Moved(gate),
+                        Ok(None) => MoveResponse::NotFound,
+                        Err(why) => {
+                            error!("Failed to move gate: {:?}", why);
+                            MoveResponse::Error(why)
+                        }
+                    };
+                    if let Err(why) = response_tx.send(response) {
+                        error!("Failed to send move response: {:?}", why);
+                    }
+                }
                 Message::Roles {
                     guild_id,
                     response,
                     span,
-                } => self.list_roles(guild_id, response, span),
+                } => {
+                    let guild_roles = self.guild_roles.get(&guild_id).cloned();
+                    self.spawn_read(span, move |storage| {
+                        list_roles(storage, guild_roles.as_ref(), guild_id, response)
+                    })
+                }
                 Message::List {
                     guild_id,
                     response,
                     span,
-                } => self.list_gates(guild_id, response, span),
+                } => self.spawn_read(span, move |storage| list_gates(storage, guild_id, response)),
                 Message::Delete {
                     guild_id,
                     gate,
                     span,
-                } => self.delete_gate(guild_id, gate, span),
+                } => self.delete_gate(guild_id, gate, span).await,
                 Message::Check {
                     username,
                     user_id,
@@ -216,17 +738,77 @@ impl<S: Storage + Send + 'static + std::marker::Sync> Controller<S> {
                     response_tx,
                     span,
                 } => {
-                    self.check(guild_id, username, user_id, response_tx, span)
-                        .await
+                    let guild_roles = self.guild_roles.get(&guild_id).cloned();
+                    tokio::spawn(check(
+                        self.storage.clone(),
+                        self.recent_checks.clone(),
+                        self.check_cooldown,
+                        guild_roles,
+                        guild_id,
+                        username,
+                        user_id,
+                        response_tx,
+                        span,
+                    ));
+                }
+                Message::Link {
+                    user_id,
+                    username,
+                    response_tx,
+                    span,
+                } => {
+                    let _enter = span.enter();
+                    debug!("Creating a registration link");
+                    if let Err(why) = response_tx.send(registration_link(user_id, username)) {
+                        error!("Failed to send link response: {:?}", why);
+                    }
                 }
                 Message::Batch {
                     guild_id,
                     user_ids,
+                    force,
+                    bypass_cache,
+                    held_roles,
+                    report_unregistered,
+                    role_id,
                     response_tx,
                     span,
                 } => {
-                    self.batch_check(guild_id, user_ids, response_tx, span)
-                        .await
+                    if self.is_frozen(guild_id) {
+                        self.report_frozen(response_tx, span).await;
+                        continue;
+                    }
+                    if report_unregistered {
+                        self.report_unregistered(&held_roles, &response_tx, &span)
+                            .await;
+                    }
+                    self.batch_check(
+                        guild_id,
+                        user_ids,
+                        force,
+                        bypass_cache,
+                        held_roles,
+                        role_id,
+                        response_tx,
+                        span,
+                    )
+                    .await
+                }
+                Message::Coverage {
+                    guild_id,
+                    identifier,
+                    user_ids,
+                    response_tx,
+                    span,
+                } => self.coverage(guild_id, identifier, user_ids, response_tx, span),
+                Message::Preview {
+                    user_id,
+                    gate,
+                    response_tx,
+                    span,
+                } => self.preview(user_id, gate, response_tx, span),
+                Message::BatchAllGuilds { response_tx, span } => {
+                    self.batch_check_all_guilds(response_tx, span).await
                 }
                 Message::Register {
                     user_id,
@@ -259,140 +841,250 @@ impl<S: Storage + Send + 'static + std::marker::Sync> Controller<S> {
                     self.delete_user(session, response_tx, pending_unregisters.clone(), span)
                         .await
                 }
+                Message::GuildRoles {
+                    guild_id,
+                    roles,
+                    span,
+                } => {
+                    let _enter = span.enter();
+                    debug!(guild_id, roles = roles.len(), "Updating guild roles");
+                    self.guild_roles.insert(guild_id, roles);
+                }
+                Message::GuildCount { response, span } => self.spawn_read(span, move |storage| {
+                    let count = storage.list_guilds().count();
+                    if let Err(why) = response.send(count) {
+                        error!("Failed to send guild count: {:?}", why);
+                    }
+                }),
+                Message::Stats { response, span } => {
+                    self.spawn_read(span, move |storage| match storage.registration_stats() {
+                        Ok(stats) => {
+                            if let Err(why) = response.send(stats) {
+                                error!("Failed to send stats: {:?}", why);
+                            }
+                        }
+                        Err(why) => error!("Failed to get stats: {:?}", why),
+                    })
+                }
+                Message::Freeze {
+                    guild_id,
+                    frozen,
+                    response_tx,
+                    span,
+                } => {
+                    let _enter = span.enter();
+                    info!(?guild_id, frozen, "Setting the gating freeze");
+                    let result = self
+                        .offload_write(move |storage| storage.set_frozen(guild_id, frozen))
+                        .await;
+                    if let Err(why) = response_tx.send(result) {
+                        error!("Failed to send freeze response: {:?}", why);
+                    }
+                }
+                Message::AutoGrant {
+                    guild_id,
+                    enabled,
+                    response_tx,
+                    span,
+                } => {
+                    let _enter = span.enter();
+                    info!(guild_id, enabled, "Setting the auto grant on join");
+                    let result = self
+                        .offload_write(move |storage| storage.set_auto_grant(guild_id, enabled))
+                        .await;
+                    if let Err(why) = response_tx.send(result) {
+                        error!("Failed to send auto grant response: {:?}", why);
+                    }
+                }
+                Message::MemberJoined {
+                    guild_id,
+                    user_id,
+                    response_tx,
+                    span,
+                } => {
+                    if !self.auto_grant(guild_id) {
+                        let _enter = span.enter();
+                        debug!("Auto grant is disabled, skipping the joined member");
+                        if let Err(why) = response_tx.send(BatchResponse::Done).await {
+                            error!("Failed to send batch response: {:?}", why);
+                        }
+                        continue;
+                    }
+                    if self.is_frozen(guild_id) {
+                        self.report_frozen(response_tx, span).await;
+                        continue;
+                    }
+                    // a joined member has no roles yet, so there is nothing
+                    // fresh to skip
+                    self.batch_check(
+                        guild_id,
+                        vec![user_id],
+                        true,
+                        false,
+                        None,
+                        response_tx,
+                        span,
+                    )
+                    .await
+                }
             }
         }
+        reaper.abort();
     }
 
-    async fn add_gate(&mut self, guild_id: u64, gate: Gate, span: Span) {
+    async fn add_gate(
+        &mut self,
+        guild_id: u64,
+        gate: Gate,
+        response_tx: oneshot::Sender<Result<()>>,
+        span: Span,
+    ) {
         let _enter = span.enter();
         debug!(?gate, "Adding gate:");
-        if let Err(why) = self.storage.add_gate(&guild_id, gate) {
-            error!("Failed to add gate: {:?}", why);
+        let result = self
+            .offload_write(move |storage| storage.add_gate(&guild_id, gate))
+            .await;
+        if let Err(why) = &result {
+            StorageErrorKind::report(why, "add gate");
+        }
+        if let Err(why) = response_tx.send(result) {
+            error!("Failed to send add gate response: {:?}", why);
         }
     }
 
-    fn list_roles(&mut self, guild_id: u64, response: oneshot::Sender<HashSet<u64>>, span: Span) {
-        let _enter = span.enter();
-        match self.storage.list_gates(&guild_id) {
-            Ok(gates) => {
-                let roles = HashSet::from_iter(gates.into_iter().map(|gate| gate.role_id));
-                if let Err(why) = response.send(roles) {
-                    error!("Failed to send roles: {:?}", why);
-                }
-            }
-            Err(why) => {
-                error!("Failed to list gates: {:?}", why);
-            }
-        }
+    /// The gates of a guild together with the global gates whose role exists
+    /// in the guild
+    fn gates_for_guild(&self, guild_id: u64) -> Result<Vec<Gate>> {
+        guild_gates(
+            &*self.read_storage(),
+            self.guild_roles.get(&guild_id),
+            guild_id,
+        )
     }
 
-    fn list_gates(&mut self, guild_id: u64, response: oneshot::Sender<Vec<Gate>>, span: Span) {
+    async fn delete_gate(&mut self, guild_id: u64, gate: Gate, span: Span) {
         let _enter = span.enter();
-        debug!("Received list request for guild");
-        match self.storage.list_gates(&guild_id) {
-            Ok(gate_iter) => {
-                let gates = gate_iter.collect::<Vec<Gate>>();
-                debug!(?gates, "Sending list response");
-                if let Err(why) = response.send(gates) {
-                    error!("Failed to send list response: {:?}", why);
-                }
-            }
-            Err(why) => {
-                error!("Failed to list gates: {:?}", why);
-            }
+        debug!("Deleting gate: {:?}", gate);
+        let identifier = gate.identifier();
+        let result = self
+            .offload_write(move |storage| storage.remove_gate(&guild_id, identifier))
+            .await;
+        if let Err(why) = result {
+            error!("Failed to delete gate: {:?}", why);
         }
     }
 
-    fn delete_gate(&mut self, guild_id: u64, gate: Gate, span: Span) {
+    fn is_frozen(&self, guild_id: u64) -> bool {
+        is_frozen(&*self.read_storage(), guild_id)
+    }
+
+    fn auto_grant(&self, guild_id: u64) -> bool {
+        self.read_storage()
+            .auto_grant(&guild_id)
+            .unwrap_or_else(|why| {
+                error!("Failed to get the auto grant setting: {:?}", why);
+                false
+            })
+    }
+
+    async fn report_frozen(&self, response_tx: mpsc::Sender<BatchResponse>, span: Span) {
         let _enter = span.enter();
-        debug!("Deleting gate: {:?}", gate);
-        if let Err(why) = self.storage.remove_gate(&guild_id, gate.identifier()) {
-            error!("Failed to delete gate: {:?}", why);
+        debug!("Gating is frozen, skipping the batch check");
+        for response in [BatchResponse::Frozen, BatchResponse::Done] {
+            if let Err(why) = response_tx.send(response).in_current_span().await {
+                error!("Failed to send batch response: {:?}", why);
+            }
         }
     }
 
-    async fn check(
+    async fn toggle_gate(
         &mut self,
         guild_id: u64,
-        username: String,
-        user_id: u64,
-        response_tx: oneshot::Sender<CheckResponse>,
+        identifier: u128,
+        response_tx: oneshot::Sender<ToggleResponse>,
         span: Span,
     ) {
         let _enter = span.enter();
-        debug!("Checking user");
-        if !self.storage.contains_user(&user_id) {
-            debug!("User not registered");
-            let url = CONFIG.wait().server.url.clone();
-            let session = match Session::new(user_id, username) {
-                Ok(session) => session,
-                Err(why) => {
-                    error!("Failed to create session: {:?}", why);
-                    if let Err(why) = response_tx.send(CheckResponse::Error(why)) {
-                        error!("Failed to send register response: {:?}", why);
+        debug!(identifier, "Toggling gate");
+        let response = self
+            .offload_write(move |storage| match storage.list_gates(&guild_id) {
+                Ok(mut gates) => match gates.find(|gate| gate.identifier() == identifier) {
+                    Some(mut gate) => {
+                        gate.enabled = !gate.enabled;
+                        // the identifier does not depend on the enabled flag,
+                        // so we can just replace the stored gate
+                        match storage
+                            .remove_gate(&guild_id, identifier)
+                            .and_then(|_| storage.add_gate(&guild_id, gate.clone()))
+                        {
+                            Ok(_) => ToggleResponse::Toggled(gate),
+                            Err(why) => {
+                                error!("Failed to store toggled gate: {:?}", why);
+                                ToggleResponse::Error(why)
+                            }
+                        }
                     }
-                    return;
+                    None => ToggleResponse::NotFound,
+                },
+                Err(why) => {
+                    error!("Failed to list gates: {:?}", why);
+                    ToggleResponse::Error(why)
                 }
-            };
-
-            let encoded_session = match session.encode() {
-                Ok(session) => session,
-                Err(why) => {
-                    error!("Failed to encode session: {:?}", why);
-                    if let Err(why) = response_tx.send(CheckResponse::Error(why)) {
-                        error!("Failed to send register response: {:?}", why);
-                    }
-                    return;
-                }
-            };
-            let url = format!(
-                // "{}/register/{}/{}",
-                "{}/getin.html?username={}&session={}",
-                url,
-                urlencoding::encode(&session.username),
-                encoded_session
-            );
-            if let Err(why) = response_tx.send(CheckResponse::Register(url)) {
-                error!("Failed to send CheckResponse::Register: {:?}", why);
-            };
-            return;
+            })
+            .await;
+        if let Err(why) = response_tx.send(response) {
+            error!("Failed to send toggle response: {:?}", why);
         }
+    }
 
-        let wallet = match self.storage.get_user(&user_id) {
-            Ok(wallet) => wallet,
-            Err(why) => {
-                error!("Failed to get user: {:?}", why);
-                if let Err(why) = response_tx.send(CheckResponse::Error(why)) {
-                    error!("Failed to send CheckResponse::Error: {:?}", why);
-                }
-                return;
-            }
-        };
-        match self.storage.list_gates(&guild_id) {
-            Err(why) => {
-                error!("Failed to list gates: {:?}", why);
-                if let Err(why) = response_tx.send(CheckResponse::Error(why)) {
-                    error!("Failed to send error response: {:?}", why);
-                }
-            }
-            Ok(gates) => {
-                debug!("Found wallet for user");
-                let granted_roles = check_with_wallet(wallet[0].clone(), gates)
-                    .in_current_span()
-                    .await;
-                let _guard = span.enter();
-                debug!(?granted_roles, "Roles granted");
-                if let Err(why) = response_tx.send(CheckResponse::Grant(granted_roles)) {
-                    error!("Failed to send CheckResponse::Grant: {:?}", why);
-                };
+    /// Reports the members that hold gated roles but are not registered, so
+    /// admins can decide whether to remove the roles
+    async fn report_unregistered(
+        &self,
+        held_roles: &HashMap<u64, Vec<u64>>,
+        response_tx: &mpsc::Sender<BatchResponse>,
+        span: &Span,
+    ) {
+        let _enter = span.enter();
+        for (user_id, roles) in self.unregistered_holders(held_roles) {
+            debug!(user_id, ?roles, "Unregistered member holds gated roles");
+            if let Err(why) = response_tx
+                .send(BatchResponse::Unregistered { user_id, roles })
+                .in_current_span()
+                .await
+            {
+                error!("Failed to send BatchResponse::Unregistered: {:?}", why);
             }
         }
     }
 
+    /// The members holding any of their roles without being registered,
+    /// ordered by user id
+    fn unregistered_holders(&self, held_roles: &HashMap<u64, Vec<u64>>) -> Vec<(u64, Vec<u64>)> {
+        let storage = self.read_storage();
+        let mut holders = held_roles
+            .iter()
+            .filter(|(user_id, roles)| !roles.is_empty() && !storage.contains_user(user_id))
+            .map(|(user_id, roles)| (*user_id, roles.clone()))
+            .collect::<Vec<_>>();
+        holders.sort_unstable();
+        holders
+    }
+
+    /// Checks the users against the gates of the guild. With a role only
+    /// the gates managing that role are checked, since the other gates are
+    /// not checked such a run neither skips nor refreshes recently checked
+    /// users. Such a run is also not subject to the grace period, it is used
+    /// when the gates of the role are deleted
+    #[allow(clippy::too_many_arguments)]
     async fn batch_check(
         &mut self,
         guild_id: u64,
         user_ids: Vec<u64>,
+        force: bool,
+        bypass_cache: bool,
+        held_roles: HashMap<u64, Vec<u64>>,
+        role_id: Option<u64>,
         response_tx: mpsc::Sender<BatchResponse>,
         span: Span,
     ) where
@@ -400,29 +1092,60 @@ impl<S: Storage + Send + 'static + std::marker::Sync> Controller<S> {
         <S as Storage>::GateIter: Send,
     {
         let _enter = span.enter();
-        debug!(?user_ids, "Batch checking");
+        debug!(?user_ids, force, bypass_cache, ?role_id, "Batch checking");
+        let now = unix_timestamp();
+        let freshness = if force || role_id.is_some() {
+            0
+        } else {
+            CONFIG.wait().check_freshness
+        };
+        let user_ids = self.users_to_check(guild_id, user_ids, now, freshness);
+        let grace = if role_id.is_none() {
+            self.revoke_grace_period
+        } else {
+            0
+        };
+        // the roles of disabled gates are neither granted nor revoked, so
+        // their failures are not tracked
+        let gated_roles = if grace == 0 {
+            HashSet::new()
+        } else {
+            match self.gates_for_guild(guild_id) {
+                Ok(gates) => gates
+                    .into_iter()
+                    .filter(|gate| gate.enabled)
+                    .flat_map(|gate| gate.role_ids())
+                    .collect::<HashSet<_>>(),
+                Err(why) => {
+                    error!("Failed to list gates: {:?}", why);
+                    HashSet::new()
+                }
+            }
+        };
         let check_futures = user_ids
             .into_iter()
-            .filter(|user_id| self.storage.contains_user(user_id))
-            .filter_map(|user_id| match self.storage.get_user(&user_id) {
+            .filter_map(|user_id| match self.read_storage().get_user(&user_id) {
                 Ok(wallet) => Some((user_id, wallet)),
                 Err(why) => {
                     error!("Failed to get user: {:?}", why);
                     None
                 }
             })
-            .filter_map(
-                |(user_id, wallet)| match self.storage.list_gates(&guild_id) {
-                    Ok(gates) => Some(
-                        check_with_wallet(wallet[0].clone(), gates)
-                            .map(move |granted_roles| (user_id, granted_roles)),
-                    ),
-                    Err(why) => {
-                        error!("Failed to list gates: {:?}", why);
-                        None
-                    }
-                },
-            );
+            .filter_map(|(user_id, wallet)| match self.gates_for_guild(guild_id) {
+                Ok(gates) => {
+                    let gates = gates.into_iter().filter(move |gate| {
+                        role_id.is_none_or(|role_id| gate.role_ids().contains(&role_id))
+                    });
+                    Some(
+                        check_gates(wallet, gates, bypass_cache)
+                            .map(move |outcome| (user_id, outcome.scoped_to(role_id))),
+                    )
+                }
+                Err(why) => {
+                    error!("Failed to list gates: {:?}", why);
+                    None
+                }
+            });
         let mut set = JoinSet::new();
         for fut in check_futures {
             set.spawn(fut.in_current_span());
@@ -430,10 +1153,34 @@ impl<S: Storage + Send + 'static + std::marker::Sync> Controller<S> {
         while let Some(result) = set.join_next().in_current_span().await {
             let _enter = span.enter();
             match result {
-                Ok((user_id, roles)) => {
-                    debug!(user_id, ?roles, "Batch result");
+                Ok((user_id, mut outcome)) => {
+                    if grace > 0 {
+                        let roles = gated_roles.clone();
+                        let held = held_roles.get(&user_id).cloned().unwrap_or_default();
+                        outcome = self
+                            .offload_write(move |storage| {
+                                outcome.in_grace = track_failures(
+                                    storage, guild_id, user_id, &roles, &held, &outcome, now, grace,
+                                );
+                                outcome
+                            })
+                            .await;
+                    }
+                    debug!(user_id, ?outcome, "Batch result");
+                    // a user with gates that could not be checked is not
+                    // fresh, the next enforcement should try again
+                    if outcome.errored.is_empty() && role_id.is_none() {
+                        let stored = self
+                            .offload_write(move |storage| {
+                                storage.set_last_checked(&guild_id, &user_id, now)
+                            })
+                            .await;
+                        if let Err(why) = stored {
+                            error!("Failed to store last check: {:?}", why);
+                        }
+                    }
                     if let Err(why) = response_tx
-                        .send(BatchResponse::Grant { user_id, roles })
+                        .send(BatchResponse::Grant { user_id, outcome })
                         .in_current_span()
                         .await
                     {
@@ -455,6 +1202,216 @@ impl<S: Storage + Send + 'static + std::marker::Sync> Controller<S> {
         };
     }
 
+    /// Counts the registered members that qualify for a gate. The checks run
+    /// in a separate task, so the controller is not blocked while they wait
+    /// for the rate limits
+    fn coverage(
+        &mut self,
+        guild_id: u64,
+        identifier: u128,
+        user_ids: Vec<u64>,
+        response_tx: oneshot::Sender<CoverageResponse>,
+        span: Span,
+    ) {
+        let _enter = span.enter();
+        debug!(identifier, "Calculating gate coverage");
+        let storage = self.read_storage();
+        let gate = match storage.list_gates(&guild_id) {
+            Ok(mut gates) => gates.find(|gate| gate.identifier() == identifier),
+            Err(why) => {
+                error!("Failed to list gates: {:?}", why);
+                if let Err(why) = response_tx.send(CoverageResponse::Error(why)) {
+                    error!("Failed to send coverage response: {:?}", why);
+                }
+                return;
+            }
+        };
+        let gate = match gate {
+            Some(gate) => gate,
+            None => {
+                if let Err(why) = response_tx.send(CoverageResponse::NotFound) {
+                    error!("Failed to send coverage response: {:?}", why);
+                }
+                return;
+            }
+        };
+        let wallets = user_ids
+            .iter()
+            .filter(|user_id| storage.contains_user(user_id))
+            .filter_map(|user_id| match storage.get_user(user_id) {
+                Ok(wallets) => wallets.into_iter().next(),
+                Err(why) => {
+                    error!("Failed to get user: {:?}", why);
+                    None
+                }
+            })
+            .collect::<Vec<_>>();
+        tokio::spawn(
+            async move {
+                let coverage = gate_coverage(&gate, wallets).in_current_span().await;
+                debug!(?coverage, "Gate coverage");
+                if let Err(why) = response_tx.send(CoverageResponse::Coverage { gate, coverage }) {
+                    error!("Failed to send coverage response: {:?}", why);
+                }
+            }
+            .in_current_span(),
+        );
+    }
+
+    /// Checks the gate for the wallets of the user without storing the gate
+    fn preview(
+        &mut self,
+        user_id: u64,
+        gate: Gate,
+        response_tx: oneshot::Sender<PreviewResponse>,
+        span: Span,
+    ) {
+        let _enter = span.enter();
+        debug!(?gate, "Previewing gate");
+        let storage = self.read_storage();
+        let wallets = if storage.contains_user(&user_id) {
+            storage.get_user(&user_id)
+        } else {
+            if let Err(why) = response_tx.send(PreviewResponse::Unregistered) {
+                error!("Failed to send preview response: {:?}", why);
+            }
+            return;
+        };
+        let wallets = match wallets {
+            Ok(wallets) => wallets,
+            Err(why) => {
+                error!("Failed to get user: {:?}", why);
+                if let Err(why) = response_tx.send(PreviewResponse::Error(why)) {
+                    error!("Failed to send preview response: {:?}", why);
+                }
+                return;
+            }
+        };
+        tokio::spawn(
+            async move {
+                let outcome = check_with_wallets(wallets, std::iter::once(gate))
+                    .in_current_span()
+                    .await;
+                debug!(?outcome, "Previewed gate");
+                if let Err(why) = response_tx.send(PreviewResponse::Outcome(outcome)) {
+                    error!("Failed to send preview response: {:?}", why);
+                }
+            }
+            .in_current_span(),
+        );
+    }
+
+    /// Filters the registered users that need to be checked, skipping the ones
+    /// that were checked less than `freshness` seconds before `now`
+    fn users_to_check(
+        &self,
+        guild_id: u64,
+        user_ids: Vec<u64>,
+        now: u64,
+        freshness: u64,
+    ) -> Vec<u64> {
+        let storage = self.read_storage();
+        user_ids
+            .into_iter()
+            .filter(|user_id| storage.contains_user(user_id))
+            .filter(|user_id| {
+                if freshness == 0 {
+                    return true;
+                }
+                match storage.last_checked(&guild_id, user_id) {
+                    Ok(Some(last_checked)) => now.saturating_sub(last_checked) >= freshness,
+                    Ok(None) => true,
+                    Err(why) => {
+                        error!("Failed to get last check: {:?}", why);
+                        true
+                    }
+                }
+            })
+            .collect()
+    }
+
+    /// Checks all registered users against the gates of every guild. This
+    /// does not know which users are members of which guild, so it reports
+    /// what every registered user would be granted in each guild
+    async fn batch_check_all_guilds(
+        &mut self,
+        response_tx: mpsc::Sender<GuildBatchResponse>,
+        span: Span,
+    ) where
+        S: Storage + Send + 'static,
+        <S as Storage>::GateIter: Send,
+    {
+        let _enter = span.enter();
+        let users = match self.read_storage().list_users() {
+            Ok(users) => users.collect::<Vec<_>>(),
+            Err(why) => {
+                error!("Failed to list users: {:?}", why);
+                Vec::new()
+            }
+        };
+        let guild_ids = self.read_storage().list_guilds().collect::<Vec<_>>();
+        debug!(?guild_ids, users = users.len(), "Batch checking all guilds");
+        for guild_id in guild_ids {
+            if is_enforcement_paused() {
+                info!(
+                    guild_id,
+                    "The enforcement is paused, skipping the remaining guilds"
+                );
+                break;
+            }
+            if self.is_frozen(guild_id) {
+                debug!(guild_id, "Gating is frozen, skipping the guild");
+                continue;
+            }
+            let gates = match self.read_storage().list_gates(&guild_id) {
+                Ok(gates) => gates.collect::<Vec<_>>(),
+                Err(why) => {
+                    error!(guild_id, "Failed to list gates: {:?}", why);
+                    continue;
+                }
+            };
+            let mut set = JoinSet::new();
+            for (user_id, wallets) in users.iter() {
+                let user_id = *user_id;
+                let check = check_with_wallets(wallets.clone(), gates.clone().into_iter());
+                set.spawn(
+                    check
+                        .map(move |outcome| (user_id, outcome))
+                        .in_current_span(),
+                );
+            }
+            while let Some(result) = set.join_next().in_current_span().await {
+                match result {
+                    Ok((user_id, outcome)) => {
+                        debug!(guild_id, user_id, ?outcome, "Batch result");
+                        if let Err(why) = response_tx
+                            .send(GuildBatchResponse::Grant {
+                                guild_id,
+                                user_id,
+                                outcome,
+                            })
+                            .in_current_span()
+                            .await
+                        {
+                            error!("Failed to send GuildBatchResponse::Grant: {:?}", why);
+                        };
+                    }
+                    Err(why) => {
+                        error!("Failed to check user: {:?}", why);
+                    }
+                }
+            }
+        }
+        debug!("Batch check of all guilds complete, sending done");
+        if let Err(why) = response_tx
+            .send(GuildBatchResponse::Done)
+            .in_current_span()
+            .await
+        {
+            error!("Failed to send GuildBatchResponse::Done: {:?}", why);
+        };
+    }
+
     async fn register(
         &mut self,
         user_id: u64,
@@ -464,21 +1421,30 @@ impl<S: Storage + Send + 'static + std::marker::Sync> Controller<S> {
     ) {
         let _enter = span.enter();
         debug!("Registering user {} with wallet {:?}", user_id, wallet);
-        if self.storage.contains_user(&user_id) {
-            debug!("User {} already registered", user_id);
-            if let Err(why) = response_tx.send(RegisterResponse::AlreadyRegistered) {
-                error!(
-                    "Failed to send RegisterResponse::AlreadyRegistered: {:?}",
-                    why
-                );
-            };
-        } else if let Err(why) = self.storage.add_user(user_id, vec![wallet]) {
-            error!("Failed to add user: {:?}", why);
-            if let Err(why) = response_tx.send(RegisterResponse::Error(why)) {
-                error!("Failed to send RegisterResponse::Error: {:?}", why);
-            };
-        } else if let Err(why) = response_tx.send(RegisterResponse::Success) {
-            error!("Failed to send RegisterResponse::Success: {:?}", why);
+        let max = self.max_wallets_per_user;
+        // a new wallet may meet gates the last checks did not
+        self.recent_checks.forget(user_id);
+        let response = self
+            .offload_write(move |storage| {
+                if storage.contains_user(&user_id) {
+                    return link_wallet(storage, user_id, wallet, max);
+                }
+                match storage.add_user(user_id, vec![wallet]) {
+                    Ok(()) => {
+                        if let Err(why) = storage.count_registration() {
+                            error!("Failed to count registration: {:?}", why);
+                        }
+                        RegisterResponse::Success
+                    }
+                    Err(why) => {
+                        let kind = StorageErrorKind::report(&why, "add user");
+                        RegisterResponse::Error { kind, why }
+                    }
+                }
+            })
+            .await;
+        if let Err(why) = response_tx.send(response) {
+            error!("Failed to send register response: {:?}", why);
         }
     }
 
@@ -488,12 +1454,12 @@ impl<S: Storage + Send + 'static + std::marker::Sync> Controller<S> {
         user_id: u64,
         response_tx: oneshot::Sender<UnRegisterResponse>,
         removed_tx: oneshot::Sender<RemoveUserResponse>,
-        pending_unregisters: Arc<Mutex<HashMap<String, oneshot::Sender<RemoveUserResponse>>>>,
+        pending_unregisters: PendingUnregisters,
         span: Span,
     ) {
         let _enter = span.enter();
         debug!("Unregistering user");
-        if !self.storage.contains_user(&user_id) {
+        if !self.read_storage().contains_user(&user_id) {
             if let Err(why) = response_tx.send(UnRegisterResponse::NotRegistered) {
                 error!(
                     "Failed to send UnregisterResponse::NotRegistered: {:?}",
@@ -523,45 +1489,39 @@ impl<S: Storage + Send + 'static + std::marker::Sync> Controller<S> {
                 return;
             }
         };
-        debug!(?session, ?encoded_session, "Created session");
-        let url = format!(
-            // "{}/unregister/{}/{}",
-            "{}/getout.html?username={}&session={}",
-            url,
-            urlencoding::encode(&session.username),
-            encoded_session,
+        debug!(
+            ?session,
+            ?encoded_session,
+            correlation_id = %Session::correlation_id(&encoded_session),
+            "Created session"
+        );
+        let url = build_link(
+            &url,
+            &format!(
+                "getout.html?username={}&session={}",
+                urlencoding::encode(&session.username),
+                encoded_session,
+            ),
         );
         if let Err(why) = response_tx.send(UnRegisterResponse::Unregister(url)) {
             error!("Failed to send CheckResponse::Register: {:?}", why);
         };
-        let pending_unregisters2 = pending_unregisters.clone();
-        let esession = encoded_session.clone();
+        let expiration = Duration::from_secs(CONFIG.wait().unregister_session_secs());
         let mut guard = pending_unregisters.lock().in_current_span().await;
-        tokio::spawn(async move {
-            let span = info_span!("unregister_timeout");
-            let expiration = CONFIG.wait().session_expiration;
-            tokio::time::sleep(std::time::Duration::from_secs(expiration))
-                .in_current_span()
-                .await;
-            let _enter = span.enter();
-            info!("Session expired");
-            let mut guard = pending_unregisters2.lock().in_current_span().await;
-            if let Some(removed_tx) = guard.remove(&esession) {
-                if let Err(why) =
-                    removed_tx.send(RemoveUserResponse::Error(anyhow!("Session expired")))
-                {
-                    error!("Failed to send RemoveUserResponse::Expired: {:?}", why);
-                };
-            }
-        });
-        guard.insert(encoded_session, removed_tx);
+        guard.insert(
+            encoded_session,
+            PendingUnregister {
+                removed_tx,
+                expires_at: Instant::now() + expiration,
+            },
+        );
     }
 
     async fn delete_user(
         &mut self,
         session_str: String,
         response_tx: oneshot::Sender<RemoveUserResponse>,
-        pending_unregisters: Arc<Mutex<HashMap<String, oneshot::Sender<RemoveUserResponse>>>>,
+        pending_unregisters: PendingUnregisters,
         span: Span,
     ) {
         let _enter = span.enter();
@@ -576,8 +1536,10 @@ impl<S: Storage + Send + 'static + std::marker::Sync> Controller<S> {
             }
         };
         let mut guard = pending_unregisters.lock().in_current_span().await;
-        let removed_tx = guard.remove(&session_str);
-        if session.expired() {
+        let removed_tx = guard
+            .remove(&session_str)
+            .map(|pending| pending.removed_tx);
+        if session.expired(CONFIG.wait().unregister_session_secs()) {
             error!(?session, "Session expired");
             if let Err(why) =
                 response_tx.send(RemoveUserResponse::Error(anyhow!("Session expired")))
@@ -596,9 +1558,8 @@ impl<S: Storage + Send + 'static + std::marker::Sync> Controller<S> {
             return;
         }
         debug!(session.user_id, "Removing user");
-        if let Err(why) = self.storage.remove_user(&session.user_id) {
-            error!("Failed to remove user: {:?}", why);
-        }
+        let soft = CONFIG.wait().storage.soft_delete_users;
+        self.remove_user(session.user_id, soft).await;
         if let Err(why) = response_tx.send(RemoveUserResponse::Success) {
             error!("Failed to send RemoveUserResponse::Success: {:?}", why);
         };
@@ -610,125 +1571,816 @@ impl<S: Storage + Send + 'static + std::marker::Sync> Controller<S> {
             error!("No pending unregister for session {}", session_str);
         }
     }
+
+    /// Removes a user who confirmed the unregistration and counts it
+    async fn remove_user(&mut self, user_id: u64, soft: bool) {
+        self.offload_write(move |storage| {
+            // a soft deleted user left already, only their tombstone is removed
+            let registered = storage.contains_user(&user_id);
+            match storage.remove_user(&user_id, soft) {
+                Ok(()) if registered => {
+                    if let Err(why) = storage.count_unregistration() {
+                        error!("Failed to count unregistration: {:?}", why);
+                    }
+                }
+                Ok(()) => debug!(user_id, "Removed a soft deleted user"),
+                Err(why) => error!("Failed to remove user: {:?}", why),
+            }
+        })
+        .await
+    }
 }
 
-#[instrument(level = "debug", skip(wallet, gates))]
-pub async fn check_with_wallet(
+/// Points the gate with the identifier at another role and returns it, or
+/// none if the guild has no such gate. The role is part of the identifier,
+/// so the gate is stored under a new key and the old one is removed. The
+/// new key is written first, an interrupted move leaves both behind rather
+/// than none
+pub fn move_gate<S: Storage>(
+    storage: &mut S,
+    guild_id: u64,
+    identifier: u128,
+    new_role_id: u64,
+) -> Result<Option<Gate>> {
+    let mut gate = match storage
+        .list_gates(&guild_id)?
+        .find(|gate| gate.identifier() == identifier)
+    {
+        Some(gate) => gate,
+        None => return Ok(None),
+    };
+    if gate.role_id == new_role_id {
+        return Ok(Some(gate));
+    }
+    gate.role_id = new_role_id;
+    storage.add_gate(&guild_id, gate.clone())?;
+    storage.remove_gate(&guild_id, identifier)?;
+    Ok(Some(gate))
+}
+
+/// Links another wallet to a registered user, unless the wallet is
+/// linked already or the user has as many wallets as allowed
+fn link_wallet<S: Storage>(
+    storage: &mut S,
+    user_id: u64,
     wallet: SecretString,
-    gates: impl Iterator<Item = Gate>,
-) -> Vec<u64> {
-    debug!("Checking with the user's wallet");
-    let wallet = match H160::from_str(wallet.expose_secret()) {
-        Ok(wallet) => wallet,
+    max: usize,
+) -> RegisterResponse {
+    let mut wallets = match storage.get_user(&user_id) {
+        Ok(wallets) => wallets,
         Err(why) => {
-            error!("Invalid wallet address: {:?}:{:?}", wallet, why);
-            return Vec::new();
+            let kind = StorageErrorKind::report(&why, "get user");
+            return RegisterResponse::Error { kind, why };
         }
     };
-    let wallet_arc = Arc::new(wallet);
-    let mut set = JoinSet::new();
-    for gate in gates {
-        debug!(
-            name = gate.name(),
-            gate.role_id,
-            identifier = gate.identifier(),
-            "Checking gate"
-        );
-        let wallet = wallet_arc.clone();
-        set.spawn(gate.check_condition(*wallet).in_current_span());
-    }
-    let mut granted_roles = Vec::new();
-    while let Some(check_result) = set.join_next().in_current_span().await {
-        match check_result {
-            Ok(result) => match result {
-                Some(role_id) => granted_roles.push(role_id),
-                None => debug!("Gate did not grant a role"),
-            },
-            Err(why) => {
-                error!("Failed to check gate: {:?}", why);
-            }
+    if wallets.iter().any(|linked| {
+        linked
+            .expose_secret()
+            .eq_ignore_ascii_case(wallet.expose_secret())
+    }) {
+        debug!("User {} already registered with the wallet", user_id);
+        return RegisterResponse::AlreadyRegistered;
+    }
+    if max > 0 && wallets.len() >= max {
+        debug!(linked = wallets.len(), max, "User has too many wallets");
+        return RegisterResponse::TooManyWallets { max };
+    }
+    wallets.push(wallet);
+    match storage.add_user(user_id, wallets) {
+        Ok(()) => RegisterResponse::Success,
+        Err(why) => {
+            let kind = StorageErrorKind::report(&why, "link wallet");
+            RegisterResponse::Error { kind, why }
         }
     }
-    granted_roles.sort();
-    granted_roles.dedup();
-    granted_roles
 }
 
-/// This represents a session for a user that has not yet registered their
-/// and is used to generate a url for the user to register their wallet.
-/// The session is encoded as a nonce and string separated by a dot.
-/// The string is an encrypted version of the user information
-#[derive(Debug)]
-pub struct Session {
-    pub user_id: u64,
-    pub username: String,
-    pub timestamp: u64,
+/// Keeps track of since when the user fails the gates of the held roles and
+/// returns the failed roles that are still within the grace period. Only a
+/// held role can be in grace, the failure of a role the user does not hold
+/// is not recorded, and a granted role starts over. Roles whose gates could
+/// not be checked are left alone, as are roles whose failure can not be
+/// looked up
+#[allow(clippy::too_many_arguments)]
+fn track_failures<S: Storage>(
+    storage: &mut S,
+    guild_id: u64,
+    user_id: u64,
+    roles: &HashSet<u64>,
+    held: &[u64],
+    outcome: &CheckOutcome,
+    now: u64,
+    grace: u64,
+) -> Vec<u64> {
+    let mut in_grace = Vec::new();
+    for role_id in roles {
+        if outcome.errored.contains(role_id) {
+            continue;
+        }
+        let failing = held.contains(role_id) && !outcome.granted.contains(role_id);
+        let first_failed = match storage.first_failed(&guild_id, &user_id, role_id) {
+            Ok(first_failed) => first_failed,
+            Err(why) => {
+                error!(role_id, "Failed to get the first failure: {:?}", why);
+                if failing {
+                    in_grace.push(*role_id);
+                }
+                continue;
+            }
+        };
+        if !failing {
+            if first_failed.is_some() {
+                if let Err(why) = storage.set_first_failed(&guild_id, &user_id, role_id, None) {
+                    error!(role_id, "Failed to clear the first failure: {:?}", why);
+                }
+            }
+            continue;
+        }
+        let first_failed = match first_failed {
+            Some(first_failed) => first_failed,
+            None => {
+                if let Err(why) = storage.set_first_failed(&guild_id, &user_id, role_id, Some(now))
+                {
+                    error!(role_id, "Failed to store the first failure: {:?}", why);
+                }
+                now
+            }
+        };
+        if now.saturating_sub(first_failed) < grace {
+            in_grace.push(*role_id);
+        }
+    }
+    in_grace.sort();
+    in_grace
 }
 
-impl Session {
-    pub fn new(user_id: u64, username: String) -> Result<Self> {
-        let timestamp = SystemTime::now().duration_since(UNIX_EPOCH)?.as_secs();
-        Ok(Session {
-            user_id,
-            username,
-            timestamp,
-        })
+/// Ends the grace period of the granted roles, e.g. after `/get in` granted
+/// them, so a later failure starts a new one. The storage is only locked for
+/// writing if a failure is recorded
+fn clear_failures<S: Storage>(storage: &RwLock<S>, guild_id: u64, user_id: u64, granted: &[u64]) {
+    let failed = {
+        let storage = read(storage);
+        granted
+            .iter()
+            .filter(|role_id| {
+                matches!(
+                    storage.first_failed(&guild_id, &user_id, role_id),
+                    Ok(Some(_))
+                )
+            })
+            .copied()
+            .collect::<Vec<_>>()
+    };
+    if failed.is_empty() {
+        return;
     }
-
-    pub fn expired(&self) -> bool {
-        let timestamp = SystemTime::now()
-            .duration_since(UNIX_EPOCH)
-            .expect("Failed to get system timestamp")
-            .as_secs();
-        timestamp - self.timestamp > CONFIG.wait().session_expiration
+    let mut storage = write(storage);
+    for role_id in failed {
+        if let Err(why) = storage.set_first_failed(&guild_id, &user_id, &role_id, None) {
+            error!(role_id, "Failed to clear the first failure: {:?}", why);
+        }
     }
+}
 
-    pub fn encode(&self) -> Result<String> {
-        let plaintext_str = format!("{}:{}:{}", self.user_id, self.username, self.timestamp);
+/// A panicking storage call poisons the lock, but should not take down all
+/// the later messages, so the poisoning is ignored
+fn read<S>(storage: &RwLock<S>) -> RwLockReadGuard<'_, S> {
+    storage.read().unwrap_or_else(PoisonError::into_inner)
+}
 
-        let plaintext = plaintext_str.as_bytes();
-        let key_bytes = SESSION_KEY.wait();
-        let key = GenericArray::from_slice(key_bytes);
+fn write<S>(storage: &RwLock<S>) -> RwLockWriteGuard<'_, S> {
+    storage.write().unwrap_or_else(PoisonError::into_inner)
+}
 
-        let cipher = ChaCha20Poly1305::new(key);
-        let nonce = ChaCha20Poly1305::generate_nonce(&mut OsRng);
-        let ciphertext = cipher
-            .encrypt(&nonce, plaintext)
-            .map_err(|e| anyhow!("{e}"))?;
-        let encoded_nonce = hex::encode(nonce);
-        let encoded_ciphertext = hex::encode(ciphertext);
-        Ok(format!("{}.{}", encoded_nonce, encoded_ciphertext))
-    }
+/// The gates of a guild together with the global gates whose role exists
+/// in the guild
+fn guild_gates<S: Storage>(
+    storage: &S,
+    guild_roles: Option<&HashMap<String, u64>>,
+    guild_id: u64,
+) -> Result<Vec<Gate>> {
+    let global_gates = storage.list_global_gates()?;
+    let mut gates = match storage.list_gates(&guild_id) {
+        Ok(gates) => gates.collect::<Vec<_>>(),
+        // a guild without gates of its own still gets the global ones
+        Err(why) if !global_gates.is_empty() => {
+            debug!("No gates for guild: {:?}", why);
+            Vec::new()
+        }
+        Err(why) => return Err(why),
+    };
+    gates.extend(resolve_global_gates(global_gates, guild_roles));
+    Ok(gates)
 }
 
-impl FromStr for Session {
-    type Err = anyhow::Error;
-    fn from_str(s: &str) -> Result<Self> {
-        let key_bytes = SESSION_KEY.wait();
-        let key = GenericArray::from_slice(key_bytes);
+/// Whether gating is frozen in the guild. A failed lookup is logged and
+/// does not freeze the guild, so a storage problem does not silently stop
+/// all gating
+fn is_frozen<S: Storage>(storage: &S, guild_id: u64) -> bool {
+    storage.is_frozen(&guild_id).unwrap_or_else(|why| {
+        error!("Failed to get the gating freeze: {:?}", why);
+        false
+    })
+}
+
+fn list_roles<S: Storage>(
+    storage: &S,
+    guild_roles: Option<&HashMap<String, u64>>,
+    guild_id: u64,
+    response: oneshot::Sender<HashSet<u64>>,
+) {
+    match guild_gates(storage, guild_roles, guild_id) {
+        Ok(gates) => {
+            // roles of disabled gates are left alone, so they are neither
+            // granted nor revoked while the gate is switched off
+            let roles = HashSet::from_iter(
+                gates
+                    .into_iter()
+                    .filter(|gate| gate.enabled)
+                    .flat_map(|gate| gate.role_ids()),
+            );
+            if let Err(why) = response.send(roles) {
+                error!("Failed to send roles: {:?}", why);
+            }
+        }
+        Err(why) => {
+            error!("Failed to list gates: {:?}", why);
+        }
+    }
+}
+
+fn list_gates<S: Storage>(storage: &S, guild_id: u64, response: oneshot::Sender<Vec<Gate>>) {
+    debug!("Received list request for guild");
+    match storage.list_gates(&guild_id) {
+        Ok(gate_iter) => {
+            let gates = gate_iter.collect::<Vec<Gate>>();
+            debug!(?gates, "Sending list response");
+            if let Err(why) = response.send(gates) {
+                error!("Failed to send list response: {:?}", why);
+            }
+        }
+        Err(why) => {
+            error!("Failed to list gates: {:?}", why);
+        }
+    }
+}
+
+/// What a check found in the storage for the user
+enum Lookup {
+    Unregistered {
+        was_registered: bool,
+    },
+    Frozen,
+    Found {
+        wallets: Vec<SecretString>,
+        gates: Vec<Gate>,
+    },
+    Failed(Error),
+}
+
+/// Checks a user beside the loop. The storage is read on the blocking pool,
+/// then the gates are checked without holding the storage. Within the
+/// cooldown of the last check of the user, its outcome is sent instead
+#[allow(clippy::too_many_arguments)]
+async fn check<S>(
+    storage: Arc<RwLock<S>>,
+    recent_checks: Arc<RecentChecks>,
+    cooldown: Duration,
+    guild_roles: Option<HashMap<String, u64>>,
+    guild_id: u64,
+    username: String,
+    user_id: u64,
+    response_tx: oneshot::Sender<CheckResponse>,
+    span: Span,
+) where
+    S: Storage + Send + Sync + 'static,
+{
+    let lookup_span = span.clone();
+    let lookup_storage = storage.clone();
+    let lookup = tokio::task::spawn_blocking(move || {
+        let _enter = lookup_span.enter();
+        let storage = read(&lookup_storage);
+        if !storage.contains_user(&user_id) {
+            return Lookup::Unregistered {
+                was_registered: storage.was_registered(&user_id),
+            };
+        }
+        if is_frozen(&*storage, guild_id) {
+            return Lookup::Frozen;
+        }
+        let wallets = match storage.get_user(&user_id) {
+            Ok(wallets) => wallets,
+            Err(why) => {
+                error!("Failed to get user: {:?}", why);
+                return Lookup::Failed(why);
+            }
+        };
+        match guild_gates(&*storage, guild_roles.as_ref(), guild_id) {
+            Ok(gates) => Lookup::Found { wallets, gates },
+            Err(why) => {
+                error!("Failed to list gates: {:?}", why);
+                Lookup::Failed(why)
+            }
+        }
+    })
+    .await
+    .unwrap_or_else(|why| Lookup::Failed(anyhow!("Failed to read the storage: {}", why)));
+
+    let _enter = span.enter();
+    debug!("Checking user");
+    let response = match lookup {
+        Lookup::Unregistered { was_registered } => {
+            debug!(was_registered, "User not registered");
+            match registration_link(user_id, username) {
+                Ok(url) if was_registered => CheckResponse::Reregister(url),
+                Ok(url) => CheckResponse::Register(url),
+                Err(why) => CheckResponse::Error(why),
+            }
+        }
+        Lookup::Frozen => {
+            debug!("Gating is frozen");
+            CheckResponse::Frozen
+        }
+        Lookup::Failed(why) => CheckResponse::Error(why),
+        Lookup::Found { wallets, gates } => {
+            debug!("Found wallet for user");
+            match recent_checks.get(guild_id, user_id, cooldown) {
+                Some(outcome) => {
+                    debug!(?outcome, "Roles granted by the last check");
+                    CheckResponse::Grant(outcome)
+                }
+                None => {
+                    let outcome = check_with_wallets(wallets, gates.into_iter())
+                        .in_current_span()
+                        .await;
+                    debug!(?outcome, "Roles granted");
+                    if !outcome.granted.is_empty() {
+                        let granted = outcome.granted.clone();
+                        let clear_span = Span::current();
+                        let cleared = tokio::task::spawn_blocking(move || {
+                            let _enter = clear_span.enter();
+                            clear_failures(&storage, guild_id, user_id, &granted)
+                        })
+                        .await;
+                        if let Err(why) = cleared {
+                            error!("Failed to clear the first failures: {:?}", why);
+                        }
+                    }
+                    // errored gates are checked again right away
+                    if !cooldown.is_zero() && outcome.errored.is_empty() {
+                        recent_checks.insert(guild_id, user_id, &outcome, cooldown);
+                    }
+                    CheckResponse::Grant(outcome)
+                }
+            }
+        }
+    };
+    if let Err(why) = response_tx.send(response) {
+        error!("Failed to send check response: {:?}", why);
+    }
+}
+
+/// Creates a registration session for the user and the link to the
+/// registration page with it
+fn registration_link(user_id: u64, username: String) -> Result<String> {
+    let url = CONFIG.wait().server.url.clone();
+    let session = Session::new(user_id, username).map_err(|why| {
+        error!("Failed to create session: {:?}", why);
+        why
+    })?;
+    let encoded_session = session.encode().map_err(|why| {
+        error!("Failed to encode session: {:?}", why);
+        why
+    })?;
+    debug!(
+        correlation_id = %Session::correlation_id(&encoded_session),
+        "Created registration session"
+    );
+    Ok(build_link(
+        &url,
+        &format!(
+            "getin.html?username={}&session={}",
+            urlencoding::encode(&session.username),
+            encoded_session
+        ),
+    ))
+}
+
+/// Joins the configured base url with a path, so that a trailing slash in
+/// the configured url does not end up as a double slash in the link
+fn build_link(base_url: &str, path: &str) -> String {
+    format!(
+        "{}/{}",
+        base_url.trim_end_matches('/'),
+        path.trim_start_matches('/')
+    )
+}
+
+/// Periodically cleans up the pending unregisters whose session expired, so
+/// we need a single task for this instead of one per unregister
+async fn reap_pending_unregisters(pending_unregisters: PendingUnregisters, period: Duration) {
+    let mut interval = tokio::time::interval(period);
+    loop {
+        interval.tick().await;
+        let span = info_span!("unregister_reaper");
+        let mut guard = pending_unregisters.lock().instrument(span.clone()).await;
+        let _enter = span.enter();
+        reap_expired(&mut guard, Instant::now());
+    }
+}
+
+/// Removes all pending unregisters that expired before `now` and notifies
+/// the waiting discord interaction about the expiration
+fn reap_expired(pending_unregisters: &mut HashMap<String, PendingUnregister>, now: Instant) {
+    let expired = pending_unregisters
+        .iter()
+        .filter(|(_, pending)| pending.expires_at <= now)
+        .map(|(session, _)| session.clone())
+        .collect::<Vec<_>>();
+    for session in expired {
+        if let Some(pending) = pending_unregisters.remove(&session) {
+            info!("Session expired");
+            if let Err(why) = pending
+                .removed_tx
+                .send(RemoveUserResponse::Error(anyhow!("Session expired")))
+            {
+                error!("Failed to send RemoveUserResponse::Expired: {:?}", why);
+            };
+        }
+    }
+}
+
+/// Checks the condition of a single gate for all wallets, regardless of
+/// whether the gate is enabled
+#[instrument(level = "debug", skip(gate, wallets), fields(identifier = gate.identifier()))]
+async fn gate_coverage(gate: &Gate, wallets: Vec<SecretString>) -> Coverage {
+    let mut coverage = Coverage::default();
+    let mut set = JoinSet::new();
+    for wallet in wallets {
+        let wallet = match H160::from_str(wallet.expose_secret()) {
+            Ok(wallet) => wallet,
+            Err(why) => {
+                error!("Invalid wallet address: {:?}", why);
+                continue;
+            }
+        };
+        set.spawn(
+            gate.clone()
+                .check_condition(wallet, false)
+                .in_current_span(),
+        );
+    }
+    while let Some(result) = set.join_next().in_current_span().await {
+        coverage.checked += 1;
+        match result {
+            Ok(Ok(Some(_))) => coverage.qualified += 1,
+            Ok(Ok(None)) => {}
+            Ok(Err(why)) => {
+                warn!("Could not check gate: {:?}", why);
+                coverage.errored += 1;
+            }
+            Err(why) => {
+                error!("Failed to check gate: {:?}", why);
+                coverage.errored += 1;
+            }
+        }
+    }
+    coverage
+}
+
+/// Resolves the global gates to gates of a guild by the role names of the
+/// guild, gates for role names the guild doesn't have are skipped
+fn resolve_global_gates(
+    global_gates: Vec<GlobalGate>,
+    roles: Option<&HashMap<String, u64>>,
+) -> Vec<Gate> {
+    let roles = match roles {
+        Some(roles) => roles,
+        None => return Vec::new(),
+    };
+    global_gates
+        .into_iter()
+        .filter_map(|global| match roles.get(&global.role_name) {
+            Some(role_id) => Some(global.for_role(*role_id)),
+            None => {
+                debug!(role_name = %global.role_name, "Guild has no role for global gate");
+                None
+            }
+        })
+        .collect()
+}
+
+pub async fn check_with_wallet(
+    wallet: SecretString,
+    gates: impl Iterator<Item = Gate>,
+) -> CheckOutcome {
+    check_gates(vec![wallet], gates, false).await
+}
+
+/// Checks all linked wallets of a user, a role is granted if any of them
+/// meets a gate of the role. The first wallet that does is recorded in the
+/// outcome
+pub async fn check_with_wallets(
+    wallets: Vec<SecretString>,
+    gates: impl Iterator<Item = Gate>,
+) -> CheckOutcome {
+    check_gates(wallets, gates, false).await
+}
+
+/// Like [`check_with_wallet`], but looks up live data instead of cached
+/// results, used for rechecks an admin triggers
+pub async fn check_with_wallet_uncached(
+    wallet: SecretString,
+    gates: impl Iterator<Item = Gate>,
+) -> CheckOutcome {
+    check_gates(vec![wallet], gates, true).await
+}
+
+/// Checks the wallets one after the other, so a user with several wallets
+/// does not multiply the concurrent lookups
+#[instrument(level = "debug", skip(wallets, gates))]
+async fn check_gates(
+    wallets: Vec<SecretString>,
+    gates: impl Iterator<Item = Gate>,
+    bypass_cache: bool,
+) -> CheckOutcome {
+    let gates = gates.collect::<Vec<_>>();
+    if wallets.len() > 1 && !bypass_cache {
+        prefetch(&wallets, &gates).in_current_span().await;
+    }
+    let mut outcome = CheckOutcome::default();
+    for wallet in wallets {
+        let wallet_outcome = check_gates_with_wallet(wallet, gates.iter().cloned(), bypass_cache)
+            .in_current_span()
+            .await;
+        outcome.merge(wallet_outcome);
+    }
+    outcome
+}
+
+/// Looks up the data of all wallets of a user at once where the gates
+/// support it, e.g. the reputations in the same domain. If that fails the
+/// wallets are simply looked up one by one by the checks
+#[instrument(level = "debug", skip(wallets, gates))]
+async fn prefetch(wallets: &[SecretString], gates: &[Gate]) {
+    let wallets = wallets
+        .iter()
+        .filter_map(|wallet| H160::from_str(wallet.expose_secret()).ok())
+        .collect::<Vec<_>>();
+    for gate in gates.iter().filter(|gate| gate.enabled) {
+        if let Err(why) = gate.condition.prefetch(&wallets).in_current_span().await {
+            debug!(
+                identifier = gate.identifier(),
+                "Could not prefetch the wallets: {:?}", why
+            );
+        }
+    }
+}
+
+/// Checks the gates with a single wallet. Gates of the same cost are checked
+/// concurrently, cheaper ones first, and gates whose roles were granted by
+/// a cheaper gate already are skipped
+#[instrument(level = "debug", skip(wallet, gates))]
+async fn check_gates_with_wallet(
+    wallet: SecretString,
+    gates: impl Iterator<Item = Gate>,
+    bypass_cache: bool,
+) -> CheckOutcome {
+    debug!("Checking with the user's wallet");
+    let wallet = match H160::from_str(wallet.expose_secret()) {
+        Ok(wallet) => wallet,
+        Err(why) => {
+            error!("Invalid wallet address: {:?}:{:?}", wallet, why);
+            return CheckOutcome::default();
+        }
+    };
+    let wallet_arc = Arc::new(wallet);
+    let mut gates = gates
+        .filter(|gate| {
+            if !gate.enabled {
+                debug!(identifier = gate.identifier(), "Skipping disabled gate");
+            }
+            gate.enabled
+        })
+        .collect::<Vec<_>>();
+    // cheap gates are checked first, a role they grant needs no expensive
+    // lookups for the other gates of the role anymore
+    gates.sort_by_key(|gate| gate.condition.cost());
+    let mut outcome = CheckOutcome::default();
+    for batch in gates.chunk_by(|a, b| a.condition.cost() == b.condition.cost()) {
+        let mut set = JoinSet::new();
+        for gate in batch {
+            // a gate may manage several roles, e.g. tiers, of which it
+            // grants at most one, an error leaves all of them untouched
+            let role_ids = gate.role_ids();
+            if role_ids.iter().all(|role| outcome.granted.contains(role)) {
+                debug!(
+                    identifier = gate.identifier(),
+                    "Skipping gate, its roles are granted already"
+                );
+                continue;
+            }
+            debug!(
+                name = gate.name(),
+                gate.role_id,
+                identifier = gate.identifier(),
+                "Checking gate"
+            );
+            let wallet = wallet_arc.clone();
+            let grant_message = gate.grant_message.clone();
+            let identifier = gate.identifier();
+            // the panic is caught within the task, so we still know which
+            // roles the panicking gate manages
+            let check = AssertUnwindSafe(
+                gate.clone()
+                    .check_explained(*wallet, bypass_cache)
+                    .in_current_span(),
+            )
+            .catch_unwind();
+            set.spawn(async move {
+                let result = check.await.unwrap_or_else(|panic| {
+                    error!(
+                        identifier,
+                        ?role_ids,
+                        "Gate panicked while checking: {}",
+                        panic_message(panic.as_ref())
+                    );
+                    Err(GatePanicked.into())
+                });
+                (role_ids, grant_message, result)
+            });
+        }
+        while let Some(check_result) = set.join_next().in_current_span().await {
+            match check_result {
+                Ok((role_ids, _, Err(why))) if why.is::<GatePanicked>() => {
+                    outcome.errored.extend(role_ids.iter().copied());
+                    outcome.panicked.extend(role_ids);
+                }
+                Ok((_, grant_message, Ok((Some(role_id), reason)))) => {
+                    debug!(role_id, ?reason, "Gate granted role");
+                    outcome.granted.push(role_id);
+                    if let Some(message) = grant_message {
+                        outcome.grant_messages.entry(role_id).or_insert(message);
+                    }
+                    if let Some(reason) = reason {
+                        outcome.reasons.entry(role_id).or_insert(reason);
+                    }
+                    outcome.wallets.entry(role_id).or_insert(wallet);
+                }
+                Ok((_, _, Ok((None, _)))) => debug!("Gate did not grant a role"),
+                Ok((role_ids, _, Err(why))) => {
+                    warn!(?role_ids, "Could not check gate: {:?}", why);
+                    outcome.errored.extend(role_ids);
+                }
+                Err(why) => {
+                    error!("Failed to check gate: {:?}", why);
+                }
+            }
+        }
+    }
+    outcome.granted.sort();
+    outcome.granted.dedup();
+    // a role granted by one gate does not need to be reported as errored
+    // because of another gate
+    outcome
+        .errored
+        .retain(|role_id| !outcome.granted.contains(role_id));
+    outcome.errored.sort();
+    outcome.errored.dedup();
+    outcome
+        .panicked
+        .retain(|role_id| outcome.errored.contains(role_id));
+    outcome.panicked.sort();
+    outcome.panicked.dedup();
+    outcome
+}
+
+/// The error of a gate check that panicked
+#[derive(Debug)]
+struct GatePanicked;
+
+impl std::fmt::Display for GatePanicked {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "The gate panicked while checking")
+    }
+}
+
+impl std::error::Error for GatePanicked {}
+
+/// The message of a caught panic, if it was raised with one
+fn panic_message(panic: &(dyn std::any::Any + Send)) -> &str {
+    if let Some(message) = panic.downcast_ref::<&str>() {
+        message
+    } else if let Some(message) = panic.downcast_ref::<String>() {
+        message
+    } else {
+        "unknown panic"
+    }
+}
+
+/// This represents a session for a user that has not yet registered their
+/// and is used to generate a url for the user to register their wallet.
+/// The session is encoded as a nonce and string separated by a dot.
+/// The string is an encrypted version of the user information
+#[derive(Debug)]
+pub struct Session {
+    pub user_id: u64,
+    pub username: String,
+    pub timestamp: u64,
+}
+
+impl Session {
+    /// Creates a session for the user, the username is truncated to
+    /// [`MAX_SESSION_USERNAME_CHARS`] characters
+    pub fn new(user_id: u64, username: String) -> Result<Self> {
+        let timestamp = SystemTime::now().duration_since(UNIX_EPOCH)?.as_secs();
+        let username = match username.char_indices().nth(MAX_SESSION_USERNAME_CHARS) {
+            Some((end, _)) => username[..end].to_string(),
+            None => username,
+        };
+        Ok(Session {
+            user_id,
+            username,
+            timestamp,
+        })
+    }
+
+    /// A short id derived from the random nonce of an encoded session. It is
+    /// logged when the session is created and shown to the user when a
+    /// request with this session fails, so support can find the logs for it
+    pub fn correlation_id(encoded_session: &str) -> String {
+        encoded_session.chars().take(CORRELATION_ID_LEN).collect()
+    }
+
+    /// Whether the session is older than the lifetime in seconds, which
+    /// differs for registration and unregistration sessions
+    pub fn expired(&self, lifetime: u64) -> bool {
+        self.expired_at(unix_timestamp(), lifetime)
+    }
+
+    fn expired_at(&self, now: u64, lifetime: u64) -> bool {
+        now.saturating_sub(self.timestamp) > lifetime
+    }
+
+    pub fn encode(&self) -> Result<String> {
+        let keys = SESSION_KEYS
+            .wait()
+            .read()
+            .map_err(|_| anyhow!("The session keys are poisoned"))?;
+        self.encode_with(&keys)
+    }
+
+    fn encode_with(&self, keys: &SessionKeys) -> Result<String> {
+        let plaintext_str = format!("{}:{}:{}", self.user_id, self.username, self.timestamp);
+
+        let plaintext = plaintext_str.as_bytes();
+        let key = GenericArray::from_slice(&keys.current);
+
         let cipher = ChaCha20Poly1305::new(key);
+        let nonce = ChaCha20Poly1305::generate_nonce(&mut OsRng);
+        let ciphertext = cipher
+            .encrypt(&nonce, plaintext)
+            .map_err(|e| anyhow!("{e}"))?;
+        let encoded_nonce = hex::encode(nonce);
+        let encoded_ciphertext = hex::encode(ciphertext);
+        Ok(format!("{}.{}", encoded_nonce, encoded_ciphertext))
+    }
+
+    /// Decodes a session with the current key, or with the previous key
+    /// during its grace period
+    fn decode_with(s: &str, keys: &SessionKeys, now: u64) -> Result<Self> {
         let uri_parts: Vec<_> = s.split('.').collect();
         if uri_parts.len() != 2 {
             bail!("Invalid Uri: could not split in two parts");
         }
         let nonce_bytes = hex::decode(uri_parts[0])?;
+        if nonce_bytes.len() != 12 {
+            bail!("Invalid Uri: invalid nonce");
+        }
         let nonce = GenericArray::from_slice(&nonce_bytes);
 
         let ciphertext = hex::decode(uri_parts[1])?;
-        let plaintext = if let Ok(plaintext) = cipher.decrypt(nonce, ciphertext.as_slice()) {
-            plaintext
-        } else {
-            bail!("Invalid Uri: could not decrypt");
-        };
+        let plaintext = keys
+            .valid_keys(now)
+            .find_map(|key| {
+                ChaCha20Poly1305::new(GenericArray::from_slice(key))
+                    .decrypt(nonce, ciphertext.as_slice())
+                    .ok()
+            })
+            .ok_or(anyhow!("Invalid Uri: could not decrypt"))?;
         let plaintext_str = String::from_utf8(plaintext)?;
 
-        let parts: Vec<_> = plaintext_str.split(':').collect();
-        if parts.len() != 3 {
-            bail!("Invalid session string");
-        }
-        let user_id = parts[0].parse()?;
-        let username = parts[1].parse()?;
-        let timestamp = parts[2].parse()?;
+        // the username may contain the separator itself, so the user id and
+        // the timestamp are split off at the first and last separator
+        let (user_id, rest) = plaintext_str
+            .split_once(':')
+            .ok_or(anyhow!("Invalid session string"))?;
+        let (username, timestamp) = rest
+            .rsplit_once(':')
+            .ok_or(anyhow!("Invalid session string"))?;
+        let user_id = user_id.parse()?;
+        let username = username.to_string();
+        let timestamp = timestamp.parse()?;
 
         Ok(Self {
             user_id,
@@ -738,29 +2390,1696 @@ impl FromStr for Session {
     }
 }
 
+impl FromStr for Session {
+    type Err = anyhow::Error;
+    fn from_str(s: &str) -> Result<Self> {
+        let keys = SESSION_KEYS
+            .wait()
+            .read()
+            .map_err(|_| anyhow!("The session keys are poisoned"))?;
+        Session::decode_with(s, &keys, unix_timestamp())
+    }
+}
+
+/// The keys of the registration sessions. New sessions are encrypted with
+/// the current key, the previous key only decrypts sessions that were issued
+/// before the last rotation, until its grace period ends
+struct SessionKeys {
+    current: Vec<u8>,
+    /// The previous key with the unix timestamp at which it expires
+    previous: Option<(Vec<u8>, u64)>,
+}
+
+impl SessionKeys {
+    fn new() -> Self {
+        Self {
+            current: ChaCha20Poly1305::generate_key(&mut OsRng).to_vec(),
+            previous: None,
+        }
+    }
+
+    /// Replaces the current key with a new one, the replaced key keeps
+    /// decrypting sessions for the grace period
+    fn rotate(&mut self, now: u64, grace: Duration) {
+        let new_key = ChaCha20Poly1305::generate_key(&mut OsRng).to_vec();
+        let previous = std::mem::replace(&mut self.current, new_key);
+        self.previous = Some((previous, now + grace.as_secs()));
+    }
+
+    /// The keys that decrypt sessions at the given time
+    fn valid_keys(&self, now: u64) -> impl Iterator<Item = &Vec<u8>> {
+        let previous = self
+            .previous
+            .iter()
+            .filter(move |(_, expires_at)| now < *expires_at)
+            .map(|(key, _)| key);
+        std::iter::once(&self.current).chain(previous)
+    }
+}
+
+/// Replaces the session key, e.g. because it may have leaked. Sessions that
+/// were issued with the previous key stay valid for the grace period, so
+/// users in the middle of a registration are not interrupted
+pub fn rotate_session_key(grace: Duration) -> Result<()> {
+    SESSION_KEYS
+        .get()
+        .ok_or(anyhow!("The controller is not started"))?
+        .write()
+        .map_err(|_| anyhow!("The session keys are poisoned"))?
+        .rotate(unix_timestamp(), grace);
+    info!(grace = grace.as_secs(), "Rotated the session key");
+    Ok(())
+}
+
+/// Rotates the session key whenever the process receives SIGHUP, with the
+/// session expiration as grace period, so no issued session breaks
+#[cfg(unix)]
+pub async fn rotate_session_key_on_hangup() {
+    use tokio::signal::unix::{signal, SignalKind};
+    let mut hangups = match signal(SignalKind::hangup()) {
+        Ok(hangups) => hangups,
+        Err(why) => {
+            error!("Failed to listen for SIGHUP: {:?}", why);
+            return;
+        }
+    };
+    while hangups.recv().await.is_some() {
+        // the sessions issued with the old key stay valid for their lifetime
+        let config = CONFIG.wait();
+        let grace = Duration::from_secs(
+            config
+                .register_session_secs()
+                .max(config.unregister_session_secs()),
+        );
+        if let Err(why) = rotate_session_key(grace) {
+            error!("Failed to rotate the session key: {:?}", why);
+        }
+    }
+}
+
+/// Whether the enforcement is paused, see [`set_enforcement_paused`]
+pub fn is_enforcement_paused() -> bool {
+    ENFORCEMENT_PAUSED.load(Ordering::SeqCst)
+}
+
+/// Pauses or resumes the enforcement. A running enforcement stops before its
+/// next guild or page of members, checks of single users are not affected
+pub fn set_enforcement_paused(paused: bool) {
+    ENFORCEMENT_PAUSED.store(paused, Ordering::SeqCst);
+    info!(paused, "Setting the enforcement pause");
+    #[cfg(feature = "metrics")]
+    tracing::info!(
+        gauge.enforcement_paused = paused as u64,
+        "Enforcement paused"
+    );
+}
+
+/// Pauses the enforcement whenever the process receives SIGUSR1 and resumes
+/// it on SIGUSR2, so it can be stopped during an incident without a restart
+#[cfg(unix)]
+pub async fn pause_enforcement_on_signals() {
+    use tokio::signal::unix::{signal, SignalKind};
+    let (mut pauses, mut resumes) = match (
+        signal(SignalKind::user_defined1()),
+        signal(SignalKind::user_defined2()),
+    ) {
+        (Ok(pauses), Ok(resumes)) => (pauses, resumes),
+        (Err(why), _) | (_, Err(why)) => {
+            error!("Failed to listen for SIGUSR1 and SIGUSR2: {:?}", why);
+            return;
+        }
+    };
+    loop {
+        tokio::select! {
+            Some(()) = pauses.recv() => set_enforcement_paused(true),
+            Some(()) = resumes.recv() => set_enforcement_paused(false),
+            else => break,
+        }
+    }
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
     use crate::cli::CliConfig;
     use crate::config::setup_config;
+    use crate::gate::{AllowlistGate, GateOption, GateOptionValue, GatingCondition};
     use crate::storage;
+    use serde::{Deserialize, Serialize};
 
-    async fn setup() {
-        let mut cfg = CliConfig::default();
-        cfg.discord.token = Some(secrecy::SecretString::new("dummyToken".to_owned()));
-        cfg.storage.key= Some(secrecy::SecretString::new("dummyKey".to_owned()));
-        setup_config(&cfg).unwrap();
-        Controller::<storage::InMemoryStorage>::init().await;
+    /// A gate whose condition can never be checked, like a gate whose RPC
+    /// endpoint is down
+    #[derive(Debug, Clone, Serialize, Deserialize)]
+    struct UncheckableGate;
+
+    #[typetag::serde]
+    #[async_trait::async_trait]
+    impl GatingCondition for UncheckableGate {
+        fn name() -> &'static str {
+            "uncheckable"
+        }
+        fn description() -> &'static str {
+            "Always fails to check the condition"
+        }
+        fn options() -> Vec<GateOption> {
+            Vec::new()
+        }
+        async fn from_options(_options: &[GateOptionValue]) -> Result<Box<Self>> {
+            Ok(Box::new(Self))
+        }
+        async fn check(&self, _wallet_address: H160) -> Result<bool> {
+            bail!("RPC unavailable")
+        }
+        fn hashed(&self) -> u64 {
+            0
+        }
+        fn fields(&self) -> Vec<GateOptionValue> {
+            Vec::new()
+        }
+        fn instance_name(&self) -> &'static str {
+            Self::name()
+        }
     }
 
-    #[tokio::test]
-    async fn test_session() {
-        setup().await;
-        let session = Session::new(123, "test".to_string()).unwrap();
-        let encoded = session.encode().unwrap();
-        let decoded = Session::from_str(&encoded).unwrap();
+    /// A gate with a bug that panics on every check, like an arithmetic
+    /// overflow
+    #[derive(Debug, Clone, Serialize, Deserialize)]
+    struct PanickingGate;
+
+    #[typetag::serde]
+    #[async_trait::async_trait]
+    impl GatingCondition for PanickingGate {
+        fn name() -> &'static str {
+            "panicking"
+        }
+        fn description() -> &'static str {
+            "Panics when checking the condition"
+        }
+        fn options() -> Vec<GateOption> {
+            Vec::new()
+        }
+        async fn from_options(_options: &[GateOptionValue]) -> Result<Box<Self>> {
+            Ok(Box::new(Self))
+        }
+        async fn check(&self, _wallet_address: H160) -> Result<bool> {
+            panic!("attempt to multiply with overflow")
+        }
+        fn hashed(&self) -> u64 {
+            1
+        }
+        fn fields(&self) -> Vec<GateOptionValue> {
+            Vec::new()
+        }
+        fn instance_name(&self) -> &'static str {
+            Self::name()
+        }
+    }
+
+    /// How often an [`ExpensiveGate`] was checked, by its counter, so tests
+    /// running at the same time do not count each other's checks
+    static EXPENSIVE_CHECKS: [std::sync::atomic::AtomicUsize; 2] = [
+        std::sync::atomic::AtomicUsize::new(0),
+        std::sync::atomic::AtomicUsize::new(0),
+    ];
+
+    /// A gate that is met by every wallet and counts its checks on the
+    /// counter at the index, like a reputation gate that counts its RPC calls
+    #[derive(Debug, Clone, Serialize, Deserialize)]
+    struct ExpensiveGate(usize);
+
+    #[typetag::serde]
+    #[async_trait::async_trait]
+    impl GatingCondition for ExpensiveGate {
+        fn name() -> &'static str {
+            "expensive"
+        }
+        fn description() -> &'static str {
+            "Counts the checks of the condition"
+        }
+        fn options() -> Vec<GateOption> {
+            Vec::new()
+        }
+        async fn from_options(_options: &[GateOptionValue]) -> Result<Box<Self>> {
+            Ok(Box::new(Self(0)))
+        }
+        async fn check(&self, _wallet_address: H160) -> Result<bool> {
+            EXPENSIVE_CHECKS[self.0].fetch_add(1, std::sync::atomic::Ordering::SeqCst);
+            Ok(true)
+        }
+        fn hashed(&self) -> u64 {
+            2
+        }
+        fn fields(&self) -> Vec<GateOptionValue> {
+            Vec::new()
+        }
+        fn instance_name(&self) -> &'static str {
+            Self::name()
+        }
+    }
+
+    /// How long the faulty storage takes to read the gates of a slow guild
+    const SLOW_READ: Duration = Duration::from_millis(500);
+
+    /// An in-memory storage whose writes of users, and of gates if enabled,
+    /// fail like a full disk or with broken data, and whose reads of a slow
+    /// guild hang like a disk under pressure
+    #[derive(Debug)]
+    struct FaultyStorage {
+        inner: storage::InMemoryStorage,
+        failure: StorageErrorKind,
+        fail_gates: bool,
+        slow_guild: Option<u64>,
+    }
+
+    impl FaultyStorage {
+        fn fail(&self, context: &'static str) -> Error {
+            let why: Error = match self.failure {
+                StorageErrorKind::Io => sled::Error::Io(std::io::Error::new(
+                    std::io::ErrorKind::StorageFull,
+                    "No space left on device",
+                ))
+                .into(),
+                StorageErrorKind::Serialization => {
+                    Box::new(bincode::ErrorKind::Custom("broken".to_string())).into()
+                }
+                StorageErrorKind::Other => anyhow!("Something else"),
+            };
+            why.context(context)
+        }
+    }
+
+    impl Storage for FaultyStorage {
+        type GateIter = <storage::InMemoryStorage as Storage>::GateIter;
+        type UserIter = <storage::InMemoryStorage as Storage>::UserIter;
+        type GuildIter = <storage::InMemoryStorage as Storage>::GuildIter;
+
+        fn new() -> Self {
+            Self {
+                inner: storage::InMemoryStorage::new(),
+                failure: StorageErrorKind::Io,
+                fail_gates: false,
+                slow_guild: None,
+            }
+        }
+        fn list_guilds(&self) -> Self::GuildIter {
+            self.inner.list_guilds()
+        }
+        fn remove_guild(&mut self, guild_id: u64) -> Result<()> {
+            self.inner.remove_guild(guild_id)
+        }
+        fn add_gate(&mut self, guild_id: &u64, gate: Gate) -> Result<()> {
+            if self.fail_gates {
+                return Err(self.fail("Failed to add gate"));
+            }
+            self.inner.add_gate(guild_id, gate)
+        }
+        fn list_gates(&self, guild_id: &u64) -> Result<Self::GateIter> {
+            if self.slow_guild == Some(*guild_id) {
+                std::thread::sleep(SLOW_READ);
+            }
+            self.inner.list_gates(guild_id)
+        }
+        fn remove_gate(&mut self, guild_id: &u64, identifier: u128) -> Result<()> {
+            self.inner.remove_gate(guild_id, identifier)
+        }
+        fn add_global_gate(&mut self, gate: GlobalGate) -> Result<()> {
+            self.inner.add_global_gate(gate)
+        }
+        fn list_global_gates(&self) -> Result<Vec<GlobalGate>> {
+            self.inner.list_global_gates()
+        }
+        fn remove_global_gate(&mut self, identifier: u128) -> Result<()> {
+            self.inner.remove_global_gate(identifier)
+        }
+        fn get_user(&self, user_id: &u64) -> Result<Vec<SecretString>> {
+            self.inner.get_user(user_id)
+        }
+        fn list_users(&self) -> Result<Self::UserIter> {
+            self.inner.list_users()
+        }
+        fn add_user(&mut self, _user_id: u64, _wallets: Vec<SecretString>) -> Result<()> {
+            Err(self.fail("Failed to add user"))
+        }
+        fn contains_user(&self, user_id: &u64) -> bool {
+            self.inner.contains_user(user_id)
+        }
+        fn remove_user(&mut self, user_id: &u64, soft: bool) -> Result<()> {
+            self.inner.remove_user(user_id, soft)
+        }
+        fn removed_at(&self, user_id: &u64) -> Result<Option<u64>> {
+            self.inner.removed_at(user_id)
+        }
+        fn last_checked(&self, guild_id: &u64, user_id: &u64) -> Result<Option<u64>> {
+            self.inner.last_checked(guild_id, user_id)
+        }
+        fn set_last_checked(
+            &mut self,
+            guild_id: &u64,
+            user_id: &u64,
+            timestamp: u64,
+        ) -> Result<()> {
+            self.inner.set_last_checked(guild_id, user_id, timestamp)
+        }
+        fn first_failed(
+            &self,
+            guild_id: &u64,
+            user_id: &u64,
+            role_id: &u64,
+        ) -> Result<Option<u64>> {
+            self.inner.first_failed(guild_id, user_id, role_id)
+        }
+        fn set_first_failed(
+            &mut self,
+            guild_id: &u64,
+            user_id: &u64,
+            role_id: &u64,
+            timestamp: Option<u64>,
+        ) -> Result<()> {
+            self.inner
+                .set_first_failed(guild_id, user_id, role_id, timestamp)
+        }
+        fn registration_stats(&self) -> Result<RegistrationStats> {
+            self.inner.registration_stats()
+        }
+        fn count_registration(&mut self) -> Result<()> {
+            self.inner.count_registration()
+        }
+        fn count_unregistration(&mut self) -> Result<()> {
+            self.inner.count_unregistration()
+        }
+        fn migrate_gates(&mut self) -> Result<usize> {
+            self.inner.migrate_gates()
+        }
+        fn is_frozen(&self, guild_id: &u64) -> Result<bool> {
+            self.inner.is_frozen(guild_id)
+        }
+        fn set_frozen(&mut self, guild_id: Option<u64>, frozen: bool) -> Result<()> {
+            self.inner.set_frozen(guild_id, frozen)
+        }
+        fn auto_grant(&self, guild_id: &u64) -> Result<bool> {
+            self.inner.auto_grant(guild_id)
+        }
+        fn set_auto_grant(&mut self, guild_id: u64, enabled: bool) -> Result<()> {
+            self.inner.set_auto_grant(guild_id, enabled)
+        }
+    }
+
+    fn allowlist_gate(role_id: u64, wallets: Vec<H160>) -> Gate {
+        Gate {
+            role_id,
+            condition: Box::new(AllowlistGate { wallets }),
+            enabled: true,
+            grant_message: None,
+        }
+    }
+
+    async fn setup() {
+        let mut cfg = CliConfig::default();
+        cfg.discord.token = Some(secrecy::SecretString::new("dummyToken".to_owned()));
+        cfg.storage.key= Some(secrecy::SecretString::new("dummyKey".to_owned()));
+        setup_config(&cfg).unwrap();
+        Controller::<storage::InMemoryStorage>::init().await;
+    }
+
+    fn disabled_token_gate(role_id: u64) -> Gate {
+        Gate {
+            role_id,
+            condition: Box::new(crate::gate::TokenGate {
+                chain_id: colony_rs::U256::from(100),
+                token_address: H160::zero(),
+                token_symbol: "CLNY".to_string(),
+                token_decimals: 18,
+                amount: 1,
+            }),
+            enabled: false,
+            grant_message: None,
+        }
+    }
+
+    #[tokio::test]
+    async fn test_disabled_gate_grants_nothing() {
+        let wallet = SecretString::new(format!("{:?}", H160::zero()));
+        let gates = vec![disabled_token_gate(1), disabled_token_gate(2)];
+        let outcome = check_with_wallet(wallet, gates.into_iter()).await;
+        assert_eq!(outcome, CheckOutcome::default());
+    }
+
+    #[tokio::test]
+    async fn test_cheap_gates_spare_expensive_checks() {
+        let expensive = |role_id| Gate {
+            role_id,
+            condition: Box::new(ExpensiveGate(0)),
+            enabled: true,
+            grant_message: None,
+        };
+        let wallet = SecretString::new(format!("{:?}", H160::zero()));
+        // the expensive gate comes first, but the matching allowlist of the
+        // same role is checked before it
+        let gates = vec![expensive(1), allowlist_gate(1, vec![H160::zero()])];
+        let outcome = check_with_wallet(wallet.clone(), gates.into_iter()).await;
+        assert_eq!(outcome.granted, vec![1]);
+        assert_eq!(
+            EXPENSIVE_CHECKS[0].load(std::sync::atomic::Ordering::SeqCst),
+            0
+        );
+
+        // without a matching allowlist the expensive gate is still checked
+        let gates = vec![expensive(2), allowlist_gate(2, vec![H160::repeat_byte(1)])];
+        let outcome = check_with_wallet(wallet, gates.into_iter()).await;
+        assert_eq!(outcome.granted, vec![2]);
+        assert_eq!(
+            EXPENSIVE_CHECKS[0].load(std::sync::atomic::Ordering::SeqCst),
+            1
+        );
+    }
+
+    #[tokio::test]
+    async fn test_errored_and_denied_gates() {
+        let wallet = SecretString::new(format!("{:?}", H160::zero()));
+        let gates = vec![
+            allowlist_gate(1, vec![H160::zero()]),
+            allowlist_gate(2, vec![H160::repeat_byte(1)]),
+            Gate {
+                role_id: 3,
+                condition: Box::new(UncheckableGate),
+                enabled: true,
+                grant_message: None,
+            },
+            // the role is granted by another gate, so it is not errored
+            Gate {
+                role_id: 1,
+                condition: Box::new(UncheckableGate),
+                enabled: true,
+                grant_message: None,
+            },
+        ];
+        let outcome = check_with_wallet(wallet, gates.into_iter()).await;
+        assert_eq!(outcome.granted, vec![1]);
+        assert_eq!(outcome.errored, vec![3]);
+    }
+
+    #[tokio::test]
+    async fn test_panicking_gate_is_isolated() {
+        let wallet = SecretString::new(format!("{:?}", H160::zero()));
+        let panicking = |role_id| Gate {
+            role_id,
+            condition: Box::new(PanickingGate),
+            enabled: true,
+            grant_message: None,
+        };
+        let gates = vec![
+            allowlist_gate(1, vec![H160::zero()]),
+            panicking(2),
+            allowlist_gate(3, vec![H160::repeat_byte(1)]),
+            Gate {
+                role_id: 4,
+                condition: Box::new(UncheckableGate),
+                enabled: true,
+                grant_message: None,
+            },
+        ];
+        let outcome = check_with_wallet(wallet, gates.into_iter()).await;
+        // the other gates are still evaluated
+        assert_eq!(outcome.granted, vec![1]);
+        // the panicking gate is not denied, so its role is left untouched,
+        // but it is told apart from the gate that just could not be checked
+        assert_eq!(outcome.errored, vec![2, 4]);
+        assert_eq!(outcome.panicked, vec![2]);
+    }
+
+    #[tokio::test]
+    async fn test_matching_wallet_is_attributed() {
+        let (first, second) = (H160::repeat_byte(1), H160::repeat_byte(2));
+        let wallets = vec![
+            SecretString::new(format!("{:?}", first)),
+            SecretString::new(format!("{:?}", second)),
+        ];
+        let gates = vec![
+            allowlist_gate(1, vec![second]),
+            allowlist_gate(2, vec![first, second]),
+            allowlist_gate(3, vec![H160::zero()]),
+            Gate {
+                role_id: 4,
+                condition: Box::new(UncheckableGate),
+                enabled: true,
+                grant_message: None,
+            },
+        ];
+        let outcome = check_with_wallets(wallets, gates.into_iter()).await;
+        assert_eq!(outcome.granted, vec![1, 2]);
+        // only the second wallet meets the first gate, both meet the second
+        // one, where the first wallet is recorded
+        assert_eq!(outcome.wallets, HashMap::from([(1, second), (2, first)]));
+        assert_eq!(outcome.errored, vec![4]);
+    }
+
+    #[tokio::test]
+    async fn test_grant_message_of_granting_gate() {
+        let wallet = SecretString::new(format!("{:?}", H160::zero()));
+        let gates = vec![
+            allowlist_gate(1, vec![H160::zero()])
+                .with_grant_message(Some("Welcome to the DAO core team!".to_string())),
+            allowlist_gate(2, vec![H160::zero()]),
+            allowlist_gate(3, vec![H160::repeat_byte(1)])
+                .with_grant_message(Some("Not for you".to_string())),
+        ];
+        let outcome = check_with_wallet(wallet, gates.into_iter()).await;
+        assert_eq!(outcome.granted, vec![1, 2]);
+        assert_eq!(outcome.grant_messages.len(), 1);
+        assert_eq!(outcome.grant_messages[&1], "Welcome to the DAO core team!");
+    }
+
+    #[tokio::test]
+    async fn test_toggle_gate() {
+        let mut controller = Controller::<storage::InMemoryStorage>::new();
+        let gate = disabled_token_gate(1);
+        let identifier = gate.identifier();
+        controller.write_storage().add_gate(&1, gate).unwrap();
+
+        let (tx, rx) = oneshot::channel();
+        controller
+            .toggle_gate(1, identifier, tx, Span::none())
+            .await;
+        match rx.await.unwrap() {
+            ToggleResponse::Toggled(gate) => assert!(gate.enabled),
+            response => panic!("Unexpected response {:?}", response),
+        }
+        let gates = controller
+            .read_storage()
+            .list_gates(&1)
+            .unwrap()
+            .collect::<Vec<_>>();
+        assert_eq!(gates.len(), 1);
+        assert!(gates[0].enabled);
+        assert_eq!(gates[0].identifier(), identifier);
+
+        let (tx, rx) = oneshot::channel();
+        controller
+            .toggle_gate(1, identifier + 1, tx, Span::none())
+            .await;
+        assert!(matches!(rx.await.unwrap(), ToggleResponse::NotFound));
+    }
+
+    #[tokio::test]
+    async fn test_register_classifies_storage_errors() {
+        for failure in [
+            StorageErrorKind::Io,
+            StorageErrorKind::Serialization,
+            StorageErrorKind::Other,
+        ] {
+            let mut controller = Controller::<FaultyStorage>::new();
+            controller.write_storage().failure = failure;
+            let (tx, rx) = oneshot::channel();
+            let wallet = SecretString::new(format!("{:?}", H160::zero()));
+            controller.register(1, wallet, tx, Span::none()).await;
+            match rx.await.unwrap() {
+                RegisterResponse::Error { kind, .. } => assert_eq!(kind, failure),
+                response => panic!("Unexpected response {:?}", response),
+            }
+        }
+    }
+
+    #[test]
+    fn test_move_gate() {
+        let mut storage = storage::InMemoryStorage::new();
+        let mut gate = allowlist_gate(10, vec![H160::zero()]);
+        gate.grant_message = Some("Welcome".to_string());
+        let identifier = gate.identifier();
+        let condition = format!("{:?}", gate.condition);
+        storage.add_gate(&1, gate).unwrap();
+
+        let moved = move_gate(&mut storage, 1, identifier, 20).unwrap().unwrap();
+        assert_eq!(moved.role_id, 20);
+        assert_ne!(moved.identifier(), identifier);
+        assert_eq!(
+            moved.identifier(),
+            (20u128 << 64) | (identifier & u64::MAX as u128)
+        );
+        assert_eq!(format!("{:?}", moved.condition), condition);
+        assert_eq!(moved.grant_message.as_deref(), Some("Welcome"));
+        let gates = storage.list_gates(&1).unwrap().collect::<Vec<_>>();
+        assert_eq!(gates.len(), 1);
+        assert_eq!(gates[0].identifier(), moved.identifier());
+
+        // the old identifier is gone
+        assert!(move_gate(&mut storage, 1, identifier, 30)
+            .unwrap()
+            .is_none());
+    }
+
+    #[tokio::test]
+    async fn test_add_gate_reports_full_disk() {
+        let mut controller = Controller::<FaultyStorage>::new();
+        controller.write_storage().fail_gates = true;
+        let (tx, rx) = oneshot::channel();
+        controller
+            .add_gate(1, allowlist_gate(10, Vec::new()), tx, Span::none())
+            .await;
+        let why = rx.await.unwrap().unwrap_err();
+        assert_eq!(StorageErrorKind::of(&why), StorageErrorKind::Io);
+        assert!(StorageErrorKind::is_disk_full(&why));
+
+        controller.write_storage().failure = StorageErrorKind::Serialization;
+        let (tx, rx) = oneshot::channel();
+        controller
+            .add_gate(1, allowlist_gate(10, Vec::new()), tx, Span::none())
+            .await;
+        let why = rx.await.unwrap().unwrap_err();
+        assert_eq!(StorageErrorKind::of(&why), StorageErrorKind::Serialization);
+        assert!(!StorageErrorKind::is_disk_full(&why));
+    }
+
+    #[tokio::test]
+    async fn test_registrations_are_counted() {
+        let mut controller = Controller::<storage::InMemoryStorage>::new();
+        let wallet = SecretString::new(format!("{:?}", H160::zero()));
+        // an already registered user is not counted again
+        for user_id in [1, 2, 1] {
+            let (tx, _rx) = oneshot::channel();
+            controller
+                .register(user_id, wallet.clone(), tx, Span::none())
+                .await;
+        }
+        controller.remove_user(1, false).await;
+        // removing an unknown user is not an unregistration
+        controller.remove_user(3, false).await;
+        assert_eq!(
+            controller.read_storage().registration_stats().unwrap(),
+            RegistrationStats {
+                registrations: 2,
+                unregistrations: 1,
+            }
+        );
+
+        // the failed registration is not counted
+        let mut controller = Controller::<FaultyStorage>::new();
+        let (tx, _rx) = oneshot::channel();
+        controller.register(1, wallet, tx, Span::none()).await;
+        assert_eq!(
+            controller.read_storage().registration_stats().unwrap(),
+            RegistrationStats::default()
+        );
+    }
+
+    #[tokio::test]
+    async fn test_wallets_per_user_are_capped() {
+        let mut controller = Controller::<storage::InMemoryStorage>::new();
+        controller.max_wallets_per_user = 2;
+        let mut responses = Vec::new();
+        for byte in 1..=3 {
+            let (tx, rx) = oneshot::channel();
+            let wallet = SecretString::new(format!("{:?}", H160::repeat_byte(byte)));
+            controller.register(1, wallet, tx, Span::none()).await;
+            responses.push(rx.await.unwrap());
+        }
+        assert!(matches!(responses[0], RegisterResponse::Success));
+        assert!(matches!(responses[1], RegisterResponse::Success));
+        assert!(matches!(
+            responses[2],
+            RegisterResponse::TooManyWallets { max: 2 }
+        ));
+        assert_eq!(controller.read_storage().get_user(&1).unwrap().len(), 2);
+        // linking another wallet is not a new registration
+        assert_eq!(
+            controller
+                .read_storage()
+                .registration_stats()
+                .unwrap()
+                .registrations,
+            1
+        );
+
+        // a linked wallet is not linked twice, even without a cap
+        controller.max_wallets_per_user = 0;
+        let (tx, rx) = oneshot::channel();
+        let wallet = SecretString::new(format!("{:?}", H160::repeat_byte(1)));
+        controller.register(1, wallet, tx, Span::none()).await;
+        assert!(matches!(
+            rx.await.unwrap(),
+            RegisterResponse::AlreadyRegistered
+        ));
+    }
+
+    #[test]
+    fn test_global_gates_are_merged_by_role_name() {
+        let mut controller = Controller::<storage::InMemoryStorage>::new();
+        controller
+            .write_storage()
+            .add_gate(&1, allowlist_gate(10, vec![H160::zero()]))
+            .unwrap();
+        controller
+            .write_storage()
+            .add_global_gate(GlobalGate::new(
+                "Holder".to_string(),
+                allowlist_gate(0, vec![H160::zero()]).condition,
+            ))
+            .unwrap();
+        // the roles of the guilds are not known yet
+        let gates = controller.gates_for_guild(1).unwrap();
+        assert_eq!(gates.len(), 1);
+
+        controller.guild_roles.insert(
+            1,
+            HashMap::from([("Holder".to_string(), 11), ("Other".to_string(), 12)]),
+        );
+        controller
+            .guild_roles
+            .insert(2, HashMap::from([("Holder".to_string(), 21)]));
+        controller
+            .guild_roles
+            .insert(3, HashMap::from([("Other".to_string(), 31)]));
+        let roles = |guild_id| {
+            controller
+                .gates_for_guild(guild_id)
+                .unwrap()
+                .into_iter()
+                .map(|gate| gate.role_id)
+                .collect::<Vec<_>>()
+        };
+        assert_eq!(roles(1), vec![10, 11]);
+        // guilds without gates of their own still get the global ones
+        assert_eq!(roles(2), vec![21]);
+        assert!(roles(3).is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_gate_coverage() {
+        let wallets = (1..=4)
+            .map(|byte| SecretString::new(format!("{:?}", H160::repeat_byte(byte))))
+            .collect::<Vec<_>>();
+        let mut gate = allowlist_gate(1, vec![H160::repeat_byte(1), H160::repeat_byte(3)]);
+        // coverage is calculated for disabled gates as well
+        gate.enabled = false;
+        let coverage = gate_coverage(&gate, wallets.clone()).await;
+        assert_eq!(
+            coverage,
+            Coverage {
+                checked: 4,
+                qualified: 2,
+                errored: 0,
+            }
+        );
+
+        let gate = Gate {
+            role_id: 2,
+            condition: Box::new(UncheckableGate),
+            enabled: true,
+            grant_message: None,
+        };
+        let coverage = gate_coverage(&gate, wallets).await;
+        assert_eq!(coverage.qualified, 0);
+        assert_eq!(coverage.errored, 4);
+    }
+
+    #[test]
+    fn test_recently_checked_users_are_skipped() {
+        let mut controller = Controller::<storage::InMemoryStorage>::new();
+        let wallet = SecretString::new(format!("{:?}", H160::zero()));
+        controller
+            .write_storage()
+            .add_user(1, vec![wallet.clone()])
+            .unwrap();
+        controller
+            .write_storage()
+            .add_user(2, vec![wallet])
+            .unwrap();
+        controller
+            .write_storage()
+            .set_last_checked(&10, &1, 1000)
+            .unwrap();
+        // user 3 is not registered at all
+        let user_ids = vec![1, 2, 3];
+
+        let users = controller.users_to_check(10, user_ids.clone(), 1030, 60);
+        assert_eq!(users, vec![2]);
+        // forcing disables the freshness window
+        let users = controller.users_to_check(10, user_ids.clone(), 1030, 0);
+        assert_eq!(users, vec![1, 2]);
+        let users = controller.users_to_check(10, user_ids.clone(), 1060, 60);
+        assert_eq!(users, vec![1, 2]);
+        // the check was in another guild
+        let users = controller.users_to_check(20, user_ids, 1030, 60);
+        assert_eq!(users, vec![1, 2]);
+    }
+
+    #[test]
+    fn test_unregistered_role_holders() {
+        let mut controller = Controller::<storage::InMemoryStorage>::new();
+        let wallet = SecretString::new(format!("{:?}", H160::zero()));
+        controller
+            .write_storage()
+            .add_user(1, vec![wallet])
+            .unwrap();
+        let held_roles = HashMap::from([
+            // registered, holds a gated role
+            (1, vec![100]),
+            // not registered, holds gated roles
+            (2, vec![100, 200]),
+            // not registered, holds no gated role
+            (3, vec![]),
+        ]);
+        assert_eq!(
+            controller.unregistered_holders(&held_roles),
+            vec![(2, vec![100, 200])]
+        );
+        assert!(controller.unregistered_holders(HashMap::new()).is_empty());
+    }
+
+    #[test]
+    fn test_tombstoned_users_are_not_gated() {
+        let mut controller = Controller::<storage::InMemoryStorage>::new();
+        let wallet = SecretString::new(format!("{:?}", H160::zero()));
+        controller
+            .write_storage()
+            .add_user(1, vec![wallet.clone()])
+            .unwrap();
+        controller
+            .write_storage()
+            .add_user(2, vec![wallet])
+            .unwrap();
+        controller.write_storage().remove_user(&1, true).unwrap();
+
+        assert!(controller.read_storage().was_registered(&1));
+        let users = controller.users_to_check(10, vec![1, 2], 1000, 0);
+        assert_eq!(users, vec![2]);
+    }
+
+    #[tokio::test]
+    async fn test_batch_check_of_a_single_role() {
+        let mut controller = Controller::<storage::InMemoryStorage>::new();
+        let wallet = H160::repeat_byte(1);
+        controller
+            .write_storage()
+            .add_user(1, vec![SecretString::new(format!("{:?}", wallet))])
+            .unwrap();
+        controller
+            .write_storage()
+            .add_gate(&10, allowlist_gate(100, vec![wallet]))
+            .unwrap();
+        controller
+            .write_storage()
+            .add_gate(&10, allowlist_gate(200, vec![wallet]))
+            .unwrap();
+        controller
+            .write_storage()
+            .add_gate(
+                &10,
+                Gate {
+                    role_id: 300,
+                    condition: Box::new(UncheckableGate),
+                    enabled: true,
+                    grant_message: None,
+                },
+            )
+            .unwrap();
+
+        let (tx, mut rx) = mpsc::channel(10);
+        controller
+            .batch_check(10, vec![1], false, false, Some(100), tx, Span::none())
+            .await;
+        let mut outcomes = Vec::new();
+        while let Some(response) = rx.recv().await {
+            match response {
+                BatchResponse::Grant { user_id, outcome } => outcomes.push((user_id, outcome)),
+                BatchResponse::Unregistered { .. } | BatchResponse::Frozen => {}
+                BatchResponse::Done => break,
+            }
+        }
+        // the other gates are not checked, so their roles are neither
+        // granted nor errored and stay untouched by the enforcement
+        assert_eq!(outcomes.len(), 1);
+        assert_eq!(outcomes[0].0, 1);
+        assert_eq!(outcomes[0].1.granted, vec![100]);
+        assert!(outcomes[0].1.errored.is_empty());
+        // the user was not checked against all gates, so it is not fresh
+        assert_eq!(
+            controller.read_storage().last_checked(&10, &1).unwrap(),
+            None
+        );
+    }
+
+    /// Enforces the gates of a seeded guild and sums up the responses, as
+    /// the enforcement in discord does
+    async fn seeded_enforcement_summary() -> EnforcementSummary {
+        let mut controller = Controller::<storage::InMemoryStorage>::new();
+        let holder = H160::repeat_byte(1);
+        let other = H160::repeat_byte(2);
+        controller
+            .write_storage()
+            .add_user(1, vec![SecretString::new(format!("{:?}", holder))])
+            .unwrap();
+        controller
+            .write_storage()
+            .add_user(2, vec![SecretString::new(format!("{:?}", other))])
+            .unwrap();
+        controller
+            .write_storage()
+            .add_gate(&10, allowlist_gate(100, vec![holder]))
+            .unwrap();
+        controller
+            .write_storage()
+            .add_gate(&10, allowlist_gate(200, vec![holder, other]))
+            .unwrap();
+        controller
+            .write_storage()
+            .add_gate(&10, allowlist_gate(300, vec![]))
+            .unwrap();
+
+        // the third user is not registered
+        let (tx, mut rx) = mpsc::channel(10);
+        controller
+            .batch_check(10, vec![1, 2, 3], true, false, None, tx, Span::none())
+            .await;
+        let mut summary = EnforcementSummary::for_roles([100, 200, 300]);
+        while let Some(response) = rx.recv().await {
+            match response {
+                BatchResponse::Grant { outcome, .. } => summary.add_outcome(&outcome),
+                BatchResponse::Unregistered { .. } | BatchResponse::Frozen => {}
+                BatchResponse::Done => break,
+            }
+        }
+        summary.skip(3 - summary.processed);
+        summary
+    }
+
+    #[tokio::test]
+    async fn test_enforcement_summary() {
+        let mut summary = seeded_enforcement_summary().await;
+        assert_eq!(
+            summary,
+            EnforcementSummary {
+                processed: 2,
+                granted: 3,
+                revoked: 0,
+                skipped: 1,
+                failed: 0,
+                qualifying: HashMap::from([(100, 1), (200, 2), (300, 0)]),
+            }
+        );
+
+        summary.add_member(0, 1, true);
+        assert_eq!(summary.processed, 3);
+        assert_eq!(summary.revoked, 1);
+        assert_eq!(summary.failed, 1);
+    }
+
+    #[cfg(feature = "metrics")]
+    #[tokio::test]
+    async fn test_enforcement_records_qualifying_members() {
+        let summary = seeded_enforcement_summary().await;
+        let (recorder, guard) = crate::logging::MetricRecorder::install("gauge.");
+        summary.record_qualifying(10);
+        drop(guard);
+
+        let mut gauges = recorder
+            .events()
+            .iter()
+            .map(|fields| {
+                assert_eq!(fields["guild"], "10");
+                (
+                    fields["role"].clone(),
+                    fields["gauge.qualifying_members"].clone(),
+                )
+            })
+            .collect::<Vec<_>>();
+        gauges.sort();
+        assert_eq!(
+            gauges,
+            vec![
+                ("100".to_string(), "1".to_string()),
+                ("200".to_string(), "2".to_string()),
+                ("300".to_string(), "0".to_string()),
+            ]
+        );
+    }
+
+    async fn send_batch(channel: &mpsc::Sender<Message>, guild_id: u64) -> Vec<BatchResponse> {
+        let (tx, mut rx) = mpsc::channel(10);
+        channel
+            .send(Message::Batch {
+                guild_id,
+                user_ids: vec![1],
+                force: true,
+                bypass_cache: false,
+                held_roles: HashMap::from([(2, vec![100])]),
+                report_unregistered: true,
+                role_id: None,
+                response_tx: tx,
+                span: Span::none(),
+            })
+            .await
+            .unwrap();
+        let mut responses = Vec::new();
+        while let Some(response) = rx.recv().await {
+            let done = matches!(response, BatchResponse::Done);
+            responses.push(response);
+            if done {
+                break;
+            }
+        }
+        responses
+    }
+
+    #[tokio::test]
+    async fn test_slow_read_does_not_hold_up_other_guilds() {
+        let mut storage = FaultyStorage::new();
+        storage.slow_guild = Some(1);
+        storage
+            .add_gate(&1, allowlist_gate(100, Vec::new()))
+            .unwrap();
+        storage
+            .add_gate(&2, allowlist_gate(200, Vec::new()))
+            .unwrap();
+        let controller = Controller::with_storage(storage);
+        let channel = controller.message_tx.clone();
+        controller.spawn().await;
+
+        let (slow_tx, mut slow_rx) = oneshot::channel();
+        channel
+            .send(Message::List {
+                guild_id: 1,
+                response: slow_tx,
+                span: Span::none(),
+            })
+            .await
+            .unwrap();
+        let (tx, rx) = oneshot::channel();
+        channel
+            .send(Message::List {
+                guild_id: 2,
+                response: tx,
+                span: Span::none(),
+            })
+            .await
+            .unwrap();
+        let gates = tokio::time::timeout(SLOW_READ / 2, rx)
+            .await
+            .expect("The other guild waited for the slow read")
+            .unwrap();
+        assert_eq!(gates[0].role_id, 200);
+        assert!(slow_rx.try_recv().is_err());
+
+        // the slow read still gets its answer
+        let gates = slow_rx.await.unwrap();
+        assert_eq!(gates[0].role_id, 100);
+    }
+
+    async fn send_freeze(channel: &mpsc::Sender<Message>, guild_id: Option<u64>, frozen: bool) {
+        let (tx, rx) = oneshot::channel();
+        channel
+            .send(Message::Freeze {
+                guild_id,
+                frozen,
+                response_tx: tx,
+                span: Span::none(),
+            })
+            .await
+            .unwrap();
+        rx.await.unwrap().unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_frozen_gating_changes_no_roles() {
+        let wallet = H160::repeat_byte(1);
+        let mut storage = storage::InMemoryStorage::new();
+        storage
+            .add_user(1, vec![SecretString::new(format!("{:?}", wallet))])
+            .unwrap();
+        storage
+            .add_gate(&10, allowlist_gate(100, vec![wallet]))
+            .unwrap();
+        storage
+            .add_gate(&20, allowlist_gate(100, vec![wallet]))
+            .unwrap();
+        let controller = Controller::with_storage(storage);
+        let channel = controller.message_tx.clone();
+        controller.spawn().await;
+
+        send_freeze(&channel, Some(10), true).await;
+        // neither grants nor the unregistered holders, whose roles would be
+        // revoked, are reported
+        let responses = send_batch(&channel, 10).await;
+        assert!(matches!(
+            responses.as_slice(),
+            [BatchResponse::Frozen, BatchResponse::Done]
+        ));
+        let (tx, rx) = oneshot::channel();
+        channel
+            .send(Message::Check {
+                guild_id: 10,
+                user_id: 1,
+                username: "user".to_string(),
+                response_tx: tx,
+                span: Span::none(),
+            })
+            .await
+            .unwrap();
+        assert!(matches!(rx.await.unwrap(), CheckResponse::Frozen));
+        // other guilds are not affected
+        let responses = send_batch(&channel, 20).await;
+        assert!(responses
+            .iter()
+            .any(|response| matches!(response, BatchResponse::Grant { .. })));
+
+        // the freeze of all guilds holds even if a single guild is unfrozen
+        send_freeze(&channel, None, true).await;
+        send_freeze(&channel, Some(10), false).await;
+        for guild_id in [10, 20] {
+            let responses = send_batch(&channel, guild_id).await;
+            assert!(matches!(responses[0], BatchResponse::Frozen));
+        }
+        send_freeze(&channel, None, false).await;
+        let responses = send_batch(&channel, 10).await;
+        assert!(matches!(
+            responses.as_slice(),
+            [.., BatchResponse::Grant { .. }, BatchResponse::Done]
+        ));
+    }
+
+    async fn send_batch_all_guilds(channel: &mpsc::Sender<Message>) -> Vec<GuildBatchResponse> {
+        let (tx, mut rx) = mpsc::channel(10);
+        channel
+            .send(Message::BatchAllGuilds {
+                response_tx: tx,
+                span: Span::none(),
+            })
+            .await
+            .unwrap();
+        let mut responses = Vec::new();
+        while let Some(response) = rx.recv().await {
+            let done = matches!(response, GuildBatchResponse::Done);
+            responses.push(response);
+            if done {
+                break;
+            }
+        }
+        responses
+    }
+
+    #[tokio::test]
+    async fn test_paused_enforcement_checks_no_guilds() {
+        let wallet = H160::repeat_byte(1);
+        let mut storage = storage::InMemoryStorage::new();
+        storage
+            .add_user(1, vec![SecretString::new(format!("{:?}", wallet))])
+            .unwrap();
+        storage
+            .add_gate(&10, allowlist_gate(100, vec![wallet]))
+            .unwrap();
+        let controller = Controller::with_storage(storage);
+        let channel = controller.message_tx.clone();
+        controller.spawn().await;
+
+        set_enforcement_paused(true);
+        let responses = send_batch_all_guilds(&channel).await;
+        assert!(matches!(responses.as_slice(), [GuildBatchResponse::Done]));
+        set_enforcement_paused(false);
+        let responses = send_batch_all_guilds(&channel).await;
+        assert!(matches!(
+            responses.as_slice(),
+            [
+                GuildBatchResponse::Grant { guild_id: 10, .. },
+                GuildBatchResponse::Done
+            ]
+        ));
+    }
+
+    async fn send_check(channel: &mpsc::Sender<Message>, guild_id: u64) -> CheckResponse {
+        let (tx, rx) = oneshot::channel();
+        channel
+            .send(Message::Check {
+                guild_id,
+                user_id: 1,
+                username: "user".to_string(),
+                response_tx: tx,
+                span: Span::none(),
+            })
+            .await
+            .unwrap();
+        rx.await.unwrap()
+    }
+
+    #[tokio::test]
+    async fn test_check_cooldown_reuses_last_outcome() {
+        let mut storage = storage::InMemoryStorage::new();
+        storage
+            .add_user(1, vec![SecretString::new(format!("{:?}", H160::zero()))])
+            .unwrap();
+        for guild_id in [10, 20] {
+            let gate = Gate {
+                role_id: 100,
+                condition: Box::new(ExpensiveGate(1)),
+                enabled: true,
+                grant_message: None,
+            };
+            storage.add_gate(&guild_id, gate).unwrap();
+        }
+        let mut controller = Controller::with_storage(storage);
+        controller.check_cooldown = Duration::from_secs(60);
+        let channel = controller.message_tx.clone();
+        controller.spawn().await;
+        let checks = || EXPENSIVE_CHECKS[1].load(std::sync::atomic::Ordering::SeqCst);
+
+        for _ in 0..2 {
+            match send_check(&channel, 10).await {
+                CheckResponse::Grant(outcome) => assert_eq!(outcome.granted, vec![100]),
+                response => panic!("Unexpected response: {:?}", response),
+            }
+            assert_eq!(checks(), 1);
+        }
+        // the cooldown is kept per guild
+        assert!(matches!(
+            send_check(&channel, 20).await,
+            CheckResponse::Grant(_)
+        ));
+        assert_eq!(checks(), 2);
+    }
+
+    async fn send_preview(
+        channel: &mpsc::Sender<Message>,
+        user_id: u64,
+        gate: Gate,
+    ) -> PreviewResponse {
+        let (tx, rx) = oneshot::channel();
+        channel
+            .send(Message::Preview {
+                user_id,
+                gate,
+                response_tx: tx,
+                span: Span::none(),
+            })
+            .await
+            .unwrap();
+        rx.await.unwrap()
+    }
+
+    #[tokio::test]
+    async fn test_preview_gate_stores_nothing() {
+        let wallet = H160::repeat_byte(1);
+        let mut storage = storage::InMemoryStorage::new();
+        storage
+            .add_user(1, vec![SecretString::new(format!("{:?}", wallet))])
+            .unwrap();
+        storage
+            .add_gate(&10, allowlist_gate(100, vec![wallet]))
+            .unwrap();
+        let controller = Controller::with_storage(storage);
+        let channel = controller.message_tx.clone();
+        controller.spawn().await;
+
+        match send_preview(&channel, 1, allowlist_gate(200, vec![wallet])).await {
+            PreviewResponse::Outcome(outcome) => {
+                assert_eq!(outcome.granted, vec![200]);
+                assert_eq!(
+                    outcome.reasons.get(&200).map(String::as_str),
+                    Some("wallet is on the allowlist")
+                );
+                assert_eq!(outcome.wallets.get(&200), Some(&wallet));
+            }
+            response => panic!("Unexpected response: {:?}", response),
+        }
+        let gate = allowlist_gate(200, vec![H160::zero()]);
+        match send_preview(&channel, 1, gate).await {
+            PreviewResponse::Outcome(outcome) => assert_eq!(outcome, CheckOutcome::default()),
+            response => panic!("Unexpected response: {:?}", response),
+        }
+        let gate = allowlist_gate(200, vec![wallet]);
+        assert!(matches!(
+            send_preview(&channel, 2, gate).await,
+            PreviewResponse::Unregistered
+        ));
+
+        // only the gate that was added before is stored
+        let (tx, rx) = oneshot::channel();
+        channel
+            .send(Message::List {
+                guild_id: 10,
+                response: tx,
+                span: Span::none(),
+            })
+            .await
+            .unwrap();
+        let gates = rx.await.unwrap();
+        assert_eq!(gates.len(), 1);
+        assert_eq!(gates[0].role_id, 100);
+    }
+
+    async fn send_member_joined(
+        channel: &mpsc::Sender<Message>,
+        guild_id: u64,
+        user_id: u64,
+    ) -> Vec<BatchResponse> {
+        let (tx, mut rx) = mpsc::channel(10);
+        channel
+            .send(Message::MemberJoined {
+                guild_id,
+                user_id,
+                response_tx: tx,
+                span: Span::none(),
+            })
+            .await
+            .unwrap();
+        let mut responses = Vec::new();
+        while let Some(response) = rx.recv().await {
+            let done = matches!(response, BatchResponse::Done);
+            responses.push(response);
+            if done {
+                break;
+            }
+        }
+        responses
+    }
+
+    #[tokio::test]
+    async fn test_member_join_grants_registered_user() {
+        let wallet = H160::repeat_byte(1);
+        let mut storage = storage::InMemoryStorage::new();
+        storage
+            .add_user(1, vec![SecretString::new(format!("{:?}", wallet))])
+            .unwrap();
+        storage
+            .add_gate(&10, allowlist_gate(100, vec![wallet]))
+            .unwrap();
+        let controller = Controller::with_storage(storage);
+        let channel = controller.message_tx.clone();
+        controller.spawn().await;
+
+        // auto grant is disabled by default
+        let responses = send_member_joined(&channel, 10, 1).await;
+        assert!(matches!(responses.as_slice(), [BatchResponse::Done]));
+
+        let (tx, rx) = oneshot::channel();
+        channel
+            .send(Message::AutoGrant {
+                guild_id: 10,
+                enabled: true,
+                response_tx: tx,
+                span: Span::none(),
+            })
+            .await
+            .unwrap();
+        rx.await.unwrap().unwrap();
+        let responses = send_member_joined(&channel, 10, 1).await;
+        match responses.as_slice() {
+            [BatchResponse::Grant { user_id, outcome }, BatchResponse::Done] => {
+                assert_eq!(*user_id, 1);
+                assert_eq!(outcome.granted, vec![100]);
+            }
+            responses => panic!("Unexpected responses {:?}", responses),
+        }
+        // unregistered members are left alone
+        let responses = send_member_joined(&channel, 10, 2).await;
+        assert!(matches!(responses.as_slice(), [BatchResponse::Done]));
+
+        send_freeze(&channel, Some(10), true).await;
+        let responses = send_member_joined(&channel, 10, 1).await;
+        assert!(matches!(
+            responses.as_slice(),
+            [BatchResponse::Frozen, BatchResponse::Done]
+        ));
+    }
+
+    #[test]
+    fn test_outcome_scoped_to_role() {
+        let outcome = CheckOutcome {
+            granted: vec![1, 2],
+            errored: vec![3],
+            grant_messages: HashMap::from([(2, "Welcome".to_string())]),
+            ..Default::default()
+        };
+        let scoped = outcome.scoped_to(Some(2));
+        assert_eq!(scoped.granted, vec![2]);
+        assert!(scoped.errored.is_empty());
+        assert_eq!(scoped.grant_messages.len(), 1);
+        assert_eq!(scoped.scoped_to(None).granted, vec![2]);
+    }
+
+    #[test]
+    fn test_roles_are_revoked_after_the_grace_period() {
+        let mut storage = storage::InMemoryStorage::new();
+        let roles = HashSet::from([1, 2]);
+        let held = [1, 2];
+        let failed = CheckOutcome {
+            granted: vec![2],
+            ..Default::default()
+        };
+        // a dip within the grace period keeps the role
+        assert_eq!(
+            track_failures(&mut storage, 10, 3, &roles, &held, &failed, 1000, 60),
+            vec![1]
+        );
+        assert_eq!(storage.first_failed(&10, &3, &1).unwrap(), Some(1000));
+        assert_eq!(
+            track_failures(&mut storage, 10, 3, &roles, &held, &failed, 1059, 60),
+            vec![1]
+        );
+        // later failed checks do not push the start of the failure back
+        assert_eq!(storage.first_failed(&10, &3, &1).unwrap(), Some(1000));
+
+        // qualifying again starts over
+        let granted = CheckOutcome {
+            granted: vec![1, 2],
+            ..Default::default()
+        };
+        assert!(track_failures(&mut storage, 10, 3, &roles, &held, &granted, 1030, 60).is_empty());
+        assert_eq!(storage.first_failed(&10, &3, &1).unwrap(), None);
+        assert_eq!(
+            track_failures(&mut storage, 10, 3, &roles, &held, &failed, 1100, 60),
+            vec![1]
+        );
+
+        // a failure that lasts past the grace period revokes the role
+        assert!(track_failures(&mut storage, 10, 3, &roles, &held, &failed, 1160, 60).is_empty());
+
+        // a role whose gate could not be checked neither starts nor ends the
+        // grace period
+        let errored = CheckOutcome {
+            granted: vec![2],
+            errored: vec![1],
+            ..Default::default()
+        };
+        assert!(track_failures(&mut storage, 10, 3, &roles, &held, &errored, 1200, 60).is_empty());
+        assert_eq!(storage.first_failed(&10, &3, &1).unwrap(), Some(1100));
+
+        // failing a role that is not held records nothing and ends a grace
+        // period that started while the role was held
+        assert!(track_failures(&mut storage, 10, 3, &roles, &[2], &failed, 1300, 60).is_empty());
+        assert_eq!(storage.first_failed(&10, &3, &1).unwrap(), None);
+        assert!(track_failures(&mut storage, 10, 4, &roles, &[], &failed, 1300, 60).is_empty());
+        assert_eq!(storage.first_failed(&10, &4, &1).unwrap(), None);
+    }
+
+    #[tokio::test]
+    async fn test_get_in_ends_the_grace_period() {
+        let wallet = H160::repeat_byte(1);
+        let mut storage = storage::InMemoryStorage::new();
+        storage
+            .add_user(1, vec![SecretString::new(format!("{:?}", wallet))])
+            .unwrap();
+        storage
+            .add_gate(&10, allowlist_gate(100, vec![wallet]))
+            .unwrap();
+        // the user failed the gate during an earlier enforcement
+        storage.set_first_failed(&10, &1, &100, Some(1000)).unwrap();
+        let storage = Arc::new(RwLock::new(storage));
+        let (tx, rx) = oneshot::channel();
+        check(
+            storage.clone(),
+            Arc::new(RecentChecks::default()),
+            Duration::ZERO,
+            None,
+            10,
+            "user".to_string(),
+            1,
+            tx,
+            Span::none(),
+        )
+        .await;
+        assert!(matches!(rx.await.unwrap(), CheckResponse::Grant(_)));
+        assert_eq!(read(&storage).first_failed(&10, &1, &100).unwrap(), None);
+    }
+
+    #[tokio::test]
+    async fn test_batch_check_all_guilds() {
+        let mut controller = Controller::<storage::InMemoryStorage>::new();
+        let first = H160::repeat_byte(1);
+        let second = H160::repeat_byte(2);
+        controller
+            .write_storage()
+            .add_user(1, vec![SecretString::new(format!("{:?}", first))])
+            .unwrap();
+        controller
+            .write_storage()
+            .add_user(2, vec![SecretString::new(format!("{:?}", second))])
+            .unwrap();
+        // both users qualify in the first guild, only the second one in the
+        // other guild
+        controller
+            .write_storage()
+            .add_gate(&10, allowlist_gate(100, vec![first, second]))
+            .unwrap();
+        controller
+            .write_storage()
+            .add_gate(&20, allowlist_gate(200, vec![second]))
+            .unwrap();
+
+        let (tx, mut rx) = mpsc::channel(10);
+        controller.batch_check_all_guilds(tx, Span::none()).await;
+        let mut results = Vec::new();
+        while let Some(response) = rx.recv().await {
+            match response {
+                GuildBatchResponse::Grant {
+                    guild_id,
+                    user_id,
+                    outcome,
+                } => results.push((guild_id, user_id, outcome.granted)),
+                GuildBatchResponse::Done => break,
+            }
+        }
+        results.sort();
+        assert_eq!(
+            results,
+            vec![
+                (10, 1, vec![100]),
+                (10, 2, vec![100]),
+                (20, 1, vec![]),
+                (20, 2, vec![200]),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_build_link() {
+        let expected = "https://gate.example.com/getin.html?username=a&session=b";
+        assert_eq!(
+            build_link(
+                "https://gate.example.com",
+                "getin.html?username=a&session=b"
+            ),
+            expected
+        );
+        assert_eq!(
+            build_link(
+                "https://gate.example.com/",
+                "getin.html?username=a&session=b"
+            ),
+            expected
+        );
+        assert_eq!(
+            build_link("https://example.com/gate/", "/getout.html"),
+            "https://example.com/gate/getout.html"
+        );
+    }
+
+    #[tokio::test]
+    async fn test_reaper_removes_expired_unregisters() {
+        let pending_unregisters: PendingUnregisters = Arc::new(Mutex::new(HashMap::new()));
+        let (expired_tx, expired_rx) = oneshot::channel();
+        let (pending_tx, _pending_rx) = oneshot::channel();
+        {
+            let mut guard = pending_unregisters.lock().await;
+            guard.insert(
+                "expired".to_string(),
+                PendingUnregister {
+                    removed_tx: expired_tx,
+                    expires_at: Instant::now(),
+                },
+            );
+            guard.insert(
+                "pending".to_string(),
+                PendingUnregister {
+                    removed_tx: pending_tx,
+                    expires_at: Instant::now() + Duration::from_secs(3600),
+                },
+            );
+        }
+        let reaper = tokio::spawn(reap_pending_unregisters(
+            pending_unregisters.clone(),
+            Duration::from_millis(10),
+        ));
+        let response = tokio::time::timeout(Duration::from_secs(1), expired_rx)
+            .await
+            .expect("Expired unregister was not reaped")
+            .unwrap();
+        assert!(matches!(response, RemoveUserResponse::Error(_)));
+        reaper.abort();
+        let guard = pending_unregisters.lock().await;
+        assert!(!guard.contains_key("expired"));
+        assert!(guard.contains_key("pending"));
+    }
+
+    #[tokio::test]
+    async fn test_session() {
+        setup().await;
+        let session = Session::new(123, "test".to_string()).unwrap();
+        let encoded = session.encode().unwrap();
+        let decoded = Session::from_str(&encoded).unwrap();
         assert_eq!(session.user_id, decoded.user_id);
         assert_eq!(session.username, decoded.username);
         assert_eq!(session.timestamp, decoded.timestamp);
     }
+
+    #[test]
+    fn test_session_with_unusual_usernames() {
+        let keys = SessionKeys::new();
+        for username in ["ünïcödé 🦀🎉", "with:colons:", ":", "", "100%25 sure"] {
+            let session = Session::new(123, username.to_string()).unwrap();
+            let encoded = session.encode_with(&keys).unwrap();
+            let decoded = Session::decode_with(&encoded, &keys, session.timestamp).unwrap();
+            assert_eq!(decoded.user_id, 123);
+            assert_eq!(decoded.username, username);
+            assert_eq!(decoded.timestamp, session.timestamp);
+        }
+
+        // long names are cut at a character boundary
+        let session = Session::new(123, "🦀".repeat(100)).unwrap();
+        assert_eq!(session.username, "🦀".repeat(MAX_SESSION_USERNAME_CHARS));
+        let encoded = session.encode_with(&keys).unwrap();
+        let decoded = Session::decode_with(&encoded, &keys, session.timestamp).unwrap();
+        assert_eq!(decoded.username, session.username);
+    }
+
+    #[test]
+    fn test_session_key_rotation_grace_period() {
+        let mut keys = SessionKeys::new();
+        let session = Session {
+            user_id: 123,
+            username: "test".to_string(),
+            timestamp: 1000,
+        };
+        let old = session.encode_with(&keys).unwrap();
+
+        keys.rotate(1000, Duration::from_secs(60));
+        let new = session.encode_with(&keys).unwrap();
+        // within the grace period both keys decrypt
+        let decoded = Session::decode_with(&old, &keys, 1059).unwrap();
+        assert_eq!(decoded.user_id, 123);
+        assert!(Session::decode_with(&new, &keys, 1059).is_ok());
+        // afterwards only the new key does
+        assert!(Session::decode_with(&old, &keys, 1060).is_err());
+        assert!(Session::decode_with(&new, &keys, 1060).is_ok());
+
+        // a second rotation drops the oldest key at once
+        keys.rotate(1010, Duration::from_secs(60));
+        assert!(Session::decode_with(&old, &keys, 1020).is_err());
+        assert!(Session::decode_with(&new, &keys, 1020).is_ok());
+    }
+
+    #[test]
+    fn test_session_lifetimes() {
+        let session = Session {
+            user_id: 123,
+            username: "test".to_string(),
+            timestamp: 1000,
+        };
+        let (register_secs, unregister_secs) = (600, 60);
+        // a slow registration is still valid when an unregistration is not
+        assert!(!session.expired_at(1060, unregister_secs));
+        assert!(session.expired_at(1061, unregister_secs));
+        assert!(!session.expired_at(1061, register_secs));
+        assert!(!session.expired_at(1600, register_secs));
+        assert!(session.expired_at(1601, register_secs));
+        // a clock that went backwards does not expire the session
+        assert!(!session.expired_at(900, unregister_secs));
+    }
 }
@@ -1,43 +1,79 @@
 //! Handles the communication with the Discord API.
 //!
-use crate::config::CONFIG;
+use crate::cli::FailureVisibility;
+use crate::config::{parse_permission, CONFIG};
 use crate::controller::{
-    self, BatchResponse, CheckResponse, RemoveUserResponse, UnRegisterResponse, CONTROLLER_CHANNEL,
+    self, BatchResponse, CheckOutcome, CheckResponse, CoverageResponse, EnforcementSummary,
+    PreviewResponse, RemoveUserResponse, ToggleResponse, UnRegisterResponse, CONTROLLER_CHANNEL,
+};
+use crate::gate::{
+    wallet_name, Gate, GateOption, GateOptionType, GateOptionValue, GateOptionValueType,
 };
-use crate::gate::{Gate, GateOptionType, GateOptionValue, GateOptionValueType};
 use crate::gates;
+use crate::maintenance;
+use crate::storage::StorageErrorKind;
 use anyhow::{anyhow, bail, Result};
+use colony_rs::H160;
 use futures::{stream, StreamExt};
+use once_cell::sync::{Lazy, OnceCell};
 use secrecy::ExposeSecret;
+use serde::Deserialize;
 use serenity::{
     async_trait,
-    builder::CreateApplicationCommand,
+    builder::{CreateApplicationCommand, CreateApplicationCommandOption},
+    client::bridge::gateway::event::ShardStageUpdateEvent,
+    gateway::ConnectionStage,
     http::Http,
     model::{
         application::{
-            command::Command,
+            command::{Command, CommandType},
             interaction::{
                 application_command::{ApplicationCommandInteraction, CommandDataOptionValue},
                 Interaction, InteractionResponseType,
             },
         },
         gateway::{GatewayIntents, Ready},
-        id::GuildId,
+        guild::{Guild, Member, Role},
+        id::{ChannelId, GuildId, RoleId},
         permissions::Permissions,
         prelude::command::CommandOptionType,
     },
     prelude::*,
     utils::MessageBuilder,
 };
-use std::{collections::HashMap, time::Duration};
-use tokio::sync::oneshot;
+use std::{
+    collections::{HashMap, HashSet},
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc,
+    },
+    time::{Duration, Instant},
+};
+use tokio::sync::{mpsc, oneshot};
 use tracing::{debug, error, info, info_span, instrument, warn, Instrument, Span};
 
+mod queue;
+use queue::MessageQueue;
+
+/// Set while the gateway connection to discord is up
+static CONNECTED: AtomicBool = AtomicBool::new(false);
+/// Paces the follow up messages, it is started with the first message
+static MESSAGE_QUEUE: OnceCell<MessageQueue> = OnceCell::new();
+
+/// The reply to checks while gating is frozen in the guild
+const FROZEN_MESSAGE: &str = "Role changes are paused in this server, please try again later";
+/// Replaces the progress message of `/get in` when the check takes long
+const STILL_WORKING_MESSAGE: &str = "Still working, I'll update you shortly";
+
 #[instrument(level = "debug")]
 pub async fn start() {
     info!("Starting discord bot");
     let token = &CONFIG.wait().discord.token.expose_secret();
-    let mut client = Client::builder(token, GatewayIntents::GUILD_MEMBERS)
+    // the guilds intent delivers the roles of the guilds, which are needed to
+    // resolve the global gates by role name, the members intent the members
+    // joining for the auto grant, the gate types add their own
+    let intents = GatewayIntents::GUILDS | GatewayIntents::GUILD_MEMBERS | gates!(intents);
+    let mut client = Client::builder(token, intents)
         .event_handler(Handler)
         .in_current_span()
         .await
@@ -78,6 +114,7 @@ pub async fn register_guild_slash_commands(guild_id: u64) {
         commands
             .create_application_command(make_gate_command)
             .create_application_command(make_get_command)
+            .create_application_command(make_check_roles_command)
     })
     .in_current_span()
     .await;
@@ -141,6 +178,12 @@ pub async fn register_global_slash_commands() {
     {
         error!("Error creating global slash command get: {:?}", why);
     }
+    if let Err(why) = Command::create_global_application_command(&http, make_check_roles_command)
+        .in_current_span()
+        .await
+    {
+        error!("Error creating global context menu command: {:?}", why);
+    }
     info!("Done registering slash commands globally");
 }
 
@@ -182,6 +225,7 @@ impl EventHandler for MaintenanceHandler {
             "{}({}) is connected in maintenance mode!",
             ready.user.name, ready.user.id
         );
+        CONNECTED.store(true, Ordering::Relaxed);
     }
     #[instrument(
         name = "handling_interaction_in_maintenance_mode",
@@ -203,23 +247,31 @@ impl EventHandler for MaintenanceHandler {
                 Span::current().record("command", command_name);
                 Span::current().record("interaction_id", interaction_id.as_u64());
                 debug!("Start handling command interaction");
-                if let Err(why) = respond(
-                    &ctx,
-                    command,
-                    "⚠️⚠️⚠️  The bot is currently in maintenance mode, and will be back soon",
-                    true,
-                )
-                .in_current_span()
-                .await
-                {
-                    error!("Could not respond to discord {:?}", why);
-                }
+                respond_in_maintenance(&ctx, command)
+                    .in_current_span()
+                    .await;
             }
             _ => info!("Received non-command interaction in maintenance mode, do nothing"),
         }
     }
 }
 
+/// Tells the user that the bot is in maintenance mode instead of handling
+/// the command
+async fn respond_in_maintenance(ctx: &Context, command: &ApplicationCommandInteraction) {
+    if let Err(why) = respond(
+        ctx,
+        command,
+        "⚠️⚠️⚠️  The bot is currently in maintenance mode, and will be back soon",
+        true,
+    )
+    .in_current_span()
+    .await
+    {
+        error!("Could not respond to discord {:?}", why);
+    }
+}
+
 /// The handler for the Discord client.
 struct Handler;
 
@@ -228,7 +280,60 @@ impl EventHandler for Handler {
     #[instrument(level = "trace", skip(self, _ctx))]
     async fn ready(&self, _ctx: Context, ready: Ready) {
         info!("{}({}) is connected!", ready.user.name, ready.user.id);
+        CONNECTED.store(true, Ordering::Relaxed);
+    }
+
+    #[instrument(level = "debug", skip(self, _ctx))]
+    async fn shard_stage_update(&self, _ctx: Context, event: ShardStageUpdateEvent) {
+        CONNECTED.store(event.new == ConnectionStage::Connected, Ordering::Relaxed);
+    }
+
+    #[instrument(level = "debug", skip(self, _ctx, guild))]
+    async fn guild_create(&self, _ctx: Context, guild: Guild) {
+        send_guild_roles(*guild.id.as_u64(), guild.roles.values())
+            .in_current_span()
+            .await;
+    }
+
+    #[instrument(level = "debug", skip(self, ctx, new))]
+    async fn guild_role_create(&self, ctx: Context, new: Role) {
+        update_guild_roles(&ctx, *new.guild_id.as_u64())
+            .in_current_span()
+            .await;
+    }
+
+    #[instrument(level = "debug", skip(self, ctx, new_data))]
+    async fn guild_role_update(&self, ctx: Context, new_data: Role) {
+        update_guild_roles(&ctx, *new_data.guild_id.as_u64())
+            .in_current_span()
+            .await;
+    }
+
+    #[instrument(level = "debug", skip(self, ctx))]
+    async fn guild_role_delete(&self, ctx: Context, guild_id: GuildId, _removed_role_id: RoleId) {
+        update_guild_roles(&ctx, *guild_id.as_u64())
+            .in_current_span()
+            .await;
+    }
+
+    #[instrument(
+        level = "info",
+        skip(self, ctx, new_member),
+        fields(guild_id = new_member.guild_id.0, user_id = new_member.user.id.0)
+    )]
+    async fn guild_member_addition(&self, ctx: Context, new_member: Member) {
+        if new_member.user.bot {
+            return;
+        }
+        if maintenance::in_maintenance_window() {
+            debug!("In a maintenance window, not granting roles to the joined member");
+            return;
+        }
+        grant_joined_member(&ctx, new_member.guild_id.0, new_member.user.id.0)
+            .in_current_span()
+            .await;
     }
+
     #[instrument(
         name = "handling_interaction",
         level = "info",
@@ -249,9 +354,17 @@ impl EventHandler for Handler {
                 Span::current().record("command", command_name);
                 Span::current().record("interaction_id", interaction_id.as_u64());
                 debug!("Start handling command interaction");
+                if maintenance::in_maintenance_window() {
+                    debug!("In maintenance window, not handling command");
+                    respond_in_maintenance(&ctx, command)
+                        .in_current_span()
+                        .await;
+                    return;
+                }
                 let interaction_response = match command_name {
                     "gate" => gate_interaction(command, &ctx).in_current_span().await,
                     "get" => get_interaction(command, &ctx).in_current_span().await,
+                    CHECK_ROLES_COMMAND => get_in_check(command, &ctx).in_current_span().await,
                     _ => {
                         error!("Unknown command: {}", command.data.name);
                         return;
@@ -297,6 +410,20 @@ impl EventHandler for Handler {
     }
 }
 
+/// Whether the gateway connection to discord is currently up
+pub fn is_connected() -> bool {
+    CONNECTED.load(Ordering::Relaxed)
+}
+
+fn message_queue() -> &'static MessageQueue {
+    MESSAGE_QUEUE.get_or_init(|| MessageQueue::start(CONFIG.wait().discord.messages_per_second))
+}
+
+/// The number of follow up messages waiting to be sent
+pub fn queued_messages() -> usize {
+    MESSAGE_QUEUE.get().map_or(0, MessageQueue::depth)
+}
+
 #[instrument(level = "info", skip(ctx, interaction), fields(option))]
 async fn gate_interaction(
     interaction: &ApplicationCommandInteraction,
@@ -307,8 +434,23 @@ async fn gate_interaction(
     debug!("Handling gate command");
     match option.name.as_str() {
         "add" => Ok(add_gate(interaction, ctx).in_current_span().await?),
+        "preview" => Ok(preview_gate(interaction, ctx).in_current_span().await?),
         "list" => Ok(list_gates(interaction, ctx).in_current_span().await?),
         "enforce" => Ok(enforce_gates(interaction, ctx).in_current_span().await?),
+        "toggle" => Ok(toggle_gate(interaction, ctx).in_current_span().await?),
+        "recheck" => Ok(recheck_user(interaction, ctx).in_current_span().await?),
+        "coverage" => Ok(gate_coverage(interaction, ctx).in_current_span().await?),
+        "setup" => Ok(gate_setup(interaction, ctx).in_current_span().await?),
+        "stats" => Ok(registration_stats(interaction, ctx)
+            .in_current_span()
+            .await?),
+        "freeze" => Ok(freeze_gating(interaction, ctx, true)
+            .in_current_span()
+            .await?),
+        "unfreeze" => Ok(freeze_gating(interaction, ctx, false)
+            .in_current_span()
+            .await?),
+        "autogrant" => Ok(set_auto_grant(interaction, ctx).in_current_span().await?),
         _ => Err(anyhow!("Unknown gate subcommand")),
     }
 }
@@ -321,6 +463,7 @@ async fn get_interaction(interaction: &ApplicationCommandInteraction, ctx: &Cont
     match option.name.as_str() {
         "in" => get_in_check(interaction, ctx).in_current_span().await,
         "out" => get_out_request(interaction, ctx).in_current_span().await,
+        "link" => get_link(interaction, ctx).in_current_span().await,
         _ => Err(anyhow!("Unknown get subcommand")),
     }
 }
@@ -328,25 +471,49 @@ async fn get_interaction(interaction: &ApplicationCommandInteraction, ctx: &Cont
 #[instrument(level = "info", skip(ctx, interaction))]
 async fn add_gate(interaction: &ApplicationCommandInteraction, ctx: &Context) -> Result<()> {
     debug!("Received gate add interaction");
-    let (name, role_id, role_position, guild_id, options) = extract_gate_add_options(interaction)?;
+    let (name, role_id, role_position, guild_id, options, grant_message) =
+        extract_gate_add_options(interaction, "add")?;
+    let options = resolve_tier_role_names(ctx, guild_id, options)
+        .in_current_span()
+        .await?;
     debug!(
         name,
         role_id,
         role_position,
         guild_id,
         ?options,
+        ?grant_message,
         "Extracted options",
     );
-    if role_id == guild_id {
-        return Err(anyhow!("Role cannot be @everyone"));
-    }
+    let (roles, bot_position) =
+        guild_role_hierarchy(ctx, guild_id, interaction.application_id.into())
+            .in_current_span()
+            .await?;
+    check_gateable_role(
+        role_id,
+        guild_id,
+        roles.get(&role_id).copied(),
+        bot_position,
+    )?;
     let gate = Gate::new(role_id, &name, &options)
         .in_current_span()
-        .await?;
+        .await?
+        .with_grant_message(grant_message);
+    // gates with several roles, e.g. tiers, need to be able to assign all
+    for role_id in gate.role_ids() {
+        check_gateable_role(
+            role_id,
+            guild_id,
+            roles.get(&role_id).copied(),
+            bot_position,
+        )?;
+    }
+    let (tx, rx) = oneshot::channel();
     let span = info_span!("controller");
     let message = controller::Message::Gate {
         guild_id,
         gate,
+        response_tx: tx,
         span,
     };
     if let Err(why) = CONTROLLER_CHANNEL
@@ -357,32 +524,86 @@ async fn add_gate(interaction: &ApplicationCommandInteraction, ctx: &Context) ->
     {
         error!("Error sending gate message: {:?}", why);
     }
+    if let Err(why) = rx.in_current_span().await? {
+        if StorageErrorKind::of(&why) != StorageErrorKind::Io {
+            return Err(why);
+        }
+        let message = "The gate could not be saved due to a temporary storage issue, \
+            please try again later";
+        return respond(ctx, interaction, message, true)
+            .in_current_span()
+            .await;
+    }
     let mut content = MessageBuilder::new();
     content.push("The role ");
     content.role(role_id);
     content.push_line(" is now being gated!");
-    if !is_below_bot_in_hierarchy(
-        role_position,
-        ctx,
-        guild_id,
-        interaction.application_id.into(),
-    )
-    .in_current_span()
-    .await
-    .unwrap_or(true)
-    {
-        content.push_line(
-            "⚠️  The bot is currently below this role in the role hierarchy, \
-                     so it will not be able to assign it to users. Drag the bot \
-                     role above the gated role under `Server Settings -> Roles ⚠️ ",
-        );
-    }
     content.build();
     respond(ctx, interaction, content, true)
         .in_current_span()
         .await
 }
 
+#[instrument(level = "info", skip(ctx, interaction))]
+async fn preview_gate(interaction: &ApplicationCommandInteraction, ctx: &Context) -> Result<()> {
+    debug!("Received gate preview interaction");
+    let (name, role_id, _, guild_id, options, _) =
+        extract_gate_add_options(interaction, "preview")?;
+    let options = resolve_tier_role_names(ctx, guild_id, options)
+        .in_current_span()
+        .await?;
+    let user_id = extract_preview_user(interaction)?;
+    debug!(name, role_id, user_id, ?options, "Extracted options");
+    let gate = Gate::preview(role_id, &name, &options)
+        .in_current_span()
+        .await?;
+    let (tx, rx) = oneshot::channel();
+    let span = info_span!("controller");
+    let message = controller::Message::Preview {
+        user_id,
+        gate,
+        response_tx: tx,
+        span,
+    };
+    if let Err(why) = CONTROLLER_CHANNEL
+        .wait()
+        .send(message)
+        .in_current_span()
+        .await
+    {
+        error!("Error sending preview message: {:?}", why);
+    }
+    let mut content = MessageBuilder::new();
+    content.user(user_id);
+    match rx.in_current_span().await? {
+        PreviewResponse::Outcome(outcome) => match outcome.granted.first() {
+            // gates with several roles, e.g. tiers, may grant another role
+            Some(granted) => {
+                content.push(" would pass the gate and get ");
+                content.role(*granted);
+                if let Some(reason) = outcome.reasons.get(granted) {
+                    content.push(" (").push_safe(reason.as_str()).push(")");
+                }
+            }
+            None if !outcome.errored.is_empty() => {
+                content.push(" could not be checked right now for ");
+                content.role(role_id);
+            }
+            None => {
+                content.push(" would not pass the gate for ");
+                content.role(role_id);
+            }
+        },
+        PreviewResponse::Unregistered => {
+            content.push(" has not registered a wallet with the gating bot");
+        }
+        PreviewResponse::Error(why) => return Err(why),
+    }
+    respond(ctx, interaction, content.build(), true)
+        .in_current_span()
+        .await
+}
+
 #[instrument(level = "info", skip(ctx, interaction))]
 async fn list_gates(interaction: &ApplicationCommandInteraction, ctx: &Context) -> Result<()> {
     debug!("Listing gates");
@@ -424,30 +645,44 @@ async fn list_gates(interaction: &ApplicationCommandInteraction, ctx: &Context)
             content.push("The role: ");
             content.role(gate.role_id);
             content.push_line(" is gated by the following criteria");
-            let follow_up = match interaction
-                .create_followup_message(ctx, |message| {
-                    message
-                        .ephemeral(true)
-                        .content(&content)
-                        .embed(|e| {
-                            for field in gate.fields() {
-                                e.field(field.name, field.value, true);
-                            }
-                            e
-                        })
-                        .components(|c| {
-                            c.create_action_row(|row| {
-                                row.create_button(|button| {
-                                    button
-                                    .style(serenity::model::prelude::component::ButtonStyle::Danger)
-                                    .label(format!("Delete gate (within {}s)", 15))
-                                    .custom_id("delete_gate")
+            let http = ctx.http.clone();
+            let command = interaction.clone();
+            let listed = gate.clone();
+            let send = async move {
+                command
+                    .create_followup_message(&http, |message| {
+                        message
+                            .ephemeral(true)
+                            .content(&content)
+                            .embed(|e| {
+                                e.color(embed_color());
+                                for field in listed.fields() {
+                                    e.field(field.name, field.value, true);
+                                }
+                                e.field("identifier", listed.identifier(), true);
+                                e.field("enabled", listed.enabled, true);
+                                if let Some(grant_message) = &listed.grant_message {
+                                    e.field("grant_message", grant_message, false);
+                                }
+                                e
+                            })
+                            .components(|c| {
+                                c.create_action_row(|row| {
+                                    row.create_button(|button| {
+                                        button
+                                        .style(serenity::model::prelude::component::ButtonStyle::Danger)
+                                        .label(format!("Delete gate (within {}s)", 15))
+                                        .custom_id("delete_gate")
+                                    })
                                 })
                             })
-                        })
-                })
-                .in_current_span()
+                    })
+                    .await
+            };
+            let follow_up = match message_queue()
+                .send(guild_id, send.in_current_span())
                 .await
+                .and_then(|sent| sent.map_err(anyhow::Error::from))
             {
                 Ok(follow_up) => follow_up,
                 Err(why) => {
@@ -492,6 +727,7 @@ async fn list_gates(interaction: &ApplicationCommandInteraction, ctx: &Context)
                     .create_interaction_response(&ctx.http, |response| {
                         response.interaction_response_data(|message| {
                             message.content(content).ephemeral(true).embed(|e| {
+                                e.color(embed_color());
                                 for field in gate.condition.fields() {
                                     e.field(field.name, field.value, true);
                                 }
@@ -505,6 +741,36 @@ async fn list_gates(interaction: &ApplicationCommandInteraction, ctx: &Context)
                 {
                     error!("Error responding to interaction: {:?}", why);
                 }
+                if !CONFIG.wait().discord.revoke_on_delete {
+                    continue;
+                }
+                let content = match revoke_deleted_gate_role(ctx, guild_id, gate.role_id)
+                    .in_current_span()
+                    .await
+                {
+                    Ok(revoked) => MessageBuilder::new()
+                        .role(gate.role_id)
+                        .push(format!(
+                            " was removed from {} members that no longer qualify",
+                            revoked
+                        ))
+                        .build(),
+                    Err(why) => {
+                        error!("Error enforcing the role of the deleted gate: {:?}", why);
+                        "Could not enforce the role of the deleted gate, \
+                             run `/gate enforce` for it"
+                            .to_string()
+                    }
+                };
+                if let Err(why) = interaction
+                    .create_followup_message(&ctx.http, |message| {
+                        message.content(content).ephemeral(true)
+                    })
+                    .in_current_span()
+                    .await
+                {
+                    error!("Error sending follow up message: {:?}", why);
+                }
             }
         })
         .in_current_span()
@@ -512,17 +778,152 @@ async fn list_gates(interaction: &ApplicationCommandInteraction, ctx: &Context)
     Ok(())
 }
 
+/// The members of a page that `/gate enforce` checks, by user id with their
+/// managed roles. Bots are left out if configured, as are the excluded
+/// users, so none of their roles are touched
+fn enforced_members(
+    page: Vec<GuildMember>,
+    managed_roles: &HashSet<u64>,
+    skip_bots: bool,
+    excluded_users: &[u64],
+) -> HashMap<u64, Vec<u64>> {
+    page.into_iter()
+        .filter(|member| !(skip_bots && member.bot))
+        .filter(|member| !excluded_users.contains(&member.user_id))
+        .map(|member| {
+            let roles = member
+                .roles
+                .into_iter()
+                .filter(|id| managed_roles.contains(id))
+                .collect::<Vec<_>>();
+            (member.user_id, roles)
+        })
+        .collect()
+}
+
+/// Enforces the role of a deleted gate on the members holding it and returns
+/// how many lost it. Members who qualify for another gate of the role keep
+/// it, as do unregistered members, who were not granted it by the bot
+#[instrument(level = "info", skip(ctx))]
+async fn revoke_deleted_gate_role(ctx: &Context, guild_id: u64, role_id: u64) -> Result<usize> {
+    debug!("Revoking the role of a deleted gate");
+    let mut revoked = 0;
+    let mut pages = MemberPages::of_guild(ctx.http.as_ref(), guild_id);
+    while let Some(page) = pages.next_page().in_current_span().await? {
+        let holders = page
+            .into_iter()
+            .filter(|member| member.roles.contains(&role_id))
+            .map(|member| member.user_id)
+            .collect::<Vec<_>>();
+        if holders.is_empty() {
+            continue;
+        }
+        let losses = deleted_gate_losses(CONTROLLER_CHANNEL.wait(), guild_id, role_id, holders)
+            .in_current_span()
+            .await;
+        for (user_id, diff) in losses {
+            let report = reconcile_member(ctx, guild_id, user_id, diff)
+                .in_current_span()
+                .await;
+            if !report.member_left && report.failed_losses.is_empty() {
+                revoked += 1;
+            }
+        }
+    }
+    Ok(revoked)
+}
+
+/// The role losses of the members holding the role of a deleted gate. Only
+/// the remaining gates of the role, if any, are checked, so a member that
+/// qualified only through the deleted gate loses the role
+async fn deleted_gate_losses(
+    controller: &mpsc::Sender<controller::Message>,
+    guild_id: u64,
+    role_id: u64,
+    holders: Vec<u64>,
+) -> Vec<(u64, RoleDiff)> {
+    let (tx, mut rx) = mpsc::channel(100);
+    let span = info_span!("controller");
+    let message = controller::Message::Batch {
+        guild_id,
+        user_ids: holders,
+        force: true,
+        bypass_cache: false,
+        held_roles: HashMap::new(),
+        report_unregistered: false,
+        role_id: Some(role_id),
+        response_tx: tx,
+        span,
+    };
+    if let Err(err) = controller.send(message).in_current_span().await {
+        error!("Error sending message to controller: {:?}", err);
+        return Vec::new();
+    }
+    let mut losses = Vec::new();
+    while let Some(response) = rx.recv().in_current_span().await {
+        match response {
+            BatchResponse::Grant { user_id, outcome } => {
+                let diff = RoleDiff::new(&[role_id], &outcome);
+                if !diff.lost.is_empty() {
+                    losses.push((user_id, diff));
+                }
+            }
+            // nothing is revoked while gating is frozen
+            BatchResponse::Unregistered { .. } | BatchResponse::Frozen => {}
+            BatchResponse::Done => break,
+        }
+    }
+    losses
+}
+
+/// Shows the cumulative registrations and unregistrations. Users register
+/// once for all servers, so these are the counts of the whole bot
 #[instrument(level = "info", skip(ctx, interaction))]
-async fn enforce_gates(interaction: &ApplicationCommandInteraction, ctx: &Context) -> Result<()> {
-    debug!("Enforcing gates");
+async fn registration_stats(
+    interaction: &ApplicationCommandInteraction,
+    ctx: &Context,
+) -> Result<()> {
+    debug!("Getting registration stats");
+    let (tx, rx) = oneshot::channel();
+    let span = info_span!("controller");
+    let message = controller::Message::Stats { response: tx, span };
+    if let Err(err) = CONTROLLER_CHANNEL
+        .wait()
+        .send(message)
+        .in_current_span()
+        .await
+    {
+        error!("Error sending message to controller: {:?}", err);
+    }
+    let stats = rx.in_current_span().await?;
+    debug!(?stats, "Received response from controller");
+    let message = format!(
+        "Across all servers {} users registered and {} unregistered so far",
+        stats.registrations, stats.unregistrations
+    );
+    respond(ctx, interaction, message, true)
+        .in_current_span()
+        .await
+}
+
+/// Freezes or unfreezes gating in the guild. While frozen, no roles are
+/// granted or revoked, e.g. while a misconfigured gate is investigated
+#[instrument(level = "info", skip(ctx, interaction))]
+async fn freeze_gating(
+    interaction: &ApplicationCommandInteraction,
+    ctx: &Context,
+    frozen: bool,
+) -> Result<()> {
+    debug!("Setting the gating freeze");
     let guild_id = interaction
         .guild_id
         .ok_or(anyhow!("Error getting guild id from command"))?;
-    let (role_tx, role_rx) = tokio::sync::oneshot::channel();
+    let (tx, rx) = oneshot::channel();
     let span = info_span!("controller");
-    let message = controller::Message::Roles {
-        guild_id: guild_id.into(),
-        response: role_tx,
+    let message = controller::Message::Freeze {
+        guild_id: Some(guild_id.into()),
+        frozen,
+        response_tx: tx,
         span,
     };
     if let Err(err) = CONTROLLER_CHANNEL
@@ -533,41 +934,31 @@ async fn enforce_gates(interaction: &ApplicationCommandInteraction, ctx: &Contex
     {
         error!("Error sending message to controller: {:?}", err);
     }
-    let managed_roles = role_rx.in_current_span().await?;
-    debug!(?managed_roles, "Received response from controller");
-    let (tx, mut rx) = tokio::sync::mpsc::channel(100);
-    let members = ctx
-        .http
-        .get_guild_members(guild_id.into(), None, None)
+    rx.in_current_span().await??;
+    let message = if frozen {
+        "Gating is frozen, no roles will be granted or revoked until it is unfrozen"
+    } else {
+        "Gating is unfrozen, roles are granted and revoked again"
+    };
+    respond(ctx, interaction, message, true)
         .in_current_span()
-        .await?;
-    let user_ids = members
-        .iter()
-        .map(|m| *m.user.id.as_u64())
-        .collect::<Vec<_>>();
-    let member_map = members
-        .into_iter()
-        .map(|m| {
-            (
-                *m.user.id.as_u64(),
-                m.roles
-                    .iter()
-                    .filter_map(|&r| {
-                        let id = u64::from(r);
-                        if managed_roles.contains(&id) {
-                            Some(id)
-                        } else {
-                            None
-                        }
-                    })
-                    .collect::<Vec<_>>(),
-            )
-        })
-        .collect::<HashMap<_, _>>();
+        .await
+}
+
+/// Enables or disables granting the roles of registered members as soon as
+/// they join the guild
+#[instrument(level = "info", skip(ctx, interaction))]
+async fn set_auto_grant(interaction: &ApplicationCommandInteraction, ctx: &Context) -> Result<()> {
+    debug!("Setting the auto grant on join");
+    let guild_id = interaction
+        .guild_id
+        .ok_or(anyhow!("Error getting guild id from command"))?;
+    let enabled = extract_auto_grant_flag(interaction);
+    let (tx, rx) = oneshot::channel();
     let span = info_span!("controller");
-    let message = controller::Message::Batch {
+    let message = controller::Message::AutoGrant {
         guild_id: guild_id.into(),
-        user_ids,
+        enabled,
         response_tx: tx,
         span,
     };
@@ -579,114 +970,363 @@ async fn enforce_gates(interaction: &ApplicationCommandInteraction, ctx: &Contex
     {
         error!("Error sending message to controller: {:?}", err);
     }
-    let mut message = MessageBuilder::new();
-    message.push("Enforcing gates for all server members and the following roles");
-    for role in managed_roles.iter() {
-        message.role(*role);
-    }
+    rx.in_current_span().await??;
+    let message = if enabled {
+        "Registered members get their roles as soon as they join this server"
+    } else {
+        "Members need to use `/get in` to get their roles after joining this server"
+    };
     respond(ctx, interaction, message, true)
         .in_current_span()
-        .await?;
+        .await
+}
+
+/// Grants a member that just joined the roles they qualify for, if they are
+/// registered and the guild enabled the auto grant. Nothing is revoked, the
+/// member can't hold gated roles yet
+async fn grant_joined_member(ctx: &Context, guild_id: u64, user_id: u64) {
+    let (tx, mut rx) = mpsc::channel(10);
+    let span = info_span!("controller");
+    let message = controller::Message::MemberJoined {
+        guild_id,
+        user_id,
+        response_tx: tx,
+        span,
+    };
+    if let Err(err) = CONTROLLER_CHANNEL
+        .wait()
+        .send(message)
+        .in_current_span()
+        .await
+    {
+        error!("Error sending message to controller: {:?}", err);
+        return;
+    }
     while let Some(response) = rx.recv().in_current_span().await {
         match response {
-            BatchResponse::Grant { user_id, roles } => {
-                let gained_roles = roles
-                    .iter()
-                    .filter(|&r| !member_map[&user_id].contains(r))
-                    .collect::<Vec<_>>();
-                let lost_roles = member_map[&user_id]
-                    .iter()
-                    .filter(|&r| !roles.contains(r))
-                    .collect::<Vec<_>>();
-                debug!(
-                    user_id,
-                    ?gained_roles,
-                    ?lost_roles,
-                    "Roles to grant or remove for user"
-                );
-                let mut message = MessageBuilder::new();
-                if gained_roles.is_empty() && lost_roles.is_empty() {
+            BatchResponse::Grant { outcome, .. } => {
+                let diff = RoleDiff::new(&[], &outcome);
+                if diff.is_empty() {
                     continue;
                 }
-                let mut failed_grants = Vec::new();
-                let mut failed_losses = Vec::new();
+                let report = reconcile_member(ctx, guild_id, user_id, diff)
+                    .in_current_span()
+                    .await;
+                info!(
+                    granted = ?report.diff.gained,
+                    failed = ?report.failed_grants,
+                    "Granted roles to the joined member"
+                );
+            }
+            BatchResponse::Frozen => {
+                debug!("Gating is frozen, not granting roles to the joined member")
+            }
+            BatchResponse::Unregistered { .. } => {}
+            BatchResponse::Done => break,
+        }
+    }
+}
 
-                for role in gained_roles.clone() {
-                    if let Err(why) = ctx
-                        .http
-                        .add_member_role(guild_id.into(), user_id, *role, None)
-                        .in_current_span()
-                        .await
-                    {
-                        info!("Error granting role: {:?}", why);
-                        failed_grants.push(role);
+#[instrument(level = "info", skip(ctx, interaction))]
+async fn enforce_gates(interaction: &ApplicationCommandInteraction, ctx: &Context) -> Result<()> {
+    debug!("Enforcing gates");
+    let guild_id = interaction
+        .guild_id
+        .ok_or(anyhow!("Error getting guild id from command"))?;
+    let (role_tx, role_rx) = tokio::sync::oneshot::channel();
+    let span = info_span!("controller");
+    let message = controller::Message::Roles {
+        guild_id: guild_id.into(),
+        response: role_tx,
+        span,
+    };
+    if let Err(err) = CONTROLLER_CHANNEL
+        .wait()
+        .send(message)
+        .in_current_span()
+        .await
+    {
+        error!("Error sending message to controller: {:?}", err);
+    }
+    let mut managed_roles = role_rx.in_current_span().await?;
+    debug!(?managed_roles, "Received response from controller");
+    let role_id = extract_enforce_role(interaction);
+    if let Some(role_id) = role_id {
+        if !managed_roles.contains(&role_id) {
+            let mut message = MessageBuilder::new();
+            message
+                .role(role_id)
+                .push(" is not managed by any active gate, nothing to enforce");
+            return respond(ctx, interaction, message, true)
+                .in_current_span()
+                .await;
+        }
+        // only the gates of the role are checked, all other roles are left
+        // as they are
+        managed_roles.retain(|id| *id == role_id);
+    }
+    let force = extract_enforce_flag(interaction, "force");
+    let report_unregistered = extract_enforce_flag(interaction, "report_unregistered");
+    let mut message = MessageBuilder::new();
+    message.push("Enforcing gates for all server members and the following roles");
+    for role in managed_roles.iter() {
+        message.role(*role);
+    }
+    respond(ctx, interaction, message, true)
+        .in_current_span()
+        .await?;
+    // the members are checked one page at a time, so large servers don't
+    // need to be held in memory at once
+    let discord_config = &CONFIG.wait().discord;
+    let mut pages = MemberPages::of_guild(ctx.http.as_ref(), guild_id.into());
+    let mut summary = EnforcementSummary::for_roles(managed_roles.iter().copied());
+    loop {
+        if controller::is_enforcement_paused() {
+            info!(?summary, "The enforcement is paused, stopping early");
+            let message = "The enforcement is paused, so it stopped early. The roles \
+                changed so far are kept";
+            return follow_up(ctx, interaction, message, true)
+                .in_current_span()
+                .await;
+        }
+        let page = match pages.next_page().in_current_span().await {
+            Ok(Some(page)) => page,
+            Ok(None) => break,
+            Err(why) => {
+                error!("Failed to fetch members for enforcement: {:?}", why);
+                let message = "Could not fetch the members of this server from discord, \
+                    so the enforcement stopped early. The roles changed so far are kept, \
+                    please try again later";
+                return follow_up(ctx, interaction, message, true)
+                    .in_current_span()
+                    .await;
+            }
+        };
+        let page_len = page.len();
+        let processed_before = summary.processed;
+        let member_map = enforced_members(
+            page,
+            &managed_roles,
+            discord_config.enforce_skip_bots,
+            &discord_config.enforce_excluded_users,
+        );
+        let (tx, mut rx) = tokio::sync::mpsc::channel(100);
+        let span = info_span!("controller");
+        let message = controller::Message::Batch {
+            guild_id: guild_id.into(),
+            user_ids: member_map.keys().copied().collect(),
+            force,
+            bypass_cache: false,
+            held_roles: member_map.clone(),
+            report_unregistered,
+            role_id,
+            response_tx: tx,
+            span,
+        };
+        if let Err(err) = CONTROLLER_CHANNEL
+            .wait()
+            .send(message)
+            .in_current_span()
+            .await
+        {
+            error!("Error sending message to controller: {:?}", err);
+        }
+        while let Some(response) = rx.recv().in_current_span().await {
+            match response {
+                BatchResponse::Grant { user_id, outcome } => {
+                    summary.add_qualifying(&outcome.granted);
+                    let diff = RoleDiff::new(&member_map[&user_id], &outcome);
+                    debug!(user_id, ?diff, "Roles to grant or remove for user");
+                    if diff.is_empty() {
+                        summary.add_member(0, 0, !outcome.errored.is_empty());
+                        continue;
                     }
-                }
-                for role in lost_roles.clone() {
-                    if let Err(why) = ctx
-                        .http
-                        .remove_member_role(guild_id.into(), user_id, *role, None)
+                    let report = reconcile_member(ctx, guild_id.into(), user_id, diff)
                         .in_current_span()
-                        .await
-                    {
-                        info!("Could not remove role: {:?}", why);
-                        failed_losses.push(role);
-                    }
-                }
-                message.user(user_id);
-                message.push_line("");
-                if !gained_roles.is_empty() {
-                    message.push("has been granted the following roles: ");
-                    for role in gained_roles {
-                        message.role(*role);
-                    }
-                    message.push_line("");
+                        .await;
+                    report.count(&mut summary, !outcome.errored.is_empty());
+                    follow_up(ctx, interaction, report.message(user_id), true)
+                        .in_current_span()
+                        .await?;
                 }
-                if !lost_roles.is_empty() {
-                    message.push("lost the following roles: ");
-                    for role in lost_roles {
-                        message.role(*role);
+                BatchResponse::Unregistered { user_id, roles } => {
+                    let mut message = MessageBuilder::new();
+                    message.user(user_id).push(" holds ");
+                    for role in roles {
+                        message.role(role);
                     }
+                    message.push(" without a registered wallet");
+                    follow_up(ctx, interaction, message.build(), true)
+                        .in_current_span()
+                        .await?;
                 }
-                if !failed_grants.is_empty() {
-                    message.push_line("");
-                    message.push("there were problems granting the roles: ");
-                    for role in failed_grants {
-                        message.role(*role);
-                    }
+                BatchResponse::Frozen => {
+                    return follow_up(ctx, interaction, FROZEN_MESSAGE, true)
+                        .in_current_span()
+                        .await;
                 }
-                if !failed_losses.is_empty() {
-                    message.push_line("");
-                    message.push("couldn't remove the following roles: ");
-                    for role in failed_losses {
-                        message.role(*role);
-                    }
+                BatchResponse::Done => break,
+            }
+        }
+        // bots, excluded members and members the controller did not check,
+        // e.g. unregistered or recently checked ones
+        summary.skip(page_len - (summary.processed - processed_before));
+    }
+    info!(?summary, "Finished enforcement of gates");
+    // without force, recently checked members were skipped and would be
+    // missing from the qualifying members, a single role is always checked
+    // for all members
+    #[cfg(feature = "metrics")]
+    if force || role_id.is_some() {
+        summary.record_qualifying(guild_id.into());
+    }
+    follow_up_summary(ctx, interaction, summary)
+        .in_current_span()
+        .await
+}
+
+/// Sends the counts of a finished enforcement as an embed
+async fn follow_up_summary(
+    ctx: &Context,
+    interaction: &ApplicationCommandInteraction,
+    summary: EnforcementSummary,
+) -> Result<()> {
+    let http = ctx.http.clone();
+    let interaction = interaction.clone();
+    let guild_id = interaction.guild_id.map_or(0, |id| id.0);
+    let send = async move {
+        interaction
+            .create_followup_message(&http, |message| {
+                message
+                    .ephemeral(true)
+                    .content("Finished enforcement of gates")
+                    .embed(|e| {
+                        e.color(embed_color());
+                        e.field("processed", summary.processed, true);
+                        e.field("granted", summary.granted, true);
+                        e.field("revoked", summary.revoked, true);
+                        e.field("skipped", summary.skipped, true);
+                        e.field("failed", summary.failed, true);
+                        e
+                    })
+            })
+            .await
+            .map(|_| ())
+    };
+    Ok(message_queue()
+        .send(guild_id, send.in_current_span())
+        .await??)
+}
+
+#[instrument(level = "info", skip(ctx, interaction))]
+async fn recheck_user(interaction: &ApplicationCommandInteraction, ctx: &Context) -> Result<()> {
+    debug!("Rechecking user");
+    let guild_id = interaction
+        .guild_id
+        .ok_or(anyhow!("Error getting guild id from command"))?;
+    let user_id = extract_recheck_user(interaction)?;
+    let (role_tx, role_rx) = tokio::sync::oneshot::channel();
+    let span = info_span!("controller");
+    let message = controller::Message::Roles {
+        guild_id: guild_id.into(),
+        response: role_tx,
+        span,
+    };
+    if let Err(err) = CONTROLLER_CHANNEL
+        .wait()
+        .send(message)
+        .in_current_span()
+        .await
+    {
+        error!("Error sending message to controller: {:?}", err);
+    }
+    let managed_roles = role_rx.in_current_span().await?;
+    let member = ctx
+        .http
+        .get_member(guild_id.into(), user_id)
+        .in_current_span()
+        .await?;
+    let current_roles = member
+        .roles
+        .iter()
+        .map(|&r| u64::from(r))
+        .filter(|id| managed_roles.contains(id))
+        .collect::<Vec<_>>();
+    let (tx, mut rx) = tokio::sync::mpsc::channel(1);
+    let span = info_span!("controller");
+    let message = controller::Message::Batch {
+        guild_id: guild_id.into(),
+        user_ids: vec![user_id],
+        // an explicit recheck should never be skipped and should reflect the
+        // live on-chain state, since it is usually triggered by disputed roles
+        force: true,
+        bypass_cache: true,
+        held_roles: HashMap::from([(user_id, current_roles.clone())]),
+        report_unregistered: false,
+        role_id: None,
+        response_tx: tx,
+        span,
+    };
+    if let Err(err) = CONTROLLER_CHANNEL
+        .wait()
+        .send(message)
+        .in_current_span()
+        .await
+    {
+        error!("Error sending message to controller: {:?}", err);
+    }
+    while let Some(response) = rx.recv().in_current_span().await {
+        match response {
+            BatchResponse::Grant { user_id, outcome } => {
+                let diff = RoleDiff::new(&current_roles, &outcome);
+                debug!(user_id, ?diff, "Roles to grant or remove for user");
+                if diff.is_empty() {
+                    let message = MessageBuilder::new()
+                        .user(user_id)
+                        .push(" already has the right roles")
+                        .build();
+                    return respond(ctx, interaction, message, true)
+                        .in_current_span()
+                        .await;
                 }
-                message.build();
-                follow_up(ctx, interaction, message, true)
+                let report = reconcile_member(ctx, guild_id.into(), user_id, diff)
                     .in_current_span()
-                    .await?;
+                    .await;
+                return respond(ctx, interaction, report.message(user_id), true)
+                    .in_current_span()
+                    .await;
+            }
+            BatchResponse::Unregistered { .. } => {}
+            BatchResponse::Frozen => {
+                return respond(ctx, interaction, FROZEN_MESSAGE, true)
+                    .in_current_span()
+                    .await;
             }
             BatchResponse::Done => break,
         }
     }
-    follow_up(ctx, interaction, "Finished enforcement of gates", true)
+    let message = MessageBuilder::new()
+        .user(user_id)
+        .push(" has not registered a wallet with the gating bot")
+        .build();
+    respond(ctx, interaction, message, true)
         .in_current_span()
         .await
 }
 
 #[instrument(level = "info", skip(ctx, interaction))]
-async fn get_in_check(interaction: &ApplicationCommandInteraction, ctx: &Context) -> Result<()> {
-    debug!("checking `get in` request");
+async fn toggle_gate(interaction: &ApplicationCommandInteraction, ctx: &Context) -> Result<()> {
+    debug!("Toggling gate");
+    let guild_id = interaction
+        .guild_id
+        .ok_or(anyhow!("Error getting guild id from command"))?
+        .into();
+    let identifier = extract_gate_identifier(interaction, "toggle")?;
     let (tx, rx) = oneshot::channel();
     let span = info_span!("controller");
-    let message = controller::Message::Check {
-        user_id: interaction.user.id.into(),
-        username: interaction.user.name.clone(),
-        guild_id: interaction
-            .guild_id
-            .ok_or(anyhow!("Error getting guild id from command"))?
-            .into(),
+    let message = controller::Message::Toggle {
+        guild_id,
+        identifier,
         response_tx: tx,
         span,
     };
@@ -698,54 +1338,102 @@ async fn get_in_check(interaction: &ApplicationCommandInteraction, ctx: &Context
     {
         error!("Error sending message to controller: {:?}", err);
     }
-    interaction
-        .create_interaction_response(&ctx, |response| {
-            response.interaction_response_data(|message| message.ephemeral(true));
-            response.kind(InteractionResponseType::DeferredChannelMessageWithSource)
-        })
+    match rx.in_current_span().await? {
+        ToggleResponse::Toggled(gate) => {
+            let mut content = MessageBuilder::new();
+            content.push("The gate for the role ");
+            content.role(gate.role_id);
+            if gate.enabled {
+                content.push_line(" is now enabled");
+            } else {
+                content.push_line(" is now disabled");
+            }
+            content.build();
+            respond(ctx, interaction, content, true)
+                .in_current_span()
+                .await
+        }
+        ToggleResponse::NotFound => bail!("No gate found with identifier {}", identifier),
+        ToggleResponse::Error(why) => bail!("Error toggling gate: {}", why),
+    }
+}
+
+#[instrument(level = "info", skip(ctx, interaction))]
+async fn gate_coverage(interaction: &ApplicationCommandInteraction, ctx: &Context) -> Result<()> {
+    debug!("Calculating gate coverage");
+    let guild_id = interaction
+        .guild_id
+        .ok_or(anyhow!("Error getting guild id from command"))?;
+    let identifier = extract_gate_identifier(interaction, "coverage")?;
+    let mut user_ids = Vec::new();
+    let mut pages = MemberPages::of_guild(ctx.http.as_ref(), guild_id.into());
+    while let Some(page) = pages.next_page().in_current_span().await? {
+        user_ids.extend(page.into_iter().map(|member| member.user_id));
+    }
+    let (tx, rx) = oneshot::channel();
+    let span = info_span!("controller");
+    let message = controller::Message::Coverage {
+        guild_id: guild_id.into(),
+        identifier,
+        user_ids,
+        response_tx: tx,
+        span,
+    };
+    if let Err(err) = CONTROLLER_CHANNEL
+        .wait()
+        .send(message)
         .in_current_span()
-        .await?;
-    follow_up(
+        .await
+    {
+        error!("Error sending message to controller: {:?}", err);
+    }
+    respond(
         ctx,
         interaction,
-        "Checking conditions for you to get your roles,\
-              this might take a while...",
+        "Checking the gate for all registered members, this might take a while...",
         true,
     )
     .in_current_span()
     .await?;
-    let response = match rx.in_current_span().await {
-        Ok(repsonse) => repsonse,
-        Err(why) => {
-            error!("Error receiving response from controller: {:?}", why);
-            bail!("Error receiving response from controller: {:?}", why);
-        }
-    };
-    match response {
-        CheckResponse::Grant(roles) => {
-            grant_roles(ctx, interaction, &roles)
+    match rx.in_current_span().await? {
+        CoverageResponse::Coverage { gate, coverage } => {
+            let mut content = MessageBuilder::new();
+            content
+                .push(format!(
+                    "{} of {} registered members qualify for the gate of the role ",
+                    coverage.qualified, coverage.checked
+                ))
+                .role(gate.role_id);
+            if coverage.errored > 0 {
+                content.push(format!(", {} could not be checked", coverage.errored));
+            }
+            follow_up(ctx, interaction, content.build(), true)
                 .in_current_span()
                 .await
         }
-        CheckResponse::Register(url) => {
-            register_user(ctx, interaction, &url)
-                .in_current_span()
-                .await
+        CoverageResponse::NotFound => {
+            follow_up(
+                ctx,
+                interaction,
+                format!("No gate found with identifier {}", identifier),
+                true,
+            )
+            .in_current_span()
+            .await
         }
-        CheckResponse::Error(why) => bail!("Error checking your reputation: {}", why),
+        CoverageResponse::Error(why) => bail!("Error calculating gate coverage: {}", why),
     }
 }
 
+/// Shows admins a checklist of the setup steps the bot can verify live: the
+/// role hierarchy, the active gates and the registration url
 #[instrument(level = "info", skip(ctx, interaction))]
-async fn get_out_request(interaction: &ApplicationCommandInteraction, ctx: &Context) -> Result<()> {
-    debug!("checking `get out` request");
-    let (tx, rx) = oneshot::channel();
-    let (role_tx, role_rx) = oneshot::channel();
-    let (removed_tx, removed_rx) = oneshot::channel();
+async fn gate_setup(interaction: &ApplicationCommandInteraction, ctx: &Context) -> Result<()> {
+    debug!("Checking the setup");
     let guild_id = interaction
         .guild_id
         .ok_or(anyhow!("Error getting guild id from command"))?;
-    let user_id = interaction.user.id;
+    let (role_tx, role_rx) = oneshot::channel();
     let span = info_span!("controller");
     let message = controller::Message::Roles {
         guild_id: guild_id.into(),
@@ -760,13 +1448,58 @@ async fn get_out_request(interaction: &ApplicationCommandInteraction, ctx: &Cont
     {
         error!("Error sending message to controller: {:?}", err);
     }
-    let roles = role_rx.in_current_span().await?;
+    let mut gated_roles = role_rx
+        .in_current_span()
+        .await?
+        .into_iter()
+        .collect::<Vec<_>>();
+    gated_roles.sort_unstable();
+    let (roles, bot_position) =
+        guild_role_hierarchy(ctx, guild_id.into(), interaction.application_id.into())
+            .in_current_span()
+            .await?;
+    let hierarchy = hierarchy_status(guild_id.into(), &gated_roles, &roles, bot_position);
+    let gates = if gated_roles.is_empty() {
+        SetupStatus::problem("No active gates yet, add one with `/gate add`")
+    } else {
+        SetupStatus::ok(format!("{} gated roles", gated_roles.len()))
+    };
+    let registration_url = CONFIG.wait().server.url.clone();
+    interaction
+        .create_interaction_response(&ctx.http, |response| {
+            response
+                .kind(InteractionResponseType::ChannelMessageWithSource)
+                .interaction_response_data(|message| {
+                    message
+                        .ephemeral(true)
+                        .content("Here is the setup checklist for this server")
+                        .embed(|e| {
+                            e.color(embed_color());
+                            e.field(hierarchy.title("Role hierarchy"), &hierarchy.detail, false);
+                            e.field(gates.title("Gates"), &gates.detail, false);
+                            e.field("Registration url", registration_url, false);
+                            e
+                        })
+                })
+        })
+        .in_current_span()
+        .await?;
+    Ok(())
+}
+
+#[instrument(level = "info", skip(ctx, interaction))]
+async fn get_in_check(interaction: &ApplicationCommandInteraction, ctx: &Context) -> Result<()> {
+    debug!("checking `get in` request");
+    let (tx, rx) = oneshot::channel();
     let span = info_span!("controller");
-    let message = controller::Message::Unregister {
-        user_id: user_id.into(),
+    let message = controller::Message::Check {
+        user_id: interaction.user.id.into(),
         username: interaction.user.name.clone(),
+        guild_id: interaction
+            .guild_id
+            .ok_or(anyhow!("Error getting guild id from command"))?
+            .into(),
         response_tx: tx,
-        removed_tx,
         span,
     };
     if let Err(err) = CONTROLLER_CHANNEL
@@ -777,37 +1510,190 @@ async fn get_out_request(interaction: &ApplicationCommandInteraction, ctx: &Cont
     {
         error!("Error sending message to controller: {:?}", err);
     }
-    let response = rx.in_current_span().await?;
-    match response {
-        UnRegisterResponse::NotRegistered => {
-            respond(ctx, interaction, "You are not registered", true)
-                .in_current_span()
-                .await?
-        }
-        UnRegisterResponse::Unregister(url) => {
-            unregister_user(ctx, interaction, &url)
-                .in_current_span()
-                .await?
-        }
-        UnRegisterResponse::Error(why) => bail!("Error unregistering: {}", why),
+    interaction
+        .create_interaction_response(&ctx, |response| {
+            response.interaction_response_data(|message| message.ephemeral(true));
+            response.kind(InteractionResponseType::DeferredChannelMessageWithSource)
+        })
+        .in_current_span()
+        .await?;
+    follow_up(
+        ctx,
+        interaction,
+        "Checking conditions for you to get your roles,\
+              this might take a while...",
+        true,
+    )
+    .in_current_span()
+    .await?;
+    // slow lookups don't leave the user without feedback, the result still
+    // arrives within the lifetime of the interaction token
+    let notice_timeout = Duration::from_secs(CONFIG.wait().discord.check_notice_timeout);
+    let still_working = async {
+        if let Err(why) = interaction
+            .edit_original_interaction_response(&ctx.http, |response| {
+                response.content(STILL_WORKING_MESSAGE)
+            })
+            .in_current_span()
+            .await
+        {
+            warn!(
+                "Failed to tell the user the check is still running: {:?}",
+                why
+            );
+        }
+    };
+    let response = match wait_with_notice(rx, notice_timeout, still_working)
+        .in_current_span()
+        .await
+    {
+        Ok(repsonse) => repsonse,
+        Err(why) => {
+            error!("Error receiving response from controller: {:?}", why);
+            bail!("Error receiving response from controller: {:?}", why);
+        }
+    };
+    match response {
+        CheckResponse::Grant(outcome) => {
+            let verbose = extract_get_in_flag(interaction, "verbose");
+            grant_roles(ctx, interaction, &outcome, verbose)
+                .in_current_span()
+                .await
+        }
+        CheckResponse::Register(url) => {
+            register_user(ctx, interaction, &url, false)
+                .in_current_span()
+                .await
+        }
+        CheckResponse::Reregister(url) => {
+            register_user(ctx, interaction, &url, true)
+                .in_current_span()
+                .await
+        }
+        CheckResponse::Frozen => {
+            follow_up(ctx, interaction, FROZEN_MESSAGE, true)
+                .in_current_span()
+                .await
+        }
+        CheckResponse::Error(why) => bail!("Error checking your reputation: {}", why),
+    }
+}
+
+/// Waits for the response of the controller. If it takes longer than the
+/// timeout, the notice runs once and the wait continues
+async fn wait_with_notice<T>(
+    mut rx: oneshot::Receiver<T>,
+    timeout: Duration,
+    notice: impl std::future::Future<Output = ()>,
+) -> Result<T, oneshot::error::RecvError> {
+    match tokio::time::timeout(timeout, &mut rx).await {
+        Ok(response) => response,
+        Err(_) => {
+            debug!("The controller is slow, sending a notice");
+            notice.await;
+            rx.await
+        }
+    }
+}
+
+/// Hands out a fresh registration link, which also works for registered
+/// users that want to link another wallet
+#[instrument(level = "info", skip(ctx, interaction))]
+async fn get_link(interaction: &ApplicationCommandInteraction, ctx: &Context) -> Result<()> {
+    debug!("checking `get link` request");
+    let (tx, rx) = oneshot::channel();
+    let span = info_span!("controller");
+    let message = controller::Message::Link {
+        user_id: interaction.user.id.into(),
+        username: interaction.user.name.clone(),
+        response_tx: tx,
+        span,
+    };
+    if let Err(err) = CONTROLLER_CHANNEL
+        .wait()
+        .send(message)
+        .in_current_span()
+        .await
+    {
+        error!("Error sending message to controller: {:?}", err);
+    }
+    let url = rx.in_current_span().await??;
+    let message = format!(
+        "To link a wallet address with your discord user go to {} and follow \
+        the instructions. A wallet you registered before stays linked.",
+        url
+    );
+    respond(ctx, interaction, message, true)
+        .in_current_span()
+        .await
+}
+
+#[instrument(level = "info", skip(ctx, interaction))]
+async fn get_out_request(interaction: &ApplicationCommandInteraction, ctx: &Context) -> Result<()> {
+    debug!("checking `get out` request");
+    let (tx, rx) = oneshot::channel();
+    let (role_tx, role_rx) = oneshot::channel();
+    let (removed_tx, removed_rx) = oneshot::channel();
+    let guild_id = interaction
+        .guild_id
+        .ok_or(anyhow!("Error getting guild id from command"))?;
+    let user_id = interaction.user.id;
+    let span = info_span!("controller");
+    let message = controller::Message::Roles {
+        guild_id: guild_id.into(),
+        response: role_tx,
+        span,
+    };
+    if let Err(err) = CONTROLLER_CHANNEL
+        .wait()
+        .send(message)
+        .in_current_span()
+        .await
+    {
+        error!("Error sending message to controller: {:?}", err);
+    }
+    let roles = role_rx.in_current_span().await?;
+    let span = info_span!("controller");
+    let message = controller::Message::Unregister {
+        user_id: user_id.into(),
+        username: interaction.user.name.clone(),
+        response_tx: tx,
+        removed_tx,
+        span,
+    };
+    if let Err(err) = CONTROLLER_CHANNEL
+        .wait()
+        .send(message)
+        .in_current_span()
+        .await
+    {
+        error!("Error sending message to controller: {:?}", err);
+    }
+    let response = rx.in_current_span().await?;
+    match response {
+        UnRegisterResponse::NotRegistered => {
+            respond(ctx, interaction, "You are not registered", true)
+                .in_current_span()
+                .await?
+        }
+        UnRegisterResponse::Unregister(url) => {
+            unregister_user(ctx, interaction, &url)
+                .in_current_span()
+                .await?
+        }
+        UnRegisterResponse::Error(why) => bail!("Error unregistering: {}", why),
     };
     match removed_rx.in_current_span().await? {
         RemoveUserResponse::Success => {
-            let mut message = MessageBuilder::new();
-            message.push("You have been removed from the following roles: ");
-            for role in roles.iter() {
-                if let Err(why) = ctx
-                    .http
-                    .remove_member_role(guild_id.into(), user_id.into(), *role, None)
-                    .in_current_span()
-                    .await
-                {
-                    info!("Could not remove role: {:?}", why);
-                }
-
-                message.role(*role);
-            }
-            message.build();
+            let message = revoke_unregistered_roles(
+                ctx.http.as_ref(),
+                guild_id.into(),
+                user_id.into(),
+                &roles,
+                CONFIG.wait().discord.unregister_revokes_roles,
+            )
+            .in_current_span()
+            .await;
             follow_up(ctx, interaction, message, true)
                 .in_current_span()
                 .await
@@ -824,13 +1710,22 @@ async fn register_user(
     ctx: &Context,
     interaction: &ApplicationCommandInteraction,
     url: &str,
+    unregistered_before: bool,
 ) -> Result<()> {
     debug!("Registering user");
-    let message = format!(
-        "You need to register your wallet address with your discord user to get \
-        gated roles. Please go to {} and follow the instructions.",
-        url
-    );
+    let message = if unregistered_before {
+        format!(
+            "You unregistered your wallet, so you don't get gated roles. If you \
+            changed your mind, you can register again at {}.",
+            url
+        )
+    } else {
+        format!(
+            "You need to register your wallet address with your discord user to get \
+            gated roles. Please go to {} and follow the instructions.",
+            url
+        )
+    };
     follow_up(ctx, interaction, message, true)
         .in_current_span()
         .await
@@ -857,12 +1752,14 @@ async fn unregister_user(
 async fn grant_roles(
     ctx: &Context,
     interaction: &ApplicationCommandInteraction,
-    roles: &Vec<u64>,
+    outcome: &CheckOutcome,
+    verbose: bool,
 ) -> Result<()> {
-    debug!(?roles, "Granting roles in discord");
+    debug!(?outcome, "Granting roles in discord");
     let mut granted_roles = Vec::new();
     let mut failed_roles = Vec::new();
-    for role in roles.iter() {
+    let mut missing_permissions = Vec::new();
+    for role in outcome.granted.iter() {
         if let Err(why) = ctx
             .http
             .add_member_role(
@@ -878,6 +1775,9 @@ async fn grant_roles(
             .await
         {
             warn!(role, "Error adding role: {:?}", why);
+            if classify_role_error(&why) == RoleUpdateError::MissingPermissions {
+                missing_permissions.push(*role);
+            }
             failed_roles.push(*role);
         } else {
             debug!(role, "Role added");
@@ -887,229 +1787,1597 @@ async fn grant_roles(
 
     let mut content = MessageBuilder::new();
     content.user(&interaction.user);
-    if granted_roles.is_empty() {
-        content.push_line(" used the `/get in` but sadly, didn't get any roles yet 😢");
-    } else {
-        content.push(" used the `/get in` command and got the following roles: ");
-        for role in granted_roles.iter() {
+    push_grant_summary(
+        &mut content,
+        &granted_roles,
+        &failed_roles,
+        outcome,
+        &CONFIG.wait().discord.no_roles_message,
+    );
+    push_grant_messages(&mut content, &granted_roles, outcome);
+    if verbose {
+        let names = matched_wallet_names(&granted_roles, outcome)
+            .in_current_span()
+            .await;
+        push_matched_wallets(&mut content, &granted_roles, outcome, &names);
+    }
+    let (broken, unverified): (Vec<u64>, Vec<u64>) = outcome
+        .errored
+        .iter()
+        .copied()
+        .partition(|role| outcome.panicked.contains(role));
+    if !unverified.is_empty() {
+        content.push(
+            "We couldn't verify the conditions for the following roles right now, \
+            please try again later: ",
+        );
+        for role in unverified.iter() {
             content.role(*role);
         }
-        content.push_line("  🎉");
-    };
+        content.push_line("");
+    }
+    if !broken.is_empty() {
+        content.push("The gates of the following roles are broken, please tell an admin: ");
+        for role in broken.iter() {
+            content.role(*role);
+        }
+        content.push_line("");
+    }
     if !failed_roles.is_empty() {
         content.push("Got error while granting roles: ");
         for role in failed_roles.iter() {
             content.role(*role);
         }
         content.push_line("");
-        content.push("Maybe your admin should check the role hierarchy!  🤔");
+        content.push(role_failure_hint(!missing_permissions.is_empty()));
     }
     content.build();
-
-    let ephemeral = match (granted_roles.is_empty(), failed_roles.is_empty()) {
-        (false, false) => false,
-        (true, true) => true,
-        (true, false) => false,
-        (false, true) => false,
-    };
-    if ephemeral {
-        follow_up(ctx, interaction, &content, ephemeral)
-            .in_current_span()
-            .await
-    } else {
-        interaction
-            .channel_id
-            .say(&ctx.http, &content)
+    if let Some(guild_id) = interaction.guild_id {
+        alert_missing_permissions(&ctx.http, guild_id.into(), &missing_permissions)
             .in_current_span()
-            .await?;
-        Ok(())
+            .await;
+    }
+
+    let visibility = grant_visibility(
+        !granted_roles.is_empty(),
+        !failed_roles.is_empty(),
+        &CONFIG.wait().discord.grant_failure_visibility,
+    );
+    debug!(?visibility, "Showing the granted roles");
+    match visibility {
+        GrantVisibility::Public => {
+            interaction
+                .channel_id
+                .say(&ctx.http, &content)
+                .in_current_span()
+                .await?;
+            Ok(())
+        }
+        GrantVisibility::Ephemeral => {
+            follow_up(ctx, interaction, &content, true)
+                .in_current_span()
+                .await
+        }
+        GrantVisibility::EphemeralWithAdminPing => {
+            follow_up(ctx, interaction, &content, true)
+                .in_current_span()
+                .await?;
+            ping_admin(ctx, interaction, &failed_roles)
+                .in_current_span()
+                .await
+        }
     }
 }
 
-#[instrument(level = "info")]
-fn make_gate_command(command: &mut CreateApplicationCommand) -> &mut CreateApplicationCommand {
-    debug!("Creating gate slash command");
-    let options = gates!(options);
-    let descriptions = gates!(descriptions);
-    command
-        .name("gate")
-        .description("Create a new gate for a role on this server")
-        .create_option(|option| {
-            for (gate_name, gate_option) in options.into_iter() {
-                option.create_sub_option(|sub_option| {
-                    sub_option
-                        .name(gate_name)
-                        .kind(CommandOptionType::SubCommand)
-                        .description(descriptions.get(gate_name).expect(
-                            "Did not find description, in the gates! \
-                                    macro generated map. This should not happen",
-                        ));
-                    for o in gate_option.into_iter() {
-                        sub_option.create_sub_option(|sub_sub_option| {
-                            sub_sub_option
-                                .name(o.name)
-                                .description(o.description)
-                                .required(o.required);
-                            match o.option_type {
-                                GateOptionType::String {
-                                    min_length,
-                                    max_length,
-                                } => {
-                                    sub_sub_option.kind(CommandOptionType::String);
-                                    if let Some(min_length) = min_length {
-                                        sub_sub_option.min_length(min_length);
-                                    }
-                                    if let Some(max_length) = max_length {
-                                        sub_sub_option.max_length(max_length);
-                                    }
-                                }
-                                GateOptionType::I64 { min, max } => {
-                                    sub_sub_option.kind(CommandOptionType::Integer);
-                                    if let Some(min) = min {
-                                        sub_sub_option.min_int_value(min);
-                                    }
-                                    if let Some(max) = max {
-                                        sub_sub_option.max_int_value(max);
-                                    }
-                                }
-                                GateOptionType::F64 { min, max } => {
-                                    sub_sub_option.kind(CommandOptionType::Number);
-                                    if let Some(min) = min {
-                                        sub_sub_option.min_number_value(min);
-                                    }
-                                    if let Some(max) = max {
-                                        sub_sub_option.max_number_value(max);
-                                    }
-                                }
-                            };
-                            sub_sub_option
-                        });
-                    }
-                    sub_option.create_sub_option(|sub_option| {
-                        sub_option
-                            .name("role")
-                            .description("The role to be gated")
-                            .kind(CommandOptionType::Role)
-                            .required(true)
-                    });
-                    sub_option
-                });
-            }
-            option
-                .name("add")
-                .description("Add a new gate to protect a role on the server")
-                .kind(CommandOptionType::SubCommandGroup)
-        })
-        .create_option(|option| {
-            option
-                .name("list")
-                .description("Lists gates that are currently active for this server.")
-                .kind(CommandOptionType::SubCommand)
-        })
-        .create_option(|option| {
-            option
-                .name("enforce")
-                .description("Enforce the active gates on all members of the server")
-                .kind(CommandOptionType::SubCommand)
-        })
-        .default_member_permissions(Permissions::MANAGE_GUILD)
+/// Who sees the result of `/get in`
+#[derive(Debug, PartialEq, Eq)]
+enum GrantVisibility {
+    Public,
+    Ephemeral,
+    EphemeralWithAdminPing,
 }
 
-#[instrument(level = "info")]
-fn make_get_command(command: &mut CreateApplicationCommand) -> &mut CreateApplicationCommand {
-    debug!("Creating get slash command");
-    command
-        .name("get")
-        .description("Get in or out of gated roles")
-        .create_option(|option| {
-            option
-                .name("in")
-                .description("Get roles granted that are gated by the gating bot")
-                .kind(CommandOptionType::SubCommand)
-        })
-        .create_option(|option| {
-            option
-                .name("out")
-                .description("Deregister your discord user and wallet address from the gating bot")
-                .kind(CommandOptionType::SubCommand)
-        })
+/// Granted roles are announced in the channel, a user who got nothing is
+/// only told privately. Failed roles are shown as configured, since a
+/// public message alerts admins about the hierarchy, but also shows that
+/// the user got nothing
+fn grant_visibility(granted: bool, failed: bool, policy: &FailureVisibility) -> GrantVisibility {
+    match (failed, policy) {
+        (true, FailureVisibility::Public) => GrantVisibility::Public,
+        (true, FailureVisibility::AdminPing) => GrantVisibility::EphemeralWithAdminPing,
+        (true, FailureVisibility::Ephemeral) => GrantVisibility::Ephemeral,
+        (false, _) if granted => GrantVisibility::Public,
+        (false, _) => GrantVisibility::Ephemeral,
+    }
 }
 
-#[instrument(level = "info", skip(ctx, interaction))]
-async fn respond(
+/// Tells the owner of the server in the channel which roles could not be
+/// granted, without naming the user who tried to get them
+async fn ping_admin(
     ctx: &Context,
     interaction: &ApplicationCommandInteraction,
-    message: impl ToString + std::fmt::Debug,
-    ephemeral: bool,
+    failed_roles: &[u64],
 ) -> Result<()> {
-    debug!("Responding to interaction");
-    Ok(interaction
-        .create_interaction_response(&ctx.http, |response| {
-            response
-                .kind(InteractionResponseType::ChannelMessageWithSource)
-                .interaction_response_data(|m| m.content(message).ephemeral(ephemeral))
-        })
+    let guild = interaction
+        .guild_id
+        .ok_or(anyhow!("Error getting guild id from command"))?
+        .to_partial_guild(&ctx.http)
         .in_current_span()
-        .await?)
+        .await?;
+    let mut content = MessageBuilder::new();
+    content
+        .user(guild.owner_id)
+        .push(" I could not grant the following roles: ");
+    for role in failed_roles.iter() {
+        content.role(*role);
+    }
+    content.push_line("");
+    content.push("Please check the role hierarchy!  🤔");
+    interaction
+        .channel_id
+        .say(&ctx.http, content.build())
+        .in_current_span()
+        .await?;
+    Ok(())
 }
 
-#[instrument(level = "info", skip(ctx, interaction))]
-async fn follow_up(
-    ctx: &Context,
-    interaction: &ApplicationCommandInteraction,
-    message: impl ToString + std::fmt::Debug,
+/// Adds the masked wallet that met the gate of each granted role, for users
+/// with several linked wallets
+fn push_matched_wallets(
+    content: &mut MessageBuilder,
+    granted_roles: &[u64],
+    outcome: &CheckOutcome,
+    names: &HashMap<H160, String>,
+) {
+    for role in granted_roles.iter() {
+        if let Some(wallet) = outcome.wallets.get(role) {
+            content.role(*role);
+            content.push(" was granted for the wallet ");
+            match names.get(wallet) {
+                Some(name) => {
+                    content.push_mono(mask_wallet(wallet));
+                    content.push(" (").push_safe(name.as_str()).push_line(")");
+                }
+                None => {
+                    content.push_mono_line(mask_wallet(wallet));
+                }
+            }
+        }
+    }
+}
+
+/// The names the wallets that met the gates of the granted roles resolve
+/// to, wallets without a name are left out
+async fn matched_wallet_names(
+    granted_roles: &[u64],
+    outcome: &CheckOutcome,
+) -> HashMap<H160, String> {
+    let wallets = granted_roles
+        .iter()
+        .filter_map(|role| outcome.wallets.get(role))
+        .collect::<HashSet<_>>();
+    let mut names = HashMap::new();
+    for wallet in wallets {
+        if let Some(name) = wallet_name(wallet).in_current_span().await {
+            names.insert(*wallet, name);
+        }
+    }
+    names
+}
+
+/// Shortens a wallet address to its first and last four hex digits, e.g.
+/// `0x1234…abcd`, since the message may be visible to the whole channel
+fn mask_wallet(wallet: &H160) -> String {
+    let address = format!("{:x}", wallet);
+    format!("0x{}…{}", &address[..4], &address[address.len() - 4..])
+}
+
+/// Adds what `/get in` granted after the mention of the user. Without any
+/// granted role and any failure the configured no roles message is shown
+fn push_grant_summary(
+    content: &mut MessageBuilder,
+    granted_roles: &[u64],
+    failed_roles: &[u64],
+    outcome: &CheckOutcome,
+    no_roles_message: &str,
+) {
+    if granted_roles.is_empty() && failed_roles.is_empty() && outcome.errored.is_empty() {
+        content.push(" ").push_line(no_roles_message.trim());
+    } else if granted_roles.is_empty() {
+        content.push_line(" used the `/get in` command");
+    } else {
+        content.push(" used the `/get in` command and got the following roles: ");
+        for role in granted_roles.iter() {
+            content.role(*role);
+        }
+        content.push_line("  🎉");
+    }
+}
+
+/// Adds the custom messages of the gates for the roles that were granted
+fn push_grant_messages(
+    content: &mut MessageBuilder,
+    granted_roles: &[u64],
+    outcome: &CheckOutcome,
+) {
+    for role in granted_roles.iter() {
+        if let Some(message) = outcome.grant_messages.get(role) {
+            content.role(*role);
+            content.push(": ");
+            content.push_line_safe(message);
+        }
+    }
+}
+
+/// The translation of the name and description of a command or option for
+/// a locale, either can be left out to keep the English one
+#[derive(Debug, Clone, Default, Deserialize)]
+struct Localization {
+    name: Option<String>,
+    description: Option<String>,
+}
+
+/// The translations of the commands by discord locale, e.g. "de", and path
+/// of the command or option, e.g. "gate enforce force"
+type CommandLocalizations = HashMap<String, HashMap<String, Localization>>;
+
+/// The configured translations are read once and then kept for the runtime
+static COMMAND_LOCALIZATIONS: OnceCell<CommandLocalizations> = OnceCell::new();
+
+/// The configured translations of the commands, none if they are not
+/// configured or can't be read, so the commands are still registered
+fn command_localizations() -> &'static CommandLocalizations {
+    COMMAND_LOCALIZATIONS.get_or_init(|| match &CONFIG.wait().discord.command_localizations {
+        Some(path) => read_command_localizations(path).unwrap_or_else(|why| {
+            error!(?path, "Failed to read the command localizations: {:?}", why);
+            CommandLocalizations::new()
+        }),
+        None => CommandLocalizations::new(),
+    })
+}
+
+fn read_command_localizations(path: &std::path::Path) -> Result<CommandLocalizations> {
+    let file = std::fs::File::open(path)?;
+    Ok(serde_json::from_reader(std::io::BufReader::new(file))?)
+}
+
+/// The translations of the command or option at the path by locale
+fn localizations_of<'a>(
+    localizations: &'a CommandLocalizations,
+    path: &'a str,
+) -> impl Iterator<Item = (&'a String, &'a Localization)> {
+    localizations
+        .iter()
+        .filter_map(move |(locale, paths)| Some((locale, paths.get(path)?)))
+}
+
+fn localize_command<'a>(
+    command: &'a mut CreateApplicationCommand,
+    localizations: &CommandLocalizations,
+    path: &str,
+) -> &'a mut CreateApplicationCommand {
+    for (locale, localization) in localizations_of(localizations, path) {
+        if let Some(name) = &localization.name {
+            command.name_localized(locale, name);
+        }
+        if let Some(description) = &localization.description {
+            command.description_localized(locale, description);
+        }
+    }
+    command
+}
+
+fn localize_option<'a>(
+    option: &'a mut CreateApplicationCommandOption,
+    localizations: &CommandLocalizations,
+    path: &str,
+) -> &'a mut CreateApplicationCommandOption {
+    for (locale, localization) in localizations_of(localizations, path) {
+        if let Some(name) = &localization.name {
+            option.name_localized(locale, name);
+        }
+        if let Some(description) = &localization.description {
+            option.description_localized(locale, description);
+        }
+    }
+    option
+}
+
+#[instrument(level = "info")]
+fn make_gate_command(command: &mut CreateApplicationCommand) -> &mut CreateApplicationCommand {
+    // the permission was validated when the config was set up
+    let permission = match parse_permission(&CONFIG.wait().discord.gate_permission) {
+        Ok(permission) => permission,
+        Err(why) => {
+            error!("{}, using MANAGE_GUILD", why);
+            Some(Permissions::MANAGE_GUILD)
+        }
+    };
+    build_gate_command(command, permission, command_localizations())
+}
+
+#[instrument(level = "info")]
+fn build_gate_command<'a>(
+    command: &'a mut CreateApplicationCommand,
+    permission: Option<Permissions>,
+    localizations: &CommandLocalizations,
+) -> &'a mut CreateApplicationCommand {
+    debug!("Creating gate slash command");
+    localize_command(command, localizations, "gate")
+        .name("gate")
+        .description("Create a new gate for a role on this server")
+        .create_option(|option| {
+            create_gate_kind_options(option, localizations, "add");
+            localize_option(option, localizations, "gate add")
+                .name("add")
+                .description("Add a new gate to protect a role on the server")
+                .kind(CommandOptionType::SubCommandGroup)
+        })
+        .create_option(|option| {
+            create_gate_kind_options(option, localizations, "preview");
+            localize_option(option, localizations, "gate preview")
+                .name("preview")
+                .description("Check if a member would pass a new gate, without adding it")
+                .kind(CommandOptionType::SubCommandGroup)
+        })
+        .create_option(|option| {
+            localize_option(option, localizations, "gate list")
+                .name("list")
+                .description("Lists gates that are currently active for this server.")
+                .kind(CommandOptionType::SubCommand)
+        })
+        .create_option(|option| {
+            localize_option(option, localizations, "gate enforce")
+                .name("enforce")
+                .description("Enforce the active gates on all members of the server")
+                .kind(CommandOptionType::SubCommand)
+                .create_sub_option(|sub_option| {
+                    localize_option(sub_option, localizations, "gate enforce force")
+                        .name("force")
+                        .description("Also check members that were checked recently")
+                        .kind(CommandOptionType::Boolean)
+                        .required(false)
+                })
+                .create_sub_option(|sub_option| {
+                    localize_option(sub_option, localizations, "gate enforce role")
+                        .name("role")
+                        .description("Only enforce the gates of this role")
+                        .kind(CommandOptionType::Role)
+                        .required(false)
+                })
+                .create_sub_option(|sub_option| {
+                    localize_option(
+                        sub_option,
+                        localizations,
+                        "gate enforce report_unregistered",
+                    )
+                    .name("report_unregistered")
+                    .description("Report members holding gated roles without a registered wallet")
+                    .kind(CommandOptionType::Boolean)
+                    .required(false)
+                })
+        })
+        .create_option(|option| {
+            localize_option(option, localizations, "gate toggle")
+                .name("toggle")
+                .description("Enable or disable a gate without deleting it")
+                .kind(CommandOptionType::SubCommand)
+                .create_sub_option(|sub_option| {
+                    localize_option(sub_option, localizations, "gate toggle identifier")
+                        .name("identifier")
+                        .description("The identifier of the gate as shown by /gate list")
+                        .kind(CommandOptionType::String)
+                        .required(true)
+                })
+        })
+        .create_option(|option| {
+            localize_option(option, localizations, "gate recheck")
+                .name("recheck")
+                .description("Check the gates for a single member and update their roles")
+                .kind(CommandOptionType::SubCommand)
+                .create_sub_option(|sub_option| {
+                    localize_option(sub_option, localizations, "gate recheck user")
+                        .name("user")
+                        .description("The member whose roles should be checked")
+                        .kind(CommandOptionType::User)
+                        .required(true)
+                })
+        })
+        .create_option(|option| {
+            localize_option(option, localizations, "gate setup")
+                .name("setup")
+                .description("Check the setup of the bot on this server")
+                .kind(CommandOptionType::SubCommand)
+        })
+        .create_option(|option| {
+            localize_option(option, localizations, "gate stats")
+                .name("stats")
+                .description("Show how many users registered and unregistered so far")
+                .kind(CommandOptionType::SubCommand)
+        })
+        .create_option(|option| {
+            localize_option(option, localizations, "gate freeze")
+                .name("freeze")
+                .description("Stop granting and revoking all gated roles in this server")
+                .kind(CommandOptionType::SubCommand)
+        })
+        .create_option(|option| {
+            localize_option(option, localizations, "gate unfreeze")
+                .name("unfreeze")
+                .description("Grant and revoke the gated roles in this server again")
+                .kind(CommandOptionType::SubCommand)
+        })
+        .create_option(|option| {
+            localize_option(option, localizations, "gate autogrant")
+                .name("autogrant")
+                .description("Grant registered members their roles as soon as they join")
+                .kind(CommandOptionType::SubCommand)
+                .create_sub_option(|sub_option| {
+                    localize_option(sub_option, localizations, "gate autogrant enabled")
+                        .name("enabled")
+                        .description("Whether joining members get their roles right away")
+                        .kind(CommandOptionType::Boolean)
+                        .required(true)
+                })
+        })
+        .create_option(|option| {
+            localize_option(option, localizations, "gate coverage")
+                .name("coverage")
+                .description("Count the registered members that qualify for a gate")
+                .kind(CommandOptionType::SubCommand)
+                .create_sub_option(|sub_option| {
+                    localize_option(sub_option, localizations, "gate coverage identifier")
+                        .name("identifier")
+                        .description("The identifier of the gate as shown by /gate list")
+                        .kind(CommandOptionType::String)
+                        .required(true)
+                })
+        });
+    if let Some(permission) = permission {
+        command.default_member_permissions(permission);
+    }
+    command
+}
+
+/// Creates a subcommand with the options of each kind of gate in the `add`
+/// or `preview` subcommand group of the `/gate` command
+fn create_gate_kind_options<'a>(
+    option: &'a mut CreateApplicationCommandOption,
+    localizations: &CommandLocalizations,
+    group: &str,
+) -> &'a mut CreateApplicationCommandOption {
+    let options = gates!(options);
+    let descriptions = gates!(descriptions);
+    let preview = group == "preview";
+    for (gate_name, gate_option) in options.into_iter() {
+        let gate_path = format!("gate {} {}", group, gate_name);
+        option.create_sub_option(|sub_option| {
+            localize_option(sub_option, localizations, &gate_path)
+                .name(gate_name)
+                .kind(CommandOptionType::SubCommand)
+                .description(descriptions.get(gate_name).expect(
+                    "Did not find description, in the gates! \
+                            macro generated map. This should not happen",
+                ));
+            // discord rejects required options after optional ones
+            let (required, optional): (Vec<_>, Vec<_>) =
+                gate_option.into_iter().partition(|o| o.required);
+            for o in required {
+                let path = format!("{} {}", gate_path, o.name);
+                sub_option.create_sub_option(|sub_sub_option| {
+                    make_gate_option(localize_option(sub_sub_option, localizations, &path), o)
+                });
+            }
+            sub_option.create_sub_option(|sub_option| {
+                localize_option(sub_option, localizations, &format!("{} role", gate_path))
+                    .name("role")
+                    .description("The role to be gated")
+                    .kind(CommandOptionType::Role)
+                    .required(true)
+            });
+            if preview {
+                sub_option.create_sub_option(|sub_option| {
+                    localize_option(sub_option, localizations, &format!("{} user", gate_path))
+                        .name("user")
+                        .description("The member to check the gate for")
+                        .kind(CommandOptionType::User)
+                        .required(true)
+                });
+            }
+            for o in optional {
+                let path = format!("{} {}", gate_path, o.name);
+                sub_option.create_sub_option(|sub_sub_option| {
+                    make_gate_option(localize_option(sub_sub_option, localizations, &path), o)
+                });
+            }
+            // a preview is not stored, so it has no grant message
+            if !preview {
+                sub_option.create_sub_option(|sub_option| {
+                    localize_option(
+                        sub_option,
+                        localizations,
+                        &format!("{} grant_message", gate_path),
+                    )
+                    .name("grant_message")
+                    .description("A message shown to members when they get the role")
+                    .kind(CommandOptionType::String)
+                    .max_length(GRANT_MESSAGE_MAX_LENGTH)
+                    .required(false)
+                });
+            }
+            sub_option
+        });
+    }
+    option
+}
+
+/// The longest custom grant message, so that the grant response stays below
+/// discord's message length limit
+const GRANT_MESSAGE_MAX_LENGTH: u16 = 300;
+
+fn make_gate_option(
+    option: &mut CreateApplicationCommandOption,
+    gate_option: GateOption,
+) -> &mut CreateApplicationCommandOption {
+    option
+        .name(gate_option.name)
+        .description(gate_option.description)
+        .required(gate_option.required);
+    apply_option_type(option, gate_option.option_type)
+}
+
+/// Sets the discord option kind and bounds for a gate option type
+fn apply_option_type(
+    option: &mut CreateApplicationCommandOption,
+    option_type: GateOptionType,
+) -> &mut CreateApplicationCommandOption {
+    match option_type {
+        GateOptionType::String {
+            min_length,
+            max_length,
+        } => {
+            option.kind(CommandOptionType::String);
+            if let Some(min_length) = min_length {
+                option.min_length(min_length);
+            }
+            if let Some(max_length) = max_length {
+                option.max_length(max_length);
+            }
+        }
+        GateOptionType::I64 { min, max } => {
+            option.kind(CommandOptionType::Integer);
+            if let Some(min) = min {
+                option.min_int_value(min);
+            }
+            if let Some(max) = max {
+                option.max_int_value(max);
+            }
+        }
+        GateOptionType::F64 { min, max } => {
+            option.kind(CommandOptionType::Number);
+            if let Some(min) = min {
+                option.min_number_value(min);
+            }
+            if let Some(max) = max {
+                option.max_number_value(max);
+            }
+        }
+    };
+    option
+}
+
+#[instrument(level = "info")]
+fn make_get_command(command: &mut CreateApplicationCommand) -> &mut CreateApplicationCommand {
+    build_get_command(command, command_localizations())
+}
+
+fn build_get_command<'a>(
+    command: &'a mut CreateApplicationCommand,
+    localizations: &CommandLocalizations,
+) -> &'a mut CreateApplicationCommand {
+    debug!("Creating get slash command");
+    localize_command(command, localizations, "get")
+        .name("get")
+        .description("Get in or out of gated roles")
+        .create_option(|option| {
+            localize_option(option, localizations, "get in")
+                .name("in")
+                .description("Get roles granted that are gated by the gating bot")
+                .kind(CommandOptionType::SubCommand)
+                .create_sub_option(|option| {
+                    localize_option(option, localizations, "get in verbose")
+                        .name("verbose")
+                        .description("Also show which of your wallets met the gate of each role")
+                        .kind(CommandOptionType::Boolean)
+                        .required(false)
+                })
+        })
+        .create_option(|option| {
+            localize_option(option, localizations, "get out")
+                .name("out")
+                .description("Deregister your discord user and wallet address from the gating bot")
+                .kind(CommandOptionType::SubCommand)
+        })
+        .create_option(|option| {
+            localize_option(option, localizations, "get link")
+                .name("link")
+                .description("Get a new registration link, e.g. to link another wallet")
+                .kind(CommandOptionType::SubCommand)
+        })
+}
+
+/// The name of the context menu command, that works like `/get in`
+const CHECK_ROLES_COMMAND: &str = "Check my roles";
+
+#[instrument(level = "info")]
+fn make_check_roles_command(
+    command: &mut CreateApplicationCommand,
+) -> &mut CreateApplicationCommand {
+    debug!("Creating check roles context menu command");
+    // context menu commands have no description
+    command.name(CHECK_ROLES_COMMAND).kind(CommandType::User)
+}
+
+#[instrument(level = "info", skip(ctx, interaction))]
+async fn respond(
+    ctx: &Context,
+    interaction: &ApplicationCommandInteraction,
+    message: impl ToString + std::fmt::Debug,
     ephemeral: bool,
 ) -> Result<()> {
-    debug!("Following up with interaction");
+    debug!("Responding to interaction");
     Ok(interaction
-        .create_followup_message(&ctx.http, |m| m.content(message).ephemeral(ephemeral))
+        .create_interaction_response(&ctx.http, |response| {
+            response
+                .kind(InteractionResponseType::ChannelMessageWithSource)
+                .interaction_response_data(|m| m.content(message).ephemeral(ephemeral))
+        })
+        .in_current_span()
+        .await?)
+}
+
+#[instrument(level = "info", skip(ctx, interaction))]
+async fn follow_up(
+    ctx: &Context,
+    interaction: &ApplicationCommandInteraction,
+    message: impl ToString + std::fmt::Debug,
+    ephemeral: bool,
+) -> Result<()> {
+    debug!("Following up with interaction");
+    let http = ctx.http.clone();
+    let interaction = interaction.clone();
+    let message = message.to_string();
+    let guild_id = interaction.guild_id.map_or(0, |id| id.0);
+    let send = async move {
+        interaction
+            .create_followup_message(&http, |m| m.content(message).ephemeral(ephemeral))
+            .await
+            .map(|_| ())
+    };
+    Ok(message_queue()
+        .send(guild_id, send.in_current_span())
+        .await??)
+}
+
+/// The properties of a role that decide whether the bot can assign it
+#[derive(Debug, Clone, Copy)]
+struct GateableRole {
+    /// Managed roles belong to bots, integrations or server boosts and can't
+    /// be assigned by anyone
+    managed: bool,
+    position: i64,
+}
+
+/// Replaces the role names in the tiers option, e.g. `500:@Silver`, with the
+/// mentions of the roles of that name, since the gate only takes role ids.
+/// The roles are only fetched if there is a tiers option
+async fn resolve_tier_role_names(
+    ctx: &Context,
+    guild_id: u64,
+    mut options: Vec<GateOptionValue>,
+) -> Result<Vec<GateOptionValue>> {
+    if !options.iter().any(|option| option.name == "tiers") {
+        return Ok(options);
+    }
+    let roles = ctx
+        .http
+        .get_guild_roles(guild_id)
+        .in_current_span()
+        .await?
+        .into_iter()
+        .map(|role| (role.name, u64::from(role.id)))
+        .collect::<HashMap<_, _>>();
+    for option in options.iter_mut().filter(|option| option.name == "tiers") {
+        if let GateOptionValueType::String(tiers) = &mut option.value {
+            *tiers = replace_tier_role_names(tiers, &roles)?;
+        }
+    }
+    Ok(options)
+}
+
+/// Replaces each `@name` role of the `amount:role` tiers with the mention of
+/// the role of that name. Role ids and mentions are kept as they are
+fn replace_tier_role_names(tiers: &str, roles: &HashMap<String, u64>) -> Result<String> {
+    let tiers = tiers
+        .split(',')
+        .map(|tier| {
+            let (amount, role) = match tier.split_once(':') {
+                Some(tier) => tier,
+                // malformed tiers are reported by the gate
+                None => return Ok(tier.to_string()),
+            };
+            match role.trim().strip_prefix('@') {
+                Some(name) if name.parse::<u64>().is_err() => match roles.get(name) {
+                    Some(role_id) => Ok(format!("{}:<@&{}>", amount.trim(), role_id)),
+                    None => bail!("There is no role @{} on this server", name),
+                },
+                _ => Ok(tier.to_string()),
+            }
+        })
+        .collect::<Result<Vec<_>>>()?;
+    Ok(tiers.join(","))
+}
+
+/// Fetches the roles of a guild after they changed and passes them on to the
+/// controller
+async fn update_guild_roles(ctx: &Context, guild_id: u64) {
+    match ctx.http.get_guild_roles(guild_id).in_current_span().await {
+        Ok(roles) => {
+            send_guild_roles(guild_id, roles.iter())
+                .in_current_span()
+                .await
+        }
+        Err(why) => error!("Failed to get guild roles: {:?}", why),
+    }
+}
+
+/// Sends the role ids of a guild by name to the controller, which uses them
+/// to resolve the global gates. The @everyone role is left out
+async fn send_guild_roles<'a>(guild_id: u64, roles: impl Iterator<Item = &'a Role>) {
+    let roles = roles
+        .filter(|role| *role.id.as_u64() != guild_id)
+        .map(|role| (role.name.clone(), *role.id.as_u64()))
+        .collect::<HashMap<_, _>>();
+    let message = controller::Message::GuildRoles {
+        guild_id,
+        roles,
+        span: Span::current(),
+    };
+    if let Err(why) = CONTROLLER_CHANNEL
+        .wait()
+        .send(message)
+        .in_current_span()
+        .await
+    {
+        error!("Error sending guild roles message: {:?}", why);
+    }
+}
+
+/// Looks up the roles of a guild by id and the position of the highest role
+/// of the bot on the server
+#[instrument(level = "info", skip(ctx))]
+async fn guild_role_hierarchy(
+    ctx: &Context,
+    guild_id: u64,
+    bot_user_id: u64,
+) -> Result<(HashMap<u64, GateableRole>, Option<i64>)> {
+    let bot_member = ctx
+        .http
+        .get_member(guild_id, bot_user_id)
         .in_current_span()
+        .await?;
+    let guild_roles = ctx.http.get_guild_roles(guild_id).in_current_span().await?;
+    let bot_position = guild_roles
+        .iter()
+        .filter(|r| bot_member.roles.iter().any(|&br| br == r.id))
+        .map(|r| r.position)
+        .max();
+    let roles = guild_roles
+        .iter()
+        .map(|r| {
+            let role = GateableRole {
+                managed: r.managed,
+                position: r.position,
+            };
+            (u64::from(r.id), role)
+        })
+        .collect();
+    Ok((roles, bot_position))
+}
+
+/// Rejects roles the bot could never assign, so that a gate for them does
+/// not silently fail on every check
+fn check_gateable_role(
+    role_id: u64,
+    guild_id: u64,
+    role: Option<GateableRole>,
+    bot_position: Option<i64>,
+) -> Result<()> {
+    if role_id == guild_id {
+        bail!("Role cannot be @everyone");
+    }
+    let role = role.ok_or(anyhow!("Role not found on the server"))?;
+    if role.managed {
+        bail!(
+            "The role is managed by discord or an integration, e.g. a bot or \
+            the server booster role, and can't be assigned to users"
+        );
+    }
+    match bot_position {
+        Some(bot_position) if role.position < bot_position => Ok(()),
+        Some(_) => bail!(
+            "The bot is below this role in the role hierarchy, so it will not \
+            be able to assign it to users. Drag the bot role above the gated \
+            role under `Server Settings -> Roles` and try again"
+        ),
+        None => bail!("The bot has no role on this server, so it can't assign any roles"),
+    }
+}
+
+/// The result of one item of the setup checklist
+#[derive(Debug)]
+struct SetupStatus {
+    ok: bool,
+    detail: String,
+}
+
+impl SetupStatus {
+    fn ok(detail: impl ToString) -> Self {
+        Self {
+            ok: true,
+            detail: detail.to_string(),
+        }
+    }
+
+    fn problem(detail: impl ToString) -> Self {
+        Self {
+            ok: false,
+            detail: detail.to_string(),
+        }
+    }
+
+    /// The name of the checklist item, marked with its status
+    fn title(&self, name: &str) -> String {
+        format!("{} {}", if self.ok { "✅" } else { "⚠️" }, name)
+    }
+}
+
+/// Checks whether the bot can assign all gated roles, listing the roles it
+/// can't assign with the reason
+fn hierarchy_status(
+    guild_id: u64,
+    gated_roles: &[u64],
+    roles: &HashMap<u64, GateableRole>,
+    bot_position: Option<i64>,
+) -> SetupStatus {
+    if bot_position.is_none() {
+        return SetupStatus::problem(
+            "The bot has no role on this server, so it can't assign any roles. \
+            Invite it with the MANAGE_ROLES permission",
+        );
+    }
+    let problems = gated_roles
+        .iter()
+        .filter_map(|&role_id| {
+            check_gateable_role(
+                role_id,
+                guild_id,
+                roles.get(&role_id).copied(),
+                bot_position,
+            )
+            .err()
+            .map(|why| format!("<@&{}>: {}", role_id, why))
+        })
+        .collect::<Vec<_>>();
+    if problems.is_empty() {
+        SetupStatus::ok("The bot can assign all gated roles")
+    } else {
+        SetupStatus::problem(embed_field_lines(&problems))
+    }
+}
+
+/// Discord rejects embed field values longer than this many characters
+const EMBED_FIELD_LIMIT: usize = 1024;
+
+/// Joins the lines for an embed field value, the lines that don't fit into
+/// the field are replaced by a count of them
+fn embed_field_lines(lines: &[String]) -> String {
+    let joined = lines.join("\n");
+    if joined.chars().count() <= EMBED_FIELD_LIMIT {
+        return joined;
+    }
+    let mut value = String::new();
+    for (shown, line) in lines.iter().enumerate() {
+        let more = format!("… and {} more", lines.len() - shown);
+        if value.chars().count() + line.chars().count() + 1 + more.chars().count()
+            > EMBED_FIELD_LIMIT
+        {
+            value.push_str(&more);
+            break;
+        }
+        value.push_str(line);
+        value.push('\n');
+    }
+    value
+}
+
+/// The invite url is looked up once and then kept for the runtime
+static INVITE_URL: OnceCell<String> = OnceCell::new();
+
+/// The url to invite the bot to a server, if none is configured it is
+/// generated from the application id of the bot
+#[instrument(level = "debug")]
+pub async fn invite_url() -> Result<String> {
+    if let Some(url) = INVITE_URL.get() {
+        return Ok(url.clone());
+    }
+    let configured = CONFIG.wait().discord.invite_url.trim();
+    let url = if configured.is_empty() {
+        let token = &CONFIG.wait().discord.token.expose_secret();
+        let http = Http::new(token);
+        let application = http
+            .get_current_application_info()
+            .in_current_span()
+            .await?;
+        generate_invite_url(application.id.into())
+    } else {
+        configured.to_string()
+    };
+    Ok(INVITE_URL.get_or_init(|| url).clone())
+}
+
+/// The invite url requests the permissions all gate types need together
+fn generate_invite_url(application_id: u64) -> String {
+    format!(
+        "https://discord.com/api/oauth2/authorize?client_id={}&permissions={}&scope=bot",
+        application_id,
+        gates!(permissions).bits()
+    )
+}
+
+/// Used for embeds if the configured color can not be parsed
+const DEFAULT_EMBED_COLOR: u32 = 0x289BDC;
+
+/// The parsed embed color, so an invalid color is only warned about once
+static EMBED_COLOR: OnceCell<u32> = OnceCell::new();
+
+/// The configured color for all embeds sent by the bot
+fn embed_color() -> u32 {
+    *EMBED_COLOR.get_or_init(|| {
+        let color = &CONFIG.wait().discord.embed_color;
+        parse_color(color).unwrap_or_else(|| {
+            warn!("Invalid embed color {}, using default", color);
+            DEFAULT_EMBED_COLOR
+        })
+    })
+}
+
+/// Parses a hex color string with or without a leading `#`
+fn parse_color(color: &str) -> Option<u32> {
+    let hex = color.trim().trim_start_matches('#');
+    if hex.len() != 6 || !hex.chars().all(|c| c.is_ascii_hexdigit()) {
+        return None;
+    }
+    u32::from_str_radix(hex, 16).ok()
+}
+
+/// The maximum number of members discord returns in one page
+const MEMBER_PAGE_LIMIT: u64 = 1000;
+/// The wait before the first retry of a failed page of members, every
+/// further retry waits twice as long
+const MEMBER_FETCH_BACKOFF: Duration = Duration::from_secs(1);
+
+/// A member of a guild with the ids of its roles
+#[derive(Debug, Clone, PartialEq, Eq)]
+struct GuildMember {
+    user_id: u64,
+    roles: Vec<u64>,
+    /// Whether the member is a bot account
+    bot: bool,
+}
+
+/// Removes roles from members. The http client of discord implements this,
+/// tests use a mock
+#[async_trait]
+trait RoleRemover {
+    async fn remove_role(&self, guild_id: u64, user_id: u64, role_id: u64) -> Result<()>;
+}
+
+#[async_trait]
+impl RoleRemover for Http {
+    async fn remove_role(&self, guild_id: u64, user_id: u64, role_id: u64) -> Result<()> {
+        Ok(self
+            .remove_member_role(guild_id, user_id, role_id, None)
+            .in_current_span()
+            .await?)
+    }
+}
+
+/// Takes the gated roles from a user that unregistered and tells them what
+/// happened. Without `revoke` only the wallet was unlinked and the roles are
+/// kept, the enforcement does not check unregistered members and only reports
+/// them with `report_unregistered`
+async fn revoke_unregistered_roles(
+    remover: &(dyn RoleRemover + Send + Sync),
+    guild_id: u64,
+    user_id: u64,
+    roles: &[u64],
+    revoke: bool,
+) -> String {
+    if !revoke {
+        return "Your wallet has been unlinked, you keep your roles until an admin of the \
+            server removes them"
+            .to_string();
+    }
+    let mut message = MessageBuilder::new();
+    message.push("You have been removed from the following roles: ");
+    for role in roles {
+        if let Err(why) = remover
+            .remove_role(guild_id, user_id, *role)
+            .in_current_span()
+            .await
+        {
+            info!("Could not remove role: {:?}", why);
+        }
+        message.role(*role);
+    }
+    message.build()
+}
+
+/// Fetches the members of a guild one page at a time. The http client of
+/// discord implements this, tests use a mock
+#[async_trait]
+trait MemberSource {
+    /// At most `limit` members with a higher user id than `after`, ordered
+    /// by user id
+    async fn member_page(
+        &self,
+        guild_id: u64,
+        limit: u64,
+        after: Option<u64>,
+    ) -> Result<Vec<GuildMember>>;
+}
+
+#[async_trait]
+impl MemberSource for Http {
+    async fn member_page(
+        &self,
+        guild_id: u64,
+        limit: u64,
+        after: Option<u64>,
+    ) -> Result<Vec<GuildMember>> {
+        let members = self
+            .get_guild_members(guild_id, Some(limit), after)
+            .in_current_span()
+            .await?;
+        Ok(members
+            .into_iter()
+            .map(|m| GuildMember {
+                user_id: *m.user.id.as_u64(),
+                roles: m.roles.iter().map(|&r| u64::from(r)).collect(),
+                bot: m.user.bot,
+            })
+            .collect())
+    }
+}
+
+/// Walks all members of a guild page by page, each page continues after the
+/// highest user id of the previous one. Every page is retried on its own, so
+/// a transient failure does not restart the walk
+struct MemberPages<'a, S: ?Sized> {
+    source: &'a S,
+    guild_id: u64,
+    limit: u64,
+    after: Option<u64>,
+    done: bool,
+    /// How often a failed or timed out page is fetched again
+    retries: u32,
+    /// How long one attempt to fetch a page may take
+    timeout: Duration,
+    backoff: Duration,
+}
+
+impl<'a> MemberPages<'a, Http> {
+    /// The members of a guild fetched from discord with the configured
+    /// retries and timeout
+    fn of_guild(http: &'a Http, guild_id: u64) -> Self {
+        let cfg = &CONFIG.wait().discord;
+        MemberPages::new(
+            http,
+            guild_id,
+            cfg.member_fetch_retries,
+            Duration::from_secs(cfg.member_fetch_timeout),
+        )
+    }
+}
+
+impl<'a, S: MemberSource + ?Sized> MemberPages<'a, S> {
+    fn new(source: &'a S, guild_id: u64, retries: u32, timeout: Duration) -> Self {
+        Self {
+            source,
+            guild_id,
+            limit: MEMBER_PAGE_LIMIT,
+            after: None,
+            done: false,
+            retries,
+            timeout,
+            backoff: MEMBER_FETCH_BACKOFF,
+        }
+    }
+
+    /// The next page of members, `None` once all members were fetched
+    async fn next_page(&mut self) -> Result<Option<Vec<GuildMember>>> {
+        if self.done {
+            return Ok(None);
+        }
+        let page = self.fetch_page().await?;
+        debug!(after = ?self.after, members = page.len(), "Fetched page of members");
+        // a page that is not full is the last one
+        self.done = (page.len() as u64) < self.limit;
+        match page.iter().map(|member| member.user_id).max() {
+            Some(last) => self.after = Some(last),
+            None => return Ok(None),
+        }
+        Ok(Some(page))
+    }
+
+    /// Fetches the page after the current one, retrying failed and timed out
+    /// attempts with a doubling backoff
+    async fn fetch_page(&self) -> Result<Vec<GuildMember>> {
+        let mut attempt = 0;
+        loop {
+            let page = self
+                .source
+                .member_page(self.guild_id, self.limit, self.after);
+            let why = match tokio::time::timeout(self.timeout, page).await {
+                Ok(Ok(page)) => return Ok(page),
+                Ok(Err(why)) => why,
+                Err(_) => anyhow!("Timed out after {:?}", self.timeout),
+            };
+            if attempt >= self.retries {
+                return Err(why.context(format!(
+                    "Failed to fetch the members after {} attempts",
+                    attempt + 1
+                )));
+            }
+            let wait = self.backoff.saturating_mul(2u32.saturating_pow(attempt));
+            warn!(
+                after = ?self.after,
+                attempt,
+                ?wait,
+                "Failed to fetch page of members, retrying: {:?}",
+                why
+            );
+            tokio::time::sleep(wait).await;
+            attempt += 1;
+        }
+    }
+}
+
+/// Discord's JSON error code for a member that is not part of the guild
+const UNKNOWN_MEMBER: isize = 10007;
+/// Discord's JSON error code for a request the bot lacks the permission for,
+/// for roles also returned if the role is above the highest role of the bot
+const MISSING_PERMISSIONS: isize = 50013;
+
+/// The reasons why granting or removing a role can fail, that are handled
+/// differently
+#[derive(Debug, PartialEq, Eq)]
+enum RoleUpdateError {
+    /// The member left the server in the meantime
+    MemberLeft,
+    /// The bot lacks the Manage Roles permission or its role is below the
+    /// role in the role hierarchy
+    MissingPermissions,
+    /// Anything else, e.g. an outage of discord
+    Other,
+}
+
+/// Extracts discord's JSON error code from a failed http request
+fn discord_error_code(why: &SerenityError) -> Option<isize> {
+    match why {
+        SerenityError::Http(http_error) => match http_error.as_ref() {
+            HttpError::UnsuccessfulRequest(response) => Some(response.error.code),
+            _ => None,
+        },
+        _ => None,
+    }
+}
+
+fn classify_error_code(code: Option<isize>) -> RoleUpdateError {
+    match code {
+        Some(UNKNOWN_MEMBER) => RoleUpdateError::MemberLeft,
+        Some(MISSING_PERMISSIONS) => RoleUpdateError::MissingPermissions,
+        _ => RoleUpdateError::Other,
+    }
+}
+
+fn classify_role_error(why: &SerenityError) -> RoleUpdateError {
+    classify_error_code(discord_error_code(why))
+}
+
+/// What members are told about roles that could not be changed. A lack of
+/// permissions is named, since admins can fix it right away
+fn role_failure_hint(missing_permissions: bool) -> &'static str {
+    if missing_permissions {
+        "The bot lacks the Manage Roles permission or its role is below these roles, \
+        please ask an admin to fix it in the server settings"
+    } else {
+        "Maybe your admin should check the role hierarchy!  🤔"
+    }
+}
+
+/// How long no alert is sent about a role after one was sent, so a failing
+/// enforcement doesn't alert for every member
+const PERMISSION_ALERT_INTERVAL: Duration = Duration::from_secs(60 * 60);
+
+/// Remembers when the alert channel was last told about a role of a guild
+#[derive(Debug, Default)]
+struct PermissionAlerts(std::sync::Mutex<HashMap<(u64, u64), Instant>>);
+
+impl PermissionAlerts {
+    /// The roles of the guild that were not alerted about within the
+    /// interval, they count as alerted from now on
+    fn due(&self, guild_id: u64, roles: &[u64], now: Instant) -> Vec<u64> {
+        let mut alerted = self.0.lock().unwrap();
+        alerted.retain(|_, at| now.duration_since(*at) < PERMISSION_ALERT_INTERVAL);
+        roles
+            .iter()
+            .filter(|role| {
+                let due = !alerted.contains_key(&(guild_id, **role));
+                if due {
+                    alerted.insert((guild_id, **role), now);
+                }
+                due
+            })
+            .copied()
+            .collect()
+    }
+}
+
+static PERMISSION_ALERTS: Lazy<PermissionAlerts> = Lazy::new(PermissionAlerts::default);
+
+/// Tells the configured alert channel that the bot can't manage the roles
+/// in the guild, unless it was told about them recently
+#[instrument(level = "info", skip(http))]
+async fn alert_missing_permissions(http: &Arc<Http>, guild_id: u64, roles: &[u64]) {
+    if roles.is_empty() {
+        return;
+    }
+    let channel_id = CONFIG.wait().discord.permission_alert_channel;
+    if channel_id == 0 {
+        return;
+    }
+    let roles = PERMISSION_ALERTS.due(guild_id, roles, Instant::now());
+    if roles.is_empty() {
+        debug!("Missing permissions were alerted recently");
+        return;
+    }
+    let content = format!(
+        "Missing permissions in guild {}: the bot can't manage the roles {}. It lacks the \
+        Manage Roles permission or its role is below these roles",
+        guild_id,
+        roles
+            .iter()
+            .map(u64::to_string)
+            .collect::<Vec<_>>()
+            .join(", ")
+    );
+    let http = http.clone();
+    let send = async move {
+        ChannelId(channel_id)
+            .say(&http, content)
+            .in_current_span()
+            .await
+            .map(|_| ())
+    };
+    if let Err(why) = message_queue()
+        .send(guild_id, send.in_current_span())
         .await
-        .map(|_| ())?)
+        .and_then(|sent| sent.map_err(anyhow::Error::from))
+    {
+        error!("Error alerting missing permissions: {:?}", why);
+    }
+}
+
+/// The gated roles a member gains and loses when reconciling their roles
+/// with the outcome of a check
+#[derive(Debug, Default, PartialEq, Eq)]
+struct RoleDiff {
+    gained: Vec<u64>,
+    lost: Vec<u64>,
+}
+
+impl RoleDiff {
+    /// Compares the gated roles a member currently has with a check outcome,
+    /// roles whose gates could not be checked or that are still within the
+    /// grace period are kept as they are
+    fn new(current_roles: &[u64], outcome: &CheckOutcome) -> Self {
+        let gained = outcome
+            .granted
+            .iter()
+            .filter(|r| !current_roles.contains(r))
+            .copied()
+            .collect();
+        let lost = current_roles
+            .iter()
+            .filter(|r| {
+                !outcome.granted.contains(r)
+                    && !outcome.errored.contains(r)
+                    && !outcome.in_grace.contains(r)
+            })
+            .copied()
+            .collect();
+        Self { gained, lost }
+    }
+
+    fn is_empty(&self) -> bool {
+        self.gained.is_empty() && self.lost.is_empty()
+    }
+}
+
+/// What happened when applying a [`RoleDiff`] to a member
+#[derive(Debug, Default, PartialEq, Eq)]
+struct ReconcileReport {
+    diff: RoleDiff,
+    failed_grants: Vec<u64>,
+    failed_losses: Vec<u64>,
+    /// The failed roles the bot lacks the permissions for
+    missing_permissions: Vec<u64>,
+    member_left: bool,
+}
+
+impl ReconcileReport {
+    /// Counts the roles that were actually changed, a member that left in
+    /// the meantime counts as skipped
+    fn count(&self, summary: &mut EnforcementSummary, errored: bool) {
+        if self.member_left {
+            return;
+        }
+        summary.add_member(
+            self.diff.gained.len() - self.failed_grants.len(),
+            self.diff.lost.len() - self.failed_losses.len(),
+            errored || !self.failed_grants.is_empty() || !self.failed_losses.is_empty(),
+        );
+    }
+
+    fn message(&self, user_id: u64) -> String {
+        let mut message = MessageBuilder::new();
+        message.user(user_id);
+        if self.member_left {
+            message.push(" left the server, skipping");
+            return message.build();
+        }
+        message.push_line("");
+        if !self.diff.gained.is_empty() {
+            message.push("has been granted the following roles: ");
+            for role in self.diff.gained.iter() {
+                message.role(*role);
+            }
+            message.push_line("");
+        }
+        if !self.diff.lost.is_empty() {
+            message.push("lost the following roles: ");
+            for role in self.diff.lost.iter() {
+                message.role(*role);
+            }
+        }
+        if !self.failed_grants.is_empty() {
+            message.push_line("");
+            message.push("there were problems granting the roles: ");
+            for role in self.failed_grants.iter() {
+                message.role(*role);
+            }
+        }
+        if !self.failed_losses.is_empty() {
+            message.push_line("");
+            message.push("couldn't remove the following roles: ");
+            for role in self.failed_losses.iter() {
+                message.role(*role);
+            }
+        }
+        if !self.missing_permissions.is_empty() {
+            message.push_line("");
+            message.push(role_failure_hint(true));
+        }
+        message.build()
+    }
 }
 
+/// Grants and removes the roles of a single member according to the diff
 #[instrument(level = "info", skip(ctx))]
-async fn is_below_bot_in_hierarchy(
-    position: u64,
+async fn reconcile_member(
     ctx: &Context,
     guild_id: u64,
-    bot_user_id: u64,
-) -> Result<bool> {
-    let bot_member = ctx
-        .http
-        .get_member(guild_id, bot_user_id)
+    user_id: u64,
+    diff: RoleDiff,
+) -> ReconcileReport {
+    let mut report = ReconcileReport::default();
+    for role in diff.gained.iter() {
+        if let Err(why) = ctx
+            .http
+            .add_member_role(guild_id, user_id, *role, None)
+            .in_current_span()
+            .await
+        {
+            match classify_role_error(&why) {
+                RoleUpdateError::MemberLeft => {
+                    debug!(user_id, "Member left the server during reconciliation");
+                    report.member_left = true;
+                    break;
+                }
+                RoleUpdateError::MissingPermissions => report.missing_permissions.push(*role),
+                RoleUpdateError::Other => {}
+            }
+            info!("Error granting role: {:?}", why);
+            report.failed_grants.push(*role);
+        }
+    }
+    if !report.member_left {
+        for role in diff.lost.iter() {
+            if let Err(why) = ctx
+                .http
+                .remove_member_role(guild_id, user_id, *role, None)
+                .in_current_span()
+                .await
+            {
+                match classify_role_error(&why) {
+                    RoleUpdateError::MemberLeft => {
+                        debug!(user_id, "Member left the server during reconciliation");
+                        report.member_left = true;
+                        break;
+                    }
+                    RoleUpdateError::MissingPermissions => report.missing_permissions.push(*role),
+                    RoleUpdateError::Other => {}
+                }
+                info!("Could not remove role: {:?}", why);
+                report.failed_losses.push(*role);
+            }
+        }
+    }
+    alert_missing_permissions(&ctx.http, guild_id, &report.missing_permissions)
         .in_current_span()
-        .await?;
-    let bot_roles = bot_member.roles;
-    let guild_roles = ctx.http.get_guild_roles(guild_id).in_current_span().await?;
-    if let Some(max) = guild_roles
+        .await;
+    report.diff = diff;
+    report
+}
+
+#[instrument(level = "info", skip(interaction))]
+fn extract_gate_identifier(
+    interaction: &ApplicationCommandInteraction,
+    subcommand: &str,
+) -> Result<u128> {
+    let option = interaction
+        .data
+        .options
         .iter()
-        .filter(|r| bot_roles.iter().any(|&br| br == r.id))
-        .map(|r| r.position)
-        .max()
+        .find(|o| o.name.as_str() == subcommand)
+        .ok_or(anyhow!("No {} option found", subcommand))?;
+    match option
+        .options
+        .iter()
+        .find(|o| o.name.as_str() == "identifier")
+        .and_then(|o| o.resolved.as_ref())
     {
-        Ok(position < max as u64)
-    } else {
-        error!("No bot roles found");
-        anyhow::bail!("No bot roles found");
+        Some(CommandDataOptionValue::String(s)) => Ok(s.trim().parse()?),
+        _ => bail!("Gate identifier missing"),
+    }
+}
+
+/// Whether a boolean option of the enforcement is set, e.g. `force` to ignore
+/// when members were checked last
+#[instrument(level = "info", skip(interaction))]
+fn extract_enforce_flag(interaction: &ApplicationCommandInteraction, name: &str) -> bool {
+    let flag = interaction
+        .data
+        .options
+        .iter()
+        .find(|o| o.name.as_str() == "enforce")
+        .and_then(|option| option.options.iter().find(|o| o.name.as_str() == name))
+        .and_then(|o| o.resolved.as_ref());
+    matches!(flag, Some(CommandDataOptionValue::Boolean(true)))
+}
+
+/// Whether `/gate autogrant` enables the auto grant
+#[instrument(level = "info", skip(interaction))]
+fn extract_auto_grant_flag(interaction: &ApplicationCommandInteraction) -> bool {
+    let flag = interaction
+        .data
+        .options
+        .iter()
+        .find(|o| o.name.as_str() == "autogrant")
+        .and_then(|option| option.options.iter().find(|o| o.name.as_str() == "enabled"))
+        .and_then(|o| o.resolved.as_ref());
+    matches!(flag, Some(CommandDataOptionValue::Boolean(true)))
+}
+
+/// Whether a boolean option of `/get in` is set, e.g. `verbose`. The context
+/// menu command has no options, so it is never set there
+#[instrument(level = "info", skip(interaction))]
+fn extract_get_in_flag(interaction: &ApplicationCommandInteraction, name: &str) -> bool {
+    let flag = interaction
+        .data
+        .options
+        .iter()
+        .find(|o| o.name.as_str() == "in")
+        .and_then(|option| option.options.iter().find(|o| o.name.as_str() == name))
+        .and_then(|o| o.resolved.as_ref());
+    matches!(flag, Some(CommandDataOptionValue::Boolean(true)))
+}
+
+/// The role the enforcement is limited to, if any
+#[instrument(level = "info", skip(interaction))]
+fn extract_enforce_role(interaction: &ApplicationCommandInteraction) -> Option<u64> {
+    let role = interaction
+        .data
+        .options
+        .iter()
+        .find(|o| o.name.as_str() == "enforce")
+        .and_then(|option| option.options.iter().find(|o| o.name.as_str() == "role"))
+        .and_then(|o| o.resolved.as_ref());
+    match role {
+        Some(CommandDataOptionValue::Role(role)) => Some(role.id.into()),
+        _ => None,
+    }
+}
+
+#[instrument(level = "info", skip(interaction))]
+fn extract_recheck_user(interaction: &ApplicationCommandInteraction) -> Result<u64> {
+    let option = interaction
+        .data
+        .options
+        .iter()
+        .find(|o| o.name.as_str() == "recheck")
+        .ok_or(anyhow!("No recheck option found"))?;
+    match option
+        .options
+        .iter()
+        .find(|o| o.name.as_str() == "user")
+        .and_then(|o| o.resolved.as_ref())
+    {
+        Some(CommandDataOptionValue::User(user, _)) => Ok(user.id.into()),
+        _ => bail!("User missing"),
+    }
+}
+
+/// The member a gate is previewed for, the other options are the same as
+/// for adding the gate
+fn extract_preview_user(interaction: &ApplicationCommandInteraction) -> Result<u64> {
+    let option = interaction
+        .data
+        .options
+        .iter()
+        .find(|o| o.name.as_str() == "preview")
+        .and_then(|o| o.options.first())
+        .ok_or(anyhow!("No preview option found"))?;
+    match option
+        .options
+        .iter()
+        .find(|o| o.name.as_str() == "user")
+        .and_then(|o| o.resolved.as_ref())
+    {
+        Some(CommandDataOptionValue::User(user, _)) => Ok(user.id.into()),
+        _ => bail!("User missing"),
     }
 }
 
 #[instrument(level = "info", skip(interaction))]
 fn extract_gate_add_options(
     interaction: &ApplicationCommandInteraction,
-) -> Result<(String, u64, u64, u64, Vec<GateOptionValue>)> {
+    subcommand: &str,
+) -> Result<(String, u64, u64, u64, Vec<GateOptionValue>, Option<String>)> {
     let mut role_id: Option<u64> = None;
+    let mut grant_message: Option<String> = None;
     let mut role_position: u64 = 0;
     let mut guild_id: u64 = 0;
     let add_option = interaction
         .data
         .options
         .iter()
-        .find(|o| o.name.as_str() == "add")
-        .ok_or(anyhow!("No add option found"))?;
+        .find(|o| o.name.as_str() == subcommand)
+        .ok_or(anyhow!("No {} option found", subcommand))?;
     if add_option.options.is_empty() {
-        return Err(anyhow!("No options found on add found"));
+        return Err(anyhow!("No options found on {} found", subcommand));
     }
     let sub_option = &add_option.options[0];
     let name = sub_option.name.clone();
@@ -1127,6 +3395,16 @@ fn extract_gate_add_options(
                 }
                 None
             }
+            // the member of a preview, see extract_preview_user
+            "user" => None,
+            "grant_message" => {
+                if let Some(CommandDataOptionValue::String(s)) = sub_sub_option.resolved.as_ref() {
+                    grant_message = Some(s.clone());
+                } else {
+                    error!("Grant message field did not hold a string type");
+                }
+                None
+            }
             _ => {
                 let value = match sub_sub_option.resolved.as_ref() {
                     Some(CommandDataOptionValue::String(s)) => {
@@ -1148,8 +3426,683 @@ fn extract_gate_add_options(
         })
         .collect();
     if let Some(role_id) = role_id {
-        Ok((name, role_id, role_position, guild_id, options))
+        Ok((
+            name,
+            role_id,
+            role_position,
+            guild_id,
+            options,
+            grant_message,
+        ))
     } else {
         Err(anyhow!("Role id missing"))
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_color() {
+        assert_eq!(parse_color("#289BDC"), Some(0x289BDC));
+        assert_eq!(parse_color("289bdc"), Some(0x289BDC));
+        assert_eq!(parse_color(" #000000 "), Some(0));
+        assert_eq!(parse_color("#FFF"), None);
+        assert_eq!(parse_color("not a color"), None);
+        assert_eq!(parse_color("#+12345"), None);
+    }
+
+    #[test]
+    fn test_grant_visibility() {
+        use FailureVisibility::*;
+        for policy in [Public, AdminPing, Ephemeral] {
+            // without failures the policy does not matter
+            assert_eq!(
+                grant_visibility(true, false, &policy),
+                GrantVisibility::Public
+            );
+            assert_eq!(
+                grant_visibility(false, false, &policy),
+                GrantVisibility::Ephemeral
+            );
+        }
+        for granted in [true, false] {
+            assert_eq!(
+                grant_visibility(granted, true, &Public),
+                GrantVisibility::Public
+            );
+            assert_eq!(
+                grant_visibility(granted, true, &AdminPing),
+                GrantVisibility::EphemeralWithAdminPing
+            );
+            assert_eq!(
+                grant_visibility(granted, true, &Ephemeral),
+                GrantVisibility::Ephemeral
+            );
+        }
+    }
+
+    #[test]
+    fn test_gate_command_permission() {
+        let permission = parse_permission("manage_roles").unwrap();
+        assert_eq!(permission, Some(Permissions::MANAGE_ROLES));
+        let mut command = CreateApplicationCommand::default();
+        build_gate_command(&mut command, permission, &CommandLocalizations::new());
+        let configured = command
+            .0
+            .get("default_member_permissions")
+            .and_then(|value| value.as_str());
+        assert_eq!(
+            configured,
+            Some(Permissions::MANAGE_ROLES.bits().to_string().as_str())
+        );
+
+        let permission = parse_permission("NONE").unwrap();
+        let mut command = CreateApplicationCommand::default();
+        build_gate_command(&mut command, permission, &CommandLocalizations::new());
+        assert!(!command.0.contains_key("default_member_permissions"));
+    }
+
+    /// A guild with the user ids 1 to `members`, counting the fetched pages
+    struct MockMembers {
+        members: u64,
+        pages: std::sync::atomic::AtomicUsize,
+    }
+
+    #[async_trait]
+    impl MemberSource for MockMembers {
+        async fn member_page(
+            &self,
+            _guild_id: u64,
+            limit: u64,
+            after: Option<u64>,
+        ) -> Result<Vec<GuildMember>> {
+            self.pages.fetch_add(1, std::sync::atomic::Ordering::SeqCst);
+            let first = after.unwrap_or(0) + 1;
+            let last = (first + limit - 1).min(self.members);
+            Ok((first..=last)
+                .map(|user_id| GuildMember {
+                    user_id,
+                    roles: vec![],
+                    bot: false,
+                })
+                .collect())
+        }
+    }
+
+    async fn fetch_all(members: u64) -> (Vec<u64>, usize) {
+        let source = MockMembers {
+            members,
+            pages: Default::default(),
+        };
+        let mut pages = MemberPages::new(&source, 1, 0, Duration::from_secs(1));
+        let mut user_ids = Vec::new();
+        while let Some(page) = pages.next_page().await.unwrap() {
+            user_ids.extend(page.into_iter().map(|member| member.user_id));
+        }
+        (user_ids, source.pages.into_inner())
+    }
+
+    #[tokio::test]
+    async fn test_members_are_fetched_in_pages() {
+        let (user_ids, pages) = fetch_all(2500).await;
+        assert_eq!(user_ids, (1..=2500).collect::<Vec<_>>());
+        assert_eq!(pages, 3);
+        // a full last page needs one more request to see that it was the last
+        let (user_ids, pages) = fetch_all(2000).await;
+        assert_eq!(user_ids.len(), 2000);
+        assert_eq!(pages, 3);
+        let (user_ids, pages) = fetch_all(0).await;
+        assert!(user_ids.is_empty());
+        assert_eq!(pages, 1);
+    }
+
+    /// Fails the first attempt at the second page and stalls at the first
+    /// attempt at the third page, the other pages come from the inner guild
+    struct FlakyMembers {
+        inner: MockMembers,
+        attempted: std::sync::Mutex<Vec<Option<u64>>>,
+    }
+
+    #[async_trait]
+    impl MemberSource for FlakyMembers {
+        async fn member_page(
+            &self,
+            guild_id: u64,
+            limit: u64,
+            after: Option<u64>,
+        ) -> Result<Vec<GuildMember>> {
+            let first_attempt = {
+                let mut attempted = self.attempted.lock().unwrap();
+                let first_attempt = !attempted.contains(&after);
+                attempted.push(after);
+                first_attempt
+            };
+            match after {
+                Some(1000) if first_attempt => Err(anyhow!("Connection reset")),
+                Some(2000) if first_attempt => std::future::pending().await,
+                _ => self.inner.member_page(guild_id, limit, after).await,
+            }
+        }
+    }
+
+    fn flaky_members(members: u64) -> FlakyMembers {
+        FlakyMembers {
+            inner: MockMembers {
+                members,
+                pages: Default::default(),
+            },
+            attempted: Default::default(),
+        }
+    }
+
+    #[tokio::test]
+    async fn test_failed_member_pages_are_retried() {
+        let source = flaky_members(2500);
+        let mut pages = MemberPages::new(&source, 1, 1, Duration::from_millis(50));
+        pages.backoff = Duration::ZERO;
+        let mut user_ids = Vec::new();
+        while let Some(page) = pages.next_page().await.unwrap() {
+            user_ids.extend(page.into_iter().map(|member| member.user_id));
+        }
+        assert_eq!(user_ids, (1..=2500).collect::<Vec<_>>());
+        // only the failed pages were fetched again
+        assert_eq!(
+            *source.attempted.lock().unwrap(),
+            vec![None, Some(1000), Some(1000), Some(2000), Some(2000)]
+        );
+
+        // without retries the failure ends the walk
+        let source = flaky_members(2500);
+        let mut pages = MemberPages::new(&source, 1, 0, Duration::from_millis(50));
+        assert!(pages.next_page().await.unwrap().is_some());
+        assert!(pages.next_page().await.is_err());
+    }
+
+    #[test]
+    fn test_enforcement_skips_bots_and_excluded_users() {
+        let member = |user_id, bot| GuildMember {
+            user_id,
+            roles: vec![10, 20],
+            bot,
+        };
+        let page = vec![member(1, false), member(2, true), member(3, false)];
+        let managed_roles = HashSet::from([10]);
+
+        let members = enforced_members(page.clone(), &managed_roles, true, &[3]);
+        // only the first member is part of the batch, so the roles of the bot
+        // and the excluded user are never changed
+        assert_eq!(members, HashMap::from([(1, vec![10])]));
+
+        let members = enforced_members(page, &managed_roles, false, &[]);
+        let mut user_ids = members.keys().copied().collect::<Vec<_>>();
+        user_ids.sort_unstable();
+        assert_eq!(user_ids, vec![1, 2, 3]);
+    }
+
+    #[tokio::test]
+    async fn test_slow_check_sends_notice_before_result() {
+        let events = std::sync::Mutex::new(Vec::new());
+        // the slow controller answers after the notice timeout
+        let (tx, rx) = oneshot::channel();
+        tokio::spawn(async move {
+            tokio::time::sleep(Duration::from_millis(100)).await;
+            tx.send("result").unwrap();
+        });
+        let notice = async { events.lock().unwrap().push("notice") };
+        let response = wait_with_notice(rx, Duration::from_millis(10), notice)
+            .await
+            .unwrap();
+        events.lock().unwrap().push(response);
+        assert_eq!(*events.lock().unwrap(), vec!["notice", "result"]);
+
+        // a fast controller gets no notice
+        let events = std::sync::Mutex::new(Vec::new());
+        let (tx, rx) = oneshot::channel();
+        tx.send("result").unwrap();
+        let notice = async { events.lock().unwrap().push("notice") };
+        let response = wait_with_notice(rx, Duration::from_secs(10), notice)
+            .await
+            .unwrap();
+        events.lock().unwrap().push(response);
+        assert_eq!(*events.lock().unwrap(), vec!["result"]);
+
+        // a controller that drops the request is still an error
+        let (tx, rx) = oneshot::channel::<&str>();
+        drop(tx);
+        assert!(wait_with_notice(rx, Duration::from_secs(10), async {})
+            .await
+            .is_err());
+    }
+
+    #[test]
+    fn test_classify_error_code() {
+        assert_eq!(
+            classify_error_code(Some(UNKNOWN_MEMBER)),
+            RoleUpdateError::MemberLeft
+        );
+        assert_eq!(
+            classify_error_code(Some(MISSING_PERMISSIONS)),
+            RoleUpdateError::MissingPermissions
+        );
+        assert_eq!(classify_error_code(Some(50001)), RoleUpdateError::Other);
+        assert_eq!(classify_error_code(None), RoleUpdateError::Other);
+        assert_eq!(
+            classify_role_error(&SerenityError::Other("not an http error")),
+            RoleUpdateError::Other
+        );
+    }
+
+    #[test]
+    fn test_recheck_role_diff() {
+        // member holds role 1 (still granted), 2 (no longer granted) and
+        // 3 (gate errored), and newly qualifies for role 4
+        let outcome = CheckOutcome {
+            granted: vec![1, 4],
+            errored: vec![3],
+            ..Default::default()
+        };
+        let diff = RoleDiff::new(&[1, 2, 3], &outcome);
+        assert_eq!(
+            diff,
+            RoleDiff {
+                gained: vec![4],
+                lost: vec![2],
+            }
+        );
+        assert!(!diff.is_empty());
+
+        let unchanged = RoleDiff::new(&[1, 4], &outcome);
+        assert!(unchanged.is_empty());
+
+        // a role within the grace period is not lost yet
+        let in_grace = CheckOutcome {
+            granted: vec![1, 4],
+            in_grace: vec![2],
+            ..Default::default()
+        };
+        assert!(RoleDiff::new(&[1, 2, 4], &in_grace).is_empty());
+
+        let report = ReconcileReport {
+            diff,
+            failed_losses: vec![2],
+            ..Default::default()
+        };
+        let message = report.message(42);
+        assert!(message.starts_with("<@42>"));
+        assert!(message.contains("has been granted the following roles: <@&4>"));
+        assert!(message.contains("couldn't remove the following roles: <@&2>"));
+
+        assert!(!message.contains("Manage Roles"));
+
+        let forbidden = ReconcileReport {
+            diff: RoleDiff {
+                gained: vec![4],
+                lost: Vec::new(),
+            },
+            failed_grants: vec![4],
+            missing_permissions: vec![4],
+            ..Default::default()
+        };
+        let message = forbidden.message(42);
+        assert!(message.contains("there were problems granting the roles: <@&4>"));
+        assert!(message.ends_with(role_failure_hint(true)));
+
+        let left = ReconcileReport {
+            member_left: true,
+            ..Default::default()
+        };
+        assert_eq!(left.message(42), "<@42> left the server, skipping");
+    }
+
+    #[test]
+    fn test_missing_permissions_hint_and_alerts() {
+        assert!(role_failure_hint(true).contains("Manage Roles permission"));
+        assert!(!role_failure_hint(false).contains("Manage Roles"));
+
+        // each role is alerted once per interval, not per member
+        let alerts = PermissionAlerts::default();
+        let now = Instant::now();
+        assert_eq!(alerts.due(1, &[10, 20], now), vec![10, 20]);
+        assert!(alerts.due(1, &[10, 20], now).is_empty());
+        assert_eq!(alerts.due(1, &[10, 30], now), vec![30]);
+        assert_eq!(alerts.due(2, &[10], now), vec![10]);
+        let later = now + PERMISSION_ALERT_INTERVAL;
+        assert_eq!(alerts.due(1, &[10], later), vec![10]);
+    }
+
+    #[test]
+    fn test_large_integer_bounds() {
+        let mut option = CreateApplicationCommandOption::default();
+        apply_option_type(
+            &mut option,
+            GateOptionType::I64 {
+                min: Some(i64::MIN),
+                max: Some(i64::MAX),
+            },
+        );
+        let bound = |key: &str| option.0.get(key).and_then(|value| value.as_i64());
+        assert_eq!(bound("min_value"), Some(i64::MIN));
+        assert_eq!(bound("max_value"), Some(i64::MAX));
+    }
+
+    #[test]
+    fn test_generate_invite_url() {
+        let url = generate_invite_url(1125748696043225180);
+        assert_eq!(
+            url,
+            "https://discord.com/api/oauth2/authorize?client_id=1125748696043225180\
+            &permissions=268435456&scope=bot"
+        );
+        assert!(crate::config::validate_invite_url(&url).is_ok());
+    }
+
+    #[test]
+    fn test_grant_message_for_granted_role() {
+        let outcome = CheckOutcome {
+            granted: vec![1, 2],
+            grant_messages: HashMap::from([
+                (1, "Welcome to the DAO core team!".to_string()),
+                (3, "Not granted".to_string()),
+            ]),
+            ..Default::default()
+        };
+        let mut content = MessageBuilder::new();
+        // role 2 has no message and role 3 was not granted
+        push_grant_messages(&mut content, &[1, 2], &outcome);
+        assert_eq!(content.build(), "<@&1>: Welcome to the DAO core team!\n");
+    }
+
+    #[test]
+    fn test_no_roles_message() {
+        let message = "has no roles yet, see https://example.com/roles for what they need";
+        let mut content = MessageBuilder::new();
+        push_grant_summary(&mut content, &[], &[], &CheckOutcome::default(), message);
+        assert_eq!(content.build(), format!(" {}\n", message));
+
+        // a failed check or grant is not a lack of roles
+        let outcome = CheckOutcome {
+            errored: vec![1],
+            ..Default::default()
+        };
+        let mut content = MessageBuilder::new();
+        push_grant_summary(&mut content, &[], &[], &outcome, message);
+        assert_eq!(content.build(), " used the `/get in` command\n");
+        let mut content = MessageBuilder::new();
+        push_grant_summary(&mut content, &[], &[2], &CheckOutcome::default(), message);
+        assert_eq!(content.build(), " used the `/get in` command\n");
+    }
+
+    #[test]
+    fn test_required_gate_options_first() {
+        let mut command = CreateApplicationCommand::default();
+        build_gate_command(&mut command, None, &CommandLocalizations::new());
+        let options = command.0["options"].as_array().unwrap();
+        let add = options.iter().find(|o| o["name"] == "add").unwrap();
+        for gate in add["options"].as_array().unwrap() {
+            let gate_options = gate["options"].as_array().unwrap();
+            let required = gate_options
+                .iter()
+                .map(|o| o["required"].as_bool().unwrap_or(false))
+                .collect::<Vec<_>>();
+            assert!(
+                required.windows(2).all(|pair| pair[0] || !pair[1]),
+                "required option after optional one in {}",
+                gate["name"]
+            );
+            assert_eq!(gate_options.last().unwrap()["name"], "grant_message");
+        }
+        // a preview takes the same options and the member, but no grant message
+        let preview = options.iter().find(|o| o["name"] == "preview").unwrap();
+        for gate in preview["options"].as_array().unwrap() {
+            let gate_options = gate["options"].as_array().unwrap();
+            let user = gate_options.iter().find(|o| o["name"] == "user").unwrap();
+            assert_eq!(user["required"], true);
+            assert!(gate_options.iter().all(|o| o["name"] != "grant_message"));
+        }
+    }
+
+    #[test]
+    fn test_replace_tier_role_names() {
+        let roles = HashMap::from([("Silver".to_string(), 2), ("Gold".to_string(), 3)]);
+        assert_eq!(
+            replace_tier_role_names("500:@Silver, 1000:<@&3>", &roles).unwrap(),
+            "500:<@&2>, 1000:<@&3>"
+        );
+        assert_eq!(
+            replace_tier_role_names("500:@2,1000:@Gold", &roles).unwrap(),
+            "500:@2,1000:<@&3>"
+        );
+        assert!(replace_tier_role_names("500:@Bronze", &roles).is_err());
+    }
+
+    #[test]
+    fn test_check_gateable_role() {
+        let assignable = GateableRole {
+            managed: false,
+            position: 2,
+        };
+        assert!(check_gateable_role(10, 1, Some(assignable), Some(5)).is_ok());
+
+        let everyone = check_gateable_role(1, 1, Some(assignable), Some(5)).unwrap_err();
+        assert!(everyone.to_string().contains("@everyone"));
+
+        let booster = GateableRole {
+            managed: true,
+            position: 2,
+        };
+        let managed = check_gateable_role(10, 1, Some(booster), Some(5)).unwrap_err();
+        assert!(managed.to_string().contains("managed"));
+
+        let above_bot = check_gateable_role(10, 1, Some(assignable), Some(2)).unwrap_err();
+        assert!(above_bot.to_string().contains("role hierarchy"));
+        assert!(check_gateable_role(10, 1, Some(assignable), None).is_err());
+        assert!(check_gateable_role(10, 1, None, Some(5)).is_err());
+    }
+
+    #[test]
+    fn test_command_localizations() {
+        let localizations: CommandLocalizations = serde_json::from_str(
+            r#"{
+                "de": {
+                    "get": {"name": "holen", "description": "Gesperrte Rollen holen"},
+                    "get in verbose": {"description": "Auch die Wallets zeigen"}
+                },
+                "fr": {"get out": {"name": "sortir"}}
+            }"#,
+        )
+        .unwrap();
+        let mut command = CreateApplicationCommand::default();
+        build_get_command(&mut command, &localizations);
+        assert_eq!(command.0["name"], "get");
+        assert_eq!(command.0["name_localizations"]["de"], "holen");
+        assert_eq!(
+            command.0["description_localizations"]["de"],
+            "Gesperrte Rollen holen"
+        );
+        let options = command.0["options"].as_array().unwrap();
+        let get_in = options.iter().find(|o| o["name"] == "in").unwrap();
+        assert!(get_in.get("name_localizations").is_none());
+        let verbose = &get_in["options"][0];
+        assert_eq!(verbose["name"], "verbose");
+        assert_eq!(
+            verbose["description_localizations"]["de"],
+            "Auch die Wallets zeigen"
+        );
+        let get_out = options.iter().find(|o| o["name"] == "out").unwrap();
+        assert_eq!(get_out["name_localizations"]["fr"], "sortir");
+        assert!(get_out.get("description_localizations").is_none());
+    }
+
+    #[test]
+    fn test_check_roles_context_menu_command() {
+        let mut command = CreateApplicationCommand::default();
+        make_check_roles_command(&mut command);
+        assert_eq!(command.0["name"], CHECK_ROLES_COMMAND);
+        assert_eq!(command.0["type"], CommandType::User as u8);
+        assert!(!command.0.contains_key("description"));
+    }
+
+    #[test]
+    fn test_setup_hierarchy_status() {
+        let role = |position| GateableRole {
+            managed: false,
+            position,
+        };
+        let roles = HashMap::from([
+            (10, role(2)),
+            (11, role(6)),
+            (
+                12,
+                GateableRole {
+                    managed: true,
+                    position: 1,
+                },
+            ),
+        ]);
+        let status = hierarchy_status(1, &[10], &roles, Some(5));
+        assert!(status.ok);
+        assert!(status.title("Role hierarchy").starts_with('✅'));
+        assert!(hierarchy_status(1, &[], &roles, Some(5)).ok);
+
+        let status = hierarchy_status(1, &[10, 11, 12, 13], &roles, Some(5));
+        assert!(!status.ok);
+        assert!(status.title("Role hierarchy").starts_with('⚠'));
+        let problems = status.detail.lines().collect::<Vec<_>>();
+        assert_eq!(problems.len(), 3);
+        assert!(problems[0].starts_with("<@&11>") && problems[0].contains("role hierarchy"));
+        assert!(problems[1].starts_with("<@&12>") && problems[1].contains("managed"));
+        assert!(problems[2].starts_with("<@&13>") && problems[2].contains("not found"));
+
+        let status = hierarchy_status(1, &[10], &roles, None);
+        assert!(!status.ok);
+        assert!(status.detail.contains("no role"));
+
+        let missing = (100..300).collect::<Vec<_>>();
+        let status = hierarchy_status(1, &missing, &roles, Some(5));
+        assert!(!status.ok);
+        assert!(status.detail.chars().count() <= EMBED_FIELD_LIMIT);
+        assert!(status.detail.starts_with("<@&100>"));
+        assert!(status.detail.ends_with(" more"), "{}", status.detail);
+    }
+
+    #[test]
+    fn test_embed_field_lines() {
+        let lines = vec!["a".to_string(), "b".to_string()];
+        assert_eq!(embed_field_lines(&lines), "a\nb");
+        let lines = vec!["x".repeat(600); 3];
+        let value = embed_field_lines(&lines);
+        assert!(value.chars().count() <= EMBED_FIELD_LIMIT);
+        assert_eq!(value, format!("{}\n… and 2 more", "x".repeat(600)));
+        let value = embed_field_lines(&["x".repeat(2000)]);
+        assert_eq!(value, "… and 1 more");
+    }
+
+    #[tokio::test]
+    async fn test_deleted_gate_role_is_revoked() {
+        use crate::controller::Controller;
+        use crate::gate::AllowlistGate;
+        use crate::storage::{InMemoryStorage, Storage};
+        use secrecy::SecretString;
+
+        let gate = |wallets| Gate {
+            role_id: 100,
+            condition: Box::new(AllowlistGate { wallets }),
+            enabled: true,
+            grant_message: None,
+        };
+        let (first, second) = (H160::repeat_byte(1), H160::repeat_byte(2));
+        let mut storage = InMemoryStorage::new();
+        storage
+            .add_user(1, vec![SecretString::new(format!("{:?}", first))])
+            .unwrap();
+        storage
+            .add_user(2, vec![SecretString::new(format!("{:?}", second))])
+            .unwrap();
+        let deleted = gate(vec![first, second]);
+        storage.add_gate(&10, deleted.clone()).unwrap();
+        storage.add_gate(&10, gate(vec![second])).unwrap();
+        let controller = Controller::with_storage(storage);
+        let channel = controller.message_tx.clone();
+        controller.spawn().await;
+
+        channel
+            .send(controller::Message::Delete {
+                guild_id: 10,
+                gate: deleted,
+                span: Span::none(),
+            })
+            .await
+            .unwrap();
+        // the second user still qualifies through the remaining gate
+        let losses = deleted_gate_losses(&channel, 10, 100, vec![1, 2]).await;
+        assert_eq!(losses.len(), 1);
+        assert_eq!(losses[0].0, 1);
+        assert_eq!(losses[0].1.lost, vec![100]);
+        assert!(losses[0].1.gained.is_empty());
+    }
+
+    /// Records the removed roles
+    #[derive(Default)]
+    struct RecordingRemover {
+        removed: std::sync::Mutex<Vec<(u64, u64, u64)>>,
+    }
+
+    #[async_trait]
+    impl RoleRemover for RecordingRemover {
+        async fn remove_role(&self, guild_id: u64, user_id: u64, role_id: u64) -> Result<()> {
+            self.removed
+                .lock()
+                .unwrap()
+                .push((guild_id, user_id, role_id));
+            Ok(())
+        }
+    }
+
+    #[tokio::test]
+    async fn test_unregister_revokes_roles_only_if_configured() {
+        let remover = RecordingRemover::default();
+        let message = revoke_unregistered_roles(&remover, 10, 1, &[100, 200], true).await;
+        assert_eq!(
+            message,
+            "You have been removed from the following roles: <@&100><@&200>"
+        );
+        assert_eq!(
+            *remover.removed.lock().unwrap(),
+            vec![(10, 1, 100), (10, 1, 200)]
+        );
+
+        let remover = RecordingRemover::default();
+        let message = revoke_unregistered_roles(&remover, 10, 1, &[100, 200], false).await;
+        assert!(message.starts_with("Your wallet has been unlinked"));
+        assert!(message.contains("keep your roles"));
+        assert!(remover.removed.lock().unwrap().is_empty());
+    }
+
+    #[test]
+    fn test_matched_wallet_with_name() {
+        let named = H160::repeat_byte(0x11);
+        let unnamed = H160::repeat_byte(0x22);
+        let outcome = CheckOutcome {
+            granted: vec![1, 2],
+            wallets: HashMap::from([(1, named), (2, unnamed)]),
+            ..Default::default()
+        };
+        let names = HashMap::from([(named, "alice.eth".to_string())]);
+        let mut content = MessageBuilder::new();
+        push_matched_wallets(&mut content, &[1, 2], &outcome, &names);
+        assert_eq!(
+            content.build(),
+            "<@&1> was granted for the wallet `0x1111…1111` (alice.eth)\n\
+             <@&2> was granted for the wallet `0x2222…2222`\n"
+        );
+    }
+
+    #[test]
+    fn test_mask_wallet() {
+        use std::str::FromStr;
+        let wallet = H160::from_str("0x1234567890abcdef1234567890abcdef12345678").unwrap();
+        assert_eq!(mask_wallet(&wallet), "0x1234…5678");
+    }
+}
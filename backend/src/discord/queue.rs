@@ -0,0 +1,276 @@
+//! Paces the follow up messages sent to discord. An enforcement of a large
+//! server sends a report per changed member, which can trip the global rate
+//! limit of discord, while serenity's limiter only delays the messages once
+//! the limit is hit. The messages of every guild wait in their own queue and
+//! the guilds take turns, so a long enforcement in one guild does not hold up
+//! the messages of the others. The messages are sent in their own tasks once
+//! it is their turn, so a slow send only holds up the later messages of its
+//! own guild.
+//!
+
+use anyhow::{anyhow, Result};
+use futures::future::BoxFuture;
+use std::collections::{HashMap, HashSet, VecDeque};
+use std::future::Future;
+use std::sync::{
+    atomic::{AtomicUsize, Ordering},
+    Arc,
+};
+use std::time::Duration;
+use tokio::sync::{mpsc, oneshot};
+use tokio::time::MissedTickBehavior;
+use tracing::trace;
+
+/// A message of a guild, it is sent when the future is awaited
+type Queued = (u64, BoxFuture<'static, ()>);
+
+/// The handle to the task sending the queued messages
+#[derive(Debug)]
+pub(super) struct MessageQueue {
+    tx: mpsc::UnboundedSender<Queued>,
+    depth: Arc<AtomicUsize>,
+}
+
+impl MessageQueue {
+    /// Starts the task that sends at most `per_second` messages per second,
+    /// with 0 the messages are sent as they come
+    pub(super) fn start(per_second: u32) -> Self {
+        let (tx, rx) = mpsc::unbounded_channel();
+        let depth = Arc::new(AtomicUsize::new(0));
+        let period = (per_second > 0).then(|| Duration::from_secs(1) / per_second);
+        tokio::spawn(dispatch(rx, period, depth.clone()));
+        MessageQueue { tx, depth }
+    }
+
+    /// Queues a message of a guild, the messages of a guild are sent in the
+    /// order they were queued
+    pub(super) fn push(
+        &self,
+        guild_id: u64,
+        send: impl Future<Output = ()> + Send + 'static,
+    ) -> Result<()> {
+        record_depth(self.depth.fetch_add(1, Ordering::SeqCst) + 1);
+        self.tx.send((guild_id, Box::pin(send))).map_err(|_| {
+            record_depth(self.depth.fetch_sub(1, Ordering::SeqCst) - 1);
+            anyhow!("The message queue is closed")
+        })
+    }
+
+    /// Queues a message of a guild and waits until it is sent
+    pub(super) async fn send<T: Send + 'static>(
+        &self,
+        guild_id: u64,
+        send: impl Future<Output = T> + Send + 'static,
+    ) -> Result<T> {
+        let (result_tx, result_rx) = oneshot::channel();
+        self.push(guild_id, async move {
+            // the caller may have given up waiting, then the result is dropped
+            let _ = result_tx.send(send.await);
+        })?;
+        result_rx
+            .await
+            .map_err(|_| anyhow!("The message was dropped from the queue"))
+    }
+
+    /// The number of messages waiting to be sent
+    pub(super) fn depth(&self) -> usize {
+        self.depth.load(Ordering::SeqCst)
+    }
+}
+
+/// The waiting messages of every guild and the order in which the guilds
+/// take turns. A guild whose message is being sent sits out the turns until
+/// it is sent, so the messages of a guild keep their order
+#[derive(Default)]
+struct Pending {
+    turns: VecDeque<u64>,
+    messages: HashMap<u64, VecDeque<BoxFuture<'static, ()>>>,
+    sending: HashSet<u64>,
+}
+
+impl Pending {
+    fn push(&mut self, (guild_id, send): Queued) {
+        let messages = self.messages.entry(guild_id).or_default();
+        if messages.is_empty() && !self.sending.contains(&guild_id) {
+            self.turns.push_back(guild_id);
+        }
+        messages.push_back(send);
+    }
+
+    /// The next message of the guild whose turn it is, the guild takes turns
+    /// again once the message is [`Pending::sent`]
+    fn pop(&mut self) -> Option<(u64, BoxFuture<'static, ()>)> {
+        let guild_id = self.turns.pop_front()?;
+        let send = self.messages.get_mut(&guild_id)?.pop_front()?;
+        self.sending.insert(guild_id);
+        Some((guild_id, send))
+    }
+
+    /// The message of the guild was sent, the guild moves to the back if it
+    /// has more messages waiting
+    fn sent(&mut self, guild_id: u64) {
+        self.sending.remove(&guild_id);
+        match self.messages.get(&guild_id) {
+            Some(messages) if !messages.is_empty() => self.turns.push_back(guild_id),
+            _ => {
+                self.messages.remove(&guild_id);
+            }
+        }
+    }
+
+    /// Whether no guild has a message ready to be sent
+    fn is_empty(&self) -> bool {
+        self.turns.is_empty()
+    }
+
+    /// Whether no message is waiting or being sent
+    fn is_idle(&self) -> bool {
+        self.is_empty() && self.sending.is_empty()
+    }
+}
+
+async fn dispatch(
+    mut rx: mpsc::UnboundedReceiver<Queued>,
+    period: Option<Duration>,
+    depth: Arc<AtomicUsize>,
+) {
+    let mut pending = Pending::default();
+    let (sent_tx, mut sent_rx) = mpsc::unbounded_channel();
+    let mut closed = false;
+    let mut ticks = period.map(|period| {
+        let mut ticks = tokio::time::interval(period);
+        // after a quiet time the next message goes out right away, but the
+        // ones after it are paced again instead of catching up
+        ticks.set_missed_tick_behavior(MissedTickBehavior::Delay);
+        ticks
+    });
+    loop {
+        if pending.is_empty() {
+            // the messages that are still being sent are awaited, so the
+            // later messages of their guilds are not lost
+            if closed && pending.is_idle() {
+                return;
+            }
+            tokio::select! {
+                queued = rx.recv(), if !closed => match queued {
+                    Some(queued) => pending.push(queued),
+                    None => closed = true,
+                },
+                Some(guild_id) = sent_rx.recv() => pending.sent(guild_id),
+            }
+            continue;
+        }
+        if let Some(ticks) = ticks.as_mut() {
+            ticks.tick().await;
+        }
+        // the messages queued while waiting take part in the turns
+        while let Ok(queued) = rx.try_recv() {
+            pending.push(queued);
+        }
+        while let Ok(guild_id) = sent_rx.try_recv() {
+            pending.sent(guild_id);
+        }
+        if let Some((guild_id, send)) = pending.pop() {
+            let (sent_tx, depth) = (sent_tx.clone(), depth.clone());
+            tokio::spawn(async move {
+                send.await;
+                record_depth(depth.fetch_sub(1, Ordering::SeqCst) - 1);
+                // the dispatch only stops once every send reported back
+                let _ = sent_tx.send(guild_id);
+            });
+        }
+    }
+}
+
+/// Logs the queue depth, with the metrics feature it is emitted as a
+/// histogram event that can be picked up by a metrics layer
+fn record_depth(depth: usize) {
+    trace!(depth, "Message queue depth");
+    #[cfg(feature = "metrics")]
+    tracing::info!(
+        histogram.discord_message_queue_depth = depth as u64,
+        "Message queue depth"
+    );
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::Mutex;
+    use tokio::time::Instant;
+
+    #[tokio::test]
+    async fn test_messages_are_paced() {
+        let queue = MessageQueue::start(20);
+        let sent =
+            futures::future::join_all((0..5).map(|_| queue.send(1, async { Instant::now() })))
+                .await
+                .into_iter()
+                .collect::<Result<Vec<_>>>()
+                .unwrap();
+        for (earlier, later) in sent.iter().zip(sent.iter().skip(1)) {
+            // the timer has a resolution of a millisecond
+            assert!(
+                later.duration_since(*earlier) >= Duration::from_millis(49),
+                "{:?}",
+                sent
+            );
+        }
+        assert_eq!(queue.depth(), 0);
+    }
+
+    #[tokio::test]
+    async fn test_guilds_take_turns() {
+        let queue = MessageQueue::start(0);
+        let sent = Arc::new(Mutex::new(Vec::new()));
+        for guild_id in [1, 1, 1, 2] {
+            let sent = sent.clone();
+            queue
+                .push(guild_id, async move {
+                    sent.lock().unwrap().push(guild_id);
+                })
+                .unwrap();
+        }
+        assert_eq!(queue.depth(), 4);
+        // the last message of the first guild is sent after all the others
+        queue.send(1, async {}).await.unwrap();
+        assert_eq!(*sent.lock().unwrap(), vec![1, 2, 1, 1]);
+        assert_eq!(queue.depth(), 0);
+    }
+
+    #[tokio::test]
+    async fn test_slow_send_holds_up_only_its_guild() {
+        let queue = MessageQueue::start(20);
+        let sent = Arc::new(Mutex::new(Vec::new()));
+        let (release_tx, release_rx) = oneshot::channel::<()>();
+        queue
+            .push(1, async move {
+                let _ = release_rx.await;
+            })
+            .unwrap();
+        let stalled = sent.clone();
+        queue
+            .push(1, async move {
+                stalled.lock().unwrap().push(1);
+            })
+            .unwrap();
+        let other = sent.clone();
+        tokio::time::timeout(
+            Duration::from_secs(1),
+            queue.send(2, async move {
+                other.lock().unwrap().push(2);
+            }),
+        )
+        .await
+        .expect("The stalled guild held up the other guild")
+        .unwrap();
+        // the later message of the stalled guild waits for the stalled one
+        assert_eq!(*sent.lock().unwrap(), vec![2]);
+        assert_eq!(queue.depth(), 2);
+
+        release_tx.send(()).unwrap();
+        queue.send(1, async {}).await.unwrap();
+        assert_eq!(*sent.lock().unwrap(), vec![2, 1]);
+        assert_eq!(queue.depth(), 0);
+    }
+}
@@ -1,16 +1,40 @@
-use anyhow::{bail, Result};
+use crate::config::CONFIG;
+use anyhow::{anyhow, bail, Context, Result};
 use async_trait::async_trait;
-use colony_rs::H160;
+use colony_rs::{H160, U256};
 use dyn_clone::DynClone;
 use serde::{Deserialize, Serialize};
+use serenity::model::{gateway::GatewayIntents, permissions::Permissions};
 use std::boxed::Box;
+use std::collections::hash_map::DefaultHasher;
 use std::fmt::Display;
+use std::hash::{Hash, Hasher};
+mod allowlist;
+pub use allowlist::{AllowlistGate, InvalidAddress};
+mod builder;
+pub use builder::GateBuilder;
+mod client;
+pub use client::{
+    init_reputation_client, init_token_client, wallet_name, ColonyClient, ReputationClient,
+    TokenClient, WalletActivity,
+};
+mod composite;
+pub use composite::CompositeGate;
 mod reputation;
 pub use reputation::ReputationGate;
 pub use reputation::PRECISION_FACTOR;
+pub(crate) mod rpc;
+mod staked_token;
+pub use staked_token::StakedTokenGate;
+mod tiered_token;
+pub use tiered_token::TieredTokenGate;
 mod token;
 pub use token::TokenGate;
-use tracing::{instrument, Instrument};
+mod token_supply_share;
+pub use token_supply_share::TokenSupplyShareGate;
+mod wallet_age;
+use tracing::{debug, instrument, warn, Instrument};
+pub use wallet_age::WalletAgeGate;
 
 /// This macro gives us a way to access associated functions on all GatingConditions
 /// A new GatingCondition must be added to This macro to be useful in different
@@ -52,24 +76,338 @@ macro_rules! gates {
             construct
         }
     };
+
+    (@static_constructor: $($gate:ident),*) => {
+        {
+            async fn construct(gate_type: &str, options: &[GateOptionValue]) -> Result<Box<dyn $crate::gate::GatingCondition>> {
+                $(
+                    if $crate::gate::$gate::name() == gate_type {
+                        if !$crate::gate::$gate::is_static() {
+                            bail!("The {} gate needs network lookups and can not be created offline", gate_type);
+                        }
+                        return Ok($crate::gate::$gate::from_options(options).await? as Box<dyn $crate::gate::GatingCondition>);
+                    }
+                )*
+                bail!("Unknown gate type: {}", gate_type)
+            }
+            construct
+        }
+    };
+    (@intents: $($gate:ident),*) => {
+        {
+            use $crate::gate::GatingCondition;
+            serenity::model::gateway::GatewayIntents::empty()
+                $(| $crate::gate::$gate::required_intents())*
+        }
+    };
+
+    (@permissions: $($gate:ident),*) => {
+        {
+            use $crate::gate::GatingCondition;
+            serenity::model::permissions::Permissions::empty()
+                $(| $crate::gate::$gate::required_permissions())*
+        }
+    };
     ($($slector:ident)*) => {
         // Here new gating conditions can be added as long as they implement the
         // GatingCondition trait.
-        gates!(@$($slector)*: ReputationGate, TokenGate)
+        gates!(@$($slector)*: ReputationGate, TokenGate, StakedTokenGate, AllowlistGate, CompositeGate, TieredTokenGate, WalletAgeGate, TokenSupplyShareGate)
     };
 }
 
+/// Prefix of stored gates, followed by a single version byte. Gates stored
+/// before versioning was introduced have no prefix
+const GATE_MARKER: &[u8] = b"GATE";
+
+/// The storage layout version written by [`Gate::to_bytes`]. Bump this and
+/// add a legacy struct for the previous layout to [`Gate::from_bytes`]
+/// whenever the fields of the gate change
+pub const GATE_VERSION: u8 = 3;
+
+/// The chain of gates that don't name one, they were all created for gnosis
+pub const DEFAULT_CHAIN_ID: u64 = 100;
+
+/// The chain id new gates are created with
+pub(crate) fn default_chain_id() -> U256 {
+    U256::from(DEFAULT_CHAIN_ID)
+}
+
 #[derive(Clone, Debug, Eq, Deserialize, Serialize)]
 pub struct Gate {
     /// The role to be granted
     pub role_id: u64,
     pub condition: Box<dyn GatingCondition>,
+    /// Disabled gates are kept in storage but skipped when checking users.
+    /// This is not part of the identifier, so toggling a gate keeps it
+    /// addressable under the same key
+    #[serde(default = "enabled_by_default")]
+    pub enabled: bool,
+    /// An optional message shown to the user when this gate grants the role.
+    /// Like `enabled`, this is not part of the identifier
+    #[serde(default)]
+    pub grant_message: Option<String>,
+}
+
+fn enabled_by_default() -> bool {
+    true
+}
+
+/// The layout gates were stored with after the `enabled` flag and before the
+/// `grant_message` was added
+#[derive(Deserialize)]
+struct LegacyToggleableGate {
+    role_id: u64,
+    condition: Box<dyn GatingCondition>,
+    enabled: bool,
+}
+
+impl From<LegacyToggleableGate> for Gate {
+    fn from(legacy: LegacyToggleableGate) -> Self {
+        Self {
+            role_id: legacy.role_id,
+            condition: legacy.condition,
+            enabled: legacy.enabled,
+            grant_message: None,
+        }
+    }
+}
+
+/// The layout gates were stored with before the `enabled` flag was added.
+/// Bincode is not self describing, so the serde default alone does not help
+/// when reading those entries and we need to fall back to this explicitly.
+#[derive(Deserialize)]
+struct LegacyGate {
+    role_id: u64,
+    condition: Box<dyn GatingCondition>,
+}
+
+impl From<LegacyGate> for Gate {
+    fn from(legacy: LegacyGate) -> Self {
+        Self {
+            role_id: legacy.role_id,
+            condition: legacy.condition,
+            enabled: true,
+            grant_message: None,
+        }
+    }
+}
+
+/// The layout gates were stored with before they named their chain. Back
+/// then only reputation and token gates existed and all of them were created
+/// for gnosis, so they are read with the [`DEFAULT_CHAIN_ID`]
+#[derive(Deserialize)]
+struct PreMultichainGate {
+    role_id: u64,
+    condition: PreMultichainCondition,
+}
+
+#[derive(Deserialize)]
+struct PreMultichainReputationGate {
+    colony_address: H160,
+    colony_name: String,
+    colony_domain: u64,
+    reputation_threshold_scaled: U256,
+}
+
+#[derive(Deserialize)]
+struct PreMultichainTokenGate {
+    token_address: H160,
+    token_symbol: String,
+    token_decimals: u8,
+    amount: u64,
+}
+
+enum PreMultichainCondition {
+    Reputation(PreMultichainReputationGate),
+    Token(PreMultichainTokenGate),
+}
+
+/// The conditions were tagged by typetag, which writes a map with the type
+/// name as the only key, so the tag is read by hand instead of deriving an
+/// enum, which bincode would expect as a variant index
+impl<'de> Deserialize<'de> for PreMultichainCondition {
+    fn deserialize<D: serde::Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        struct TagVisitor;
+
+        impl<'de> serde::de::Visitor<'de> for TagVisitor {
+            type Value = PreMultichainCondition;
+
+            fn expecting(&self, formatter: &mut std::fmt::Formatter) -> std::fmt::Result {
+                formatter.write_str("a reputation or token gate without a chain id")
+            }
+
+            fn visit_map<A: serde::de::MapAccess<'de>>(
+                self,
+                mut map: A,
+            ) -> Result<Self::Value, A::Error> {
+                let tag: String = match map.next_key()? {
+                    Some(tag) => tag,
+                    None => return Err(serde::de::Error::invalid_length(0, &self)),
+                };
+                match tag.as_str() {
+                    "ReputationGate" => Ok(PreMultichainCondition::Reputation(map.next_value()?)),
+                    "TokenGate" => Ok(PreMultichainCondition::Token(map.next_value()?)),
+                    _ => Err(serde::de::Error::unknown_variant(
+                        &tag,
+                        &["ReputationGate", "TokenGate"],
+                    )),
+                }
+            }
+        }
+
+        deserializer.deserialize_map(TagVisitor)
+    }
+}
+
+impl From<PreMultichainGate> for Gate {
+    fn from(legacy: PreMultichainGate) -> Self {
+        let condition: Box<dyn GatingCondition> = match legacy.condition {
+            PreMultichainCondition::Reputation(gate) => Box::new(ReputationGate {
+                chain_id: default_chain_id(),
+                colony_address: gate.colony_address,
+                colony_name: gate.colony_name,
+                colony_domain: gate.colony_domain,
+                reputation_threshold_scaled: gate.reputation_threshold_scaled,
+            }),
+            PreMultichainCondition::Token(gate) => Box::new(TokenGate {
+                chain_id: default_chain_id(),
+                token_address: gate.token_address,
+                token_symbol: gate.token_symbol,
+                token_decimals: gate.token_decimals,
+                amount: gate.amount,
+            }),
+        };
+        Self {
+            role_id: legacy.role_id,
+            condition,
+            enabled: true,
+            grant_message: None,
+        }
+    }
 }
 
 impl Gate {
     pub async fn new(role_id: u64, gate_type: &str, options: &[GateOptionValue]) -> Result<Self> {
+        // unregistered types are rejected before the options are looked at,
+        // so they don't show up as option errors
+        if !gate_names().contains(&gate_type) {
+            warn!(gate = gate_type, "Failed to create gate of an unknown type");
+            #[cfg(feature = "metrics")]
+            record_unknown_gate_type();
+            return Err(unknown_gate_type(gate_type));
+        }
+        #[cfg(feature = "metrics")]
+        record_creation_attempt(metric_gate_label(gate_type));
+        Self::preview(role_id, gate_type, options)
+            .await
+            .inspect_err(|why| report_creation_failure(gate_type, why))
+    }
+
+    /// Creates a transient gate, e.g. for `/gate preview`. Unlike
+    /// [`Gate::new`] this is not counted or logged as a gate creation
+    pub async fn preview(
+        role_id: u64,
+        gate_type: &str,
+        options: &[GateOptionValue],
+    ) -> Result<Self> {
         let condition = gates!(constructor)(gate_type, options).await?;
-        Ok(Self { role_id, condition })
+        Ok(Self {
+            role_id,
+            condition,
+            enabled: true,
+            grant_message: None,
+        })
+    }
+
+    /// Creates a gate without touching the network. This only works for
+    /// static gates, e.g. allowlists, all other gate types fail with an error
+    /// instead of trying to reach the chain, e.g. for tests and applications
+    /// embedding the gating logic without a colony client
+    pub async fn new_static(
+        role_id: u64,
+        gate_type: &str,
+        options: &[GateOptionValue],
+    ) -> Result<Self> {
+        let condition = gates!(static_constructor)(gate_type, options).await?;
+        Ok(Self {
+            role_id,
+            condition,
+            enabled: true,
+            grant_message: None,
+        })
+    }
+
+    /// Sets the message shown to the user when this gate grants the role
+    pub fn with_grant_message(mut self, grant_message: Option<String>) -> Self {
+        self.grant_message = grant_message.filter(|message| !message.trim().is_empty());
+        self
+    }
+
+    /// Serializes the gate for storage, prefixed with the [`GATE_MARKER`]
+    /// and the current [`GATE_VERSION`]
+    pub fn to_bytes(&self) -> Result<Vec<u8>> {
+        let mut bytes = Vec::from(GATE_MARKER);
+        bytes.push(GATE_VERSION);
+        bincode::serialize_into(&mut bytes, self)?;
+        Ok(bytes)
+    }
+
+    /// Deserializes a stored gate. Versioned entries are decoded with the
+    /// layout of their version, entries written before the version prefix
+    /// existed are decoded with [`Gate::from_unversioned_bytes`]
+    pub fn from_bytes(bytes: &[u8]) -> Result<Self> {
+        match Self::split_version(bytes) {
+            Some((version, payload)) => match Self::decode_version(version, payload) {
+                Ok(gate) => Ok(gate),
+                // an unversioned entry whose role id happens to start with
+                // the marker bytes
+                Err(why) => Self::from_unversioned_bytes(bytes).map_err(|_| why),
+            },
+            None => Self::from_unversioned_bytes(bytes),
+        }
+    }
+
+    /// Whether the stored bytes are already in the current layout and don't
+    /// need to be migrated
+    pub fn is_current(bytes: &[u8]) -> bool {
+        matches!(Self::split_version(bytes), Some((GATE_VERSION, _)))
+    }
+
+    fn split_version(bytes: &[u8]) -> Option<(u8, &[u8])> {
+        let rest = bytes.strip_prefix(GATE_MARKER)?;
+        let (version, payload) = rest.split_first()?;
+        Some((*version, payload))
+    }
+
+    fn decode_version(version: u8, payload: &[u8]) -> Result<Self> {
+        match version {
+            1 => Ok(bincode::deserialize::<LegacyGate>(payload)?.into()),
+            2 => Ok(bincode::deserialize::<LegacyToggleableGate>(payload)?.into()),
+            3 => Ok(bincode::deserialize::<Gate>(payload)?),
+            _ => bail!("Unknown gate storage version {}", version),
+        }
+    }
+
+    /// Deserializes a gate stored without a version prefix, accepting entries
+    /// written before the `enabled` flag or the `grant_message` existed, or
+    /// before gates named their chain. Newer layouts are tried first, since
+    /// bincode happily ignores trailing bytes
+    fn from_unversioned_bytes(bytes: &[u8]) -> Result<Self> {
+        match bincode::deserialize::<Gate>(bytes) {
+            Ok(gate) => Ok(gate),
+            Err(why) => {
+                if let Ok(legacy) = bincode::deserialize::<LegacyToggleableGate>(bytes) {
+                    return Ok(legacy.into());
+                }
+                if let Ok(legacy) = bincode::deserialize::<LegacyGate>(bytes) {
+                    return Ok(legacy.into());
+                }
+                match bincode::deserialize::<PreMultichainGate>(bytes) {
+                    Ok(legacy) => Ok(legacy.into()),
+                    Err(_) => Err(why.into()),
+                }
+            }
+        }
     }
 
     pub fn name(&self) -> &'static str {
@@ -80,13 +418,59 @@ impl Gate {
         self.condition.fields()
     }
 
+    /// Checks the condition for the wallet, returning the role it grants.
+    /// Errors mean the condition could not be checked, not that it is unmet.
+    /// With `bypass_cache` cached lookups are ignored, e.g. for rechecks
     #[instrument(skip(self, address), fields(roled_id = self.role_id, identifier = self.identifier()))]
-    pub async fn check_condition(self, address: H160) -> Option<u64> {
-        if self.condition.check(address).in_current_span().await {
-            Some(self.role_id)
-        } else {
-            None
+    pub async fn check_condition(self, address: H160, bypass_cache: bool) -> Result<Option<u64>> {
+        self.granted_role(address, bypass_cache)
+            .in_current_span()
+            .await
+    }
+
+    /// Like [`Gate::check_condition`], with the reason why the role was
+    /// granted if the condition explains itself
+    #[instrument(skip(self, address), fields(roled_id = self.role_id, identifier = self.identifier()))]
+    pub async fn check_explained(
+        self,
+        address: H160,
+        bypass_cache: bool,
+    ) -> Result<(Option<u64>, Option<String>)> {
+        let granted = self
+            .granted_role(address, bypass_cache)
+            .in_current_span()
+            .await?;
+        if granted.is_none() {
+            return Ok((None, None));
         }
+        let reason = match self.condition.explain(address).in_current_span().await {
+            Ok(reason) => reason,
+            Err(why) => {
+                debug!("Could not explain the granted role: {:?}", why);
+                None
+            }
+        };
+        Ok((granted, reason))
+    }
+
+    /// The role the condition grants, with the metrics feature the duration
+    /// of the check is emitted as well
+    async fn granted_role(&self, address: H160, bypass_cache: bool) -> Result<Option<u64>> {
+        #[cfg(feature = "metrics")]
+        let started = std::time::Instant::now();
+        let granted = self
+            .condition
+            .granted_role(address, self.role_id, bypass_cache)
+            .in_current_span()
+            .await;
+        #[cfg(feature = "metrics")]
+        record_check_duration(self.name(), started);
+        granted
+    }
+
+    /// All roles this gate grants or revokes, see [`GatingCondition::role_ids`]
+    pub fn role_ids(&self) -> Vec<u64> {
+        self.condition.role_ids(self.role_id)
     }
 
     pub fn identifier(&self) -> u128 {
@@ -113,10 +497,79 @@ pub trait GatingCondition: std::fmt::Debug + Send + Sync + DynClone {
     fn options() -> Vec<GateOption>
     where
         Self: Sized;
+    /// Static gates can be created and checked without any network lookups
+    fn is_static() -> bool
+    where
+        Self: Sized,
+    {
+        false
+    }
+    /// The gateway intents the bot needs for gates of this type. All gates
+    /// grant roles to members, so by default the members are needed
+    fn required_intents() -> GatewayIntents
+    where
+        Self: Sized,
+    {
+        GatewayIntents::GUILD_MEMBERS
+    }
+    /// The permissions the bot needs on a server for gates of this type,
+    /// they are requested by the generated invite url
+    fn required_permissions() -> Permissions
+    where
+        Self: Sized,
+    {
+        Permissions::MANAGE_ROLES
+    }
     async fn from_options(options: &[GateOptionValue]) -> Result<Box<Self>>
     where
         Self: Sized;
-    async fn check(&self, wallet_address: H160) -> bool;
+    /// Returns whether the wallet meets the condition, errors are reserved for
+    /// cases where this could not be determined, e.g. failed network lookups
+    async fn check(&self, wallet_address: H160) -> Result<bool>;
+    /// Like [`GatingCondition::check`], but looks up live data even if a
+    /// cached result exists, conditions without a cache just check
+    async fn check_uncached(&self, wallet_address: H160) -> Result<bool> {
+        self.check(wallet_address).await
+    }
+    /// How expensive checking the condition is. The gates of a role are
+    /// checked in ascending cost, so a cheap gate can spare the lookups of
+    /// the expensive ones
+    fn cost(&self) -> GateCost {
+        GateCost::Network
+    }
+    /// Looks up the data of several wallets at once before they are checked
+    /// one by one, so the checks hit the cache. Conditions without batched
+    /// lookups do nothing here
+    async fn prefetch(&self, _wallet_addresses: &[H160]) -> Result<()> {
+        Ok(())
+    }
+    /// The role granted to the wallet by a gate for the given role. Most
+    /// conditions grant that role if they are met, conditions with several
+    /// roles, e.g. tiers, pick one of their [`GatingCondition::role_ids`]
+    async fn granted_role(
+        &self,
+        wallet_address: H160,
+        role_id: u64,
+        bypass_cache: bool,
+    ) -> Result<Option<u64>> {
+        let met = if bypass_cache {
+            self.check_uncached(wallet_address).await?
+        } else {
+            self.check(wallet_address).await?
+        };
+        Ok(met.then_some(role_id))
+    }
+    /// A short reason why the condition is met for the wallet, e.g. the
+    /// measured value against the threshold. Only asked for granted roles,
+    /// so the lookups should hit the cache of the preceding check
+    async fn explain(&self, _wallet_address: H160) -> Result<Option<String>> {
+        Ok(None)
+    }
+    /// All roles a gate for the given role manages. Managed roles that are
+    /// not granted are revoked by the enforcement
+    fn role_ids(&self, role_id: u64) -> Vec<u64> {
+        vec![role_id]
+    }
     fn hashed(&self) -> u64;
     fn fields(&self) -> Vec<GateOptionValue>;
     fn instance_name(&self) -> &'static str;
@@ -124,6 +577,16 @@ pub trait GatingCondition: std::fmt::Debug + Send + Sync + DynClone {
 
 dyn_clone::clone_trait_object!(GatingCondition);
 
+/// A hint how expensive checking a condition is, cheaper conditions are
+/// ordered first
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub enum GateCost {
+    /// Checked against the stored options only, e.g. an allowlist
+    Local,
+    /// Needs lookups over the network, e.g. a contract call
+    Network,
+}
+
 impl Eq for Box<dyn GatingCondition> {}
 
 impl PartialEq for Box<dyn GatingCondition> {
@@ -142,9 +605,11 @@ pub struct GateOption {
 
 #[derive(Debug, Clone)]
 pub enum GateOptionType {
+    /// Bounds are `i64` like the values, as discord expects them for
+    /// integer options
     I64 {
-        min: Option<u64>,
-        max: Option<u64>,
+        min: Option<i64>,
+        max: Option<i64>,
     },
     F64 {
         min: Option<f64>,
@@ -156,6 +621,255 @@ pub enum GateOptionType {
     },
 }
 
+/// A gate that applies to every guild. Role ids are different in every
+/// guild, so the role is given by name and resolved per guild at check time.
+/// The role id of the inner gate is not used
+#[derive(Clone, Debug)]
+pub struct GlobalGate {
+    pub role_name: String,
+    pub gate: Gate,
+}
+
+impl GlobalGate {
+    pub fn new(role_name: String, condition: Box<dyn GatingCondition>) -> Self {
+        Self {
+            role_name,
+            gate: Gate {
+                role_id: 0,
+                condition,
+                enabled: true,
+                grant_message: None,
+            },
+        }
+    }
+
+    /// The gate for a guild in which the role has the given id
+    pub fn for_role(&self, role_id: u64) -> Gate {
+        Gate {
+            role_id,
+            ..self.gate.clone()
+        }
+    }
+
+    /// Like [`Gate::identifier`], but with the hash of the role name instead
+    /// of the role id
+    pub fn identifier(&self) -> u128 {
+        let mut hasher = DefaultHasher::new();
+        self.role_name.hash(&mut hasher);
+        (hasher.finish() as u128) << 64 | self.gate.condition.hashed() as u128
+    }
+}
+
+/// Parses options given as `name=value` pairs, e.g. on the command line,
+/// into the option values of a gate type in the order of its options
+pub fn parse_gate_options(gate_type: &str, args: &[String]) -> Result<Vec<GateOptionValue>> {
+    let options = gates!(options)
+        .remove(gate_type)
+        .ok_or_else(|| unknown_gate_type(gate_type))?;
+    let mut values = args
+        .iter()
+        .map(|arg| {
+            arg.split_once('=')
+                .ok_or(anyhow!("Invalid option {:?}, expected name=value", arg))
+        })
+        .collect::<Result<std::collections::HashMap<_, _>>>()?;
+    let mut option_values = Vec::new();
+    for option in options {
+        let value = match values.remove(option.name) {
+            Some(value) => value.trim(),
+            None if option.required => bail!("The {} option is required", option.name),
+            None => continue,
+        };
+        let value = match option.option_type {
+            GateOptionType::I64 { .. } => GateOptionValueType::I64(
+                value
+                    .parse()
+                    .with_context(|| format!("Invalid integer for {}", option.name))?,
+            ),
+            GateOptionType::F64 { .. } => GateOptionValueType::F64(
+                value
+                    .parse()
+                    .with_context(|| format!("Invalid number for {}", option.name))?,
+            ),
+            GateOptionType::String { .. } => GateOptionValueType::String(value.to_string()),
+        };
+        option_values.push(GateOptionValue {
+            name: option.name.to_string(),
+            value,
+        });
+    }
+    if let Some(name) = values.keys().next() {
+        bail!("Unknown option {} for the {} gate", name, gate_type);
+    }
+    Ok(option_values)
+}
+
+/// The names of all gate types, as they are given on the command line
+pub fn gate_names() -> Vec<&'static str> {
+    gates!(names)
+}
+
+/// The gate type that is most likely meant by a misspelled or partial name,
+/// if any is close enough
+pub fn closest_gate_name(name: &str) -> Option<&'static str> {
+    let name = name.to_lowercase();
+    // longer names may have more typos
+    let tolerance = (name.chars().count() / 3).max(1);
+    gate_names()
+        .into_iter()
+        .map(|known| (mismatch(&name, known), known))
+        .filter(|(mismatch, _)| *mismatch <= tolerance)
+        // the first of the equally close names wins
+        .min_by_key(|(mismatch, _)| *mismatch)
+        .map(|(_, known)| known)
+}
+
+fn unknown_gate_type(gate_type: &str) -> anyhow::Error {
+    match closest_gate_name(gate_type) {
+        Some(closest) => anyhow!(
+            "Unknown gate type {}, did you mean '{}'?",
+            gate_type,
+            closest
+        ),
+        None => anyhow!("Unknown gate type {}", gate_type),
+    }
+}
+
+/// How far a name is from a gate type. The start of the type or of one of
+/// its words, like `rep` or `supply`, is a partial match, which ranks behind
+/// a whole match with the same number of edits
+fn mismatch(name: &str, known: &str) -> usize {
+    let length = name.chars().count();
+    let partial = std::iter::once(known)
+        .chain(known.split('_'))
+        .map(|part| edit_distance(name, &part.chars().take(length).collect::<String>()) + 1)
+        .min()
+        .unwrap_or(usize::MAX);
+    edit_distance(name, known).min(partial)
+}
+
+/// The number of inserted, removed or replaced characters that turn one
+/// string into the other
+fn edit_distance(from: &str, to: &str) -> usize {
+    let to = to.chars().collect::<Vec<_>>();
+    let mut previous = (0..=to.len()).collect::<Vec<_>>();
+    for (i, from_char) in from.chars().enumerate() {
+        let mut current = vec![i + 1; to.len() + 1];
+        for (j, to_char) in to.iter().enumerate() {
+            let replaced = previous[j] + usize::from(from_char != *to_char);
+            current[j + 1] = replaced.min(previous[j + 1] + 1).min(current[j] + 1);
+        }
+        previous = current;
+    }
+    previous[to.len()]
+}
+
+/// A gate option that was rejected when creating a gate. It displays as the
+/// message for the user, the kind tells apart why gate creations fail
+#[derive(Debug)]
+struct InvalidOption {
+    kind: InvalidOptionKind,
+    message: String,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum InvalidOptionKind {
+    /// An address option is not an address
+    Address,
+    /// A number is outside of its bounds, e.g. a domain the colony lacks
+    OutOfRange,
+    /// The colony or token is not allowed by the configuration
+    NotAllowed,
+}
+
+impl InvalidOption {
+    fn address(message: impl Into<String>) -> Self {
+        Self {
+            kind: InvalidOptionKind::Address,
+            message: message.into(),
+        }
+    }
+
+    fn out_of_range(message: impl Into<String>) -> Self {
+        Self {
+            kind: InvalidOptionKind::OutOfRange,
+            message: message.into(),
+        }
+    }
+
+    fn not_allowed(message: impl Into<String>) -> Self {
+        Self {
+            kind: InvalidOptionKind::NotAllowed,
+            message: message.into(),
+        }
+    }
+}
+
+impl Display for InvalidOption {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(&self.message)
+    }
+}
+
+impl std::error::Error for InvalidOption {}
+
+/// Why a gate could not be created, as label for the logs and metrics.
+/// Errors that are not an [`InvalidOption`], e.g. a failed lookup, are
+/// `other`
+fn creation_failure_reason(why: &anyhow::Error) -> &'static str {
+    let kind = why
+        .chain()
+        .find_map(|cause| cause.downcast_ref::<InvalidOption>())
+        .map(|invalid| invalid.kind);
+    match kind {
+        Some(InvalidOptionKind::Address) => "invalid_address",
+        Some(InvalidOptionKind::OutOfRange) => "out_of_range",
+        Some(InvalidOptionKind::NotAllowed) => "not_allowed",
+        None => "other",
+    }
+}
+
+/// Logs a failed gate creation with the reason, the guild is part of the
+/// span of the interaction
+fn report_creation_failure(gate_type: &str, why: &anyhow::Error) {
+    let reason = creation_failure_reason(why);
+    warn!(gate = gate_type, reason, "Failed to create gate: {:#}", why);
+    #[cfg(feature = "metrics")]
+    record_creation_failure(metric_gate_label(gate_type), reason);
+}
+
+/// Fails if the address is not on the allowed list, an empty list allows
+/// every address
+fn check_allowed(address: H160, allowed: &[H160], what: &str) -> Result<()> {
+    if !allowed.is_empty() && !allowed.contains(&address) {
+        bail!(InvalidOption::not_allowed(format!(
+            "The {} {:?} is not allowed for gates on this bot, ask the operator to allow it",
+            what, address
+        )));
+    }
+    Ok(())
+}
+
+/// Fails if colonies are restricted in the configuration and the colony is
+/// not allowed
+fn check_allowed_colony(colony: H160) -> Result<()> {
+    let allowed = CONFIG
+        .get()
+        .map(|config| config.allowed_colonies.as_slice())
+        .unwrap_or_default();
+    check_allowed(colony, allowed, "colony")
+}
+
+/// Fails if tokens are restricted in the configuration and the token is not
+/// allowed
+fn check_allowed_token(token: H160) -> Result<()> {
+    let allowed = CONFIG
+        .get()
+        .map(|config| config.allowed_tokens.as_slice())
+        .unwrap_or_default();
+    check_allowed(token, allowed, "token")
+}
+
 #[derive(Debug, Clone)]
 pub struct GateOptionValue {
     pub name: String,
@@ -179,9 +893,76 @@ impl Display for GateOptionValueType {
     }
 }
 
+/// Emits the duration of a gate check as histogram event, labeled by the
+/// gate. With telemetry the id of the trace the check belongs to is kept as
+/// exemplar, so the trace of a slow check can be looked up
+#[cfg(feature = "metrics")]
+fn record_check_duration(gate: &'static str, started: std::time::Instant) {
+    let duration = started.elapsed().as_secs_f64();
+    tracing::info!(
+        histogram.gate_check_duration_seconds = duration,
+        gate,
+        "Checked gate"
+    );
+    crate::logging::record_exemplar("gate_check_duration_seconds", ("gate", gate), duration);
+}
+
+/// The gate type as metric label, types that don't exist are grouped, so
+/// typos don't create new label values
+#[cfg(feature = "metrics")]
+fn metric_gate_label(gate_type: &str) -> &'static str {
+    gate_names()
+        .into_iter()
+        .find(|name| *name == gate_type)
+        .unwrap_or("unknown")
+}
+
+/// Counts an attempt to create a gate, labeled by the gate type
+#[cfg(feature = "metrics")]
+fn record_creation_attempt(gate: &'static str) {
+    tracing::info!(
+        monotonic_counter.gate_creation_attempts = 1u64,
+        gate,
+        "Creating gate"
+    );
+}
+
+/// Counts an attempt to create a gate of a type that doesn't exist. The
+/// type is not a label, so typos don't create new label values
+#[cfg(feature = "metrics")]
+fn record_unknown_gate_type() {
+    tracing::info!(
+        monotonic_counter.unknown_gate_types = 1u64,
+        "Unknown gate type"
+    );
+}
+
+/// Counts a failed gate creation, labeled by the gate type and the reason
+#[cfg(feature = "metrics")]
+fn record_creation_failure(gate: &'static str, reason: &'static str) {
+    tracing::info!(
+        monotonic_counter.gate_creation_failures = 1u64,
+        gate,
+        reason,
+        "Failed to create gate"
+    );
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
+    use std::str::FromStr;
+
+    #[test]
+    fn test_check_allowed() {
+        let colony = H160::repeat_byte(1);
+        let other = H160::repeat_byte(2);
+        assert!(check_allowed(colony, &[], "colony").is_ok());
+        assert!(check_allowed(colony, &[other, colony], "colony").is_ok());
+        let error = check_allowed(colony, &[other], "colony").unwrap_err();
+        assert!(error.to_string().contains("not allowed"));
+        assert!(error.to_string().contains(&format!("{:?}", colony)));
+    }
 
     #[tokio::test]
     async fn test_reputation_gate_from_options() {
@@ -278,14 +1059,405 @@ mod tests {
         assert_eq!(*amount, 1);
     }
 
+    fn token_gate(role_id: u64, enabled: bool) -> Gate {
+        Gate {
+            role_id,
+            condition: Box::new(TokenGate {
+                chain_id: colony_rs::U256::from(100),
+                token_address: H160::zero(),
+                token_symbol: "CLNY".to_string(),
+                token_decimals: 18,
+                amount: 1,
+            }),
+            enabled,
+            grant_message: None,
+        }
+    }
+
+    #[test]
+    fn test_enabled_does_not_change_identifier() {
+        let enabled = token_gate(1, true);
+        let disabled = token_gate(1, false);
+        assert_eq!(enabled.identifier(), disabled.identifier());
+    }
+
+    #[test]
+    fn test_legacy_gate_deserializes_enabled() {
+        #[derive(Serialize)]
+        struct LegacyGateRef<'a> {
+            role_id: u64,
+            condition: &'a dyn GatingCondition,
+        }
+        let gate = token_gate(1, false);
+        let bytes = bincode::serialize(&LegacyGateRef {
+            role_id: gate.role_id,
+            condition: gate.condition.as_ref(),
+        })
+        .unwrap();
+        let decoded = Gate::from_bytes(&bytes).unwrap();
+        assert_eq!(decoded.identifier(), gate.identifier());
+        assert!(decoded.enabled);
+
+        let bytes = bincode::serialize(&gate).unwrap();
+        let decoded = Gate::from_bytes(&bytes).unwrap();
+        assert!(!decoded.enabled);
+    }
+
+    #[test]
+    fn test_toggleable_gate_deserializes_without_message() {
+        #[derive(Serialize)]
+        struct LegacyToggleableGateRef<'a> {
+            role_id: u64,
+            condition: &'a dyn GatingCondition,
+            enabled: bool,
+        }
+        let gate = token_gate(1, false);
+        let bytes = bincode::serialize(&LegacyToggleableGateRef {
+            role_id: gate.role_id,
+            condition: gate.condition.as_ref(),
+            enabled: gate.enabled,
+        })
+        .unwrap();
+        let decoded = Gate::from_bytes(&bytes).unwrap();
+        assert_eq!(decoded.identifier(), gate.identifier());
+        assert!(!decoded.enabled);
+        assert_eq!(decoded.grant_message, None);
+
+        let gate = gate.with_grant_message(Some("Welcome to the core team!".to_string()));
+        let bytes = bincode::serialize(&gate).unwrap();
+        let decoded = Gate::from_bytes(&bytes).unwrap();
+        assert_eq!(decoded.identifier(), token_gate(1, false).identifier());
+        assert_eq!(
+            decoded.grant_message.as_deref(),
+            Some("Welcome to the core team!")
+        );
+    }
+
+    #[test]
+    fn test_pre_multichain_gate_defaults_to_gnosis() {
+        #[derive(Serialize)]
+        struct PreMultichainTokenGateRef {
+            token_address: H160,
+            token_symbol: String,
+            token_decimals: u8,
+            amount: u64,
+        }
+        // typetag wrote the condition as a map from its type to its fields
+        let condition = std::collections::HashMap::from([(
+            "TokenGate",
+            PreMultichainTokenGateRef {
+                token_address: H160::zero(),
+                token_symbol: "CLNY".to_string(),
+                token_decimals: 18,
+                amount: 1,
+            },
+        )]);
+        let bytes = bincode::serialize(&(1u64, condition)).unwrap();
+        assert!(!Gate::is_current(&bytes));
+        let decoded = Gate::from_bytes(&bytes).unwrap();
+        assert!(matches!(
+            &decoded.fields()[0].value,
+            GateOptionValueType::String(chain_id) if chain_id == "0x64"
+        ));
+        assert_eq!(decoded.identifier(), token_gate(1, true).identifier());
+        assert!(decoded.enabled);
+    }
+
+    #[test]
+    fn test_versioned_gate_round_trip() {
+        #[derive(Serialize)]
+        struct LegacyGateRef<'a> {
+            role_id: u64,
+            condition: &'a dyn GatingCondition,
+        }
+        let gate = token_gate(1, false);
+        let v1 = bincode::serialize(&LegacyGateRef {
+            role_id: gate.role_id,
+            condition: gate.condition.as_ref(),
+        })
+        .unwrap();
+        let mut versioned_v1 = Vec::from(GATE_MARKER);
+        versioned_v1.push(1);
+        versioned_v1.extend(&v1);
+        for bytes in [&v1, &versioned_v1] {
+            assert!(!Gate::is_current(bytes));
+            let decoded = Gate::from_bytes(bytes).unwrap();
+            assert_eq!(decoded.identifier(), gate.identifier());
+            assert!(decoded.enabled);
+        }
+
+        let bytes = gate.to_bytes().unwrap();
+        assert!(Gate::is_current(&bytes));
+        let decoded = Gate::from_bytes(&bytes).unwrap();
+        assert_eq!(decoded.identifier(), gate.identifier());
+        assert!(!decoded.enabled);
+
+        let mut unknown = Vec::from(GATE_MARKER);
+        unknown.push(GATE_VERSION + 1);
+        unknown.extend(bincode::serialize(&gate).unwrap());
+        assert!(Gate::from_bytes(&unknown).is_err());
+    }
+
+    #[test]
+    fn test_parse_gate_options() {
+        let args = vec![
+            "amount=5".to_string(),
+            "token_address=0xc9B6218AffE8Aba68a13899Cbf7cF7f14DDd304C".to_string(),
+        ];
+        let options = parse_gate_options("token", &args).unwrap();
+        // the options are in the order of the gate, not of the arguments
+        assert_eq!(options[0].name, "token_address");
+        assert_eq!(options[1].name, "amount");
+        assert!(matches!(options[1].value, GateOptionValueType::I64(5)));
+
+        assert!(parse_gate_options("token", &args[..1]).is_err());
+        assert!(parse_gate_options("token", &["amount=five".to_string()]).is_err());
+        assert!(parse_gate_options("unknown", &args).is_err());
+        let mut extra = args.clone();
+        extra.push("color=red".to_string());
+        assert!(parse_gate_options("token", &extra).is_err());
+    }
+
+    #[test]
+    fn test_misspelled_gate_type_suggests_closest() {
+        assert_eq!(closest_gate_name("reputaton"), Some("reputation"));
+        assert_eq!(closest_gate_name("Tokn"), Some("token"));
+        assert_eq!(closest_gate_name("stakedtoken"), Some("staked_token"));
+        assert_eq!(closest_gate_name("rep"), Some("reputation"));
+        assert_eq!(closest_gate_name("supply"), Some("token_supply_share"));
+        assert_eq!(closest_gate_name("nft"), None);
+        assert_eq!(edit_distance("kitten", "sitting"), 3);
+
+        let why = parse_gate_options("alowlist", &[]).unwrap_err();
+        assert!(
+            why.to_string().contains("did you mean 'allowlist'?"),
+            "{}",
+            why
+        );
+    }
+
+    #[test]
+    fn test_global_gate_for_role() {
+        let global = GlobalGate::new("Holder".to_string(), token_gate(1, true).condition);
+        let first = global.for_role(10);
+        let second = global.for_role(20);
+        assert_eq!(first.role_id, 10);
+        assert_eq!(second.role_id, 20);
+        assert_ne!(first.identifier(), second.identifier());
+        let other = GlobalGate::new("Whale".to_string(), token_gate(1, true).condition);
+        assert_ne!(global.identifier(), other.identifier());
+    }
+
     #[test]
     fn test_gate_macros() {
         let names = gates!(names);
-        assert_eq!(names, vec!["reputation", "token"]);
+        assert_eq!(
+            names,
+            vec![
+                "reputation",
+                "token",
+                "staked_token",
+                "allowlist",
+                "combo",
+                "tiered_token",
+                "wallet_age",
+                "token_supply_share"
+            ]
+        );
         let option_map = gates!(options);
         eprintln!("{:#?}", option_map);
-        assert_eq!(option_map.len(), 2);
-        assert_eq!(option_map["reputation"].len(), 3);
+        assert_eq!(option_map.len(), 8);
+        assert_eq!(option_map["reputation"].len(), 4);
         assert_eq!(option_map["token"].len(), 2);
+        assert_eq!(option_map["staked_token"].len(), 3);
+        assert_eq!(option_map["allowlist"].len(), 1);
+        assert_eq!(option_map["combo"].len(), 5);
+        assert_eq!(option_map["tiered_token"].len(), 3);
+        assert_eq!(option_map["wallet_age"].len(), 2);
+        assert_eq!(option_map["token_supply_share"].len(), 2);
+    }
+
+    #[test]
+    fn test_required_intents_and_permissions() {
+        let intents = gates!(intents);
+        assert!(intents.contains(GatewayIntents::GUILD_MEMBERS));
+        assert_eq!(gates!(permissions), Permissions::MANAGE_ROLES);
+    }
+
+    #[tokio::test]
+    async fn test_static_gate_without_network() {
+        let options = vec![GateOptionValue {
+            name: "wallets".to_string(),
+            value: GateOptionValueType::String(
+                "0xcB313f361847e245954FD338Cb21b5F4225b17d1, \
+                 0xc9B6218AffE8Aba68a13899Cbf7cF7f14DDd304C"
+                    .to_string(),
+            ),
+        }];
+        let gate = Gate::new_static(1, "allowlist", &options).await.unwrap();
+        let wallet = H160::from_str("0xcB313f361847e245954FD338Cb21b5F4225b17d1").unwrap();
+        assert_eq!(gate.check_condition(wallet, false).await.unwrap(), Some(1));
+    }
+
+    #[tokio::test]
+    async fn test_network_gate_fails_offline() {
+        let options = vec![
+            GateOptionValue {
+                name: "colony".to_string(),
+                value: GateOptionValueType::String(
+                    "0xCFD3aa1EbC6119D80Ed47955a87A9d9C281A97B3".to_string(),
+                ),
+            },
+            GateOptionValue {
+                name: "domain".to_string(),
+                value: GateOptionValueType::I64(1),
+            },
+            GateOptionValue {
+                name: "reputation".to_string(),
+                value: GateOptionValueType::F64(0.1),
+            },
+        ];
+        let why = Gate::new_static(1, "reputation", &options)
+            .await
+            .unwrap_err();
+        assert!(why.to_string().contains("needs network lookups"));
+    }
+
+    #[tokio::test]
+    async fn test_only_allowlist_is_static() {
+        // all other gates look up token or colony data when they are created
+        for name in gate_names() {
+            let why = Gate::new_static(1, name, &[]).await.unwrap_err();
+            assert_eq!(
+                why.to_string().contains("needs network lookups"),
+                name != "allowlist",
+                "{}: {}",
+                name,
+                why
+            );
+        }
+    }
+
+    /// Reputation options with domain 0, which is rejected before the domain
+    /// count of the colony is looked up
+    fn domain_out_of_range_options() -> Vec<GateOptionValue> {
+        vec![
+            GateOptionValue {
+                name: "colony".to_string(),
+                value: GateOptionValueType::String(
+                    "0xCFD3aa1EbC6119D80Ed47955a87A9d9C281A97B3".to_string(),
+                ),
+            },
+            GateOptionValue {
+                name: "domain".to_string(),
+                value: GateOptionValueType::I64(0),
+            },
+            GateOptionValue {
+                name: "reputation".to_string(),
+                value: GateOptionValueType::F64(0.1),
+            },
+        ]
+    }
+
+    #[tokio::test]
+    async fn test_creation_failure_reason() {
+        let why = Gate::new(1, "reputation", &domain_out_of_range_options())
+            .await
+            .unwrap_err();
+        // the message for the user stays the same
+        assert_eq!(why.to_string(), "Domain must be greater than 0");
+        assert_eq!(creation_failure_reason(&why), "out_of_range");
+
+        let why = AllowlistGate::from_options(&[GateOptionValue {
+            name: "wallets".to_string(),
+            value: GateOptionValueType::String("0x123".to_string()),
+        }])
+        .await
+        .unwrap_err();
+        assert_eq!(creation_failure_reason(&why), "invalid_address");
+
+        let allowed = [H160::from_low_u64_be(1)];
+        let why = check_allowed(H160::from_low_u64_be(2), &allowed, "colony")
+            .context("Failed to create reputation gate")
+            .unwrap_err();
+        assert_eq!(creation_failure_reason(&why), "not_allowed");
+
+        assert_eq!(creation_failure_reason(&anyhow!("timeout")), "other");
+    }
+
+    #[cfg(feature = "metrics")]
+    #[tokio::test]
+    async fn test_domain_out_of_range_counts_failure() {
+        let (recorder, _guard) = crate::logging::MetricRecorder::install("monotonic_counter.");
+        Gate::new(1, "reputation", &domain_out_of_range_options())
+            .await
+            .unwrap_err();
+
+        let events = recorder.events();
+        assert_eq!(events.len(), 2);
+        assert_eq!(events[0]["monotonic_counter.gate_creation_attempts"], "1");
+        assert_eq!(events[0]["gate"], "reputation");
+        assert_eq!(events[1]["monotonic_counter.gate_creation_failures"], "1");
+        assert_eq!(events[1]["gate"], "reputation");
+        assert_eq!(events[1]["reason"], "out_of_range");
+    }
+
+    #[cfg(feature = "metrics")]
+    #[tokio::test]
+    async fn test_unknown_type_is_not_an_option_failure() {
+        let (recorder, _guard) = crate::logging::MetricRecorder::install("monotonic_counter.");
+        let why = Gate::new(1, "reputaton", &domain_out_of_range_options())
+            .await
+            .unwrap_err();
+        assert_eq!(
+            why.to_string(),
+            "Unknown gate type reputaton, did you mean 'reputation'?"
+        );
+
+        let events = recorder.events();
+        assert_eq!(events.len(), 1);
+        assert_eq!(events[0]["monotonic_counter.unknown_gate_types"], "1");
+        assert!(!events[0].contains_key("gate"));
+    }
+
+    #[cfg(feature = "metrics")]
+    #[tokio::test]
+    async fn test_preview_is_not_counted() {
+        let (recorder, _guard) = crate::logging::MetricRecorder::install("monotonic_counter.");
+        Gate::preview(1, "reputation", &domain_out_of_range_options())
+            .await
+            .unwrap_err();
+        assert!(recorder.events().is_empty());
+    }
+
+    #[cfg(all(feature = "metrics", feature = "jaeger-telemetry"))]
+    #[test]
+    fn test_check_duration_carries_trace_id() {
+        use opentelemetry::trace::TracerProvider;
+        use tracing_subscriber::prelude::*;
+
+        let tracer = opentelemetry::sdk::trace::TracerProvider::builder()
+            .build()
+            .tracer("test");
+        let subscriber =
+            tracing_subscriber::registry().with(tracing_opentelemetry::layer().with_tracer(tracer));
+        // without an active span there is no trace to link
+        tracing::subscriber::with_default(subscriber, || {
+            record_check_duration("exemplar_test", std::time::Instant::now());
+            assert!(crate::logging::exemplar(
+                "gate_check_duration_seconds",
+                ("gate", "exemplar_test")
+            )
+            .is_none());
+            let span = tracing::info_span!("check");
+            let _enter = span.enter();
+            record_check_duration("exemplar_test", std::time::Instant::now());
+        });
+        let exemplar =
+            crate::logging::exemplar("gate_check_duration_seconds", ("gate", "exemplar_test"))
+                .unwrap();
+        assert_eq!(exemplar.trace_id.len(), 32);
+        assert_ne!(exemplar.trace_id, "0".repeat(32));
+        assert!(exemplar.value >= 0.0);
     }
 }
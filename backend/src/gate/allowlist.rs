@@ -0,0 +1,195 @@
+use crate::gate::{
+    GateCost, GateOption, GateOptionType, GateOptionValue, GateOptionValueType, GatingCondition,
+    InvalidOption,
+};
+use anyhow::{bail, Result};
+use async_trait::async_trait;
+use colony_rs::H160;
+use serde::{Deserialize, Serialize};
+use std::boxed::Box;
+use std::collections::hash_map::DefaultHasher;
+use std::hash::{Hash, Hasher};
+use std::str::FromStr;
+use tracing::{debug, instrument};
+
+/// Represents a gate for a discord role that is granted to a fixed list of
+/// wallet addresses. It does not need any network lookups, neither for
+/// creation nor for checking.
+#[derive(Debug, Clone, Deserialize, Hash, Serialize, PartialEq, Eq)]
+pub struct AllowlistGate {
+    /// The sorted and deduplicated wallet addresses that are granted the role
+    pub wallets: Vec<H160>,
+}
+
+#[typetag::serde]
+#[async_trait]
+impl GatingCondition for AllowlistGate {
+    fn name() -> &'static str {
+        "allowlist"
+    }
+
+    fn description() -> &'static str {
+        "Guards a role with a fixed list of wallet addresses"
+    }
+
+    fn options() -> Vec<GateOption> {
+        vec![GateOption {
+            name: "wallets",
+            description: "The wallet addresses separated by commas or spaces",
+            required: true,
+            option_type: GateOptionType::String {
+                min_length: Some(42),
+                max_length: Some(6000),
+            },
+        }]
+    }
+
+    fn is_static() -> bool {
+        true
+    }
+
+    fn cost(&self) -> GateCost {
+        GateCost::Local
+    }
+
+    #[instrument(level = "debug")]
+    async fn from_options(options: &[GateOptionValue]) -> Result<Box<Self>> {
+        debug!("Creating allowlist gate from options");
+        if options.len() != 1 {
+            bail!("Need exactly 1 option");
+        }
+        if options[0].name != "wallets" {
+            bail!("First option must be wallets");
+        }
+        let wallets = match &options[0].value {
+            GateOptionValueType::String(s) => parse_wallets(s)?,
+            _ => bail!("Invalid option type, expected string for wallets"),
+        };
+        debug!("Done creating allowlist gate from options");
+        Ok(Box::new(AllowlistGate { wallets }))
+    }
+
+    #[instrument(name = "allowlist_condition", skip(wallet_address))]
+    async fn check(&self, wallet_address: H160) -> Result<bool> {
+        Ok(self.wallets.binary_search(&wallet_address).is_ok())
+    }
+
+    async fn explain(&self, _wallet_address: H160) -> Result<Option<String>> {
+        Ok(Some("wallet is on the allowlist".to_string()))
+    }
+
+    fn hashed(&self) -> u64 {
+        let mut hasher = DefaultHasher::new();
+        self.hash(&mut hasher);
+        hasher.finish()
+    }
+
+    fn fields(&self) -> Vec<GateOptionValue> {
+        vec![GateOptionValue {
+            name: "wallets".to_string(),
+            value: GateOptionValueType::I64(self.wallets.len() as i64),
+        }]
+    }
+
+    fn instance_name(&self) -> &'static str {
+        Self::name()
+    }
+}
+
+/// An entry of an imported allowlist file that is not a wallet address
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct InvalidAddress {
+    /// The line of the entry, starting at 1
+    pub line: usize,
+    pub value: String,
+}
+
+impl AllowlistGate {
+    /// Reads an allowlist from a CSV file or a file with one address per
+    /// line. Entries that are not addresses are skipped and returned with
+    /// their line number, so a single typo doesn't fail a large import
+    pub fn from_csv(content: &str) -> Result<(Self, Vec<InvalidAddress>)> {
+        let mut wallets = Vec::new();
+        let mut invalid = Vec::new();
+        for (index, line) in content.lines().enumerate() {
+            for value in line
+                .split(',')
+                .map(|value| value.trim().trim_matches('"').trim())
+                .filter(|value| !value.is_empty())
+            {
+                match H160::from_str(value) {
+                    Ok(wallet) => wallets.push(wallet),
+                    Err(_) => invalid.push(InvalidAddress {
+                        line: index + 1,
+                        value: value.to_string(),
+                    }),
+                }
+            }
+        }
+        if wallets.is_empty() {
+            bail!("Allowlist must contain at least one wallet address");
+        }
+        wallets.sort();
+        wallets.dedup();
+        Ok((AllowlistGate { wallets }, invalid))
+    }
+}
+
+/// Parses a list of wallet addresses separated by commas or whitespace into
+/// a sorted list without duplicates
+fn parse_wallets(s: &str) -> Result<Vec<H160>> {
+    let mut wallets = s
+        .split(|c: char| c == ',' || c.is_whitespace())
+        .filter(|w| !w.is_empty())
+        .map(|w| {
+            H160::from_str(w).map_err(|_| {
+                InvalidOption::address(format!(
+                    "Failed to create allowlist gate, invalid address {}",
+                    w
+                ))
+            })
+        })
+        .collect::<Result<Vec<_>>>()?;
+    if wallets.is_empty() {
+        bail!("Allowlist must contain at least one wallet address");
+    }
+    wallets.sort();
+    wallets.dedup();
+    Ok(wallets)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_csv_with_malformed_line() {
+        let csv = "address\n\
+                   0xcB313f361847e245954FD338Cb21b5F4225b17d1\n\
+                   \"0xc9B6218AffE8Aba68a13899Cbf7cF7f14DDd304C\", 0xcb313f361847e245954fd338cb21b5f4225b17d1\n\
+                   \n\
+                   0xc9B6218AffE8Aba68a13899Cbf7cF7f14DDd30\n";
+        let (gate, invalid) = AllowlistGate::from_csv(csv).unwrap();
+        assert_eq!(
+            gate.wallets,
+            vec![
+                H160::from_str("0xc9B6218AffE8Aba68a13899Cbf7cF7f14DDd304C").unwrap(),
+                H160::from_str("0xcB313f361847e245954FD338Cb21b5F4225b17d1").unwrap(),
+            ]
+        );
+        assert_eq!(
+            invalid,
+            vec![
+                InvalidAddress {
+                    line: 1,
+                    value: "address".to_string()
+                },
+                InvalidAddress {
+                    line: 5,
+                    value: "0xc9B6218AffE8Aba68a13899Cbf7cF7f14DDd30".to_string()
+                },
+            ]
+        );
+        assert!(AllowlistGate::from_csv("address\nnot a wallet\n").is_err());
+    }
+}
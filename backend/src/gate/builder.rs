@@ -0,0 +1,163 @@
+//! A typed builder for gates, so tests and applications embedding the gating
+//! logic don't need to assemble the options of a gate type by name and in the
+//! right order. The builder produces the same options the discord command
+//! does and creates the gate with [`Gate::new`], so the options are
+//! validated the same way.
+//!
+
+use crate::gate::{Gate, GateOptionValue, GateOptionValueType};
+use anyhow::{anyhow, Result};
+use colony_rs::H160;
+
+/// Builds a [`Gate`] of one of the known gate types, e.g.
+/// `GateBuilder::reputation(colony, 1, 25.0).role(role_id).build().await`
+#[derive(Debug, Clone)]
+pub struct GateBuilder {
+    gate_type: String,
+    options: Vec<GateOptionValue>,
+    role_id: Option<u64>,
+    grant_message: Option<String>,
+}
+
+impl GateBuilder {
+    /// A gate of any type, the options are added with
+    /// [`GateBuilder::option`] in the order the gate type expects them
+    pub fn new(gate_type: &str) -> Self {
+        Self {
+            gate_type: gate_type.to_string(),
+            options: Vec::new(),
+            role_id: None,
+            grant_message: None,
+        }
+    }
+
+    /// A reputation gate requiring a percentage of the reputation in a
+    /// domain of a colony
+    pub fn reputation(colony: H160, domain: u64, percentage: f64) -> Self {
+        Self::new("reputation")
+            .option("colony", address(colony))
+            .option("domain", integer(domain))
+            .option("reputation", GateOptionValueType::F64(percentage))
+    }
+
+    /// A reputation gate with the required reputation in basis points, which
+    /// maps to the threshold without rounding
+    pub fn reputation_bps(colony: H160, domain: u64, bps: u64) -> Self {
+        Self::new("reputation")
+            .option("colony", address(colony))
+            .option("domain", integer(domain))
+            .option("reputation_bps", integer(bps))
+    }
+
+    /// A token gate requiring a balance of whole tokens
+    pub fn token(token: H160, amount: u64) -> Self {
+        Self::new("token")
+            .option("token_address", address(token))
+            .option("amount", integer(amount))
+    }
+
+    /// A gate requiring a percentage of the total supply of a token
+    pub fn token_supply_share(token: H160, share: f64) -> Self {
+        Self::new("token_supply_share")
+            .option("token_address", address(token))
+            .option("share", GateOptionValueType::F64(share))
+    }
+
+    /// A staked token gate requiring a staked amount of whole tokens, the
+    /// token is the staking contract unless given with
+    /// [`GateBuilder::staked_token_address`]
+    pub fn staked_token(staking_contract: H160, amount: u64) -> Self {
+        Self::new("staked_token")
+            .option("staking_contract", address(staking_contract))
+            .option("amount", integer(amount))
+    }
+
+    /// The staked token of a staked token gate, if it differs from the
+    /// staking contract
+    pub fn staked_token_address(self, token: H160) -> Self {
+        self.option("token_address", address(token))
+    }
+
+    /// A ladder of token balances, the role of the gate is the lowest tier
+    /// and the higher tiers are given as amount with role id
+    pub fn tiered_token(token: H160, amount: u64, tiers: &[(u64, u64)]) -> Self {
+        let tiers = tiers
+            .iter()
+            .map(|(amount, role_id)| format!("{}:{}", amount, role_id))
+            .collect::<Vec<_>>()
+            .join(", ");
+        Self::new("tiered_token")
+            .option("token_address", address(token))
+            .option("amount", integer(amount))
+            .option("tiers", GateOptionValueType::String(tiers))
+    }
+
+    /// An allowlist of wallets, which needs no network to be created
+    pub fn allowlist(wallets: &[H160]) -> Self {
+        let wallets = wallets
+            .iter()
+            .map(|wallet| format!("{:?}", wallet))
+            .collect::<Vec<_>>()
+            .join(", ");
+        Self::new("allowlist").option("wallets", GateOptionValueType::String(wallets))
+    }
+
+    /// A wallet age gate, at least one of the thresholds is required
+    pub fn wallet_age(min_age_days: Option<u64>, min_tx_count: Option<u64>) -> Self {
+        let mut builder = Self::new("wallet_age");
+        if let Some(min_age_days) = min_age_days {
+            builder = builder.option("min_age_days", integer(min_age_days));
+        }
+        if let Some(min_tx_count) = min_tx_count {
+            builder = builder.option("min_tx_count", integer(min_tx_count));
+        }
+        builder
+    }
+
+    /// Appends an option, for gate types without a typed constructor
+    pub fn option(mut self, name: &str, value: GateOptionValueType) -> Self {
+        self.options.push(GateOptionValue {
+            name: name.to_string(),
+            value,
+        });
+        self
+    }
+
+    /// The role granted by the gate, this is required
+    pub fn role(mut self, role_id: u64) -> Self {
+        self.role_id = Some(role_id);
+        self
+    }
+
+    /// The message shown to the user when the gate grants the role
+    pub fn grant_message(mut self, grant_message: &str) -> Self {
+        self.grant_message = Some(grant_message.to_string());
+        self
+    }
+
+    /// The options as the discord command would pass them to [`Gate::new`]
+    pub fn options(&self) -> &[GateOptionValue] {
+        &self.options
+    }
+
+    /// Creates the gate, which looks up the chain data of the gate type with
+    /// the configured clients
+    pub async fn build(self) -> Result<Gate> {
+        let role_id = self
+            .role_id
+            .ok_or_else(|| anyhow!("The role of the gate is required"))?;
+        Ok(Gate::new(role_id, &self.gate_type, &self.options)
+            .await?
+            .with_grant_message(self.grant_message))
+    }
+}
+
+fn address(address: H160) -> GateOptionValueType {
+    GateOptionValueType::String(format!("{:?}", address))
+}
+
+/// Amounts that don't fit the option type wrap to negative numbers, which
+/// all gate types reject
+fn integer(value: u64) -> GateOptionValueType {
+    GateOptionValueType::I64(value as i64)
+}
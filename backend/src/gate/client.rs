@@ -0,0 +1,277 @@
+//! The clients the gates use to look up colony and token data. By default
+//! the data comes from the chain via colony-rs, an application embedding the
+//! gating logic can inject its own clients instead, e.g. backed by an
+//! indexer. The clients have to be injected before the first gate is created
+//! or checked, afterwards the default clients are in place.
+//!
+
+use crate::gate::rpc;
+use anyhow::{anyhow, bail, Context, Result};
+use async_trait::async_trait;
+use cached::{proc_macro::cached, TimedCache};
+use colony_rs::{H160, U256};
+use once_cell::sync::OnceCell;
+use std::sync::Arc;
+use tracing::debug;
+
+/// How long the names of wallets are cached, names rarely change and are
+/// only shown to users
+const WALLET_NAME_CACHE_SECONDS: u64 = 3600;
+
+static REPUTATION_CLIENT: OnceCell<Arc<dyn ReputationClient>> = OnceCell::new();
+static TOKEN_CLIENT: OnceCell<Arc<dyn TokenClient>> = OnceCell::new();
+
+/// Looks up the reputation and domains of colonies
+#[async_trait]
+pub trait ReputationClient: Send + Sync {
+    /// The reputation amount of a wallet in a domain as decimal string, the
+    /// zero address gives the total reputation in the domain
+    async fn get_reputation_in_domain(
+        &self,
+        colony: &H160,
+        wallet: &H160,
+        domain: u64,
+    ) -> Result<String>;
+    /// Like [`ReputationClient::get_reputation_in_domain`] for several
+    /// wallets in a single batched call, e.g. a multicall, the reputations
+    /// are returned in the order of the wallets. Without it the wallets are
+    /// looked up one by one. Clients implementing it must also return true
+    /// from [`ReputationClient::supports_batched_reputations`]
+    async fn get_reputations_in_domain(
+        &self,
+        _colony: &H160,
+        _wallets: &[H160],
+        _domain: u64,
+    ) -> Result<Vec<String>> {
+        bail!("This client does not support batched reputation lookups")
+    }
+    /// Whether [`ReputationClient::get_reputations_in_domain`] is
+    /// implemented, so batches are only prepared for clients that take them
+    fn supports_batched_reputations(&self) -> bool {
+        false
+    }
+    async fn get_domain_count(&self, colony: H160) -> Result<u64>;
+    async fn get_colony_name(&self, colony: H160) -> Result<String>;
+}
+
+/// Looks up the balances and metadata of ERC20 tokens and the activity of
+/// wallets on the chain
+#[async_trait]
+pub trait TokenClient: Send + Sync {
+    async fn balance_of(&self, token: &H160, wallet: &H160) -> Result<U256>;
+    async fn get_token_decimals(&self, token: H160) -> Result<u8>;
+    async fn get_token_symbol(&self, token: H160) -> Result<String>;
+    /// The transaction history of a wallet, this needs an indexer since the
+    /// chain itself can't be queried by sender efficiently
+    async fn get_wallet_activity(&self, _wallet: &H160) -> Result<WalletActivity> {
+        bail!("This client does not support looking up the wallet activity")
+    }
+    /// Whether [`TokenClient::get_wallet_activity`] is implemented, so the
+    /// gates that need it are refused at creation instead of failing every
+    /// check
+    fn supports_wallet_activity(&self) -> bool {
+        false
+    }
+    /// The total supply of a token in its smallest unit
+    async fn get_total_supply(&self, _token: H160) -> Result<U256> {
+        bail!("This client does not support looking up the total supply")
+    }
+    /// The name a wallet resolves to, e.g. its ENS name or colony user
+    /// handle, `None` if it has none. The name is only used for display, so
+    /// clients without a resolver show no names
+    async fn get_wallet_name(&self, _wallet: &H160) -> Result<Option<String>> {
+        Ok(None)
+    }
+}
+
+/// The transaction history of a wallet as needed by the wallet age gate
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct WalletActivity {
+    /// The unix timestamp of the first transaction, `None` for wallets that
+    /// never transacted
+    pub first_transaction_at: Option<u64>,
+    /// The number of transactions the wallet sent
+    pub transaction_count: u64,
+}
+
+/// The default client, which calls the chain via colony-rs within the global
+/// limit of concurrent calls
+#[derive(Debug, Clone, Copy, Default)]
+pub struct ColonyClient;
+
+#[async_trait]
+impl ReputationClient for ColonyClient {
+    async fn get_reputation_in_domain(
+        &self,
+        colony: &H160,
+        wallet: &H160,
+        domain: u64,
+    ) -> Result<String> {
+        let reputation = rpc::limited(colony_rs::get_reputation_in_domain(colony, wallet, domain))
+            .await
+            .context("Failed to get reputation in domain")?;
+        Ok(reputation.reputation_amount)
+    }
+
+    async fn get_domain_count(&self, colony: H160) -> Result<u64> {
+        rpc::limited(colony_rs::get_domain_count(colony))
+            .await
+            .context("Failed to get domain count")
+    }
+
+    async fn get_colony_name(&self, colony: H160) -> Result<String> {
+        rpc::limited(colony_rs::get_colony_name(colony))
+            .await
+            .context("Failed to get colony name")
+    }
+}
+
+#[async_trait]
+impl TokenClient for ColonyClient {
+    async fn balance_of(&self, token: &H160, wallet: &H160) -> Result<U256> {
+        rpc::limited(colony_rs::balance_off(token, wallet))
+            .await
+            .context("Failed to get token balance")
+    }
+
+    async fn get_token_decimals(&self, token: H160) -> Result<u8> {
+        rpc::limited(colony_rs::get_token_decimals(token))
+            .await
+            .context("Failed to get token decimals")
+    }
+
+    async fn get_token_symbol(&self, token: H160) -> Result<String> {
+        rpc::limited(colony_rs::get_token_symbol(token))
+            .await
+            .context("Failed to get token symbol")
+    }
+
+    async fn get_total_supply(&self, token: H160) -> Result<U256> {
+        rpc::limited(colony_rs::get_token_total_supply(token))
+            .await
+            .context("Failed to get token total supply")
+    }
+}
+
+/// Sets the reputation client used by all gates, fails if it was set or
+/// used already
+pub fn init_reputation_client(client: Arc<dyn ReputationClient>) -> Result<()> {
+    REPUTATION_CLIENT
+        .set(client)
+        .map_err(|_| anyhow!("The reputation client is already set"))
+}
+
+/// Sets the token client used by all gates, fails if it was set or used
+/// already
+pub fn init_token_client(client: Arc<dyn TokenClient>) -> Result<()> {
+    TOKEN_CLIENT
+        .set(client)
+        .map_err(|_| anyhow!("The token client is already set"))
+}
+
+/// The injected reputation client or the default colony client
+pub fn reputation_client() -> &'static dyn ReputationClient {
+    REPUTATION_CLIENT
+        .get_or_init(|| Arc::new(ColonyClient))
+        .as_ref()
+}
+
+/// The injected token client or the default colony client
+pub fn token_client() -> &'static dyn TokenClient {
+    TOKEN_CLIENT.get_or_init(|| Arc::new(ColonyClient)).as_ref()
+}
+
+/// The name of a wallet to show next to its address, if the token client can
+/// resolve it. A failed lookup shows the address alone
+pub async fn wallet_name(wallet: &H160) -> Option<String> {
+    resolve_wallet_name(token_client(), wallet).await
+}
+
+async fn resolve_wallet_name(client: &dyn TokenClient, wallet: &H160) -> Option<String> {
+    match get_wallet_name_cached(client, wallet).await {
+        Ok(name) => name.filter(|name| !name.trim().is_empty()),
+        Err(why) => {
+            debug!(?wallet, "Failed to resolve wallet name: {}", why);
+            None
+        }
+    }
+}
+
+/// Wallets without a name are cached as well, failed lookups are not
+#[cached(
+    name = "WALLET_NAME_CACHE",
+    type = "TimedCache<H160, Option<String>>",
+    create = r##"{
+        TimedCache::with_lifespan(WALLET_NAME_CACHE_SECONDS)
+        }
+    "##,
+    convert = r##"{ *wallet }"##,
+    result = true
+)]
+async fn get_wallet_name_cached(client: &dyn TokenClient, wallet: &H160) -> Result<Option<String>> {
+    client.get_wallet_name(wallet).await
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+
+    const NAMED_WALLET: H160 = H160::repeat_byte(0xe1);
+    const UNNAMED_WALLET: H160 = H160::repeat_byte(0xe2);
+    const BROKEN_WALLET: H160 = H160::repeat_byte(0xe3);
+
+    /// Resolves the name of one wallet only and counts the lookups
+    #[derive(Default)]
+    struct NameMockClient {
+        lookups: AtomicUsize,
+    }
+
+    #[async_trait]
+    impl TokenClient for NameMockClient {
+        async fn balance_of(&self, _token: &H160, _wallet: &H160) -> Result<U256> {
+            bail!("Not mocked")
+        }
+
+        async fn get_token_decimals(&self, _token: H160) -> Result<u8> {
+            bail!("Not mocked")
+        }
+
+        async fn get_token_symbol(&self, _token: H160) -> Result<String> {
+            bail!("Not mocked")
+        }
+
+        async fn get_wallet_name(&self, wallet: &H160) -> Result<Option<String>> {
+            self.lookups.fetch_add(1, Ordering::SeqCst);
+            match *wallet {
+                NAMED_WALLET => Ok(Some("alice.eth".to_string())),
+                UNNAMED_WALLET => Ok(None),
+                _ => bail!("Resolver unreachable"),
+            }
+        }
+    }
+
+    #[tokio::test]
+    async fn test_resolve_wallet_name() {
+        let client = NameMockClient::default();
+        assert_eq!(
+            resolve_wallet_name(&client, &NAMED_WALLET).await,
+            Some("alice.eth".to_string())
+        );
+        assert_eq!(resolve_wallet_name(&client, &UNNAMED_WALLET).await, None);
+        assert_eq!(resolve_wallet_name(&client, &BROKEN_WALLET).await, None);
+        assert_eq!(client.lookups.load(Ordering::SeqCst), 3);
+
+        // names and their absence are cached, the failed lookup is retried
+        resolve_wallet_name(&client, &NAMED_WALLET).await;
+        resolve_wallet_name(&client, &UNNAMED_WALLET).await;
+        resolve_wallet_name(&client, &BROKEN_WALLET).await;
+        assert_eq!(client.lookups.load(Ordering::SeqCst), 4);
+
+        // the default client resolves no names
+        assert_eq!(
+            ColonyClient.get_wallet_name(&NAMED_WALLET).await.unwrap(),
+            None
+        );
+    }
+}
@@ -0,0 +1,232 @@
+use crate::gate::{
+    GateCost, GateOption, GateOptionValue, GateOptionValueType, GatingCondition, ReputationGate,
+    TokenGate,
+};
+use anyhow::{bail, Result};
+use async_trait::async_trait;
+use colony_rs::H160;
+use serde::{Deserialize, Serialize};
+use std::boxed::Box;
+use std::collections::hash_map::DefaultHasher;
+use std::hash::{Hash, Hasher};
+use tracing::{debug, instrument, Instrument};
+
+/// How the conditions of a composite gate are combined
+#[derive(Debug, Clone, Copy, Deserialize, Hash, Serialize, PartialEq, Eq)]
+pub enum Combinator {
+    /// The role is granted if any of the conditions is met
+    Any,
+    /// The role is granted only if all of the conditions are met
+    All,
+}
+
+/// Combines several conditions into a single gate for one role. The combo
+/// gate created by `/gate add combo` is a composite of a reputation and a
+/// token gate with [`Combinator::Any`], which makes the OR semantics of
+/// multiple gates for one role explicit and shows up as one gate in
+/// `/gate list`
+#[derive(Debug, Clone, Deserialize, Serialize)]
+pub struct CompositeGate {
+    pub combinator: Combinator,
+    pub conditions: Vec<Box<dyn GatingCondition>>,
+}
+
+impl CompositeGate {
+    /// Conditions are checked one after another and the check stops as soon
+    /// as the result is known. Errors only fail the check if the result
+    /// depends on the errored condition
+    async fn check_conditions(&self, wallet_address: H160, bypass_cache: bool) -> Result<bool> {
+        let mut error = None;
+        for condition in self.conditions.iter() {
+            let result = if bypass_cache {
+                condition
+                    .check_uncached(wallet_address)
+                    .in_current_span()
+                    .await
+            } else {
+                condition.check(wallet_address).in_current_span().await
+            };
+            match result {
+                Ok(true) if self.combinator == Combinator::Any => return Ok(true),
+                Ok(false) if self.combinator == Combinator::All => return Ok(false),
+                Ok(_) => {}
+                Err(why) => error = Some(why),
+            }
+        }
+        match error {
+            Some(why) => Err(why.context("Failed to check composite gate")),
+            None => Ok(self.combinator == Combinator::All),
+        }
+    }
+}
+
+#[typetag::serde]
+#[async_trait]
+impl GatingCondition for CompositeGate {
+    fn name() -> &'static str {
+        "combo"
+    }
+
+    fn description() -> &'static str {
+        "Guards a role with either a reputation percentage or a token balance"
+    }
+
+    /// The basis points option of the reputation gate is left out, see
+    /// [`required_reputation_options`]
+    fn options() -> Vec<GateOption> {
+        let mut options = required_reputation_options();
+        options.extend(TokenGate::options());
+        options
+    }
+
+    #[instrument(level = "debug")]
+    async fn from_options(options: &[GateOptionValue]) -> Result<Box<Self>> {
+        debug!("Creating combo gate from options");
+        let reputation_options = required_reputation_options().len();
+        if options.len() != reputation_options + TokenGate::options().len() {
+            bail!("Need exactly the reputation and token options");
+        }
+        let (reputation, token) = options.split_at(reputation_options);
+        let reputation = ReputationGate::from_options(reputation)
+            .in_current_span()
+            .await?;
+        let token = TokenGate::from_options(token).in_current_span().await?;
+        debug!("Done creating combo gate from options");
+        Ok(Box::new(CompositeGate {
+            combinator: Combinator::Any,
+            conditions: vec![reputation as Box<dyn GatingCondition>, token],
+        }))
+    }
+
+    #[instrument(name = "composite_condition", skip(wallet_address))]
+    async fn check(&self, wallet_address: H160) -> Result<bool> {
+        self.check_conditions(wallet_address, false)
+            .in_current_span()
+            .await
+    }
+
+    #[instrument(name = "composite_condition_uncached", skip(wallet_address))]
+    async fn check_uncached(&self, wallet_address: H160) -> Result<bool> {
+        self.check_conditions(wallet_address, true)
+            .in_current_span()
+            .await
+    }
+
+    /// A composite is as expensive as its most expensive condition
+    fn cost(&self) -> GateCost {
+        self.conditions
+            .iter()
+            .map(|condition| condition.cost())
+            .max()
+            .unwrap_or(GateCost::Local)
+    }
+
+    async fn prefetch(&self, wallet_addresses: &[H160]) -> Result<()> {
+        for condition in self.conditions.iter() {
+            condition
+                .prefetch(wallet_addresses)
+                .in_current_span()
+                .await?;
+        }
+        Ok(())
+    }
+
+    fn hashed(&self) -> u64 {
+        let mut hasher = DefaultHasher::new();
+        self.combinator.hash(&mut hasher);
+        for condition in self.conditions.iter() {
+            condition.hashed().hash(&mut hasher);
+        }
+        hasher.finish()
+    }
+
+    fn fields(&self) -> Vec<GateOptionValue> {
+        let mut fields = vec![GateOptionValue {
+            name: "combinator".to_string(),
+            value: GateOptionValueType::String(format!("{:?}", self.combinator)),
+        }];
+        for condition in self.conditions.iter() {
+            fields.extend(condition.fields().into_iter().map(|field| GateOptionValue {
+                name: format!("{}.{}", condition.instance_name(), field.name),
+                value: field.value,
+            }));
+        }
+        fields
+    }
+
+    fn instance_name(&self) -> &'static str {
+        Self::name()
+    }
+}
+
+/// The combo gate always takes the reputation threshold as percentage, so it
+/// is required here, while the reputation gate alternatively takes it in basis
+/// points
+fn required_reputation_options() -> Vec<GateOption> {
+    ReputationGate::options()
+        .into_iter()
+        .filter_map(|mut option| {
+            if option.name == "reputation" {
+                option.required = true;
+            }
+            option.required.then_some(option)
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::gate::AllowlistGate;
+    use std::str::FromStr;
+
+    fn wallet(address: &str) -> H160 {
+        H160::from_str(address).unwrap()
+    }
+
+    fn composite(combinator: Combinator, first: H160, second: H160) -> CompositeGate {
+        CompositeGate {
+            combinator,
+            conditions: vec![
+                Box::new(AllowlistGate {
+                    wallets: vec![first],
+                }) as Box<dyn GatingCondition>,
+                Box::new(AllowlistGate {
+                    wallets: vec![second],
+                }),
+            ],
+        }
+    }
+
+    #[tokio::test]
+    async fn test_combo_grants_if_either_condition_passes() {
+        let first = wallet("0xcB313f361847e245954FD338Cb21b5F4225b17d1");
+        let second = wallet("0xc9B6218AffE8Aba68a13899Cbf7cF7f14DDd304C");
+        let other = wallet("0x0000000000000000000000000000000000000001");
+        let gate = composite(Combinator::Any, first, second);
+        assert!(gate.check(first).await.unwrap());
+        assert!(gate.check(second).await.unwrap());
+        assert!(!gate.check(other).await.unwrap());
+
+        let gate = composite(Combinator::All, first, second);
+        assert!(!gate.check(first).await.unwrap());
+        let gate = composite(Combinator::All, first, first);
+        assert!(gate.check(first).await.unwrap());
+    }
+
+    #[test]
+    fn test_composite_fields_and_hash() {
+        let first = wallet("0xcB313f361847e245954FD338Cb21b5F4225b17d1");
+        let second = wallet("0xc9B6218AffE8Aba68a13899Cbf7cF7f14DDd304C");
+        let any = composite(Combinator::Any, first, second);
+        let all = composite(Combinator::All, first, second);
+        assert_ne!(any.hashed(), all.hashed());
+        let names = any
+            .fields()
+            .into_iter()
+            .map(|field| field.name)
+            .collect::<Vec<_>>();
+        assert_eq!(names[0], "combinator");
+        assert!(names[1..].iter().all(|name| name.starts_with("allowlist.")));
+    }
+}
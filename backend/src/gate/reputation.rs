@@ -1,13 +1,12 @@
 use crate::gate::{
-    GateOption, GateOptionType, GateOptionValue, GateOptionValueType, GatingCondition,
+    check_allowed_colony, client, default_chain_id, GateOption, GateOptionType, GateOptionValue,
+    GateOptionValueType, GatingCondition, InvalidOption,
 };
 use anyhow::{anyhow, bail, Context, Result};
 use async_trait::async_trait;
 use cached::{proc_macro::cached, Cached, TimedCache};
-use colony_rs::{
-    get_colony_name, get_domain_count, get_reputation_in_domain, u256_from_f64_saturating, H160,
-    U256, U512,
-};
+use colony_rs::{u256_from_f64_saturating, H160, U256, U512};
+use futures::future::{BoxFuture, FutureExt, Shared};
 use governor::{
     clock::DefaultClock,
     state::{direct::NotKeyed, InMemoryState},
@@ -17,10 +16,11 @@ use nonzero_ext::*;
 use once_cell::sync::Lazy;
 use serde::{Deserialize, Serialize};
 use std::boxed::Box;
-use std::collections::hash_map::DefaultHasher;
+use std::collections::{hash_map::DefaultHasher, HashMap};
 use std::hash::{Hash, Hasher};
 use std::str::FromStr;
 use std::time::Duration;
+use tokio::sync::Mutex;
 use tracing::{debug, info, instrument, trace, warn, Instrument};
 
 /// this must be smaller than 1e76 or so, to not overflow the later U512
@@ -29,9 +29,22 @@ pub const PRECISION_FACTOR: f64 = (std::u128::MAX >> 1) as f64 / 100.0;
 /// this must be smaller than 1e78 or so, to not overflow the U512
 /// multiplications
 static PRECISION_FACTOR_TIMES_100: Lazy<U512> = Lazy::new(|| U512::from(std::u128::MAX >> 1));
+/// The basis points in 100%, one basis point is 0.01%
+const BPS_PER_100_PERCENT: u64 = 10_000;
 
 pub static RATE_LIMITER: Lazy<RateLimiter<NotKeyed, InMemoryState, DefaultClock>> =
     Lazy::new(|| RateLimiter::direct(Quota::per_second(nonzero!(100u32))));
+
+/// The colony, wallet and domain of a reputation lookup
+type ReputationKey = (H160, H160, u64);
+type ReputationLookup = Shared<BoxFuture<'static, Result<String, String>>>;
+
+/// The reputation lookups that are in flight, so concurrent identical lookups
+/// on a cache miss, e.g. of the base reputation at the start of an
+/// enforcement, share a single call to the client
+static IN_FLIGHT: Lazy<Mutex<HashMap<ReputationKey, ReputationLookup>>> =
+    Lazy::new(|| Mutex::new(HashMap::new()));
+
 /// Represents a gate for a discord role issues by the /gate slash command.
 /// This is stored in the database for each discord server.
 #[derive(Debug, Clone, Deserialize, Hash, Serialize, PartialEq, Eq)]
@@ -48,6 +61,24 @@ pub struct ReputationGate {
     pub reputation_threshold_scaled: U256,
 }
 
+impl ReputationGate {
+    async fn check_wallet(&self, wallet_address: H160, bypass_cache: bool) -> Result<bool> {
+        check_reputation(
+            self.reputation_threshold_scaled,
+            wallet_address,
+            self.colony_address,
+            self.colony_domain,
+            bypass_cache,
+        )
+        .in_current_span()
+        .await
+        .map_err(|why| {
+            warn!("Error checking reputation: {}", why);
+            why.context("Failed to check reputation")
+        })
+    }
+}
+
 #[typetag::serde]
 #[async_trait]
 impl GatingCondition for ReputationGate {
@@ -81,29 +112,40 @@ impl GatingCondition for ReputationGate {
                 name: "reputation",
                 description:
                     "The the percentage of reputation in the domain required to be granted the role",
-                required: true,
+                required: false,
                 option_type: GateOptionType::F64 {
                     min: Some(0.0),
                     max: Some(100.0),
                 },
             },
+            GateOption {
+                name: "reputation_bps",
+                description:
+                    "The reputation required in basis points, 1 is 0.01%, instead of the percentage",
+                required: false,
+                option_type: GateOptionType::I64 {
+                    min: Some(1),
+                    max: Some(BPS_PER_100_PERCENT as i64),
+                },
+            },
         ]
     }
     #[instrument(level = "info")]
     async fn from_options(options: &[GateOptionValue]) -> Result<Box<Self>> {
         debug!("Creating reputation gate from options");
-        if options.len() != 3 {
-            bail!("Need exactly 3 options");
+        if options.len() < 3 {
+            bail!("Need the colony, domain and reputation options");
         }
         if options[0].name != "colony" {
             bail!("First option must be colony");
         }
         let colony_address = match &options[0].value {
-            GateOptionValueType::String(s) => {
-                H160::from_str(s).context("Failed to create reputation gate, invalid address")?
-            }
+            GateOptionValueType::String(s) => H160::from_str(s).map_err(|_| {
+                InvalidOption::address("Failed to create reputation gate, invalid address")
+            })?,
             _ => bail!("Invalid option type, expected string for colony address"),
         };
+        check_allowed_colony(colony_address).context("Failed to create reputation gate")?;
         if options[1].name != "domain" {
             bail!("Second option must be domain");
         }
@@ -112,40 +154,71 @@ impl GatingCondition for ReputationGate {
             _ => bail!("Invalid option type, expected integer for domain"),
         };
         if domain < 1 {
-            bail!("Domain must be greater than 0");
+            bail!(InvalidOption::out_of_range("Domain must be greater than 0"));
         }
-        if options[2].name != "reputation" {
-            bail!("Third option must be reputation");
+        let mut reputation_percentage = None;
+        let mut reputation_bps = None;
+        for option in &options[2..] {
+            match (option.name.as_str(), &option.value) {
+                ("reputation", GateOptionValueType::F64(f)) => reputation_percentage = Some(*f),
+                ("reputation_bps", GateOptionValueType::I64(i)) => reputation_bps = Some(*i),
+                ("reputation", _) => bail!("Invalid option type, expected float for reputation"),
+                ("reputation_bps", _) => {
+                    bail!("Invalid option type, expected integer for reputation_bps")
+                }
+                (name, _) => bail!("Invalid option {} for the reputation gate", name),
+            }
         }
 
-        let domaincount = get_domain_count(colony_address)
+        let domaincount = client::reputation_client()
+            .get_domain_count(colony_address)
             .await
             .context("Failed to create reputation gate, could not get domains for colony")?;
 
         if domain as u64 > domaincount {
-            bail!("The domain number is higher than the domain count in the colony");
+            bail!(InvalidOption::out_of_range(
+                "The domain number is higher than the domain count in the colony"
+            ));
         }
 
-        let reputation_percentage = match &options[2].value {
-            GateOptionValueType::F64(f) => *f,
-            _ => bail!("Invalid option type, expected float for reputation"),
+        // the basis points are preferred, since they map to the threshold
+        // without rounding, but giving both is ambiguous
+        let reputation_threshold_scaled = match (reputation_bps, reputation_percentage) {
+            (Some(_), Some(_)) => bail!("Give either reputation or reputation_bps, not both"),
+            (Some(bps), None) => {
+                if bps < 1 || bps > BPS_PER_100_PERCENT as i64 {
+                    bail!(InvalidOption::out_of_range(
+                        "Reputation basis points must be between 1 and 10000"
+                    ))
+                }
+                threshold_from_bps(bps as u64)
+            }
+            (None, Some(reputation_percentage)) => {
+                if reputation_percentage > 100.0 {
+                    bail!(InvalidOption::out_of_range(
+                        "Reputation must be 100 or less"
+                    ))
+                }
+                if reputation_percentage <= 0.0 {
+                    bail!(InvalidOption::out_of_range(
+                        "Reputation must be more than 0"
+                    ))
+                }
+                u256_from_f64_saturating(reputation_percentage * PRECISION_FACTOR)
+            }
+            (None, None) => bail!("Either reputation or reputation_bps is required"),
         };
-        if reputation_percentage > 100.0 {
-            bail!("Reputation must be 100 or less")
-        }
-        if reputation_percentage <= 0.0 {
-            bail!("Reputation must be more than 0")
-        }
-        let reputation_threshold_scaled =
-            u256_from_f64_saturating(reputation_percentage * PRECISION_FACTOR);
 
-        let colony_name = get_colony_name(colony_address).await.unwrap_or_else(|why| {
-            warn!("Error getting colony name: {}", why);
-            "".to_string()
-        });
+        let colony_name = client::reputation_client()
+            .get_colony_name(colony_address)
+            .await
+            .unwrap_or_else(|why| {
+                warn!("Error getting colony name: {}", why);
+                "".to_string()
+            });
         debug!(?colony_name, "Colony name is:");
 
-        let chain_id = U256::from(100);
+        let chain_id = default_chain_id();
         debug!("Done creating reputation gate from options");
 
         Ok(Box::new(ReputationGate {
@@ -158,20 +231,46 @@ impl GatingCondition for ReputationGate {
     }
 
     #[instrument(name = "reputation_condition", skip(wallet_address))]
-    async fn check(&self, wallet_address: H160) -> bool {
+    async fn check(&self, wallet_address: H160) -> Result<bool> {
         debug!("Checking reputation gate");
-        check_reputation(
-            self.reputation_threshold_scaled,
-            wallet_address,
-            self.colony_address,
-            self.colony_domain,
-        )
-        .in_current_span()
-        .await
-        .unwrap_or_else(|why| {
-            warn!("Error checking reputation: {}", why);
-            false
-        })
+        self.check_wallet(wallet_address, false)
+            .in_current_span()
+            .await
+    }
+
+    #[instrument(name = "reputation_condition_uncached", skip(wallet_address))]
+    async fn check_uncached(&self, wallet_address: H160) -> Result<bool> {
+        debug!("Checking reputation gate without cache");
+        self.check_wallet(wallet_address, true)
+            .in_current_span()
+            .await
+    }
+
+    async fn prefetch(&self, wallet_addresses: &[H160]) -> Result<()> {
+        prefetch_reputations(self.colony_address, wallet_addresses, self.colony_domain)
+            .in_current_span()
+            .await
+    }
+
+    /// The reputation percentage of the wallet against the threshold
+    async fn explain(&self, wallet_address: H160) -> Result<Option<String>> {
+        let colony = &self.colony_address;
+        let domain = self.colony_domain;
+        let base = get_reputation_in_domain_cached(colony, &H160::zero(), domain)
+            .in_current_span()
+            .await
+            .map_err(|why| anyhow!(why))?;
+        let user = get_reputation_in_domain_cached(colony, &wallet_address, domain)
+            .in_current_span()
+            .await
+            .map_err(|why| anyhow!(why))?;
+        let percentage =
+            reputation_percentage(U512::from_dec_str(&base)?, U512::from_dec_str(&user)?)?;
+        let threshold = self.reputation_threshold_scaled.as_u128() as f64 / PRECISION_FACTOR;
+        Ok(Some(format!(
+            "reputation {:.2}% ≥ {}%",
+            percentage, threshold
+        )))
     }
 
     fn hashed(&self) -> u64 {
@@ -206,6 +305,12 @@ impl GatingCondition for ReputationGate {
                 name: "reputation".to_string(),
                 value: GateOptionValueType::F64(reputation),
             },
+            GateOptionValue {
+                name: "reputation_bps".to_string(),
+                value: GateOptionValueType::I64(
+                    bps_from_threshold(self.reputation_threshold_scaled) as i64,
+                ),
+            },
         ]
     }
 
@@ -215,13 +320,15 @@ impl GatingCondition for ReputationGate {
 }
 
 /// This is used to gather the fraction of total reputation a wallet has in
-/// a domain in a colony
+/// a domain in a colony. With `bypass_cache` the reputation is looked up
+/// live, even if it is cached
 #[instrument(level = "debug", skip(wallet))]
 async fn check_reputation(
     reputation_percentage: U256,
     wallet: H160,
     colony: H160,
     domain: u64,
+    bypass_cache: bool,
 ) -> Result<bool> {
     debug!("Checking reputation");
     let mut interval = tokio::time::interval(Duration::from_millis(1));
@@ -234,7 +341,7 @@ async fn check_reputation(
             // we only check the user for a cache hit, this should imply a
             // cache hit for the base reputation as well, edge cases should
             // be irrelevant
-            if guard.cache_get(&(colony, wallet, domain)).is_some() {
+            if !bypass_cache && guard.cache_get(&(colony, wallet, domain)).is_some() {
                 debug!("Cache hit, can return now");
                 break;
             }
@@ -252,14 +359,26 @@ async fn check_reputation(
     let base_reputation_fut = tokio::spawn(async move {
         let colony_address = colony;
         let zero_address = colony_rs::Address::zero();
-        get_reputation_in_domain_cached(&colony_address, &zero_address, domain)
-            .in_current_span()
-            .await
+        if bypass_cache {
+            get_reputation_in_domain_refreshed(&colony_address, &zero_address, domain)
+                .in_current_span()
+                .await
+        } else {
+            get_reputation_in_domain_cached(&colony_address, &zero_address, domain)
+                .in_current_span()
+                .await
+        }
     });
     let user_reputation_fut = tokio::spawn(async move {
-        get_reputation_in_domain_cached(&colony, &wallet, domain)
-            .in_current_span()
-            .await
+        if bypass_cache {
+            get_reputation_in_domain_refreshed(&colony, &wallet, domain)
+                .in_current_span()
+                .await
+        } else {
+            get_reputation_in_domain_cached(&colony, &wallet, domain)
+                .in_current_span()
+                .await
+        }
     });
     let (base_result, user_result) = tokio::join!(base_reputation_fut, user_reputation_fut);
     let base_reputation_str = match base_result? {
@@ -278,6 +397,7 @@ async fn check_reputation(
             "0".to_string()
         }
     };
+    record_reputation_percentage(colony, domain, &base_reputation_str, &user_reputation_str);
     calculate_reputation_percentage(
         reputation_percentage,
         &base_reputation_str,
@@ -285,6 +405,115 @@ async fn check_reputation(
     )
 }
 
+/// Looks up the latest reputation of the wallets and the base reputation of
+/// the domain with one batched call of the client and caches them, so the
+/// following checks of the wallets are cache hits. Reputations that are
+/// cached already are not looked up again
+#[instrument(level = "debug", skip(wallets))]
+async fn prefetch_reputations(colony: H160, wallets: &[H160], domain: u64) -> Result<()> {
+    let client = client::reputation_client();
+    // without batch support the wallets are looked up one by one anyway, so
+    // no rate limit is spent on a batch that fails
+    if !client.supports_batched_reputations() {
+        return Ok(());
+    }
+    let mut missing = Vec::new();
+    {
+        let mut guard = COLONY_CACHE.lock().in_current_span().await;
+        for wallet in std::iter::once(H160::zero()).chain(wallets.iter().copied()) {
+            if !missing.contains(&wallet) && guard.cache_get(&(colony, wallet, domain)).is_none() {
+                missing.push(wallet);
+            }
+        }
+    }
+    // a single lookup is not worth a batch
+    if missing.len() < 2 {
+        return Ok(());
+    }
+    RATE_LIMITER.until_ready().in_current_span().await;
+    let reputations = client
+        .get_reputations_in_domain(&colony, &missing, domain)
+        .in_current_span()
+        .await?;
+    if reputations.len() != missing.len() {
+        bail!(
+            "Got {} reputations for {} wallets",
+            reputations.len(),
+            missing.len()
+        );
+    }
+    debug!(wallets = missing.len(), "Prefetched reputations");
+    let mut guard = COLONY_CACHE.lock().in_current_span().await;
+    for (wallet, reputation) in missing.into_iter().zip(reputations) {
+        guard.cache_set((colony, wallet, domain), Ok(reputation));
+    }
+    Ok(())
+}
+
+/// The scaled threshold of a reputation in basis points. Unlike the
+/// percentage this needs no float conversion, the threshold is exact up to
+/// the truncation of the integer division
+fn threshold_from_bps(bps: u64) -> U256 {
+    U256::from(std::u128::MAX >> 1) * U256::from(bps) / U256::from(BPS_PER_100_PERCENT)
+}
+
+/// The basis points of a scaled threshold, rounded to the nearest basis
+/// point, since thresholds from a percentage are not exact
+fn bps_from_threshold(reputation_threshold_scaled: U256) -> u64 {
+    let precision = U512::from(std::u128::MAX >> 1);
+    let bps = (U512::from(reputation_threshold_scaled) * U512::from(BPS_PER_100_PERCENT)
+        + precision / 2)
+        / precision;
+    bps.low_u64()
+}
+
+/// Logs the actual reputation percentage of a checked wallet. With the
+/// metrics feature it is emitted as a histogram event, labeled by colony and
+/// domain, that can be picked up by a metrics layer
+fn record_reputation_percentage(colony: H160, domain: u64, base: &str, user: &str) {
+    let percentage = match U512::from_dec_str(base)
+        .map_err(anyhow::Error::from)
+        .and_then(|base| Ok((base, U512::from_dec_str(user)?)))
+        .and_then(|(base, user)| reputation_percentage(base, user))
+    {
+        Ok(percentage) => percentage,
+        Err(why) => {
+            debug!("Could not calculate reputation percentage: {:?}", why);
+            return;
+        }
+    };
+    debug!(percentage, ?colony, domain, "Reputation percentage of the wallet");
+    #[cfg(feature = "metrics")]
+    info!(
+        histogram.reputation_percentage = percentage,
+        colony = ?colony,
+        domain,
+        "Checked reputation"
+    );
+}
+
+/// The percentage of the base reputation a user reputation amounts to.
+/// The quotient is built on the big integers scaled by the precision factor,
+/// so only the final result, which is at most 100 times the precision factor
+/// and fits into a u128, is converted to a float
+fn reputation_percentage(base_reputation: U512, user_reputation: U512) -> Result<f64> {
+    if base_reputation.is_zero() {
+        return Ok(0.0);
+    }
+    let scaled = PRECISION_FACTOR_TIMES_100
+        .checked_mul(user_reputation)
+        .ok_or(anyhow!("Failed to calculate reputation percentage, overflow"))?
+        / base_reputation;
+    // the user reputation should never exceed the base reputation, but we
+    // rather saturate than panic if it does
+    let scaled = if scaled > U512::from(u128::MAX) {
+        u128::MAX
+    } else {
+        scaled.as_u128()
+    };
+    Ok(scaled as f64 / PRECISION_FACTOR)
+}
+
 #[cached(
     name = "COLONY_CACHE",
     type = "TimedCache<(H160,H160,u64), Result<String, String>>",
@@ -298,10 +527,55 @@ async fn get_reputation_in_domain_cached(
     wallet_address: &H160,
     domain: u64,
 ) -> Result<String, String> {
-    match get_reputation_in_domain(colony_address, wallet_address, domain).await {
-        Ok(rep_no_proof) => Ok(rep_no_proof.reputation_amount),
-        Err(why) => Err(format!("{:?}", why)),
-    }
+    get_reputation_in_domain_coalesced((*colony_address, *wallet_address, domain)).await
+}
+
+/// Looks up the reputation live, unless the same lookup is in flight already,
+/// in which case its result is shared instead of calling the client again
+async fn get_reputation_in_domain_coalesced(key: ReputationKey) -> Result<String, String> {
+    let lookup = IN_FLIGHT
+        .lock()
+        .await
+        .entry(key)
+        .or_insert_with(|| {
+            let (colony_address, wallet_address, domain) = key;
+            async move {
+                let reputation =
+                    get_reputation_in_domain_live(&colony_address, &wallet_address, domain).await;
+                IN_FLIGHT.lock().await.remove(&key);
+                reputation
+            }
+            .boxed()
+            .shared()
+        })
+        .clone();
+    lookup.await
+}
+
+/// Looks up the reputation without the cache and replaces the cached value
+/// with the result, so later cached checks see the live state as well
+async fn get_reputation_in_domain_refreshed(
+    colony_address: &H160,
+    wallet_address: &H160,
+    domain: u64,
+) -> Result<String, String> {
+    let reputation = get_reputation_in_domain_live(colony_address, wallet_address, domain).await;
+    COLONY_CACHE.lock().await.cache_set(
+        (*colony_address, *wallet_address, domain),
+        reputation.clone(),
+    );
+    reputation
+}
+
+async fn get_reputation_in_domain_live(
+    colony_address: &H160,
+    wallet_address: &H160,
+    domain: u64,
+) -> Result<String, String> {
+    client::reputation_client()
+        .get_reputation_in_domain(colony_address, wallet_address, domain)
+        .await
+        .map_err(|why| format!("{:?}", why))
 }
 
 #[instrument(level = "debug")]
@@ -340,3 +614,223 @@ fn calculate_reputation_percentage(
     debug!(?left_side, ?right_side, "Calculated reputation percentage");
     Ok(left_side <= right_side)
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::gate::{init_reputation_client, Gate, ReputationClient};
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use std::sync::Arc;
+
+    /// The colony whose lookups are slow and counted, to observe coalescing
+    const COALESCED_COLONY: H160 = H160::repeat_byte(0xc2);
+    static COALESCED_LOOKUPS: AtomicUsize = AtomicUsize::new(0);
+
+    /// The colony of the test gates, the user has half of its reputation
+    const GATED_COLONY: H160 = H160::repeat_byte(0xc0);
+
+    /// Has the total reputation of 1000 in every colony, the user has 500 in
+    /// the gated colony and nothing elsewhere
+    struct MockClient;
+
+    #[async_trait]
+    impl ReputationClient for MockClient {
+        async fn get_reputation_in_domain(
+            &self,
+            colony: &H160,
+            wallet: &H160,
+            _domain: u64,
+        ) -> Result<String> {
+            if *colony == COALESCED_COLONY {
+                COALESCED_LOOKUPS.fetch_add(1, Ordering::SeqCst);
+                tokio::time::sleep(Duration::from_millis(50)).await;
+            }
+            Ok(if wallet.is_zero() {
+                "1000"
+            } else if *colony == GATED_COLONY {
+                "500"
+            } else {
+                "0"
+            }
+            .to_string())
+        }
+
+        async fn get_domain_count(&self, _colony: H160) -> Result<u64> {
+            Ok(1)
+        }
+
+        async fn get_colony_name(&self, _colony: H160) -> Result<String> {
+            Ok("mock".to_string())
+        }
+    }
+
+    fn reputation_gate() -> ReputationGate {
+        ReputationGate {
+            chain_id: U256::from(100),
+            colony_address: GATED_COLONY,
+            colony_name: "mock".to_string(),
+            colony_domain: 1,
+            reputation_threshold_scaled: u256_from_f64_saturating(25.0 * PRECISION_FACTOR),
+        }
+    }
+
+    fn options() -> Vec<GateOptionValue> {
+        vec![
+            GateOptionValue {
+                name: "colony".to_string(),
+                value: GateOptionValueType::String(format!("{:?}", GATED_COLONY)),
+            },
+            GateOptionValue {
+                name: "domain".to_string(),
+                value: GateOptionValueType::I64(1),
+            },
+            GateOptionValue {
+                name: "reputation".to_string(),
+                value: GateOptionValueType::F64(25.0),
+            },
+        ]
+    }
+
+    /// The client is global, so all tests in here share the same mock
+    fn inject_mock_client() {
+        let _ = init_reputation_client(Arc::new(MockClient));
+    }
+
+    #[tokio::test]
+    async fn test_uncached_check_ignores_cache() {
+        inject_mock_client();
+        // a different colony than the other tests, so they don't share keys
+        let colony = H160::repeat_byte(0xc1);
+        let wallet = H160::repeat_byte(1);
+        {
+            let mut cache = COLONY_CACHE.lock().await;
+            cache.cache_set((colony, H160::zero(), 1), Ok("1000".to_string()));
+            cache.cache_set((colony, wallet, 1), Ok("1000".to_string()));
+        }
+        let gate = ReputationGate {
+            colony_address: colony,
+            ..reputation_gate()
+        };
+        // the stale cached reputation grants the role, the live one doesn't
+        assert!(gate.check(wallet).await.unwrap());
+        assert!(!gate.check_uncached(wallet).await.unwrap());
+        // the live result replaces the stale one in the cache
+        assert!(!gate.check(wallet).await.unwrap());
+    }
+
+    #[tokio::test]
+    async fn test_reputation_grant_is_explained() {
+        inject_mock_client();
+        let gate = Gate {
+            role_id: 1,
+            condition: Box::new(reputation_gate()),
+            enabled: true,
+            grant_message: None,
+        };
+        let wallet = secrecy::SecretString::new(format!("{:?}", H160::repeat_byte(1)));
+        let outcome = crate::controller::check_with_wallet(wallet, vec![gate].into_iter()).await;
+        assert_eq!(outcome.granted, vec![1]);
+        assert_eq!(outcome.reasons[&1], "reputation 50.00% ≥ 25%");
+    }
+
+    #[tokio::test]
+    async fn test_concurrent_lookups_are_coalesced() {
+        inject_mock_client();
+        let zero_address = H160::zero();
+        let lookups =
+            (0..10).map(|_| get_reputation_in_domain_cached(&COALESCED_COLONY, &zero_address, 1));
+        let results = futures::future::join_all(lookups).await;
+        assert!(results.iter().all(|result| result.as_deref() == Ok("1000")));
+        assert_eq!(COALESCED_LOOKUPS.load(Ordering::SeqCst), 1);
+        assert!(IN_FLIGHT.lock().await.is_empty());
+    }
+
+    #[test]
+    fn test_reputation_percentage() {
+        let percentage = |base: &str, user: &str| {
+            reputation_percentage(
+                U512::from_dec_str(base).unwrap(),
+                U512::from_dec_str(user).unwrap(),
+            )
+            .unwrap()
+        };
+        assert_eq!(percentage("1000", "0"), 0.0);
+        assert!((percentage("1000", "1000") - 100.0).abs() < 1e-9);
+        assert!((percentage("1000", "250") - 25.0).abs() < 1e-9);
+        assert!((percentage("3", "1") - 100.0 / 3.0).abs() < 1e-9);
+        // realistic reputation amounts exceed the float precision by far
+        assert!(
+            (percentage(
+                "1000000000000000000000000000000000000000",
+                "1234500000000000000000000000000000000"
+            ) - 0.12345)
+                .abs()
+                < 1e-9
+        );
+        assert_eq!(percentage("0", "1000"), 0.0);
+    }
+
+    #[test]
+    fn test_percentage_matches_threshold_check() {
+        let threshold = u256_from_f64_saturating(25.0 * PRECISION_FACTOR);
+        assert!(calculate_reputation_percentage(threshold, "1000", "250").unwrap());
+        assert!(!calculate_reputation_percentage(threshold, "1000", "249").unwrap());
+        let base = U512::from(1000);
+        assert!(reputation_percentage(base, U512::from(250)).unwrap() >= 25.0);
+        assert!(reputation_percentage(base, U512::from(249)).unwrap() < 25.0);
+    }
+
+    #[test]
+    fn test_bps_threshold_is_exact() {
+        // 5 bps of a huge base reputation, right at the boundary
+        let threshold = threshold_from_bps(5);
+        let base = "1000000000000000000000000000000000000000";
+        assert!(calculate_reputation_percentage(
+            threshold,
+            base,
+            "500000000000000000000000000000000000"
+        )
+        .unwrap());
+        assert!(!calculate_reputation_percentage(
+            threshold,
+            base,
+            "499999000000000000000000000000000000"
+        )
+        .unwrap());
+        assert!(calculate_reputation_percentage(threshold_from_bps(2500), "1000", "250").unwrap());
+        assert!(!calculate_reputation_percentage(threshold_from_bps(2501), "1000", "250").unwrap());
+        assert_eq!(
+            threshold_from_bps(BPS_PER_100_PERCENT),
+            U256::from(std::u128::MAX >> 1)
+        );
+        for bps in 1..=BPS_PER_100_PERCENT {
+            assert_eq!(bps_from_threshold(threshold_from_bps(bps)), bps);
+        }
+        // thresholds from a percentage are shown in the nearest basis point
+        let from_percentage = u256_from_f64_saturating(0.05 * PRECISION_FACTOR);
+        assert_eq!(bps_from_threshold(from_percentage), 5);
+    }
+
+    #[tokio::test]
+    async fn test_reputation_from_bps_option() {
+        inject_mock_client();
+        let mut bps_options = options();
+        bps_options[2] = GateOptionValue {
+            name: "reputation_bps".to_string(),
+            value: GateOptionValueType::I64(2500),
+        };
+        let gate = ReputationGate::from_options(&bps_options).await.unwrap();
+        assert_eq!(gate.reputation_threshold_scaled, threshold_from_bps(2500));
+        let fields = gate.fields();
+        assert!(fields.iter().any(|field| field.name == "reputation_bps"
+            && matches!(field.value, GateOptionValueType::I64(2500))));
+        assert!(fields.iter().any(|field| field.name == "reputation"));
+
+        // exactly one representation of the threshold is required
+        bps_options.push(options()[2].clone());
+        assert!(ReputationGate::from_options(&bps_options).await.is_err());
+        let mut no_threshold = options();
+        no_threshold.remove(2);
+        assert!(ReputationGate::from_options(&no_threshold).await.is_err());
+    }
+}
@@ -0,0 +1,187 @@
+//! Bounds the number of colony client calls that hit the network at the same
+//! time. This is independent of the rate limiter of the reputation gate,
+//! which only bounds the calls per second, while many slow calls can still
+//! pile up and open a connection each.
+//!
+
+use crate::config::CONFIG;
+use crate::gate::client::{self, ReputationClient};
+use anyhow::{bail, Result};
+use colony_rs::H160;
+use once_cell::sync::Lazy;
+use std::future::Future;
+use std::time::Duration;
+use tokio::sync::Semaphore;
+use tokio::time::Instant;
+use tracing::{info, trace, warn};
+
+/// The number of concurrent calls if the config is not set up, e.g. in tests
+const DEFAULT_RPC_CONCURRENCY: usize = 32;
+/// How long the readiness probe waits between its attempts
+const PROBE_RETRY_DELAY: Duration = Duration::from_secs(2);
+
+static RPC_PERMITS: Lazy<Semaphore> = Lazy::new(|| Semaphore::new(max_concurrency()));
+
+fn max_concurrency() -> usize {
+    let permits = CONFIG
+        .get()
+        .map_or(DEFAULT_RPC_CONCURRENCY, |cfg| cfg.rpc_concurrency);
+    // no permits at all would block every check forever
+    permits.max(1)
+}
+
+/// Runs a colony client call once a permit of the global limit is available
+pub async fn limited<F: Future>(call: F) -> F::Output {
+    with_permit(&RPC_PERMITS, call).await
+}
+
+/// The number of colony client calls that currently hold a permit
+pub fn calls_in_flight() -> usize {
+    max_concurrency().saturating_sub(RPC_PERMITS.available_permits())
+}
+
+/// Waits until the rpc is reachable if a readiness probe colony is
+/// configured. After the timeout it gives up, the bot then starts anyway
+pub async fn probe_at_startup() {
+    let config = CONFIG.wait();
+    let colony = match config.readiness_probe_colony {
+        Some(colony) => colony,
+        None => return,
+    };
+    let timeout = Duration::from_secs(config.readiness_probe_timeout);
+    info!(?colony, ?timeout, "Waiting for the rpc to become reachable");
+    let client = client::reputation_client();
+    if let Err(why) = wait_until_reachable(client, colony, timeout, PROBE_RETRY_DELAY).await {
+        warn!("Starting anyway: {:?}", why);
+    }
+}
+
+/// Looks up the domain count of a colony until it succeeds or the timeout
+/// passes, waiting `retry_delay` between the attempts. This is a cheap call
+/// that tells whether the rpc is reachable, e.g. at a cold start where the
+/// rpc node may come up after the bot
+pub async fn wait_until_reachable(
+    client: &dyn ReputationClient,
+    colony: H160,
+    timeout: Duration,
+    retry_delay: Duration,
+) -> Result<()> {
+    let deadline = Instant::now() + timeout;
+    let mut attempt = 1;
+    loop {
+        match client.get_domain_count(colony).await {
+            Ok(_) => {
+                info!(attempt, "The rpc is reachable");
+                return Ok(());
+            }
+            Err(why) if Instant::now() + retry_delay < deadline => {
+                info!(attempt, "The rpc is not reachable yet, retrying: {:?}", why);
+            }
+            Err(why) => bail!(
+                "The rpc is not reachable after {} attempts: {:?}",
+                attempt,
+                why
+            ),
+        }
+        tokio::time::sleep(retry_delay).await;
+        attempt += 1;
+    }
+}
+
+async fn with_permit<F: Future>(permits: &Semaphore, call: F) -> F::Output {
+    trace!("Waiting for rpc permit");
+    let _permit = permits
+        .acquire()
+        .await
+        .expect("The rpc semaphore is never closed");
+    call.await
+}
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use super::*;
+    use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+    use std::sync::Arc;
+    use std::time::Duration;
+
+    /// An rpc that is still starting, it refuses the domain count lookups for
+    /// the given number of attempts or until it is switched up
+    #[derive(Default)]
+    pub(crate) struct StartingRpc {
+        pub(crate) up: AtomicBool,
+        pub(crate) attempts: AtomicUsize,
+        pub(crate) failures: usize,
+    }
+
+    #[async_trait::async_trait]
+    impl ReputationClient for StartingRpc {
+        async fn get_reputation_in_domain(
+            &self,
+            _colony: &H160,
+            _wallet: &H160,
+            _domain: u64,
+        ) -> Result<String> {
+            bail!("Not mocked")
+        }
+
+        async fn get_domain_count(&self, _colony: H160) -> Result<u64> {
+            let attempt = self.attempts.fetch_add(1, Ordering::SeqCst);
+            if attempt < self.failures && !self.up.load(Ordering::SeqCst) {
+                bail!("Connection refused");
+            }
+            Ok(1)
+        }
+
+        async fn get_colony_name(&self, _colony: H160) -> Result<String> {
+            bail!("Not mocked")
+        }
+    }
+
+    #[tokio::test]
+    async fn test_wait_until_reachable() {
+        let client = StartingRpc {
+            failures: 2,
+            ..Default::default()
+        };
+        let delay = Duration::from_millis(1);
+        wait_until_reachable(&client, H160::zero(), Duration::from_secs(5), delay)
+            .await
+            .unwrap();
+        assert_eq!(client.attempts.load(Ordering::SeqCst), 3);
+
+        let client = StartingRpc {
+            failures: usize::MAX,
+            ..Default::default()
+        };
+        let result =
+            wait_until_reachable(&client, H160::zero(), Duration::from_millis(20), delay).await;
+        assert!(result.is_err());
+    }
+
+    #[tokio::test]
+    async fn test_concurrent_calls_stay_within_limit() {
+        let permits = Arc::new(Semaphore::new(3));
+        let running = Arc::new(AtomicUsize::new(0));
+        let max_running = Arc::new(AtomicUsize::new(0));
+        let mut set = tokio::task::JoinSet::new();
+        for _ in 0..20 {
+            let permits = permits.clone();
+            let running = running.clone();
+            let max_running = max_running.clone();
+            set.spawn(async move {
+                with_permit(&permits, async {
+                    let now = running.fetch_add(1, Ordering::SeqCst) + 1;
+                    max_running.fetch_max(now, Ordering::SeqCst);
+                    tokio::time::sleep(Duration::from_millis(5)).await;
+                    running.fetch_sub(1, Ordering::SeqCst);
+                })
+                .await
+            });
+        }
+        while let Some(result) = set.join_next().await {
+            result.unwrap();
+        }
+        assert_eq!(max_running.load(Ordering::SeqCst), 3);
+        assert_eq!(running.load(Ordering::SeqCst), 0);
+    }
+}
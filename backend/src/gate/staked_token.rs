@@ -0,0 +1,335 @@
+use crate::gate::{
+    check_allowed_token, client, default_chain_id, GateOption, GateOptionType, GateOptionValue,
+    GateOptionValueType, GatingCondition, InvalidOption, TokenClient,
+};
+use anyhow::{anyhow, bail, Context, Result};
+use async_trait::async_trait;
+use colony_rs::{H160, U256};
+use serde::{Deserialize, Serialize};
+use std::boxed::Box;
+use std::collections::hash_map::DefaultHasher;
+use std::hash::{Hash, Hasher};
+use std::str::FromStr;
+use tracing::{debug, instrument, warn, Instrument};
+
+/// Guards a role with the amount of tokens a wallet has staked or locked in a
+/// staking contract. The staking contract must expose an ERC-20 like
+/// `balanceOf`, that returns the staked balance of a wallet.
+///
+/// The amount is given in whole tokens and scaled with the decimals of the
+/// staked token. If the staking contract holds a different token than the
+/// one it reports balances in (e.g. it wraps the token into a receipt token),
+/// the `token_address` of the staked token can be given and its decimals and
+/// symbol are used. Otherwise the staking contract itself is treated as the
+/// token.
+#[derive(Debug, Clone, Deserialize, Hash, Serialize, PartialEq, Eq)]
+pub struct StakedTokenGate {
+    pub chain_id: U256,
+    /// The staking contract on the gnosis chain
+    pub staking_contract: H160,
+    /// The staked token, if it differs from the staking contract
+    pub token_address: Option<H160>,
+    pub token_symbol: String,
+    pub token_decimals: u8,
+    /// The amount of the token staked
+    pub amount: u64,
+}
+
+impl StakedTokenGate {
+    /// Whether a staked balance as reported by the staking contract meets
+    /// the amount of this gate
+    fn meets_threshold(&self, staked: U256) -> bool {
+        let amount_scaled =
+            U256::from(self.amount) * U256::from(10).pow(self.token_decimals.into());
+        debug!(?amount_scaled, "Scaled amount");
+        amount_scaled <= staked
+    }
+
+    /// Looks up the staked balance of the wallet in the staking contract with
+    /// the given client
+    async fn check_with(&self, client: &dyn TokenClient, wallet_address: H160) -> Result<bool> {
+        let staked = match client
+            .balance_of(&self.staking_contract, &wallet_address)
+            .in_current_span()
+            .await
+        {
+            Ok(b) => b,
+            Err(why) => {
+                warn!("Failed to get staked balance: {}", why);
+                bail!("Failed to get staked balance: {}", why);
+            }
+        };
+        debug!(?staked, "Got staked balance");
+        Ok(self.meets_threshold(staked))
+    }
+}
+
+#[typetag::serde]
+#[async_trait]
+impl GatingCondition for StakedTokenGate {
+    fn name() -> &'static str {
+        "staked_token"
+    }
+
+    fn description() -> &'static str {
+        "Guards a role with a staked token balance on the gnosis chain"
+    }
+
+    fn options() -> Vec<GateOption> {
+        vec![
+            GateOption {
+                name: "staking_contract",
+                description: "The staking contract on the gnosis chain",
+                required: true,
+                option_type: GateOptionType::String {
+                    min_length: Some(42),
+                    max_length: Some(42),
+                },
+            },
+            GateOption {
+                name: "amount",
+                description: "The amount of the token that must be staked",
+                required: true,
+                option_type: GateOptionType::I64 {
+                    min: Some(1),
+                    max: None,
+                },
+            },
+            GateOption {
+                name: "token_address",
+                description: "The staked token, if it differs from the staking contract",
+                required: false,
+                option_type: GateOptionType::String {
+                    min_length: Some(42),
+                    max_length: Some(42),
+                },
+            },
+        ]
+    }
+
+    #[instrument(level = "debug")]
+    async fn from_options(options: &[GateOptionValue]) -> Result<Box<Self>> {
+        debug!("Creating staked token gate from options");
+        let mut staking_contract = None;
+        let mut amount = None;
+        let mut token_address = None;
+        for option in options {
+            match (option.name.as_str(), &option.value) {
+                ("staking_contract", GateOptionValueType::String(s)) => {
+                    staking_contract = Some(H160::from_str(s).map_err(|_| {
+                        InvalidOption::address(
+                            "Failed to create staked token gate, invalid staking contract address",
+                        )
+                    })?)
+                }
+                ("amount", GateOptionValueType::I64(i)) => amount = Some(*i),
+                ("token_address", GateOptionValueType::String(s)) => {
+                    token_address = Some(H160::from_str(s).map_err(|_| {
+                        InvalidOption::address(
+                            "Failed to create staked token gate, invalid token address",
+                        )
+                    })?)
+                }
+                (name, _) => bail!("Invalid option {} for the staked token gate", name),
+            }
+        }
+        let staking_contract =
+            staking_contract.ok_or(anyhow!("The staking_contract option is required"))?;
+        let amount = amount.ok_or(anyhow!("The amount option is required"))?;
+        if amount < 1 {
+            bail!(InvalidOption::out_of_range("The amount must be at least 1"));
+        }
+        let chain_id = default_chain_id();
+
+        let token = token_address.unwrap_or(staking_contract);
+        check_allowed_token(token).context("Failed to create staked token gate")?;
+        let token_symbol = client::token_client()
+            .get_token_symbol(token)
+            .in_current_span()
+            .await
+            .unwrap_or_else(|why| {
+                warn!("Failed to get token symbol: {}", why);
+                "".to_string()
+            });
+        debug!(token_symbol, "Token symbol is:");
+        let token_decimals = client::token_client()
+            .get_token_decimals(token)
+            .in_current_span()
+            .await
+            .context("Failed to create staked token gate, could not get token decimals")?;
+        debug!(token_decimals, "Got token decimals:");
+
+        debug!("Done creating staked token gate from options");
+        Ok(Box::new(StakedTokenGate {
+            chain_id,
+            staking_contract,
+            token_address,
+            token_symbol,
+            token_decimals,
+            amount: amount as u64,
+        }))
+    }
+
+    #[instrument(name = "staked_token_condition", skip(wallet_address))]
+    async fn check(&self, wallet_address: H160) -> Result<bool> {
+        self.check_with(client::token_client(), wallet_address)
+            .in_current_span()
+            .await
+    }
+
+    fn hashed(&self) -> u64 {
+        let mut hasher = DefaultHasher::new();
+        self.hash(&mut hasher);
+        hasher.finish()
+    }
+
+    fn fields(&self) -> Vec<GateOptionValue> {
+        let mut fields = vec![
+            GateOptionValue {
+                name: "chain_id".to_string(),
+                value: GateOptionValueType::String(format!("{:#x}", self.chain_id)),
+            },
+            GateOptionValue {
+                name: "staking_contract".to_string(),
+                value: GateOptionValueType::String(format!("{:?}", self.staking_contract)),
+            },
+        ];
+        if let Some(token_address) = self.token_address {
+            fields.push(GateOptionValue {
+                name: "token_address".to_string(),
+                value: GateOptionValueType::String(format!("{:?}", token_address)),
+            });
+        }
+        fields.extend([
+            GateOptionValue {
+                name: "token_symbol".to_string(),
+                value: GateOptionValueType::String(format!("{:?}", self.token_symbol)),
+            },
+            GateOptionValue {
+                name: "amount".to_string(),
+                value: GateOptionValueType::I64(self.amount as i64),
+            },
+        ]);
+        fields
+    }
+
+    fn instance_name(&self) -> &'static str {
+        Self::name()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const STAKER: H160 = H160::repeat_byte(0xb1);
+
+    /// Knows the staked balance of one wallet in one staking contract only
+    struct StakingMockClient {
+        staking_contract: H160,
+    }
+
+    #[async_trait]
+    impl TokenClient for StakingMockClient {
+        async fn balance_of(&self, token: &H160, wallet: &H160) -> Result<U256> {
+            if *token != self.staking_contract {
+                bail!("Not a staking contract");
+            }
+            if *wallet == STAKER {
+                Ok(U256::from(5) * U256::from(10).pow(U256::from(18)))
+            } else {
+                Ok(U256::zero())
+            }
+        }
+
+        async fn get_token_decimals(&self, _token: H160) -> Result<u8> {
+            bail!("Not mocked")
+        }
+
+        async fn get_token_symbol(&self, _token: H160) -> Result<String> {
+            bail!("Not mocked")
+        }
+    }
+
+    fn staked_gate(amount: u64, token_decimals: u8) -> StakedTokenGate {
+        StakedTokenGate {
+            chain_id: U256::from(100),
+            staking_contract: H160::from_str("0xc9B6218AffE8Aba68a13899Cbf7cF7f14DDd304C")
+                .unwrap(),
+            token_address: None,
+            token_symbol: "STK".to_string(),
+            token_decimals,
+            amount,
+        }
+    }
+
+    #[test]
+    fn test_staked_threshold() {
+        let gate = staked_gate(5, 18);
+        let one_token = U256::from(10).pow(U256::from(18));
+        assert!(!gate.meets_threshold(U256::zero()));
+        assert!(!gate.meets_threshold(one_token * U256::from(5) - U256::from(1)));
+        assert!(gate.meets_threshold(one_token * U256::from(5)));
+        assert!(gate.meets_threshold(one_token * U256::from(100)));
+    }
+
+    #[tokio::test]
+    async fn test_staked_balance_is_looked_up_in_staking_contract() {
+        // the balance is reported by the staking contract, not by the token
+        let gate = StakedTokenGate {
+            token_address: Some(H160::repeat_byte(0xb2)),
+            ..staked_gate(5, 18)
+        };
+        let client = StakingMockClient {
+            staking_contract: gate.staking_contract,
+        };
+        assert!(gate.check_with(&client, STAKER).await.unwrap());
+        assert!(!gate
+            .check_with(&client, H160::repeat_byte(0xb3))
+            .await
+            .unwrap());
+        let higher = StakedTokenGate {
+            amount: 6,
+            ..gate.clone()
+        };
+        assert!(!higher.check_with(&client, STAKER).await.unwrap());
+
+        // a failed lookup is an error, not an unmet condition
+        let other_contract = StakingMockClient {
+            staking_contract: H160::repeat_byte(0xb4),
+        };
+        assert!(gate.check_with(&other_contract, STAKER).await.is_err());
+    }
+
+    #[test]
+    fn test_staked_threshold_uses_staked_token_decimals() {
+        // e.g. a staking contract for a 6 decimals stable coin
+        let gate = staked_gate(5, 6);
+        assert!(gate.meets_threshold(U256::from(5_000_000)));
+        assert!(!gate.meets_threshold(U256::from(4_999_999)));
+    }
+
+    #[tokio::test]
+    async fn test_staked_options_validation() {
+        let missing_contract = vec![GateOptionValue {
+            name: "amount".to_string(),
+            value: GateOptionValueType::I64(1),
+        }];
+        assert!(StakedTokenGate::from_options(&missing_contract)
+            .await
+            .is_err());
+        let invalid_address = vec![
+            GateOptionValue {
+                name: "staking_contract".to_string(),
+                value: GateOptionValueType::String("0xnotanaddress".to_string()),
+            },
+            GateOptionValue {
+                name: "amount".to_string(),
+                value: GateOptionValueType::I64(1),
+            },
+        ];
+        assert!(StakedTokenGate::from_options(&invalid_address)
+            .await
+            .is_err());
+    }
+}
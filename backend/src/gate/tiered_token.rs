@@ -0,0 +1,343 @@
+use crate::gate::{
+    check_allowed_token, client, default_chain_id, GateOption, GateOptionType, GateOptionValue,
+    GateOptionValueType, GatingCondition, InvalidOption,
+};
+use anyhow::{anyhow, bail, Context, Result};
+use async_trait::async_trait;
+use colony_rs::{H160, U256};
+use serde::{Deserialize, Serialize};
+use std::boxed::Box;
+use std::collections::hash_map::DefaultHasher;
+use std::hash::{Hash, Hasher};
+use std::str::FromStr;
+use tracing::{debug, instrument, warn, Instrument};
+
+/// Guards a ladder of roles with ascending token balances, e.g. bronze,
+/// silver and gold. The role of the gate is the lowest tier, a wallet is
+/// granted only the highest tier it qualifies for, so the lower tier roles
+/// are revoked by the enforcement once a wallet moves up or down the ladder.
+#[derive(Debug, Clone, Deserialize, Hash, Serialize, PartialEq, Eq)]
+pub struct TieredTokenGate {
+    pub chain_id: U256,
+    /// The token address on the gnossis chain
+    pub token_address: H160,
+    pub token_symbol: String,
+    pub token_decimals: u8,
+    /// The amount of the token held for the lowest tier, the role of the gate
+    pub amount: u64,
+    /// The higher tiers as ascending amounts with the role they grant
+    pub tiers: Vec<(u64, u64)>,
+}
+
+impl TieredTokenGate {
+    /// The role of the highest tier the balance qualifies for, if any
+    fn tier_for_balance(&self, balance: U256, role_id: u64) -> Option<u64> {
+        let scale = U256::from(10).pow(self.token_decimals.into());
+        std::iter::once((self.amount, role_id))
+            .chain(self.tiers.iter().copied())
+            .take_while(|(amount, _)| U256::from(*amount) * scale <= balance)
+            .last()
+            .map(|(_, role_id)| role_id)
+    }
+
+    async fn balance(&self, wallet_address: H160) -> Result<U256> {
+        client::token_client()
+            .balance_of(&self.token_address, &wallet_address)
+            .in_current_span()
+            .await
+            .map_err(|why| {
+                warn!("Failed to get balance: {}", why);
+                why.context("Failed to get balance")
+            })
+    }
+}
+
+#[typetag::serde]
+#[async_trait]
+impl GatingCondition for TieredTokenGate {
+    fn name() -> &'static str {
+        "tiered_token"
+    }
+
+    fn description() -> &'static str {
+        "Guards a ladder of roles with token balances, granting only the highest tier"
+    }
+
+    fn options() -> Vec<GateOption> {
+        vec![
+            GateOption {
+                name: "token_address",
+                description: "The token address on the gnosis chain",
+                required: true,
+                option_type: GateOptionType::String {
+                    min_length: Some(42),
+                    max_length: Some(42),
+                },
+            },
+            GateOption {
+                name: "amount",
+                description: "The amount of the token for the role of the lowest tier",
+                required: true,
+                option_type: GateOptionType::I64 {
+                    min: Some(1),
+                    max: None,
+                },
+            },
+            GateOption {
+                name: "tiers",
+                description: "The higher tiers as amount:role pairs, e.g. 500:@Silver, 1000:@Gold",
+                required: true,
+                option_type: GateOptionType::String {
+                    min_length: Some(3),
+                    max_length: None,
+                },
+            },
+        ]
+    }
+
+    #[instrument(level = "debug")]
+    async fn from_options(options: &[GateOptionValue]) -> Result<Box<Self>> {
+        debug!("Creating tiered token gate from options");
+        if options.len() != 3 {
+            bail!("Need exactly 3 options");
+        }
+        if options[0].name != "token_address" {
+            bail!("First option must be token_address");
+        }
+        let token_address = match &options[0].value {
+            GateOptionValueType::String(s) => H160::from_str(s).map_err(|_| {
+                InvalidOption::address("Failed to create tiered token gate, invalid address")
+            })?,
+            _ => bail!("Invalid option type"),
+        };
+        check_allowed_token(token_address).context("Failed to create tiered token gate")?;
+        if options[1].name != "amount" {
+            bail!("Second option must be amount");
+        }
+        let amount = match &options[1].value {
+            GateOptionValueType::I64(i) if *i > 0 => *i as u64,
+            _ => bail!(InvalidOption::out_of_range(
+                "The amount must be a positive integer"
+            )),
+        };
+        if options[2].name != "tiers" {
+            bail!("Third option must be tiers");
+        }
+        let tiers = match &options[2].value {
+            GateOptionValueType::String(s) => {
+                parse_tiers(s, amount).context("Failed to create tiered token gate")?
+            }
+            _ => bail!("Invalid option type"),
+        };
+        let chain_id = default_chain_id();
+
+        let token_symbol = client::token_client()
+            .get_token_symbol(token_address)
+            .in_current_span()
+            .await
+            .unwrap_or_else(|why| {
+                warn!("Failed to get token symbol: {}", why);
+                "".to_string()
+            });
+        debug!(token_symbol, "Token symbol is:");
+        let token_decimals = client::token_client()
+            .get_token_decimals(token_address)
+            .in_current_span()
+            .await
+            .context("Failed to create tiered token gate, could not get token decimals")?;
+        debug!(token_decimals, "Got token decimals:");
+
+        debug!("Done creating tiered token gate from options");
+        Ok(Box::new(TieredTokenGate {
+            chain_id,
+            token_address,
+            token_symbol,
+            token_decimals,
+            amount,
+            tiers,
+        }))
+    }
+
+    /// Whether the wallet qualifies for any tier
+    #[instrument(name = "tiered_token_condition", skip(wallet_address))]
+    async fn check(&self, wallet_address: H160) -> Result<bool> {
+        let balance = self.balance(wallet_address).in_current_span().await?;
+        debug!(?balance, "Got token");
+        Ok(self.tier_for_balance(balance, 0).is_some())
+    }
+
+    #[instrument(name = "tiered_token_condition", skip(wallet_address))]
+    async fn granted_role(
+        &self,
+        wallet_address: H160,
+        role_id: u64,
+        _bypass_cache: bool,
+    ) -> Result<Option<u64>> {
+        let balance = self.balance(wallet_address).in_current_span().await?;
+        debug!(?balance, "Got token");
+        Ok(self.tier_for_balance(balance, role_id))
+    }
+
+    fn role_ids(&self, role_id: u64) -> Vec<u64> {
+        std::iter::once(role_id)
+            .chain(self.tiers.iter().map(|(_, role_id)| *role_id))
+            .collect()
+    }
+
+    fn hashed(&self) -> u64 {
+        let mut hasher = DefaultHasher::new();
+        self.hash(&mut hasher);
+        hasher.finish()
+    }
+
+    fn fields(&self) -> Vec<GateOptionValue> {
+        vec![
+            GateOptionValue {
+                name: "chain_id".to_string(),
+                value: GateOptionValueType::String(format!("{:#x}", self.chain_id)),
+            },
+            GateOptionValue {
+                name: "token_address".to_string(),
+                value: GateOptionValueType::String(format!("{:?}", self.token_address)),
+            },
+            GateOptionValue {
+                name: "token_symbol".to_string(),
+                value: GateOptionValueType::String(format!("{:?}", self.token_symbol)),
+            },
+            GateOptionValue {
+                name: "amount".to_string(),
+                value: GateOptionValueType::I64(self.amount as i64),
+            },
+            GateOptionValue {
+                name: "tiers".to_string(),
+                value: GateOptionValueType::String(
+                    self.tiers
+                        .iter()
+                        .map(|(amount, role_id)| format!("{}:<@&{}>", amount, role_id))
+                        .collect::<Vec<_>>()
+                        .join(", "),
+                ),
+            },
+        ]
+    }
+
+    fn instance_name(&self) -> &'static str {
+        Self::name()
+    }
+}
+
+/// Parses comma separated `amount:role` tiers, where the role is a role id,
+/// optionally after an `@`, or a role mention. Role names like `@Silver` are
+/// replaced by mentions before the gate is created in discord. The amounts
+/// must be above the amount of the lowest tier and are sorted ascending
+fn parse_tiers(s: &str, lowest_amount: u64) -> Result<Vec<(u64, u64)>> {
+    let mut tiers = s
+        .split(',')
+        .map(str::trim)
+        .filter(|tier| !tier.is_empty())
+        .map(|tier| {
+            let (amount, role) = tier
+                .split_once(':')
+                .ok_or_else(|| anyhow!("Invalid tier {:?}, expected amount:role", tier))?;
+            let amount = amount
+                .trim()
+                .parse::<u64>()
+                .with_context(|| format!("Invalid amount in tier {:?}", tier))?;
+            let role = role.trim();
+            let role_id = role
+                .strip_prefix("<@&")
+                .and_then(|role| role.strip_suffix('>'))
+                .or_else(|| role.strip_prefix('@'))
+                .unwrap_or(role)
+                .parse::<u64>()
+                .with_context(|| format!("Invalid role in tier {:?}", tier))?;
+            Ok((amount, role_id))
+        })
+        .collect::<Result<Vec<_>>>()?;
+    if tiers.is_empty() {
+        bail!("At least one tier is required");
+    }
+    tiers.sort_unstable();
+    let mut previous = lowest_amount;
+    for (amount, _) in tiers.iter() {
+        if *amount <= previous {
+            bail!(InvalidOption::out_of_range(
+                "The amounts of all tiers must be different and above the lowest amount"
+            ));
+        }
+        previous = *amount;
+    }
+    let mut role_ids = tiers
+        .iter()
+        .map(|(_, role_id)| *role_id)
+        .collect::<Vec<_>>();
+    role_ids.sort_unstable();
+    role_ids.dedup();
+    if role_ids.len() != tiers.len() {
+        bail!("Every tier needs its own role");
+    }
+    Ok(tiers)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const BRONZE: u64 = 1;
+    const SILVER: u64 = 2;
+    const GOLD: u64 = 3;
+
+    fn ladder() -> TieredTokenGate {
+        TieredTokenGate {
+            chain_id: U256::from(100),
+            token_address: H160::zero(),
+            token_symbol: "TKN".to_string(),
+            token_decimals: 18,
+            amount: 100,
+            tiers: parse_tiers(&format!("1000:<@&{}>, 500:{}", GOLD, SILVER), 100).unwrap(),
+        }
+    }
+
+    fn tokens(amount: u64) -> U256 {
+        U256::from(amount) * U256::exp10(18)
+    }
+
+    #[test]
+    fn test_ladder_selects_exactly_one_tier() {
+        let gate = ladder();
+        assert_eq!(gate.tiers, vec![(500, SILVER), (1000, GOLD)]);
+        assert_eq!(gate.tier_for_balance(tokens(99), BRONZE), None);
+        assert_eq!(gate.tier_for_balance(tokens(100), BRONZE), Some(BRONZE));
+        assert_eq!(gate.tier_for_balance(tokens(999), BRONZE), Some(SILVER));
+        assert_eq!(gate.tier_for_balance(tokens(5000), BRONZE), Some(GOLD));
+        assert_eq!(gate.role_ids(BRONZE), vec![BRONZE, SILVER, GOLD]);
+    }
+
+    #[test]
+    fn test_ladder_downgrades_when_balance_drops() {
+        let gate = ladder();
+        let gold = gate.tier_for_balance(tokens(1200), BRONZE);
+        assert_eq!(gold, Some(GOLD));
+        let silver = gate.tier_for_balance(tokens(600), BRONZE);
+        assert_eq!(silver, Some(SILVER));
+        // all tiers are managed by the gate, so the enforcement revokes gold
+        // once only silver is granted
+        assert!(gate.role_ids(BRONZE).contains(&GOLD));
+        assert_eq!(gate.tier_for_balance(tokens(0), BRONZE), None);
+    }
+
+    #[test]
+    fn test_parse_tiers() {
+        assert!(parse_tiers("", 100).is_err());
+        assert!(parse_tiers("500", 100).is_err());
+        assert!(parse_tiers("50:2", 100).is_err());
+        assert!(parse_tiers("500:2, 500:3", 100).is_err());
+        assert!(parse_tiers("500:2, 1000:2", 100).is_err());
+        assert_eq!(
+            parse_tiers(&format!("500:@{}", SILVER), 100).unwrap(),
+            vec![(500, SILVER)]
+        );
+        // names are resolved in discord, the gate only takes role ids
+        assert!(parse_tiers("500:@Silver", 100).is_err());
+    }
+}
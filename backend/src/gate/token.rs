@@ -1,9 +1,10 @@
 use crate::gate::{
-    GateOption, GateOptionType, GateOptionValue, GateOptionValueType, GatingCondition,
+    check_allowed_token, client, default_chain_id, GateOption, GateOptionType, GateOptionValue,
+    GateOptionValueType, GatingCondition, InvalidOption,
 };
 use anyhow::{anyhow, bail, Context, Result};
 use async_trait::async_trait;
-use colony_rs::{balance_off, get_token_decimals, get_token_symbol, H160, U256};
+use colony_rs::{H160, U256};
 use serde::{Deserialize, Serialize};
 use std::boxed::Box;
 use std::collections::hash_map::DefaultHasher;
@@ -68,11 +69,12 @@ impl GatingCondition for TokenGate {
             bail!("First option must be token_address");
         }
         let token_address = match &options[0].value {
-            GateOptionValueType::String(s) => {
-                H160::from_str(s).context("Failed to create token gate, invalid address")?
-            }
+            GateOptionValueType::String(s) => H160::from_str(s).map_err(|_| {
+                InvalidOption::address("Failed to create token gate, invalid address")
+            })?,
             _ => bail!("Invalid option type"),
         };
+        check_allowed_token(token_address).context("Failed to create token gate")?;
         if options[1].name != "amount" {
             bail!("Second option must be amount");
         }
@@ -80,9 +82,10 @@ impl GatingCondition for TokenGate {
             GateOptionValueType::I64(i) => *i,
             _ => return Err(anyhow!("Invalid option type").context("Failed to create token gate")),
         };
-        let chain_id = U256::from(100);
+        let chain_id = default_chain_id();
 
-        let token_symbol = get_token_symbol(token_address)
+        let token_symbol = client::token_client()
+            .get_token_symbol(token_address)
             .in_current_span()
             .await
             .unwrap_or_else(|why| {
@@ -90,7 +93,8 @@ impl GatingCondition for TokenGate {
                 "".to_string()
             });
         debug!(token_symbol, "Token symbol is:");
-        let token_decimals = get_token_decimals(token_address)
+        let token_decimals = client::token_client()
+            .get_token_decimals(token_address)
             .in_current_span()
             .await
             .context("Failed to create token gate, could not get token decimals")?;
@@ -108,22 +112,23 @@ impl GatingCondition for TokenGate {
     }
 
     #[instrument(name = "token_condition", skip(wallet_address))]
-    async fn check(&self, wallet_address: H160) -> bool {
-        let balance = match balance_off(&self.token_address, &wallet_address)
+    async fn check(&self, wallet_address: H160) -> Result<bool> {
+        let balance = match client::token_client()
+            .balance_of(&self.token_address, &wallet_address)
             .in_current_span()
             .await
         {
             Ok(b) => b,
             Err(why) => {
                 warn!("Failed to get balance: {}", why);
-                return false;
+                bail!("Failed to get balance: {}", why);
             }
         };
         debug!(?balance, "Got token");
         let amount_scaled =
             U256::from(self.amount) * U256::from(10).pow(self.token_decimals.into());
         debug!(?amount_scaled, "Scaled amount");
-        amount_scaled <= balance
+        Ok(amount_scaled <= balance)
     }
 
     fn hashed(&self) -> u64 {
@@ -0,0 +1,227 @@
+use crate::gate::{
+    check_allowed_token, client, default_chain_id, GateOption, GateOptionType, GateOptionValue,
+    GateOptionValueType, GatingCondition, InvalidOption,
+};
+use anyhow::{anyhow, bail, Context, Result};
+use async_trait::async_trait;
+use colony_rs::{H160, U256, U512};
+use serde::{Deserialize, Serialize};
+use std::boxed::Box;
+use std::collections::hash_map::DefaultHasher;
+use std::hash::{Hash, Hasher};
+use std::str::FromStr;
+use tracing::{debug, instrument, warn, Instrument};
+
+/// The share is stored in millionths of a percent. Unlike the reputation
+/// threshold it is an integer, so a wallet holding exactly the share meets
+/// it, which a float threshold can miss by rounding
+const SHARE_SCALE: f64 = 1_000_000.0;
+
+/// Guards a role with a share of the total supply of a token, e.g. holding
+/// at least 1% of all tokens. The supply is looked up with every check, so
+/// the gate follows mints and burns
+#[derive(Debug, Clone, Deserialize, Hash, Serialize, PartialEq, Eq)]
+pub struct TokenSupplyShareGate {
+    pub chain_id: U256,
+    /// The token address on the gnosis chain
+    pub token_address: H160,
+    pub token_symbol: String,
+    /// The required percentage of the total supply in millionths of a
+    /// percent
+    pub share_scaled: u64,
+}
+
+#[typetag::serde]
+#[async_trait]
+impl GatingCondition for TokenSupplyShareGate {
+    fn name() -> &'static str {
+        "token_supply_share"
+    }
+
+    fn description() -> &'static str {
+        "Guards a role with a percentage of the total supply of a token"
+    }
+
+    fn options() -> Vec<GateOption> {
+        vec![
+            GateOption {
+                name: "token_address",
+                description: "The token address on the gnosis chain",
+                required: true,
+                option_type: GateOptionType::String {
+                    min_length: Some(42),
+                    max_length: Some(42),
+                },
+            },
+            GateOption {
+                name: "share",
+                description: "The percentage of the total supply required to be granted the role",
+                required: true,
+                option_type: GateOptionType::F64 {
+                    min: Some(0.0),
+                    max: Some(100.0),
+                },
+            },
+        ]
+    }
+
+    #[instrument(level = "debug")]
+    async fn from_options(options: &[GateOptionValue]) -> Result<Box<Self>> {
+        debug!("Creating token supply share gate from options");
+        if options.len() != 2 {
+            bail!("Need exactly 2 options");
+        }
+        if options[0].name != "token_address" {
+            bail!("First option must be token_address");
+        }
+        let token_address = match &options[0].value {
+            GateOptionValueType::String(s) => H160::from_str(s).map_err(|_| {
+                InvalidOption::address("Failed to create token supply share gate, invalid address")
+            })?,
+            _ => bail!("Invalid option type, expected string for token address"),
+        };
+        check_allowed_token(token_address).context("Failed to create token supply share gate")?;
+        if options[1].name != "share" {
+            bail!("Second option must be share");
+        }
+        let share = match &options[1].value {
+            GateOptionValueType::F64(f) => *f,
+            _ => bail!("Invalid option type, expected float for share"),
+        };
+        if share > 100.0 {
+            bail!(InvalidOption::out_of_range("Share must be 100 or less"))
+        }
+        if share <= 0.0 || share.is_nan() {
+            bail!(InvalidOption::out_of_range("Share must be more than 0"))
+        }
+        // the supply must be known, otherwise the gate could never be checked
+        client::token_client()
+            .get_total_supply(token_address)
+            .in_current_span()
+            .await
+            .context("Failed to create token supply share gate, could not get total supply")?;
+        let token_symbol = client::token_client()
+            .get_token_symbol(token_address)
+            .in_current_span()
+            .await
+            .unwrap_or_else(|why| {
+                warn!("Failed to get token symbol: {}", why);
+                "".to_string()
+            });
+        debug!(token_symbol, "Token symbol is:");
+
+        debug!("Done creating token supply share gate from options");
+        Ok(Box::new(TokenSupplyShareGate {
+            chain_id: default_chain_id(),
+            token_address,
+            token_symbol,
+            share_scaled: (share * SHARE_SCALE).round() as u64,
+        }))
+    }
+
+    #[instrument(name = "token_supply_share_condition", skip(wallet_address))]
+    async fn check(&self, wallet_address: H160) -> Result<bool> {
+        let client = client::token_client();
+        let balance = client
+            .balance_of(&self.token_address, &wallet_address)
+            .in_current_span()
+            .await
+            .map_err(|why| {
+                warn!("Failed to get balance: {}", why);
+                why.context("Failed to get balance")
+            })?;
+        let supply = client
+            .get_total_supply(self.token_address)
+            .in_current_span()
+            .await
+            .map_err(|why| {
+                warn!("Failed to get total supply: {}", why);
+                why.context("Failed to get total supply")
+            })?;
+        debug!(?balance, ?supply, "Got balance and supply");
+        meets_share(self.share_scaled, supply, balance)
+    }
+
+    fn hashed(&self) -> u64 {
+        let mut hasher = DefaultHasher::new();
+        self.hash(&mut hasher);
+        hasher.finish()
+    }
+
+    fn fields(&self) -> Vec<GateOptionValue> {
+        vec![
+            GateOptionValue {
+                name: "chain_id".to_string(),
+                value: GateOptionValueType::String(format!("{:#x}", self.chain_id)),
+            },
+            GateOptionValue {
+                name: "token_address".to_string(),
+                value: GateOptionValueType::String(format!("{:?}", self.token_address)),
+            },
+            GateOptionValue {
+                name: "token_symbol".to_string(),
+                value: GateOptionValueType::String(format!("{:?}", self.token_symbol)),
+            },
+            GateOptionValue {
+                name: "share".to_string(),
+                value: GateOptionValueType::F64(self.share_scaled as f64 / SHARE_SCALE),
+            },
+        ]
+    }
+
+    fn instance_name(&self) -> &'static str {
+        Self::name()
+    }
+}
+
+/// Whether the balance is at least the scaled share of the supply. Like the
+/// reputation percentage, the quotient is never built, instead both sides
+/// are multiplied out on big integers
+///
+/// share% * SHARE_SCALE <= 100% * SHARE_SCALE * balance / supply
+/// => share * SHARE_SCALE * supply <= 100 * SHARE_SCALE * balance
+fn meets_share(share_scaled: u64, supply: U256, balance: U256) -> Result<bool> {
+    if supply.is_zero() {
+        return Ok(false);
+    }
+    let left_side = U512::from(share_scaled)
+        .checked_mul(U512::from(supply))
+        .ok_or(anyhow!(
+            "Failed to calculate supply share left side, overflow"
+        ))?;
+    let right_side = U512::from(100 * SHARE_SCALE as u64)
+        .checked_mul(U512::from(balance))
+        .ok_or(anyhow!(
+            "Failed to calculate supply share right side, overflow"
+        ))?;
+    Ok(left_side <= right_side)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn threshold(share: f64) -> u64 {
+        (share * SHARE_SCALE).round() as u64
+    }
+
+    #[test]
+    fn test_share_of_supply() {
+        let supply = U256::exp10(27);
+        let one_percent = U256::exp10(25);
+        assert!(meets_share(threshold(1.0), supply, one_percent).unwrap());
+        assert!(!meets_share(threshold(1.0), supply, one_percent - 1).unwrap());
+        assert!(meets_share(threshold(100.0), supply, supply).unwrap());
+        // minting dilutes the share of the same balance
+        assert!(!meets_share(threshold(1.0), supply * 2, one_percent).unwrap());
+        // nobody holds a share of a token without supply
+        assert!(!meets_share(threshold(1.0), U256::zero(), one_percent).unwrap());
+    }
+
+    #[test]
+    fn test_huge_supply_does_not_overflow() {
+        assert!(meets_share(threshold(50.0), U256::MAX, U256::MAX / 2 + 1).unwrap());
+        assert!(!meets_share(threshold(50.0), U256::MAX, U256::MAX / 2).unwrap());
+        assert!(meets_share(threshold(0.000001), U256::MAX, U256::MAX).unwrap());
+    }
+}
@@ -0,0 +1,332 @@
+use crate::gate::{
+    client, GateOption, GateOptionType, GateOptionValue, GateOptionValueType, GatingCondition,
+    InvalidOption, TokenClient, WalletActivity,
+};
+use crate::util::unix_timestamp;
+use anyhow::{bail, Result};
+use async_trait::async_trait;
+use cached::{proc_macro::cached, Cached, TimedCache};
+use colony_rs::H160;
+use serde::{Deserialize, Serialize};
+use std::boxed::Box;
+use std::collections::hash_map::DefaultHasher;
+use std::hash::{Hash, Hasher};
+use tracing::{debug, instrument, warn, Instrument};
+
+const SECONDS_PER_DAY: u64 = 24 * 3600;
+
+/// How long the activity of a wallet is cached. The history lookups are
+/// expensive and the activity only ever grows, so a wallet that met the gate
+/// keeps meeting it. The age is measured at check time against the cached
+/// first transaction, so a wallet qualifies by age as soon as it is old
+/// enough, only new transactions show up with this delay
+const WALLET_ACTIVITY_CACHE_SECONDS: u64 = SECONDS_PER_DAY;
+
+/// Guards a role with the on-chain history of a wallet, to deter sybil
+/// attacks with fresh wallets. The wallet must be at least `min_age_days`
+/// old, counted from its first transaction, and/or have sent at least
+/// `min_tx_count` transactions. All given thresholds must be met.
+/// The chain can't be queried for the history of a wallet, so the gate can
+/// only be created with an injected token client that implements
+/// [`TokenClient::get_wallet_activity`]
+///
+/// [`TokenClient::get_wallet_activity`]: crate::gate::TokenClient::get_wallet_activity
+#[derive(Debug, Clone, Deserialize, Hash, Serialize, PartialEq, Eq)]
+pub struct WalletAgeGate {
+    /// The minimum days since the first transaction of the wallet
+    pub min_age_days: Option<u64>,
+    /// The minimum number of transactions the wallet sent
+    pub min_tx_count: Option<u64>,
+}
+
+impl WalletAgeGate {
+    /// Whether the activity meets all thresholds of the gate at the given
+    /// unix timestamp
+    fn meets_thresholds(&self, activity: &WalletActivity, now: u64) -> bool {
+        let old_enough = self.min_age_days.is_none_or(|days| {
+            activity
+                .first_transaction_at
+                .is_some_and(|first| now.saturating_sub(first) >= days * SECONDS_PER_DAY)
+        });
+        let active_enough = self
+            .min_tx_count
+            .is_none_or(|count| activity.transaction_count >= count);
+        old_enough && active_enough
+    }
+}
+
+#[typetag::serde]
+#[async_trait]
+impl GatingCondition for WalletAgeGate {
+    fn name() -> &'static str {
+        "wallet_age"
+    }
+
+    fn description() -> &'static str {
+        "Guards a role with the age and transaction count of the wallet"
+    }
+
+    fn options() -> Vec<GateOption> {
+        vec![
+            GateOption {
+                name: "min_age_days",
+                description: "The minimum days since the first transaction of the wallet",
+                required: false,
+                option_type: GateOptionType::I64 {
+                    min: Some(1),
+                    max: None,
+                },
+            },
+            GateOption {
+                name: "min_tx_count",
+                description: "The minimum number of transactions the wallet sent",
+                required: false,
+                option_type: GateOptionType::I64 {
+                    min: Some(1),
+                    max: None,
+                },
+            },
+        ]
+    }
+
+    #[instrument(level = "debug")]
+    async fn from_options(options: &[GateOptionValue]) -> Result<Box<Self>> {
+        debug!("Creating wallet age gate from options");
+        let mut min_age_days = None;
+        let mut min_tx_count = None;
+        for option in options {
+            match (option.name.as_str(), &option.value) {
+                ("min_age_days", GateOptionValueType::I64(i)) if *i > 0 => {
+                    min_age_days = Some(*i as u64)
+                }
+                ("min_tx_count", GateOptionValueType::I64(i)) if *i > 0 => {
+                    min_tx_count = Some(*i as u64)
+                }
+                ("min_age_days" | "min_tx_count", _) => {
+                    bail!(InvalidOption::out_of_range(format!(
+                        "The {} must be a positive integer",
+                        option.name
+                    )))
+                }
+                (name, _) => bail!("Invalid option {} for the wallet age gate", name),
+            }
+        }
+        if min_age_days.is_none() && min_tx_count.is_none() {
+            bail!("At least one of min_age_days and min_tx_count is required");
+        }
+        check_activity_support(client::token_client())?;
+        debug!("Done creating wallet age gate from options");
+        Ok(Box::new(WalletAgeGate {
+            min_age_days,
+            min_tx_count,
+        }))
+    }
+
+    #[instrument(name = "wallet_age_condition", skip(wallet_address))]
+    async fn check(&self, wallet_address: H160) -> Result<bool> {
+        let activity = get_wallet_activity_cached(wallet_address)
+            .in_current_span()
+            .await?;
+        debug!(?activity, "Got wallet activity");
+        Ok(self.meets_thresholds(&activity, unix_timestamp()))
+    }
+
+    #[instrument(name = "wallet_age_condition_uncached", skip(wallet_address))]
+    async fn check_uncached(&self, wallet_address: H160) -> Result<bool> {
+        let activity = get_wallet_activity_live(wallet_address)
+            .in_current_span()
+            .await?;
+        debug!(?activity, "Got live wallet activity");
+        WALLET_ACTIVITY_CACHE
+            .lock()
+            .await
+            .cache_set(wallet_address, activity);
+        Ok(self.meets_thresholds(&activity, unix_timestamp()))
+    }
+
+    fn hashed(&self) -> u64 {
+        let mut hasher = DefaultHasher::new();
+        self.hash(&mut hasher);
+        hasher.finish()
+    }
+
+    fn fields(&self) -> Vec<GateOptionValue> {
+        let mut fields = Vec::new();
+        if let Some(min_age_days) = self.min_age_days {
+            fields.push(GateOptionValue {
+                name: "min_age_days".to_string(),
+                value: GateOptionValueType::I64(min_age_days as i64),
+            });
+        }
+        if let Some(min_tx_count) = self.min_tx_count {
+            fields.push(GateOptionValue {
+                name: "min_tx_count".to_string(),
+                value: GateOptionValueType::I64(min_tx_count as i64),
+            });
+        }
+        fields
+    }
+
+    fn instance_name(&self) -> &'static str {
+        Self::name()
+    }
+}
+
+/// Refuses clients that can't look up the wallet activity, the default
+/// client reads the chain, which can't be queried for the history of a wallet
+fn check_activity_support(client: &dyn TokenClient) -> Result<()> {
+    if !client.supports_wallet_activity() {
+        bail!(
+            "The wallet age gate needs the wallet activity from an indexer, \
+            which the bot is not set up with"
+        );
+    }
+    Ok(())
+}
+
+/// Only successful lookups are cached, so a failed lookup is retried with
+/// the next check
+#[cached(
+    name = "WALLET_ACTIVITY_CACHE",
+    type = "TimedCache<H160, WalletActivity>",
+    create = r##"{
+        TimedCache::with_lifespan(WALLET_ACTIVITY_CACHE_SECONDS)
+        }
+    "##,
+    result = true
+)]
+async fn get_wallet_activity_cached(wallet_address: H160) -> Result<WalletActivity> {
+    get_wallet_activity_live(wallet_address).await
+}
+
+async fn get_wallet_activity_live(wallet_address: H160) -> Result<WalletActivity> {
+    client::token_client()
+        .get_wallet_activity(&wallet_address)
+        .in_current_span()
+        .await
+        .map_err(|why| {
+            warn!("Failed to get wallet activity: {}", why);
+            why.context("Failed to get wallet activity")
+        })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::gate::{init_token_client, ColonyClient};
+    use anyhow::anyhow;
+    use colony_rs::U256;
+    use std::sync::Arc;
+
+    const OLD_WALLET: H160 = H160::repeat_byte(0xa1);
+    const BUSY_WALLET: H160 = H160::repeat_byte(0xa2);
+    const FRESH_WALLET: H160 = H160::repeat_byte(0xa3);
+
+    /// Knows the activity of a few wallets only, the token lookups are not
+    /// needed here
+    struct ActivityMockClient;
+
+    #[async_trait]
+    impl TokenClient for ActivityMockClient {
+        async fn balance_of(&self, _token: &H160, _wallet: &H160) -> Result<U256> {
+            Err(anyhow!("Not mocked"))
+        }
+
+        async fn get_token_decimals(&self, _token: H160) -> Result<u8> {
+            Err(anyhow!("Not mocked"))
+        }
+
+        async fn get_token_symbol(&self, _token: H160) -> Result<String> {
+            Err(anyhow!("Not mocked"))
+        }
+
+        async fn get_wallet_activity(&self, wallet: &H160) -> Result<WalletActivity> {
+            let now = unix_timestamp();
+            if *wallet == OLD_WALLET {
+                // two years old, but rarely used
+                Ok(WalletActivity {
+                    first_transaction_at: Some(now - 730 * SECONDS_PER_DAY),
+                    transaction_count: 3,
+                })
+            } else if *wallet == BUSY_WALLET {
+                // a week old with a lot of transactions
+                Ok(WalletActivity {
+                    first_transaction_at: Some(now - 7 * SECONDS_PER_DAY),
+                    transaction_count: 500,
+                })
+            } else if *wallet == FRESH_WALLET {
+                Ok(WalletActivity::default())
+            } else {
+                Err(anyhow!("Unknown wallet"))
+            }
+        }
+
+        fn supports_wallet_activity(&self) -> bool {
+            true
+        }
+    }
+
+    fn gate(min_age_days: Option<u64>, min_tx_count: Option<u64>) -> WalletAgeGate {
+        WalletAgeGate {
+            min_age_days,
+            min_tx_count,
+        }
+    }
+
+    #[tokio::test]
+    async fn test_wallet_age_and_count_thresholds() {
+        let _ = init_token_client(Arc::new(ActivityMockClient));
+        let by_age = gate(Some(365), None);
+        assert!(by_age.check(OLD_WALLET).await.unwrap());
+        assert!(!by_age.check(BUSY_WALLET).await.unwrap());
+        assert!(!by_age.check(FRESH_WALLET).await.unwrap());
+
+        let by_count = gate(None, Some(100));
+        assert!(!by_count.check(OLD_WALLET).await.unwrap());
+        assert!(by_count.check(BUSY_WALLET).await.unwrap());
+        assert!(!by_count.check(FRESH_WALLET).await.unwrap());
+
+        // both thresholds must be met
+        let both = gate(Some(5), Some(100));
+        assert!(!both.check(OLD_WALLET).await.unwrap());
+        assert!(both.check_uncached(BUSY_WALLET).await.unwrap());
+
+        // a failed lookup is an error, not an unmet condition
+        assert!(by_age.check(H160::repeat_byte(0xa4)).await.is_err());
+    }
+
+    #[test]
+    fn test_age_is_measured_at_check_time() {
+        let activity = WalletActivity {
+            first_transaction_at: Some(1000),
+            transaction_count: 1,
+        };
+        let gate = gate(Some(1), None);
+        assert!(!gate.meets_thresholds(&activity, 1000 + SECONDS_PER_DAY - 1));
+        assert!(gate.meets_thresholds(&activity, 1000 + SECONDS_PER_DAY));
+    }
+
+    #[tokio::test]
+    async fn test_wallet_age_options_validation() {
+        let _ = init_token_client(Arc::new(ActivityMockClient));
+        assert!(WalletAgeGate::from_options(&[]).await.is_err());
+        let negative = vec![GateOptionValue {
+            name: "min_tx_count".to_string(),
+            value: GateOptionValueType::I64(-1),
+        }];
+        assert!(WalletAgeGate::from_options(&negative).await.is_err());
+        let age_only = vec![GateOptionValue {
+            name: "min_age_days".to_string(),
+            value: GateOptionValueType::I64(30),
+        }];
+        let gate = WalletAgeGate::from_options(&age_only).await.unwrap();
+        assert_eq!(*gate, self::gate(Some(30), None));
+    }
+
+    #[test]
+    fn test_default_client_is_refused() {
+        let why = check_activity_support(&ColonyClient).unwrap_err();
+        assert!(why.to_string().contains("indexer"));
+        assert!(check_activity_support(&ActivityMockClient).is_ok());
+    }
+}
@@ -0,0 +1,78 @@
+//! The gating logic of the colony discord gating bot as a library.
+//!
+//! The binary uses this to run the bot, but the controller, the gates and
+//! the storage can be used on their own as well, e.g. to embed the gating
+//! logic in another application with its own colony data source. The
+//! [`start_with`] function starts the controller with an injected storage and
+//! clients, after which it is talked to with [`Message`]s via the returned
+//! channel. The http endpoints of the wallet registration are served from
+//! [`registration_routes`] against the same controller.
+//!
+
+mod cli;
+mod command;
+pub mod config;
+pub mod controller;
+mod discord;
+pub mod gate;
+mod logging;
+mod maintenance;
+mod server;
+pub mod storage;
+mod util;
+
+pub use config::GlobalConfig;
+pub use controller::{Controller, Message};
+pub use gate::{
+    init_reputation_client, init_token_client, ColonyClient, Gate, GateBuilder, GatingCondition,
+    ReputationClient, TokenClient, WalletActivity,
+};
+pub use server::registration_routes;
+pub use storage::Storage;
+
+use anyhow::{anyhow, Result};
+use clap::Parser;
+use cli::Cli;
+use std::sync::Arc;
+use tokio::sync::mpsc;
+
+/// Parses the command line, sets up the configuration and logging and
+/// executes the command via the command module
+#[doc(hidden)]
+pub fn run_cli() {
+    let cli = Cli::parse();
+    // for certain commands we need to skip the config setup
+    match cli.cmd {
+        Some(cli::Commands::Storage {
+            cmd: cli::StorageCmd::Generate,
+            ..
+        }) => {}
+        Some(cli::Commands::Config(_)) => {}
+        _ => {
+            config::setup_config(&cli.cfg).expect("Failed to setup config");
+            logging::setup_logging();
+        }
+    }
+    command::execute(&cli);
+}
+
+/// Starts the controller with the given storage and clients, without the
+/// discord bot and the http server. The returned channel takes the same
+/// messages the bot sends to the controller. The configuration and the
+/// clients are global, so this can only be called once and must be called
+/// before any gate is created or checked.
+pub async fn start_with<S>(
+    storage: S,
+    reputation_client: Arc<dyn ReputationClient>,
+    token_client: Arc<dyn TokenClient>,
+    config: GlobalConfig,
+) -> Result<mpsc::Sender<Message>>
+where
+    S: Storage + Send + Sync + 'static,
+    <S as Storage>::GateIter: Send,
+{
+    config::set_config(config).map_err(|why| anyhow!(why))?;
+    init_reputation_client(reputation_client)?;
+    init_token_client(token_client)?;
+    Ok(Controller::with_storage(storage).start().await)
+}
@@ -6,6 +6,12 @@
 //! different threads or asynchronous execution.
 //!
 //! The verbosity can be controlled via the verbosity config option.
+//!
+//! With the `metrics` feature, the events with `histogram.*`,
+//! `monotonic_counter.*` and `gauge.*` fields are recorded as metrics,
+//! independent of the verbosity, and exported for prometheus. With the
+//! `jaeger-telemetry` feature as well, histogram buckets carry the trace id
+//! of their latest sample as OpenMetrics exemplar.
 use crate::config::CONFIG;
 use once_cell::sync::OnceCell;
 use serde::{Deserialize, Serialize};
@@ -16,6 +22,17 @@ use tracing_subscriber::{filter::Targets, prelude::*};
 /// The worker guard for the tracing appender to keep it from beeing dropped
 static GUARD: OnceCell<WorkerGuard> = OnceCell::new();
 
+/// The exporter holding the recorded metrics until prometheus scrapes them
+#[cfg(feature = "metrics")]
+static METRICS_EXPORTER: OnceCell<opentelemetry_prometheus::PrometheusExporter> = OnceCell::new();
+
+/// The bucket bounds of the histograms, they cover the check durations in
+/// seconds as well as percentages and queue depths
+#[cfg(feature = "metrics")]
+const HISTOGRAM_BOUNDS: [f64; 16] = [
+    0.005, 0.01, 0.025, 0.05, 0.1, 0.25, 0.5, 1.0, 2.5, 5.0, 10.0, 25.0, 50.0, 75.0, 100.0, 1000.0,
+];
+
 /// The logging module sets up the logging system as specified in
 /// configuration.
 pub fn setup_logging() {
@@ -37,7 +54,8 @@ pub fn setup_logging() {
             .with_target("tracing_actix_web", tracing_level.clone()),
     };
 
-    // configure the subscriber
+    // configure the subscriber, the verbosity only filters the logs, so the
+    // metric events are recorded at any verbosity
     let subscriber = match tracing_level {
         LogLevel::Trace => tracing_subscriber::fmt::layer()
             .with_writer(non_blocking)
@@ -51,7 +69,8 @@ pub fn setup_logging() {
             .with_writer(non_blocking)
             .with_file(true)
             .with_line_number(true),
-    };
+    }
+    .with_filter(targets_filter.clone());
 
     #[cfg(feature = "jaeger-telemetry")]
     let tracer = opentelemetry_jaeger::new_agent_pipeline()
@@ -67,14 +86,14 @@ pub fn setup_logging() {
 
     #[cfg(feature = "jaeger-telemetry")]
     let registry = tracing_subscriber::registry()
-        .with(targets_filter)
         .with(telemetry)
         .with(subscriber);
 
     #[cfg(not(feature = "jaeger-telemetry"))]
-    let registry = tracing_subscriber::registry()
-        .with(targets_filter)
-        .with(subscriber);
+    let registry = tracing_subscriber::registry().with(subscriber);
+
+    #[cfg(feature = "metrics")]
+    let registry = registry.with(metrics_layer());
 
     tracing::subscriber::set_global_default(registry)
         .expect("Setting the default tracing subscriber failed");
@@ -82,6 +101,176 @@ pub fn setup_logging() {
     tracing_level.print();
 }
 
+/// Sets up the prometheus exporter and the layer recording the metric events
+/// of this crate into it
+#[cfg(feature = "metrics")]
+fn metrics_layer<S>() -> impl tracing_subscriber::Layer<S>
+where
+    S: tracing::Subscriber + for<'span> tracing_subscriber::registry::LookupSpan<'span>,
+{
+    use opentelemetry::sdk::{
+        export::metrics::aggregation,
+        metrics::{controllers, processors, selectors},
+    };
+
+    let controller = controllers::basic(
+        processors::factory(
+            selectors::simple::histogram(HISTOGRAM_BOUNDS),
+            aggregation::cumulative_temporality_selector(),
+        )
+        .with_memory(true),
+    )
+    .build();
+    let exporter = opentelemetry_prometheus::exporter(controller.clone()).init();
+    if METRICS_EXPORTER.set(exporter).is_err() {
+        warn!("The metrics exporter was already set up");
+    }
+    tracing_opentelemetry::MetricsLayer::new(controller)
+        .with_filter(Targets::new().with_target(env!("CARGO_CRATE_NAME"), LevelFilter::INFO))
+}
+
+/// The recorded metrics in the prometheus text format, or in the OpenMetrics
+/// format with the exemplars of the histogram buckets
+#[cfg(feature = "metrics")]
+pub fn metrics_text(openmetrics: bool) -> anyhow::Result<String> {
+    use anyhow::Context;
+    use prometheus::Encoder;
+
+    let exporter = METRICS_EXPORTER
+        .get()
+        .context("The metrics exporter is not set up")?;
+    let mut buffer = Vec::new();
+    prometheus::TextEncoder::new().encode(&exporter.registry().gather(), &mut buffer)?;
+    let text = String::from_utf8(buffer)?;
+    if openmetrics {
+        Ok(with_exemplars(&text, &EXEMPLARS.lock().unwrap()))
+    } else {
+        Ok(text)
+    }
+}
+
+/// The latest exemplar of each histogram bucket
+#[cfg(feature = "metrics")]
+static EXEMPLARS: once_cell::sync::Lazy<
+    std::sync::Mutex<std::collections::HashMap<ExemplarKey, Exemplar>>,
+> = once_cell::sync::Lazy::new(Default::default);
+
+/// The histogram bucket an exemplar belongs to
+#[cfg(feature = "metrics")]
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+struct ExemplarKey {
+    histogram: &'static str,
+    /// The label of the series as rendered, e.g. `gate="allowlist"`
+    label: String,
+    /// The upper bound of the bucket as rendered in its `le` label
+    le: String,
+}
+
+#[cfg(feature = "metrics")]
+impl ExemplarKey {
+    /// Whether the line is the sample of this bucket
+    fn matches(&self, line: &str) -> bool {
+        line.starts_with(&format!("{}_bucket{{", self.histogram))
+            && (line.contains(&format!("{{{}", self.label))
+                || line.contains(&format!(",{}", self.label)))
+            && line.contains(&format!("le=\"{}\"", self.le))
+    }
+}
+
+/// A sample of a histogram linked to the trace it was recorded in
+#[cfg(feature = "metrics")]
+#[derive(Debug, Clone, PartialEq)]
+pub(crate) struct Exemplar {
+    pub(crate) trace_id: String,
+    pub(crate) value: f64,
+    timestamp: u64,
+}
+
+/// Keeps the sample as exemplar of its histogram bucket, if it is recorded
+/// in a sampled trace. The histogram sample itself is emitted as event
+#[cfg(feature = "metrics")]
+pub(crate) fn record_exemplar(histogram: &'static str, label: (&str, &str), value: f64) {
+    let trace_id = match current_trace_id() {
+        Some(trace_id) => trace_id,
+        None => return,
+    };
+    let le = HISTOGRAM_BOUNDS
+        .iter()
+        .find(|bound| value <= **bound)
+        .map(|bound| bound.to_string())
+        .unwrap_or_else(|| "+Inf".to_string());
+    let key = ExemplarKey {
+        histogram,
+        label: format!("{}=\"{}\"", label.0, label.1),
+        le,
+    };
+    let exemplar = Exemplar {
+        trace_id,
+        value,
+        timestamp: crate::util::unix_timestamp(),
+    };
+    EXEMPLARS.lock().unwrap().insert(key, exemplar);
+}
+
+/// An exemplar kept for the series of the histogram, for tests
+#[cfg(all(test, feature = "metrics"))]
+pub(crate) fn exemplar(histogram: &'static str, label: (&str, &str)) -> Option<Exemplar> {
+    let label = format!("{}=\"{}\"", label.0, label.1);
+    EXEMPLARS
+        .lock()
+        .unwrap()
+        .iter()
+        .find(|(key, _)| key.histogram == histogram && key.label == label)
+        .map(|(_, exemplar)| exemplar.clone())
+}
+
+/// Appends the exemplars to the samples of their buckets and terminates the
+/// text, the OpenMetrics format is the only one that carries exemplars
+#[cfg(feature = "metrics")]
+fn with_exemplars(
+    text: &str,
+    exemplars: &std::collections::HashMap<ExemplarKey, Exemplar>,
+) -> String {
+    let mut openmetrics = String::with_capacity(text.len());
+    for line in text.lines() {
+        openmetrics.push_str(line);
+        if let Some(exemplar) = exemplars
+            .iter()
+            .find(|(key, _)| key.matches(line))
+            .map(|(_, exemplar)| exemplar)
+        {
+            openmetrics.push_str(&format!(
+                " # {{trace_id=\"{}\"}} {} {}",
+                exemplar.trace_id, exemplar.value, exemplar.timestamp
+            ));
+        }
+        openmetrics.push('\n');
+    }
+    openmetrics.push_str("# EOF\n");
+    openmetrics
+}
+
+/// The id of the trace of the current span, if it is sampled by the
+/// telemetry layer
+#[cfg(all(feature = "metrics", feature = "jaeger-telemetry"))]
+fn current_trace_id() -> Option<String> {
+    use opentelemetry::trace::TraceContextExt;
+    use tracing_opentelemetry::OpenTelemetrySpanExt;
+
+    let context = tracing::Span::current().context();
+    let span = context.span();
+    let span_context = span.span_context();
+    span_context
+        .is_valid()
+        .then(|| span_context.trace_id().to_string())
+}
+
+/// Without telemetry there are no traces to link
+#[cfg(all(feature = "metrics", not(feature = "jaeger-telemetry")))]
+fn current_trace_id() -> Option<String> {
+    None
+}
+
 /// The different log levels, from quiet = 0 to trace = 5
 #[derive(Debug, Clone, Serialize, Deserialize, Default)]
 pub enum LogLevel {
@@ -190,3 +379,91 @@ impl From<LogLevel> for log::LevelFilter {
         }
     }
 }
+
+/// The fields of the metric events emitted while it is installed, for tests
+/// of the emitted metrics
+#[cfg(all(test, feature = "metrics"))]
+pub(crate) struct MetricRecorder {
+    /// Only events with a field starting with this are recorded, e.g. `gauge.`
+    prefix: &'static str,
+    events: std::sync::Arc<std::sync::Mutex<Vec<std::collections::HashMap<String, String>>>>,
+}
+
+#[cfg(all(test, feature = "metrics"))]
+impl MetricRecorder {
+    /// Installs a recorder of the metric events with the given field prefix
+    /// for the current thread, until the returned guard is dropped
+    pub(crate) fn install(prefix: &'static str) -> (Self, tracing::subscriber::DefaultGuard) {
+        let recorder = Self {
+            prefix,
+            events: Default::default(),
+        };
+        let subscriber = tracing_subscriber::registry().with(Self {
+            prefix,
+            events: recorder.events.clone(),
+        });
+        (recorder, tracing::subscriber::set_default(subscriber))
+    }
+
+    /// The fields of the recorded events, by name
+    pub(crate) fn events(&self) -> Vec<std::collections::HashMap<String, String>> {
+        self.events.lock().unwrap().clone()
+    }
+}
+
+#[cfg(all(test, feature = "metrics"))]
+impl<S: tracing::Subscriber> tracing_subscriber::Layer<S> for MetricRecorder {
+    fn on_event(
+        &self,
+        event: &tracing::Event<'_>,
+        _ctx: tracing_subscriber::layer::Context<'_, S>,
+    ) {
+        struct Visitor(std::collections::HashMap<String, String>);
+        impl tracing::field::Visit for Visitor {
+            fn record_str(&mut self, field: &tracing::field::Field, value: &str) {
+                self.0.insert(field.name().to_string(), value.to_string());
+            }
+            fn record_debug(&mut self, field: &tracing::field::Field, value: &dyn std::fmt::Debug) {
+                self.0
+                    .insert(field.name().to_string(), format!("{:?}", value));
+            }
+        }
+        let mut visitor = Visitor(Default::default());
+        event.record(&mut visitor);
+        if visitor.0.keys().any(|name| name.starts_with(self.prefix)) {
+            self.events.lock().unwrap().push(visitor.0);
+        }
+    }
+}
+
+#[cfg(all(test, feature = "metrics"))]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_with_exemplars() {
+        let text = "# TYPE gate_check_duration_seconds histogram\n\
+            gate_check_duration_seconds_bucket{gate=\"allowlist\",le=\"0.005\"} 1\n\
+            gate_check_duration_seconds_bucket{gate=\"allowlist\",le=\"0.01\"} 2\n\
+            gate_check_duration_seconds_bucket{gate=\"token\",le=\"0.01\"} 1\n";
+        let key = ExemplarKey {
+            histogram: "gate_check_duration_seconds",
+            label: "gate=\"allowlist\"".to_string(),
+            le: "0.01".to_string(),
+        };
+        let exemplar = Exemplar {
+            trace_id: "ab".repeat(16),
+            value: 0.007,
+            timestamp: 1_700_000_000,
+        };
+        let openmetrics = with_exemplars(text, &std::collections::HashMap::from([(key, exemplar)]));
+        let lines = openmetrics.lines().collect::<Vec<_>>();
+        assert!(!lines[1].contains('#'));
+        assert!(lines[2].ends_with(&format!(
+            "2 # {{trace_id=\"{}\"}} 0.007 1700000000",
+            "ab".repeat(16)
+        )));
+        assert!(!lines[3].contains('#'));
+        assert_eq!(lines.last(), Some(&"# EOF"));
+    }
+}
@@ -29,6 +29,9 @@
 //! slash commands either globally or for a specific guild. Global registration
 //! may take some time to propagate, while guild registration is instant.
 //!
+//! Once the bot is on a server, admins can run `/gate setup` to check the
+//! role hierarchy, the active gates and the registration url in discord.
+//!
 //! To get started just run and go from there
 //!```bash
 //! discord-gating-bot help   
@@ -59,18 +62,7 @@
 #![warn(rustdoc::invalid_rust_codeblocks)]
 #![warn(rustdoc::invalid_html_tags)]
 
-mod cli;
-mod command;
-mod config;
-mod controller;
-mod discord;
-mod gate;
-mod logging;
-mod server;
-mod storage;
-use clap::Parser;
-use cli::Cli;
-use tracing::{instrument, warn};
+use tracing::instrument;
 
 /// The main entry point of the cli application. It sets up the logging and
 /// configuration and then executes the command via the command module.
@@ -82,17 +74,7 @@ fn main() {
         .blocklist(&["libc", "libgcc", "pthread", "vdso"])
         .build()
         .expect("Failed to start profiler");
-    let cli = Cli::parse();
-    // for certain commands we need to skip the config setup
-    match cli.cmd {
-        Some(cli::Commands::Storage(cli::StorageCmd::Generate)) => {}
-        Some(cli::Commands::Config(_)) => {}
-        _ => {
-            config::setup_config(&cli.cfg).expect("Failed to setup config");
-            logging::setup_logging();
-        }
-    }
-    command::execute(&cli);
+    discord_gating_bot::run_cli();
     #[cfg(feature = "profiling")]
     if let Ok(report) = guard.report().build() {
         let file =
@@ -0,0 +1,74 @@
+//! Keeps track of the scheduled maintenance windows. During a window the bot
+//! keeps running, but answers all interactions with a maintenance notice,
+//! since switching to the maintenance handler would need a restart of the
+//! discord client.
+//!
+
+use crate::config::CONFIG;
+use std::{
+    sync::atomic::{AtomicBool, Ordering},
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+use tracing::{info, warn};
+
+/// How often the maintenance windows are checked
+const CHECK_INTERVAL: Duration = Duration::from_secs(30);
+
+/// Set while a scheduled maintenance window is active
+static IN_MAINTENANCE_WINDOW: AtomicBool = AtomicBool::new(false);
+
+/// Whether the bot is currently in a scheduled maintenance window
+pub fn in_maintenance_window() -> bool {
+    IN_MAINTENANCE_WINDOW.load(Ordering::Relaxed)
+}
+
+/// Whether any of the `(start, end)` windows of unix timestamps contains
+/// `now`, the start is inclusive and the end exclusive
+pub fn window_active(windows: &[(u64, u64)], now: u64) -> bool {
+    windows
+        .iter()
+        .any(|&(start, end)| start <= now && now < end)
+}
+
+/// Updates the maintenance flag once and then periodically according to the
+/// configured windows, logging whenever a window starts or ends
+pub async fn watch_windows() {
+    let windows = &CONFIG.wait().maintenance_windows;
+    if windows.is_empty() {
+        return;
+    }
+    let mut interval = tokio::time::interval(CHECK_INTERVAL);
+    loop {
+        interval.tick().await;
+        let now = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .expect("Failed to get system timestamp")
+            .as_secs();
+        let active = window_active(windows, now);
+        if IN_MAINTENANCE_WINDOW.swap(active, Ordering::Relaxed) != active {
+            if active {
+                warn!("Maintenance window started, answering all commands in maintenance mode");
+            } else {
+                info!("Maintenance window ended");
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_window_active() {
+        let windows = vec![(100, 200), (300, 400)];
+        assert!(!window_active(&windows, 99));
+        assert!(window_active(&windows, 100));
+        assert!(window_active(&windows, 199));
+        assert!(!window_active(&windows, 200));
+        assert!(!window_active(&windows, 250));
+        assert!(window_active(&windows, 350));
+        assert!(!window_active(&windows, 400));
+        assert!(!window_active(&[], 100));
+    }
+}
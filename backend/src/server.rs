@@ -1,70 +1,425 @@
 //! Starts the server that handles the http requests coming from the urls
 //! sent to discord.
 //!
+//! The registration contract is independent of the wallet used to sign:
+//! - `GET /challenge/{username}/{session}` returns the message to sign as
+//!   `{"message": "...", "mobile_deep_link": bool}`, or `{"error": "..."}`
+//!   with a 400 for an invalid or expired session
+//! - `POST /register/{username}/{session}` takes
+//!   `{"signature": "0x...", "address": "0x..."}`, where the signature is a
+//!   personal_sign (EIP-191) signature of the challenge message
+//!
+//! Any wallet, browser extension or mobile app, that produces such a
+//! signature can be used for the registration.
+//!
+//! `GET /status` summarizes the operational state as JSON for uptime
+//! dashboards, it needs no authentication and holds no secrets.
+//!
+//! `GET /readyz` answers 200 once the bot is ready to serve users and 503
+//! before, e.g. for the readiness probe of an orchestrator.
+//!
+//! `GET /metrics` serves the recorded metrics for prometheus, if the bot is
+//! built with the `metrics` feature.
+//!
+//! Failed requests carry a short correlation id, derived from the session,
+//! on the error page and in the `X-Error-Ref` header. The same id is logged
+//! when the session is created, so the whole registration can be found in
+//! the logs.
+//!
+//! Every response carries `X-Content-Type-Options`, `X-Frame-Options` and
+//! the configured `Content-Security-Policy` and `Referrer-Policy`.
+//!
 
-use crate::config::CONFIG;
+use crate::cli::{parse_bind, StorageType};
+use crate::config::{validate_invite_url, CONFIG};
 use crate::controller::{
-    Message, RegisterResponse, RemoveUserResponse, Session, CONTROLLER_CHANNEL,
+    is_enforcement_paused, Message, RegisterResponse, RemoveUserResponse, Session,
+    CONTROLLER_CHANNEL,
 };
+use crate::discord;
+use crate::gate::rpc;
+use crate::storage::StorageErrorKind;
 use actix_files::Files;
-use actix_web::{post, web, App, HttpResponse, HttpResponseBuilder, HttpServer, Responder};
+use actix_web::{
+    get, http::header, middleware::DefaultHeaders, post, web, App, HttpResponse,
+    HttpResponseBuilder, HttpServer, Responder,
+};
 use anyhow::{bail, Result};
 use colony_rs::Signature;
+use once_cell::sync::Lazy;
 use sailfish::TemplateOnce;
 use secrecy::{ExposeSecret, SecretString};
-use serde::Deserialize;
+use serde::{Deserialize, Serialize};
+use std::future::Future;
+use std::path::Path;
 use std::str::FromStr;
-use tokio::sync::oneshot;
-use tracing::{debug, debug_span, error, info, instrument, warn};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::time::{Duration, Instant};
+use tokio::sync::{mpsc, oneshot};
+use tracing::{debug, debug_span, error, info, instrument, warn, Span};
 use tracing_actix_web::TracingLogger;
 
-const REGISTRATION_MESSAGE: &str = "Please sign this message to connect your \
-                                    Discord username {username} with your wallet \
-                                    address. Session ID: {session}";
+/// The response header that carries the correlation id of a failed request
+const ERROR_REF_HEADER: &str = "X-Error-Ref";
+/// How long the status waits for the controller before reporting it down
+const CONTROLLER_STATUS_TIMEOUT: Duration = Duration::from_secs(2);
+
+/// When the server was started, for the uptime in the status
+static STARTED_AT: Lazy<Instant> = Lazy::new(Instant::now);
+/// Whether the bot is ready to serve users, see [`mark_ready`]
+static READY: AtomicBool = AtomicBool::new(false);
 
 pub async fn start() -> std::io::Result<()> {
-    let host = CONFIG.wait().server.host.clone();
-    let port = CONFIG.wait().server.port;
-    info!("Starting server on {}:{}", &host, port);
-    HttpServer::new(|| {
+    Lazy::force(&STARTED_AT);
+    let invite_url = CONFIG.wait().discord.invite_url.trim();
+    if invite_url.is_empty() {
+        info!("No invite url configured, generating it from the application id");
+    } else if let Err(why) = validate_invite_url(invite_url) {
+        warn!("{}", why);
+    }
+    if let Some(path) = &CONFIG.wait().server.unix_socket {
+        return run_on_unix_socket(path).await;
+    }
+    let config = &CONFIG.wait().server;
+    bind_addresses(&config.binds, &config.host, config.port)?.await
+}
+
+/// Binds the server to each of the listen addresses, or to host and port if
+/// there are none. Every address is checked before the first bind, so a typo
+/// doesn't leave the server listening on only some of them
+fn bind_addresses(
+    binds: &[String],
+    host: &str,
+    port: u16,
+) -> std::io::Result<actix_web::dev::Server> {
+    let binds = binds
+        .iter()
+        .map(String::as_str)
+        .map(parse_bind)
+        .collect::<Result<Vec<_>, _>>()
+        .map_err(|why| std::io::Error::new(std::io::ErrorKind::InvalidInput, why))?;
+    let mut server = HttpServer::new(|| {
         App::new()
+            .wrap(configured_security_headers())
             .wrap(TracingLogger::default())
-            .service(register)
-            .service(unregister)
-            .service(
-                Files::new("/", "./frontend/dist")
-                    .index_file("invite.html")
-                    .prefer_utf8(true),
-            )
+            .configure(routes)
+    });
+    if binds.is_empty() {
+        info!("Starting server on {}:{}", host, port);
+        server = server.bind((host, port))?;
+    }
+    for bind in binds {
+        info!("Starting server on {}", bind);
+        server = server.bind(bind)?;
+    }
+    Ok(server.run())
+}
+
+/// Reports ready on `/readyz` once the rpc is reachable, if the readiness
+/// probe is configured, or right away otherwise
+pub async fn mark_ready() {
+    become_ready(rpc::probe_at_startup()).await
+}
+
+async fn become_ready(probe: impl Future<Output = ()>) {
+    probe.await;
+    READY.store(true, Ordering::SeqCst);
+    info!("Ready to serve users");
+}
+
+/// The security headers of the server configuration, only the fixed ones if
+/// there is none
+fn configured_security_headers() -> DefaultHeaders {
+    match CONFIG.get() {
+        Some(config) => security_headers(
+            &config.server.content_security_policy,
+            &config.server.referrer_policy,
+        ),
+        None => security_headers("", ""),
+    }
+}
+
+/// The headers added to every response that doesn't set them itself. Empty
+/// policies are not sent, invalid ones are logged and skipped instead of
+/// failing every request
+fn security_headers(content_security_policy: &str, referrer_policy: &str) -> DefaultHeaders {
+    let mut headers = DefaultHeaders::new()
+        .add((header::X_CONTENT_TYPE_OPTIONS, "nosniff"))
+        .add((header::X_FRAME_OPTIONS, "DENY"));
+    for (name, value) in [
+        (header::CONTENT_SECURITY_POLICY, content_security_policy),
+        (header::REFERRER_POLICY, referrer_policy),
+    ] {
+        let value = value.trim();
+        if value.is_empty() {
+            continue;
+        }
+        match header::HeaderValue::from_str(value) {
+            Ok(value) => headers = headers.add((name, value)),
+            Err(why) => warn!("Not sending invalid {} {:?}: {}", name.as_str(), value, why),
+        }
+    }
+    headers
+}
+
+fn routes(cfg: &mut web::ServiceConfig) {
+    #[cfg(feature = "metrics")]
+    cfg.service(metrics);
+    cfg.service(invite)
+        .service(status)
+        .service(readyz)
+        .configure(registration_routes)
+        .service(
+            Files::new("/", "./frontend/dist")
+                .index_file("invite.html")
+                .prefer_utf8(true),
+        );
+}
+
+/// The endpoints of the registration flow, without the static frontend. They
+/// talk to the controller started with [`crate::Controller::start`], so
+/// tests can serve them with an in memory storage
+pub fn registration_routes(cfg: &mut web::ServiceConfig) {
+    cfg.service(challenge).service(register).service(unregister);
+}
+
+/// Runs the server on a unix domain socket until it is stopped, the socket
+/// file is removed afterwards
+#[cfg(unix)]
+async fn run_on_unix_socket(path: &Path) -> std::io::Result<()> {
+    info!("Starting server on unix socket {:?}", path);
+    let result = bind_unix_socket(path)?.await;
+    if let Err(why) = std::fs::remove_file(path) {
+        warn!("Failed to remove the unix socket {:?}: {}", path, why);
+    }
+    result
+}
+
+#[cfg(not(unix))]
+async fn run_on_unix_socket(_path: &Path) -> std::io::Result<()> {
+    Err(std::io::Error::other(
+        "Unix domain sockets are only supported on unix",
+    ))
+}
+
+/// Binds the server to a unix domain socket. A socket file left behind by a
+/// previous run that was killed is removed first, any other file at the path
+/// is kept and fails the bind
+#[cfg(unix)]
+fn bind_unix_socket(path: &Path) -> std::io::Result<actix_web::dev::Server> {
+    use std::os::unix::fs::FileTypeExt;
+    if let Ok(metadata) = std::fs::symlink_metadata(path) {
+        if metadata.file_type().is_socket() {
+            debug!("Removing stale unix socket {:?}", path);
+            std::fs::remove_file(path)?;
+        }
+    }
+    Ok(HttpServer::new(|| {
+        App::new()
+            .wrap(configured_security_headers())
+            .wrap(TracingLogger::default())
+            .configure(routes)
     })
-    .bind((host, port))?
-    .run()
-    .await
+    .bind_uds(path)?
+    .run())
 }
 
-#[post("/register/{username}/{session}")]
+/// The operational state of the bot as served by `/status`
+#[derive(Debug, Serialize)]
+struct Status {
+    discord_connected: bool,
+    /// Whether the controller answered in time
+    controller_up: bool,
+    storage: &'static str,
+    /// The number of guilds with stored gates, if the controller answered
+    guilds: Option<usize>,
+    /// The colony client calls currently running against the rpc endpoint
+    rpc_calls_in_flight: usize,
+    /// The follow up messages waiting to be sent to discord
+    queued_messages: usize,
+    enforcement_paused: bool,
+    uptime_seconds: u64,
+}
+
+impl Status {
+    async fn collect(controller: Option<&mpsc::Sender<Message>>) -> Self {
+        let guilds = match controller {
+            Some(controller) => guild_count(controller).await,
+            None => None,
+        };
+        let storage = match CONFIG.get().map(|cfg| &cfg.storage.storage_type) {
+            Some(StorageType::Encrypted) => "encrypted",
+            Some(StorageType::Unencrypted) => "unencrypted",
+            Some(StorageType::InMemory) => "in_memory",
+            None => "unknown",
+        };
+        Status {
+            discord_connected: discord::is_connected(),
+            controller_up: guilds.is_some(),
+            storage,
+            guilds,
+            rpc_calls_in_flight: rpc::calls_in_flight(),
+            queued_messages: discord::queued_messages(),
+            enforcement_paused: is_enforcement_paused(),
+            uptime_seconds: STARTED_AT.elapsed().as_secs(),
+        }
+    }
+}
+
+/// Asks the controller for the number of guilds, `None` if it does not
+/// answer in time
+async fn guild_count(controller: &mpsc::Sender<Message>) -> Option<usize> {
+    let (tx, rx) = oneshot::channel();
+    let message = Message::GuildCount {
+        response: tx,
+        span: debug_span!("controller"),
+    };
+    if let Err(why) = controller.send(message).await {
+        error!("Failed to send message to controller: {:?}", why);
+        return None;
+    }
+    match tokio::time::timeout(CONTROLLER_STATUS_TIMEOUT, rx).await {
+        Ok(Ok(count)) => Some(count),
+        Ok(Err(why)) => {
+            error!("Failed to get guild count: {:?}", why);
+            None
+        }
+        Err(_) => {
+            warn!("The controller did not answer the status in time");
+            None
+        }
+    }
+}
+
+/// Summarizes the operational state for uptime dashboards
+#[get("/status")]
+#[instrument]
+async fn status() -> impl Responder {
+    HttpResponse::Ok().json(Status::collect(CONTROLLER_CHANNEL.get()).await)
+}
+
+/// Whether the bot is ready to serve users
+#[get("/readyz")]
 #[instrument]
+async fn readyz() -> impl Responder {
+    if READY.load(Ordering::SeqCst) {
+        HttpResponse::Ok().body("ready")
+    } else {
+        HttpResponse::ServiceUnavailable().body("not ready")
+    }
+}
+
+/// Serves the recorded metrics in the prometheus text format, or in the
+/// OpenMetrics format with exemplars if the scraper accepts it
+#[cfg(feature = "metrics")]
+#[get("/metrics")]
+#[instrument(skip(request))]
+async fn metrics(request: actix_web::HttpRequest) -> impl Responder {
+    let openmetrics = request
+        .headers()
+        .get(header::ACCEPT)
+        .and_then(|accept| accept.to_str().ok())
+        .is_some_and(|accept| accept.contains("application/openmetrics-text"));
+    let content_type = if openmetrics {
+        "application/openmetrics-text; version=1.0.0; charset=utf-8"
+    } else {
+        prometheus::TEXT_FORMAT
+    };
+    match crate::logging::metrics_text(openmetrics) {
+        Ok(text) => HttpResponse::Ok().content_type(content_type).body(text),
+        Err(why) => {
+            error!("Failed to encode the metrics: {:?}", why);
+            HttpResponse::InternalServerError().finish()
+        }
+    }
+}
+
+/// Redirects to the invite url of the bot, used by the invite button on the
+/// index page
+#[get("/invite")]
+#[instrument]
+async fn invite() -> impl Responder {
+    match discord::invite_url().await {
+        Ok(url) => HttpResponse::TemporaryRedirect()
+            .insert_header((header::LOCATION, url))
+            .finish(),
+        Err(why) => {
+            error!("Failed to get invite url: {:?}", why);
+            HttpResponse::InternalServerError().finish()
+        }
+    }
+}
+
+#[get("/challenge/{username}/{session}")]
+#[instrument(fields(correlation_id))]
+async fn challenge(path: web::Path<(String, String)>) -> impl Responder {
+    debug!("Received challenge request");
+    let (username_url, session_str) = path.into_inner();
+    let correlation_id = Session::correlation_id(&session_str);
+    Span::current().record("correlation_id", correlation_id.as_str());
+    let lifetime = CONFIG.wait().register_session_secs();
+    let session = match validate_session(&username_url, &session_str, lifetime) {
+        Ok(session) => session,
+        Err(why) => {
+            warn!("Invalid session: {}", why);
+            return challenge_error(&why, &correlation_id);
+        }
+    };
+    HttpResponse::Ok().json(Challenge {
+        message: registration_message(
+            &CONFIG.wait().server.registration_message,
+            &session.username,
+            &session_str,
+        ),
+        mobile_deep_link: CONFIG.wait().server.mobile_deep_link,
+    })
+}
+
+/// The challenge is fetched by the registration page, so its errors are JSON
+/// instead of an error page, with the correlation id in the header
+fn challenge_error(why: &anyhow::Error, correlation_id: &str) -> HttpResponse {
+    let error = if why.is::<SessionExpired>() {
+        "This link has expired, use /get in again to get a new one".to_string()
+    } else {
+        format!("Invalid session: {}", why)
+    };
+    HttpResponse::BadRequest()
+        .insert_header((ERROR_REF_HEADER, correlation_id))
+        .json(ChallengeError { error })
+}
+
+#[post("/register/{username}/{session}")]
+#[instrument(fields(correlation_id))]
 async fn register(path: web::Path<(String, String)>, data: web::Json<JsonData>) -> impl Responder {
     debug!("Received acknowledged registration request");
     let (username_url, session_str) = path.into_inner();
-    let session = match validate_session(&username_url, &session_str) {
+    let correlation_id = Session::correlation_id(&session_str);
+    Span::current().record("correlation_id", correlation_id.as_str());
+    let lifetime = CONFIG.wait().register_session_secs();
+    let session = match validate_session(&username_url, &session_str, lifetime) {
         Ok(session) => session,
         Err(why) => {
             warn!("Invalid session: {}", why);
-            return Skeleton::invalid_session(&why.to_string());
+            return Skeleton::session_error(&why, "/get in", &correlation_id);
         }
     };
     debug!(?session, "Valid session");
-    let wallet = match validate_signature(&data, &session, &session_str) {
+    let template = &CONFIG.wait().server.registration_message;
+    let wallet = match validate_signature(&data, template, &session, &session_str) {
         Ok(wallet) => wallet,
         Err(why) => {
             warn!("Invalid signature: {}", why);
-            return Skeleton::invalid_signature(&why.to_string());
+            return Skeleton::invalid_signature(&why.to_string(), &correlation_id);
         }
     };
     debug!(?wallet, "Valid signature");
     let (response_tx, rx) = oneshot::channel();
-    let span = debug_span!("server_register", %session.username, %session.user_id);
+    let span = debug_span!(
+        "server_register",
+        %session.username,
+        %session.user_id,
+        %correlation_id
+    );
     let message = Message::Register {
         user_id: session.user_id,
         wallet,
@@ -73,7 +428,7 @@ async fn register(path: web::Path<(String, String)>, data: web::Json<JsonData>)
     };
     if let Err(why) = CONTROLLER_CHANNEL.wait().send(message).await {
         error!("Error sending message to controller: {}", why);
-        return Skeleton::internal_error();
+        return Skeleton::internal_error(&correlation_id);
     }
     if let Ok(response) = rx.await {
         match response {
@@ -85,30 +440,49 @@ async fn register(path: web::Path<(String, String)>, data: web::Json<JsonData>)
                 debug!("User already registered");
                 Skeleton::already_registered()
             }
-            RegisterResponse::Error(why) => {
-                warn!("Internal registration error: {}", why);
-                Skeleton::internal_error()
+            RegisterResponse::TooManyWallets { max } => {
+                debug!(max, "User has too many wallets");
+                Skeleton::too_many_wallets(max)
+            }
+            RegisterResponse::Error {
+                kind: StorageErrorKind::Io,
+                why,
+            } => {
+                error!(kind = ?StorageErrorKind::Io, "Failed to store registration: {}", why);
+                Skeleton::storage_unavailable(&correlation_id)
+            }
+            RegisterResponse::Error { kind, why } => {
+                warn!(?kind, "Internal registration error: {}", why);
+                Skeleton::internal_error(&correlation_id)
             }
         }
     } else {
         error!("Failed to receive response from controller");
-        Skeleton::internal_error()
+        Skeleton::internal_error(&correlation_id)
     }
 }
 
 #[post("/unregister/{username}/{session}")]
-#[instrument]
+#[instrument(fields(correlation_id))]
 async fn unregister(path: web::Path<(String, String)>) -> impl Responder {
     debug!("Received acknowledged unregistration request");
     let (username_url, session_str) = path.into_inner();
-    let session = match validate_session(&username_url, &session_str) {
+    let correlation_id = Session::correlation_id(&session_str);
+    Span::current().record("correlation_id", correlation_id.as_str());
+    let lifetime = CONFIG.wait().unregister_session_secs();
+    let session = match validate_session(&username_url, &session_str, lifetime) {
         Ok(session) => session,
         Err(why) => {
-            warn!("Invalid session");
-            return Skeleton::invalid_session(&why.to_string());
+            warn!("Invalid session: {}", why);
+            return Skeleton::session_error(&why, "/get out", &correlation_id);
         }
     };
-    let span = debug_span!("unregister", %session.username, %session.user_id);
+    let span = debug_span!(
+        "unregister",
+        %session.username,
+        %session.user_id,
+        %correlation_id
+    );
     let (tx, rx) = oneshot::channel();
     let message = Message::RemovUser {
         session: session_str,
@@ -117,7 +491,7 @@ async fn unregister(path: web::Path<(String, String)>) -> impl Responder {
     };
     if let Err(why) = CONTROLLER_CHANNEL.wait().send(message).await {
         error!("Error sending message to controller: {}", why);
-        return Skeleton::internal_error();
+        return Skeleton::internal_error(&correlation_id);
     }
     if let Ok(response) = rx.await {
         match response {
@@ -127,43 +501,80 @@ async fn unregister(path: web::Path<(String, String)>) -> impl Responder {
             }
             RemoveUserResponse::Error(why) => {
                 error!("Error removing user: {}", why);
-                Skeleton::internal_error()
+                Skeleton::internal_error(&correlation_id)
             }
         }
     } else {
         error!("Controller hung up");
-        Skeleton::internal_error()
+        Skeleton::internal_error(&correlation_id)
     }
 }
 
 #[instrument(skip(data))]
 fn validate_signature(
     data: &JsonData,
+    template: &str,
     session: &Session,
     session_str: &str,
 ) -> Result<SecretString> {
-    let signature = Signature::from_str(data.signature.expose_secret())?;
-    let message = REGISTRATION_MESSAGE
-        .replace("{username}", &session.username)
-        .replace("{session}", session_str);
+    let message = registration_message(template, &session.username, session_str);
     debug!(?message, "Message to verify");
-    let wallet = colony_rs::Address::from_str(data.address.expose_secret())?;
-    if let Err(why) = signature.verify(message, wallet) {
-        warn!("Invalid message: {}", why);
+    let check = check_signature(
+        data.address.expose_secret(),
+        &message,
+        data.signature.expose_secret(),
+    )?;
+    if !check.matches {
+        warn!("Invalid message: the signature does not match the address");
         bail!("Invalid message");
     }
     Ok(data.address.clone())
 }
 
+/// What a signature of a message recovers to, see [`check_signature`]
+#[derive(Debug, PartialEq, Eq)]
+pub struct SignatureCheck {
+    /// The signer of the message, if the signature can be recovered at all
+    pub recovered: Option<colony_rs::Address>,
+    /// Whether the signature is a valid signature of the address
+    pub matches: bool,
+}
+
+/// Checks a personal_sign signature of a message against an address, the
+/// same way registrations are verified, e.g. to debug a failed registration
+pub fn check_signature(address: &str, message: &str, signature: &str) -> Result<SignatureCheck> {
+    let signature = Signature::from_str(signature)?;
+    let address = colony_rs::Address::from_str(address)?;
+    Ok(SignatureCheck {
+        recovered: signature.recover(message).ok(),
+        matches: signature.verify(message, address).is_ok(),
+    })
+}
+
+/// The message a user has to sign to prove ownership of the wallet, built
+/// from the configured template. The frontend gets it from the challenge
+/// endpoint, so both always use the same text
+fn registration_message(template: &str, username: &str, session_str: &str) -> String {
+    template
+        .replace("{username}", username)
+        .replace("{session}", session_str)
+}
+
 #[instrument]
-fn validate_session(username_url: &str, session_str: &str) -> Result<Session> {
+fn validate_session(username_url: &str, session_str: &str, lifetime: u64) -> Result<Session> {
     let session = Session::from_str(session_str)?;
-    if session.expired() {
+    if session.expired(lifetime) {
         debug!("Session expired");
-        bail!("Session expired");
+        return Err(SessionExpired.into());
     }
-    let username = urlencoding::decode(username_url)?;
+    check_username(username_url, &session)?;
+    Ok(session)
+}
 
+/// Fails if the username of the path is not the one of the session. The
+/// path extractor already percent-decodes the username, decoding it again
+/// would change names that contain a `%`
+fn check_username(username: &str, session: &Session) -> Result<()> {
     if username != session.username {
         warn!(
             "Username {} does not match session username {}",
@@ -171,15 +582,40 @@ fn validate_session(username_url: &str, session_str: &str) -> Result<Session> {
         );
         bail!("Invalid username");
     }
-    Ok(session)
+    Ok(())
 }
 
+/// The error of a session that was valid, but is too old. Unlike other
+/// invalid sessions, this is expected to happen and the user just needs a
+/// new link
+#[derive(Debug)]
+struct SessionExpired;
+
+impl std::fmt::Display for SessionExpired {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "Session expired")
+    }
+}
+
+impl std::error::Error for SessionExpired {}
+
 #[derive(Debug, Deserialize)]
 struct JsonData {
     signature: SecretString,
     address: SecretString,
 }
 
+#[derive(Debug, Serialize)]
+struct Challenge {
+    message: String,
+    mobile_deep_link: bool,
+}
+
+#[derive(Debug, Serialize)]
+struct ChallengeError {
+    error: String,
+}
+
 #[derive(Debug)]
 struct Button {
     text: &'static str,
@@ -200,11 +636,16 @@ struct Skeleton {
     paragraph_text: String,
     button: Option<Button>,
     form_input: Option<FormInput>,
+    /// The correlation id shown on error pages
+    error_ref: Option<String>,
 }
 
 impl Skeleton {
     #[instrument(skip(response))]
     fn render_response(self, name: &str, mut response: HttpResponseBuilder) -> HttpResponse {
+        if let Some(error_ref) = &self.error_ref {
+            response.insert_header((ERROR_REF_HEADER, error_ref.as_str()));
+        }
         match self.render_once() {
             Ok(html) => response.content_type("text/html").body(html),
             Err(why) => {
@@ -214,13 +655,41 @@ impl Skeleton {
         }
     }
 
+    /// Responds to a failed session validation, telling the user how to get a
+    /// new link with the slash `command` if the session only expired
+    fn session_error(why: &anyhow::Error, command: &str, correlation_id: &str) -> HttpResponse {
+        if why.is::<SessionExpired>() {
+            Skeleton::session_expired(command, correlation_id)
+        } else {
+            Skeleton::invalid_session(&why.to_string(), correlation_id)
+        }
+    }
+
     #[instrument]
-    fn invalid_session(reason: &str) -> HttpResponse {
+    fn session_expired(command: &str, correlation_id: &str) -> HttpResponse {
+        Skeleton {
+            index_script: None,
+            paragraph_text: format!(
+                "This link has expired. Links are only valid for a short \
+                time, please go back to Discord and use <b>{}</b> again to \
+                get a new one.",
+                command
+            ),
+            button: None,
+            form_input: None,
+            error_ref: Some(correlation_id.to_string()),
+        }
+        .render_response("session expired", HttpResponse::BadRequest())
+    }
+
+    #[instrument]
+    fn invalid_session(reason: &str, correlation_id: &str) -> HttpResponse {
         Skeleton {
             index_script: None,
             paragraph_text: format!("Invalid session: {}", reason),
             button: None,
             form_input: None,
+            error_ref: Some(correlation_id.to_string()),
         }
         .render_response("invalid session", HttpResponse::BadRequest())
     }
@@ -234,17 +703,19 @@ impl Skeleton {
                 .to_string(),
             button: None,
             form_input: None,
+            error_ref: None,
         }
         .render_response("registration page", HttpResponse::Ok())
     }
 
     #[instrument]
-    fn invalid_signature(reason: &str) -> HttpResponse {
+    fn invalid_signature(reason: &str, correlation_id: &str) -> HttpResponse {
         Skeleton {
             index_script: None,
             paragraph_text: format!("Invalid signature: {}", reason),
             button: None,
             form_input: None,
+            error_ref: Some(correlation_id.to_string()),
         }
         .render_response("invalid signature", HttpResponse::BadRequest())
     }
@@ -256,6 +727,7 @@ impl Skeleton {
             paragraph_text: "Registration successful".to_string(),
             button: None,
             form_input: None,
+            error_ref: None,
         }
         .render_response("register success", HttpResponse::Ok())
     }
@@ -267,17 +739,51 @@ impl Skeleton {
             paragraph_text: "You are already registered".to_string(),
             button: None,
             form_input: None,
+            error_ref: None,
         }
         .render_response("already registered", HttpResponse::BadRequest())
     }
 
     #[instrument]
-    fn internal_error() -> HttpResponse {
+    fn too_many_wallets(max: usize) -> HttpResponse {
+        Skeleton {
+            index_script: None,
+            paragraph_text: format!(
+                "You have linked the maximum of {} wallets already. Unregister \
+                with <b>/get out</b> to link other wallets",
+                max
+            ),
+            button: None,
+            form_input: None,
+            error_ref: None,
+        }
+        .render_response("too many wallets", HttpResponse::BadRequest())
+    }
+
+    /// The registration could not be written to the storage, which is most
+    /// likely temporary, e.g. a full disk or a locked database
+    #[instrument]
+    fn storage_unavailable(correlation_id: &str) -> HttpResponse {
+        Skeleton {
+            index_script: None,
+            paragraph_text: "Your registration could not be saved right now. Please try \
+            again in a few minutes with a new link from <b>/get in</b>"
+                .to_string(),
+            button: None,
+            form_input: None,
+            error_ref: Some(correlation_id.to_string()),
+        }
+        .render_response("storage unavailable", HttpResponse::ServiceUnavailable())
+    }
+
+    #[instrument]
+    fn internal_error(correlation_id: &str) -> HttpResponse {
         Skeleton {
             index_script: None,
             paragraph_text: "Internal error".to_string(),
             button: None,
             form_input: None,
+            error_ref: Some(correlation_id.to_string()),
         }
         .render_response("internal error", HttpResponse::InternalServerError())
     }
@@ -296,6 +802,7 @@ impl Skeleton {
                 method: "POST",
                 action: "",
             }),
+            error_ref: None,
         }
         .render_response("unregistration page", HttpResponse::Ok())
     }
@@ -308,6 +815,7 @@ impl Skeleton {
                 .to_string(),
             button: None,
             form_input: None,
+            error_ref: None,
         }
         .render_response("unregister success", HttpResponse::Ok())
     }
@@ -316,6 +824,12 @@ impl Skeleton {
 #[cfg(test)]
 mod test {
     use super::*;
+    use ethers::signers::{LocalWallet, Signer};
+
+    /// The default registration message of the config
+    const DEFAULT_TEMPLATE: &str = "Please sign this message to connect your Discord \
+                                    username {username} with your wallet address. \
+                                    Session ID: {session}";
 
     #[test]
     fn recover_from_ok_signature() {
@@ -328,6 +842,95 @@ mod test {
         assert_eq!(address, recovered_address);
     }
 
+    #[test]
+    fn check_signature_of_known_fixture() {
+        let address = "0xcB313f361847e245954FD338Cb21b5F4225b17d1";
+        let message = "Please sign this message to connect your Discord username hmuendel with your wallet address. Session ID: b2a76f67b6c1bdf61cea3b2c.046c5bfeea4351a17b8be03a516380a13ebd1396d69a57ff306a3249fc6d0763d3071171cda9d1f6250e7a3b82344fccd85c7ca92da0";
+        let signature = "0x092e15f49b64ae802fa4d5e8d2439e92a174b23dabe99650191f1028377d4e7711952f199bf84f5e49868b9db68ef2ce1f7ab5dbeb34afa6393d517afc42cd251c";
+        let check = check_signature(address, message, signature).unwrap();
+        assert_eq!(
+            check.recovered,
+            Some(colony_rs::Address::from_str(address).unwrap())
+        );
+        assert!(check.matches);
+
+        let check = check_signature(address, "another message", signature).unwrap();
+        assert!(check.recovered.is_some());
+        assert_ne!(
+            check.recovered,
+            Some(colony_rs::Address::from_str(address).unwrap())
+        );
+        assert!(!check.matches);
+
+        assert!(check_signature("not an address", message, signature).is_err());
+        assert!(check_signature(address, message, "0x1234").is_err());
+    }
+
+    #[test]
+    fn validate_signature_independent_of_source() {
+        let session_str = "b2a76f67b6c1bdf61cea3b2c.046c5bfeea4351a17b8be03a516380a13ebd1396d69a57ff306a3249fc6d0763d3071171cda9d1f6250e7a3b82344fccd85c7ca92da0";
+        let session = Session {
+            user_id: 1,
+            username: "hmuendel".to_string(),
+            timestamp: 0,
+        };
+        let signature = "0x092e15f49b64ae802fa4d5e8d2439e92a174b23dabe99650191f1028377d4e7711952f199bf84f5e49868b9db68ef2ce1f7ab5dbeb34afa6393d517afc42cd251c";
+        // browser extensions usually report checksummed addresses, while
+        // other wallets hand out lowercase ones
+        for address in [
+            "0xcB313f361847e245954FD338Cb21b5F4225b17d1",
+            "0xcb313f361847e245954fd338cb21b5f4225b17d1",
+        ] {
+            let data = JsonData {
+                signature: SecretString::new(signature.to_string()),
+                address: SecretString::new(address.to_string()),
+            };
+            let wallet =
+                validate_signature(&data, DEFAULT_TEMPLATE, &session, session_str).unwrap();
+            assert_eq!(wallet.expose_secret(), address);
+        }
+        let other_session = Session {
+            username: "someone_else".to_string(),
+            ..session
+        };
+        let data = JsonData {
+            signature: SecretString::new(signature.to_string()),
+            address: SecretString::new("0xcB313f361847e245954FD338Cb21b5F4225b17d1".to_string()),
+        };
+        assert!(validate_signature(&data, DEFAULT_TEMPLATE, &other_session, session_str).is_err());
+    }
+
+    #[tokio::test]
+    async fn custom_registration_message_round_trips() {
+        let template = "Welcome to the DAO {username}! Sign to join, session {session}";
+        let session_str = "b2a76f67b6c1bdf61cea3b2c.046c5bfeea4351a17b8be03a516380a1";
+        let session = Session {
+            user_id: 1,
+            username: "hmuendel".to_string(),
+            timestamp: 0,
+        };
+        let message = registration_message(template, &session.username, session_str);
+        assert_eq!(
+            message,
+            format!(
+                "Welcome to the DAO hmuendel! Sign to join, session {}",
+                session_str
+            )
+        );
+        // a well known test key, never use it for anything else
+        let wallet = "4c0883a69102937d6231471b5dbb6204fe5129617082792ae468d01a3f362318"
+            .parse::<LocalWallet>()
+            .unwrap();
+        let signature = wallet.sign_message(&message).await.unwrap();
+        let data = JsonData {
+            signature: SecretString::new(signature.to_string()),
+            address: SecretString::new(format!("{:?}", wallet.address())),
+        };
+        assert!(validate_signature(&data, template, &session, session_str).is_ok());
+        // the signature does not match the message of another template
+        assert!(validate_signature(&data, DEFAULT_TEMPLATE, &session, session_str).is_err());
+    }
+
     #[test]
     fn recover_from_bad_signature() {
         let address =
@@ -338,4 +941,286 @@ mod test {
         let recovered_address = signature.recover(message).unwrap();
         assert_ne!(address, recovered_address);
     }
+
+    #[test]
+    fn correlation_id_round_trips_to_error_response() {
+        let session_str = "b2a76f67b6c1bdf61cea3b2c.046c5bfeea4351a17b8be03a516380a13ebd1396d69a57ff306a3249fc6d0763d3071171cda9d1f6250e7a3b82344fccd85c7ca92da0";
+        let correlation_id = Session::correlation_id(session_str);
+        assert_eq!(correlation_id, "b2a76f67");
+        let response = Skeleton::internal_error(&correlation_id);
+        assert_eq!(
+            response.headers().get(ERROR_REF_HEADER).unwrap(),
+            correlation_id.as_str()
+        );
+        let html = Skeleton {
+            index_script: None,
+            paragraph_text: "Internal error".to_string(),
+            button: None,
+            form_input: None,
+            error_ref: Some(correlation_id.clone()),
+        }
+        .render_once()
+        .unwrap();
+        assert!(html.contains("Error ref: b2a76f67"));
+    }
+
+    #[actix_web::test]
+    async fn unusual_usernames_survive_the_path() {
+        use actix_web::test;
+
+        #[get("/challenge/{username}/{session}")]
+        async fn echo_username(path: web::Path<(String, String)>) -> impl Responder {
+            path.into_inner().0
+        }
+
+        let app = test::init_service(App::new().service(echo_username)).await;
+        for username in ["ünïcödé 🦀🎉", "with:colons", "a/b?c#d", "100%25 sure"] {
+            let session = Session {
+                user_id: 1,
+                username: username.to_string(),
+                timestamp: 0,
+            };
+            // the page encodes the username of the link as path segment
+            let uri = format!("/challenge/{}/abc.def", urlencoding::encode(username));
+            let request = test::TestRequest::get().uri(&uri).to_request();
+            let body = test::call_and_read_body(&app, request).await;
+            let extracted = String::from_utf8(body.to_vec()).unwrap();
+            check_username(&extracted, &session).unwrap();
+            let message = registration_message(DEFAULT_TEMPLATE, &session.username, "abc.def");
+            assert!(message.contains(username));
+        }
+        let session = Session {
+            user_id: 1,
+            username: "with:colons".to_string(),
+            timestamp: 0,
+        };
+        assert!(check_username("with", &session).is_err());
+    }
+
+    #[cfg(unix)]
+    #[actix_web::test]
+    async fn serves_on_unix_socket() {
+        use std::io::{Read, Write};
+        let path =
+            std::env::temp_dir().join(format!("discord-gating-bot-{}.sock", std::process::id()));
+        // a stale socket of a killed run does not fail the bind
+        std::os::unix::net::UnixListener::bind(&path).unwrap();
+        let server = bind_unix_socket(&path).unwrap();
+        let handle = server.handle();
+        let running = tokio::spawn(server);
+
+        let socket = path.clone();
+        let response = tokio::task::spawn_blocking(move || {
+            let mut stream = std::os::unix::net::UnixStream::connect(socket).unwrap();
+            stream
+                .write_all(
+                    b"GET /not-there HTTP/1.1\r\nHost: localhost\r\nConnection: close\r\n\r\n",
+                )
+                .unwrap();
+            let mut response = String::new();
+            stream.read_to_string(&mut response).unwrap();
+            response
+        })
+        .await
+        .unwrap();
+        assert!(response.starts_with("HTTP/1.1 404"), "{}", response);
+
+        handle.stop(true).await;
+        running.await.unwrap().unwrap();
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    /// Sends a request for a page that doesn't exist and returns the response
+    fn get_not_there(port: u16) -> String {
+        use std::io::{Read, Write};
+        let mut stream = std::net::TcpStream::connect(("127.0.0.1", port)).unwrap();
+        stream
+            .write_all(b"GET /not-there HTTP/1.1\r\nHost: localhost\r\nConnection: close\r\n\r\n")
+            .unwrap();
+        let mut response = String::new();
+        stream.read_to_string(&mut response).unwrap();
+        response
+    }
+
+    #[actix_web::test]
+    async fn serves_on_every_bind_address() {
+        let free_port = || {
+            std::net::TcpListener::bind("127.0.0.1:0")
+                .unwrap()
+                .local_addr()
+                .unwrap()
+                .port()
+        };
+        let ports = [free_port(), free_port()];
+        let binds = ports
+            .iter()
+            .map(|port| format!("127.0.0.1:{}", port))
+            .collect::<Vec<_>>();
+        let server = bind_addresses(&binds, "localhost", 8080).unwrap();
+        let handle = server.handle();
+        let running = tokio::spawn(server);
+
+        for port in ports {
+            let response = tokio::task::spawn_blocking(move || get_not_there(port))
+                .await
+                .unwrap();
+            assert!(response.starts_with("HTTP/1.1 404"), "{}", response);
+        }
+
+        handle.stop(true).await;
+        running.await.unwrap().unwrap();
+
+        let why = bind_addresses(&["localhost".to_string()], "localhost", 8080).unwrap_err();
+        assert_eq!(why.kind(), std::io::ErrorKind::InvalidInput);
+    }
+
+    #[actix_web::test]
+    async fn index_carries_security_headers() {
+        use actix_web::test;
+        let directory =
+            std::env::temp_dir().join(format!("discord-gating-bot-www-{}", std::process::id()));
+        std::fs::create_dir_all(&directory).unwrap();
+        std::fs::write(directory.join("invite.html"), "<html></html>").unwrap();
+        let csp = "default-src 'self'; script-src 'self'";
+        let app = test::init_service(
+            App::new()
+                .wrap(security_headers(csp, "no-referrer"))
+                .service(Files::new("/", &directory).index_file("invite.html")),
+        )
+        .await;
+        let response =
+            test::call_service(&app, test::TestRequest::get().uri("/").to_request()).await;
+        assert_eq!(response.status(), actix_web::http::StatusCode::OK);
+        let headers = response.headers();
+        assert_eq!(
+            headers.get(header::X_CONTENT_TYPE_OPTIONS).unwrap(),
+            "nosniff"
+        );
+        assert_eq!(headers.get(header::X_FRAME_OPTIONS).unwrap(), "DENY");
+        assert_eq!(headers.get(header::CONTENT_SECURITY_POLICY).unwrap(), csp);
+        assert_eq!(headers.get(header::REFERRER_POLICY).unwrap(), "no-referrer");
+
+        // empty and invalid policies are left out
+        let app = test::init_service(
+            App::new()
+                .wrap(security_headers("", "no-\nreferrer"))
+                .service(Files::new("/", &directory).index_file("invite.html")),
+        )
+        .await;
+        let response =
+            test::call_service(&app, test::TestRequest::get().uri("/").to_request()).await;
+        assert_eq!(response.status(), actix_web::http::StatusCode::OK);
+        assert!(response
+            .headers()
+            .get(header::CONTENT_SECURITY_POLICY)
+            .is_none());
+        assert!(response.headers().get(header::REFERRER_POLICY).is_none());
+        assert_eq!(
+            response
+                .headers()
+                .get(header::X_CONTENT_TYPE_OPTIONS)
+                .unwrap(),
+            "nosniff"
+        );
+        std::fs::remove_dir_all(&directory).unwrap();
+    }
+
+    #[actix_web::test]
+    async fn ready_only_once_the_rpc_answers() {
+        use crate::gate::rpc::tests::StartingRpc;
+        use actix_web::test;
+        let app = test::init_service(App::new().service(readyz)).await;
+        let client: &'static StartingRpc = Box::leak(Box::new(StartingRpc {
+            failures: usize::MAX,
+            ..Default::default()
+        }));
+        let probe = rpc::wait_until_reachable(
+            client,
+            colony_rs::H160::zero(),
+            Duration::from_secs(10),
+            Duration::from_millis(5),
+        );
+        let becoming_ready = tokio::spawn(become_ready(async move {
+            probe.await.unwrap();
+        }));
+
+        tokio::time::sleep(Duration::from_millis(50)).await;
+        assert!(client.attempts.load(Ordering::SeqCst) > 1);
+        let response =
+            test::call_service(&app, test::TestRequest::get().uri("/readyz").to_request()).await;
+        assert_eq!(
+            response.status(),
+            actix_web::http::StatusCode::SERVICE_UNAVAILABLE
+        );
+
+        client.up.store(true, Ordering::SeqCst);
+        becoming_ready.await.unwrap();
+        let response =
+            test::call_service(&app, test::TestRequest::get().uri("/readyz").to_request()).await;
+        assert_eq!(response.status(), actix_web::http::StatusCode::OK);
+    }
+
+    #[tokio::test]
+    async fn status_counts_guilds_of_the_store() {
+        use crate::controller::Controller;
+        use crate::gate::{AllowlistGate, Gate};
+        use crate::storage::{InMemoryStorage, Storage};
+
+        let mut storage = InMemoryStorage::new();
+        for guild_id in [1, 2, 3] {
+            let gate = Gate {
+                role_id: 10,
+                condition: Box::new(AllowlistGate { wallets: vec![] }),
+                enabled: true,
+                grant_message: None,
+            };
+            storage.add_gate(&guild_id, gate).unwrap();
+        }
+        let controller = Controller::with_storage(storage);
+        let channel = controller.message_tx.clone();
+        controller.spawn().await;
+
+        let response = HttpResponse::Ok().json(Status::collect(Some(&channel)).await);
+        let body = actix_web::body::to_bytes(response.into_body())
+            .await
+            .unwrap();
+        let json = String::from_utf8(body.to_vec()).unwrap();
+        assert!(json.starts_with(r#"{"discord_connected":false,"controller_up":true,"#));
+        assert!(json.contains(r#""guilds":3,"#), "{}", json);
+        for field in [
+            "storage",
+            "rpc_calls_in_flight",
+            "queued_messages",
+            "enforcement_paused",
+            "uptime_seconds",
+        ] {
+            assert!(json.contains(&format!(r#""{}":"#, field)), "{}", json);
+        }
+
+        let status = Status::collect(None).await;
+        assert!(!status.controller_up);
+        assert_eq!(status.guilds, None);
+    }
+
+    #[tokio::test]
+    async fn expired_session_gets_specific_page() {
+        let expired = anyhow::Error::new(SessionExpired);
+        let response = Skeleton::session_error(&expired, "/get in", "b2a76f67");
+        assert_eq!(response.status(), actix_web::http::StatusCode::BAD_REQUEST);
+        let body = actix_web::body::to_bytes(response.into_body())
+            .await
+            .unwrap();
+        let html = String::from_utf8(body.to_vec()).unwrap();
+        assert!(html.contains("This link has expired"));
+        assert!(html.contains("<b>/get in</b>"));
+
+        let invalid = anyhow::anyhow!("Invalid username");
+        let response = Skeleton::session_error(&invalid, "/get in", "b2a76f67");
+        let body = actix_web::body::to_bytes(response.into_body())
+            .await
+            .unwrap();
+        let html = String::from_utf8(body.to_vec()).unwrap();
+        assert!(html.contains("Invalid session: Invalid username"));
+        assert!(!html.contains("This link has expired"));
+    }
 }
@@ -3,7 +3,8 @@
 //!
 
 use crate::config::CONFIG;
-use crate::gate::Gate;
+use crate::gate::{Gate, GlobalGate};
+use crate::util::unix_timestamp;
 use anyhow::{anyhow, bail, Result};
 use chacha20poly1305::{
     aead::generic_array::GenericArray,
@@ -11,12 +12,62 @@ use chacha20poly1305::{
     ChaCha20Poly1305,
 };
 
+use colony_rs::H160;
 use secrecy::ExposeSecret;
 use secrecy::SecretString;
 use serde::{Deserialize, Serialize};
 use sled::{self, IVec};
-use std::collections::HashMap;
-use tracing::{debug, error, instrument};
+use std::collections::{HashMap, HashSet};
+use std::fs;
+use std::path::Path;
+use std::str::FromStr;
+use tracing::{debug, error, info, instrument, warn};
+
+/// The sled tree holding the time of the last complete check of a user in a
+/// guild, keyed by user and guild id, so all entries of a user can be
+/// cleared at once when the wallet changes
+const LAST_CHECKED_TREE: &[u8] = b"last_checked";
+/// The sled tree holding the time a user started failing the gates of a role
+/// in a guild, keyed by user, guild and role id, so all entries of a user can
+/// be cleared at once when the user is removed
+const FIRST_FAILED_TREE: &[u8] = b"first_failed";
+/// The sled tree holding the tombstones of soft deleted users, keyed by user
+/// id with the unix timestamp of the removal
+const REMOVED_USERS_TREE: &[u8] = b"removed_users";
+/// The sled tree holding the cumulative registration counters, keyed by the
+/// name of the counter
+const STATS_TREE: &[u8] = b"stats";
+const REGISTRATIONS_KEY: &[u8] = b"registrations";
+const UNREGISTRATIONS_KEY: &[u8] = b"unregistrations";
+/// The sled tree holding the gates that apply to all guilds
+const GLOBAL_GATES_TREE: &[u8] = b"global_gates";
+/// The sled tree holding the version of the bot that last opened the
+/// database, to detect downgrades
+const VERSION_TREE: &[u8] = b"version";
+const VERSION_KEY: &[u8] = b"crate_version";
+/// Set in the version tree of a namespace once its guild trees were moved to
+/// the prefixed names, so the migration only runs once
+const GUILD_TREES_MIGRATED_KEY: &[u8] = b"guild_trees_migrated";
+/// The sled tree holding the guilds in which gating is frozen, keyed by guild
+/// id, the freeze of all guilds is stored under its own key
+const FROZEN_TREE: &[u8] = b"frozen";
+const FROZEN_ALL_KEY: &[u8] = b"all";
+/// The sled tree holding the guilds in which registered members get their
+/// roles when they join, keyed by guild id
+const AUTO_GRANT_TREE: &[u8] = b"auto_grant";
+/// The prefix of the sled trees holding the gates of a guild, followed by the
+/// guild id in decimal, which sets them apart from the default tree of sled
+/// and any other trees
+const GUILD_TREE_PREFIX: &str = "g:";
+/// The prefix of the sled trees of a namespace, followed by the namespace
+/// and a slash. Trees without it hold the data stored without a namespace
+const NAMESPACE_PREFIX: &[u8] = b"ns:";
+/// The name of the default tree of sled, a namespace has a tree of this name
+/// in place of it
+const DEFAULT_TREE: &[u8] = b"__sled__default";
+/// The errno of a full disk on unix systems, for io errors that were not
+/// mapped to [`std::io::ErrorKind::StorageFull`]
+const ENOSPC: i32 = 28;
 
 /// The storage trait that defines the methods that need to be implemented
 /// for a storage backend
@@ -30,11 +81,145 @@ pub trait Storage {
     fn add_gate(&mut self, guild_id: &u64, gate: Gate) -> Result<()>;
     fn list_gates(&self, guild_id: &u64) -> Result<Self::GateIter>;
     fn remove_gate(&mut self, guild_id: &u64, identifier: u128) -> Result<()>;
+    fn add_global_gate(&mut self, gate: GlobalGate) -> Result<()>;
+    fn list_global_gates(&self) -> Result<Vec<GlobalGate>>;
+    fn remove_global_gate(&mut self, identifier: u128) -> Result<()>;
     fn get_user(&self, user_id: &u64) -> Result<Vec<SecretString>>;
     fn list_users(&self) -> Result<Self::UserIter>;
     fn add_user(&mut self, user_id: u64, wallets: Vec<SecretString>) -> Result<()>;
     fn contains_user(&self, user_id: &u64) -> bool;
-    fn remove_user(&mut self, user_id: &u64) -> Result<()>;
+    /// Removes the user with their wallets. With `soft` a tombstone with the
+    /// time of the removal is kept, the user is not registered anymore but
+    /// [`Storage::was_registered`] still reveals them. Removing a soft
+    /// deleted user without `soft` purges the tombstone
+    fn remove_user(&mut self, user_id: &u64, soft: bool) -> Result<()>;
+    /// The unix timestamp when the user was soft deleted, if they were and
+    /// did not register again since
+    fn removed_at(&self, user_id: &u64) -> Result<Option<u64>>;
+    /// Whether the user is registered or was before being soft deleted
+    fn was_registered(&self, user_id: &u64) -> bool {
+        self.contains_user(user_id) || matches!(self.removed_at(user_id), Ok(Some(_)))
+    }
+    /// The unix timestamp of the last complete check of the user in a guild
+    fn last_checked(&self, guild_id: &u64, user_id: &u64) -> Result<Option<u64>>;
+    fn set_last_checked(&mut self, guild_id: &u64, user_id: &u64, timestamp: u64) -> Result<()>;
+    /// The unix timestamp since when the user fails the gates of a role in a
+    /// guild, if they do
+    fn first_failed(&self, guild_id: &u64, user_id: &u64, role_id: &u64) -> Result<Option<u64>>;
+    /// Stores since when the user fails the gates of a role, or forgets it
+    /// without a time, e.g. because the user qualifies again
+    fn set_first_failed(
+        &mut self,
+        guild_id: &u64,
+        user_id: &u64,
+        role_id: &u64,
+        timestamp: Option<u64>,
+    ) -> Result<()>;
+    /// The cumulative registrations and unregistrations, see
+    /// [`RegistrationStats`]
+    fn registration_stats(&self) -> Result<RegistrationStats>;
+    fn count_registration(&mut self) -> Result<()>;
+    fn count_unregistration(&mut self) -> Result<()>;
+    /// Rewrites all stored gates in the current storage layout and returns
+    /// the number of migrated gates
+    fn migrate_gates(&mut self) -> Result<usize>;
+    /// Whether gating is frozen in the guild, either for the guild itself or
+    /// for all guilds
+    fn is_frozen(&self, guild_id: &u64) -> Result<bool>;
+    /// Freezes or unfreezes gating in a guild, or in all guilds without a
+    /// guild id
+    fn set_frozen(&mut self, guild_id: Option<u64>, frozen: bool) -> Result<()>;
+    /// Whether registered members get their roles right away when they join
+    /// the guild, without running `/get in`
+    fn auto_grant(&self, guild_id: &u64) -> Result<bool>;
+    /// Enables or disables granting the roles of registered members when
+    /// they join the guild
+    fn set_auto_grant(&mut self, guild_id: u64, enabled: bool) -> Result<()>;
+}
+
+/// The cumulative number of registrations and unregistrations since the
+/// storage was created, which unlike the metrics survive restarts. Users
+/// register once for all guilds, so the counts are global and not attributed
+/// to the guild the registration was started from
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub struct RegistrationStats {
+    pub registrations: u64,
+    pub unregistrations: u64,
+}
+
+/// The kind of a failed storage operation, which tells failures that need an
+/// operator, e.g. a full disk, apart from data that can't be stored
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum StorageErrorKind {
+    /// The database could not be written or read, e.g. because the disk is
+    /// full or the database is locked by another process
+    Io,
+    /// The data could not be serialized or encrypted
+    Serialization,
+    Other,
+}
+
+impl StorageErrorKind {
+    /// Classifies an error returned by a storage backend by its causes
+    pub fn of(why: &anyhow::Error) -> Self {
+        for cause in why.chain() {
+            if let Some(why) = cause.downcast_ref::<sled::Error>() {
+                return match why {
+                    sled::Error::Io(_) | sled::Error::Corruption { .. } => Self::Io,
+                    _ => Self::Other,
+                };
+            }
+            if cause.is::<std::io::Error>() {
+                return Self::Io;
+            }
+            if let Some(why) = cause.downcast_ref::<bincode::Error>() {
+                return match **why {
+                    bincode::ErrorKind::Io(_) => Self::Io,
+                    _ => Self::Serialization,
+                };
+            }
+            if cause.is::<hex::FromHexError>() {
+                return Self::Serialization;
+            }
+        }
+        Self::Other
+    }
+
+    /// Whether the storage failed because there is no space left on the disk
+    pub fn is_disk_full(why: &anyhow::Error) -> bool {
+        why.chain().any(|cause| {
+            let io = if let Some(sled::Error::Io(why)) = cause.downcast_ref::<sled::Error>() {
+                Some(why)
+            } else {
+                cause.downcast_ref::<std::io::Error>()
+            };
+            io.is_some_and(|why| {
+                why.kind() == std::io::ErrorKind::StorageFull || why.raw_os_error() == Some(ENOSPC)
+            })
+        })
+    }
+
+    /// Classifies and logs a failed storage operation. Failures of the disk
+    /// are logged as alerts, since they keep failing until an operator
+    /// frees up space or fixes the database
+    pub fn report(why: &anyhow::Error, operation: &str) -> Self {
+        let kind = Self::of(why);
+        if kind == Self::Io {
+            let disk_full = Self::is_disk_full(why);
+            error!(
+                alert = true,
+                disk_full, operation, "Storage is failing: {:?}", why
+            );
+            #[cfg(feature = "metrics")]
+            info!(
+                monotonic_counter.storage_io_errors = 1u64,
+                disk_full, operation, "Storage is failing"
+            );
+        } else {
+            error!(?kind, operation, "Storage operation failed: {:?}", why);
+        }
+        kind
+    }
 }
 
 /// The in-memory storage backend which does not persist data to disk
@@ -43,6 +228,13 @@ pub trait Storage {
 pub struct InMemoryStorage {
     gates: HashMap<u64, Vec<Gate>>,
     users: HashMap<u64, Vec<SecretString>>,
+    removed_users: HashMap<u64, u64>,
+    last_checked: HashMap<(u64, u64), u64>,
+    first_failed: HashMap<(u64, u64, u64), u64>,
+    global_gates: Vec<GlobalGate>,
+    stats: RegistrationStats,
+    frozen: HashSet<Option<u64>>,
+    auto_grant: HashSet<u64>,
 }
 
 impl Storage for InMemoryStorage {
@@ -54,6 +246,13 @@ impl Storage for InMemoryStorage {
         InMemoryStorage {
             gates: HashMap::new(),
             users: HashMap::new(),
+            removed_users: HashMap::new(),
+            last_checked: HashMap::new(),
+            first_failed: HashMap::new(),
+            global_gates: Vec::new(),
+            stats: RegistrationStats::default(),
+            frozen: HashSet::new(),
+            auto_grant: HashSet::new(),
         }
     }
 
@@ -104,6 +303,30 @@ impl Storage for InMemoryStorage {
         }
     }
 
+    #[instrument(skip(self))]
+    fn add_global_gate(&mut self, gate: GlobalGate) -> Result<()> {
+        debug!("Adding global gate");
+        self.global_gates.push(gate);
+        Ok(())
+    }
+
+    #[instrument(skip(self))]
+    fn list_global_gates(&self) -> Result<Vec<GlobalGate>> {
+        debug!("Listing global gates");
+        Ok(self.global_gates.clone())
+    }
+
+    #[instrument(skip(self))]
+    fn remove_global_gate(&mut self, identifier: u128) -> Result<()> {
+        debug!("Removing global gate");
+        let count = self.global_gates.len();
+        self.global_gates.retain(|g| g.identifier() != identifier);
+        if self.global_gates.len() == count {
+            bail!("Global gate {} not found", identifier);
+        }
+        Ok(())
+    }
+
     #[instrument(skip(self))]
     fn get_user(&self, user_id: &u64) -> Result<Vec<SecretString>> {
         debug!("Getting user");
@@ -123,6 +346,8 @@ impl Storage for InMemoryStorage {
     fn add_user(&mut self, user_id: u64, wallets: Vec<SecretString>) -> Result<()> {
         debug!("Adding user");
         self.users.insert(user_id, wallets);
+        self.removed_users.remove(&user_id);
+        self.last_checked.retain(|(_, user), _| *user != user_id);
         Ok(())
     }
 
@@ -133,11 +358,107 @@ impl Storage for InMemoryStorage {
     }
 
     #[instrument(skip(self))]
-    fn remove_user(&mut self, user_id: &u64) -> Result<()> {
+    fn remove_user(&mut self, user_id: &u64, soft: bool) -> Result<()> {
         debug!("Removing user");
-        self.users
-            .remove(user_id)
-            .ok_or(anyhow!("user {} does not exist", user_id))?;
+        let removed = self.users.remove(user_id).is_some();
+        // a soft deleted user only has their tombstone left, which a hard
+        // removal purges
+        match (removed, self.removed_users.contains_key(user_id)) {
+            (false, false) => bail!("user {} does not exist", user_id),
+            (false, true) if soft => return Ok(()),
+            _ => {}
+        }
+        self.last_checked.retain(|(_, user), _| user != user_id);
+        self.first_failed.retain(|(_, user, _), _| user != user_id);
+        if soft {
+            self.removed_users.insert(*user_id, unix_timestamp());
+        } else {
+            self.removed_users.remove(user_id);
+        }
+        Ok(())
+    }
+
+    #[instrument(skip(self))]
+    fn removed_at(&self, user_id: &u64) -> Result<Option<u64>> {
+        Ok(self.removed_users.get(user_id).copied())
+    }
+
+    #[instrument(skip(self))]
+    fn last_checked(&self, guild_id: &u64, user_id: &u64) -> Result<Option<u64>> {
+        Ok(self.last_checked.get(&(*guild_id, *user_id)).copied())
+    }
+
+    #[instrument(skip(self))]
+    fn set_last_checked(&mut self, guild_id: &u64, user_id: &u64, timestamp: u64) -> Result<()> {
+        self.last_checked.insert((*guild_id, *user_id), timestamp);
+        Ok(())
+    }
+
+    #[instrument(skip(self))]
+    fn first_failed(&self, guild_id: &u64, user_id: &u64, role_id: &u64) -> Result<Option<u64>> {
+        Ok(self
+            .first_failed
+            .get(&(*guild_id, *user_id, *role_id))
+            .copied())
+    }
+
+    #[instrument(skip(self))]
+    fn set_first_failed(
+        &mut self,
+        guild_id: &u64,
+        user_id: &u64,
+        role_id: &u64,
+        timestamp: Option<u64>,
+    ) -> Result<()> {
+        let key = (*guild_id, *user_id, *role_id);
+        match timestamp {
+            Some(timestamp) => self.first_failed.insert(key, timestamp),
+            None => self.first_failed.remove(&key),
+        };
+        Ok(())
+    }
+
+    fn registration_stats(&self) -> Result<RegistrationStats> {
+        Ok(self.stats)
+    }
+
+    fn count_registration(&mut self) -> Result<()> {
+        self.stats.registrations += 1;
+        Ok(())
+    }
+
+    fn count_unregistration(&mut self) -> Result<()> {
+        self.stats.unregistrations += 1;
+        Ok(())
+    }
+
+    fn migrate_gates(&mut self) -> Result<usize> {
+        Ok(0)
+    }
+
+    fn is_frozen(&self, guild_id: &u64) -> Result<bool> {
+        Ok(self.frozen.contains(&None) || self.frozen.contains(&Some(*guild_id)))
+    }
+
+    fn set_frozen(&mut self, guild_id: Option<u64>, frozen: bool) -> Result<()> {
+        if frozen {
+            self.frozen.insert(guild_id);
+        } else {
+            self.frozen.remove(&guild_id);
+        }
+        Ok(())
+    }
+
+    fn auto_grant(&self, guild_id: &u64) -> Result<bool> {
+        Ok(self.auto_grant.contains(guild_id))
+    }
+
+    fn set_auto_grant(&mut self, guild_id: u64, enabled: bool) -> Result<()> {
+        if enabled {
+            self.auto_grant.insert(guild_id);
+        } else {
+            self.auto_grant.remove(&guild_id);
+        }
         Ok(())
     }
 }
@@ -145,7 +466,30 @@ impl Storage for InMemoryStorage {
 /// The sled storage backend which persists data to disk unencrypted
 #[derive(Debug)]
 pub struct SledUnencryptedStorage {
-    db: sled::Db,
+    db: Namespaced,
+    /// The database holding the users, the same as `db` unless the users are
+    /// configured to be stored in a separate directory
+    users: Namespaced,
+}
+
+impl SledUnencryptedStorage {
+    /// Opens the database at the given path instead of the configured one
+    pub fn open(db_path: &Path) -> Self {
+        Self::open_with_users(db_path, db_path)
+    }
+
+    /// Opens the databases at the given paths instead of the configured ones,
+    /// the users are stored in the second one
+    pub fn open_with_users(db_path: &Path, user_db_path: &Path) -> Self {
+        Self::open_in_namespace(db_path, user_db_path, &namespace())
+    }
+
+    /// Opens the data of the given namespace in the databases at the given
+    /// paths, instead of the configured namespace
+    pub fn open_in_namespace(db_path: &Path, user_db_path: &Path, namespace: &str) -> Self {
+        let (db, users) = open_databases(db_path, user_db_path, namespace);
+        Self { db, users }
+    }
 }
 
 impl Storage for SledUnencryptedStorage {
@@ -158,37 +502,31 @@ impl Storage for SledUnencryptedStorage {
     type GuildIter = std::iter::FilterMap<std::vec::IntoIter<IVec>, fn(IVec) -> Option<u64>>;
 
     fn new() -> Self {
-        let db_path = &CONFIG.wait().storage.directory;
-        let db = sled::open(db_path).expect("Failed to open database");
-        SledUnencryptedStorage { db }
+        let storage = &CONFIG.wait().storage;
+        Self::open_with_users(&storage.directory, storage.user_directory())
     }
 
     #[instrument(skip(self))]
     fn list_guilds(&self) -> Self::GuildIter {
         debug!("Listing guilds");
-        self.db.tree_names().into_iter().filter_map(|tree_name| {
-            if let Ok(bytes) = tree_name.to_vec().try_into() {
-                Some(u64::from_be_bytes(bytes))
-            } else {
-                error!(?tree_name, "Failed to parse guild id from tree name");
-                None
-            }
-        })
+        self.db
+            .tree_names()
+            .into_iter()
+            .filter_map(|tree_name| guild_id_from_tree_name(&tree_name))
     }
 
     #[instrument(skip(self))]
     fn remove_guild(&mut self, guild_id: u64) -> Result<()> {
         debug!("Removing guild");
-        let tree_name = guild_id.to_be_bytes().to_vec();
-        self.db.drop_tree(tree_name)?;
+        self.db.drop_tree(guild_tree_name(guild_id))?;
         Ok(())
     }
 
     #[instrument(skip(self))]
     fn add_gate(&mut self, guild_id: &u64, gate: Gate) -> Result<()> {
         debug!("Adding gate");
-        let tree = self.db.open_tree(guild_id.to_be_bytes())?;
-        let gate_bytes = bincode::serialize(&gate)?;
+        let tree = self.db.open_tree(guild_tree_name(*guild_id))?;
+        let gate_bytes = gate.to_bytes()?;
         let key = gate.identifier();
         tree.insert(key.to_be_bytes(), gate_bytes)?;
         Ok(())
@@ -197,7 +535,7 @@ impl Storage for SledUnencryptedStorage {
     #[instrument(skip(self))]
     fn remove_gate(&mut self, guild_id: &u64, identifier: u128) -> Result<()> {
         debug!("Removing gate");
-        let tree = self.db.open_tree(guild_id.to_be_bytes())?;
+        let tree = self.db.open_tree(guild_tree_name(*guild_id))?;
         tree.remove(identifier.to_be_bytes())?;
         Ok(())
     }
@@ -205,10 +543,10 @@ impl Storage for SledUnencryptedStorage {
     #[instrument(skip(self))]
     fn list_gates(&self, guild_id: &u64) -> Result<Self::GateIter> {
         debug!("Listing gates");
-        let tree = self.db.open_tree(guild_id.to_be_bytes())?;
+        let tree = self.db.open_tree(guild_tree_name(*guild_id))?;
         Ok(tree.iter().filter_map(|result| {
             if let Ok((_, gate_bytes)) = result {
-                if let Ok(gate) = bincode::deserialize::<Gate>(&gate_bytes) {
+                if let Ok(gate) = Gate::from_bytes(&gate_bytes) {
                     Some(gate)
                 } else {
                     error!("Failed to deserialize gate");
@@ -221,10 +559,32 @@ impl Storage for SledUnencryptedStorage {
         }))
     }
 
+    #[instrument(skip(self))]
+    fn add_global_gate(&mut self, gate: GlobalGate) -> Result<()> {
+        debug!("Adding global gate");
+        add_global_gate(&self.db, gate)
+    }
+
+    #[instrument(skip(self))]
+    fn list_global_gates(&self) -> Result<Vec<GlobalGate>> {
+        debug!("Listing global gates");
+        list_global_gates(&self.db)
+    }
+
+    #[instrument(skip(self))]
+    fn remove_global_gate(&mut self, identifier: u128) -> Result<()> {
+        debug!("Removing global gate");
+        let tree = self.db.open_tree(GLOBAL_GATES_TREE)?;
+        if tree.remove(identifier.to_be_bytes())?.is_none() {
+            bail!("Global gate {} not found", identifier);
+        }
+        Ok(())
+    }
+
     #[instrument(skip(self))]
     fn get_user(&self, user_id: &u64) -> Result<Vec<SecretString>> {
         debug!("Getting user");
-        let wallet = match self.db.get(user_id.to_be_bytes())? {
+        let wallet = match self.users.get(user_id.to_be_bytes())? {
             Some(wallet) => wallet,
             None => bail!("User {} not found", user_id),
         };
@@ -234,7 +594,7 @@ impl Storage for SledUnencryptedStorage {
     #[instrument(skip(self))]
     fn list_users(&self) -> Result<Self::UserIter> {
         debug!("Listing users");
-        Ok(self.db.iter().filter_map(|result| {
+        Ok(self.users.iter().filter_map(|result| {
             if let Ok((user_id, wallets)) = result {
                 if let Ok(user_id) = user_id.to_vec().try_into() {
                     let user_id = u64::from_be_bytes(user_id);
@@ -262,30 +622,143 @@ impl Storage for SledUnencryptedStorage {
             .iter()
             .map(|wallet| wallet.expose_secret().clone())
             .collect();
-        self.db
+        self.users
             .insert(user_id.to_be_bytes(), bincode::serialize(&wallets)?)?;
+        clear_last_checked(&self.users, user_id)?;
+        set_tombstone(&self.users, user_id, None)?;
         Ok(())
     }
 
     #[instrument(skip(self))]
     fn contains_user(&self, user_id: &u64) -> bool {
         debug!("Checking if user exists");
-        self.db.contains_key(user_id.to_be_bytes()).unwrap_or(false)
+        self.users
+            .contains_key(user_id.to_be_bytes())
+            .unwrap_or(false)
     }
 
     #[instrument(skip(self))]
-    fn remove_user(&mut self, user_id: &u64) -> Result<()> {
+    fn remove_user(&mut self, user_id: &u64, soft: bool) -> Result<()> {
         debug!("Removing user");
-        self.db.remove(user_id.to_be_bytes())?;
+        let removed = self.users.remove(user_id.to_be_bytes())?.is_some();
+        // a soft deleted user only has their tombstone left, which a hard
+        // removal purges
+        match (removed, get_tombstone(&self.users, *user_id)?) {
+            (false, None) => bail!("user {} does not exist", user_id),
+            (false, Some(_)) if soft => return Ok(()),
+            _ => {}
+        }
+        clear_last_checked(&self.users, *user_id)?;
+        clear_first_failed(&self.users, *user_id)?;
+        set_tombstone(&self.users, *user_id, soft.then(unix_timestamp))?;
         Ok(())
     }
+
+    #[instrument(skip(self))]
+    fn removed_at(&self, user_id: &u64) -> Result<Option<u64>> {
+        get_tombstone(&self.users, *user_id)
+    }
+
+    #[instrument(skip(self))]
+    fn last_checked(&self, guild_id: &u64, user_id: &u64) -> Result<Option<u64>> {
+        get_last_checked(&self.users, guild_id, user_id)
+    }
+
+    #[instrument(skip(self))]
+    fn set_last_checked(&mut self, guild_id: &u64, user_id: &u64, timestamp: u64) -> Result<()> {
+        let tree = self.users.open_tree(LAST_CHECKED_TREE)?;
+        tree.insert(
+            last_checked_key(guild_id, user_id),
+            timestamp.to_be_bytes().to_vec(),
+        )?;
+        Ok(())
+    }
+
+    #[instrument(skip(self))]
+    fn first_failed(&self, guild_id: &u64, user_id: &u64, role_id: &u64) -> Result<Option<u64>> {
+        get_first_failed(&self.users, guild_id, user_id, role_id)
+    }
+
+    #[instrument(skip(self))]
+    fn set_first_failed(
+        &mut self,
+        guild_id: &u64,
+        user_id: &u64,
+        role_id: &u64,
+        timestamp: Option<u64>,
+    ) -> Result<()> {
+        set_first_failed(&self.users, guild_id, user_id, role_id, timestamp)
+    }
+
+    #[instrument(skip(self))]
+    fn registration_stats(&self) -> Result<RegistrationStats> {
+        get_registration_stats(&self.users)
+    }
+
+    #[instrument(skip(self))]
+    fn count_registration(&mut self) -> Result<()> {
+        increment_counter(&self.users, REGISTRATIONS_KEY)
+    }
+
+    #[instrument(skip(self))]
+    fn count_unregistration(&mut self) -> Result<()> {
+        increment_counter(&self.users, UNREGISTRATIONS_KEY)
+    }
+
+    #[instrument(skip(self))]
+    fn migrate_gates(&mut self) -> Result<usize> {
+        migrate_gate_trees(&self.db, self.list_guilds())
+    }
+
+    #[instrument(skip(self))]
+    fn is_frozen(&self, guild_id: &u64) -> Result<bool> {
+        is_frozen(&self.db, guild_id)
+    }
+
+    #[instrument(skip(self))]
+    fn set_frozen(&mut self, guild_id: Option<u64>, frozen: bool) -> Result<()> {
+        set_frozen(&self.db, guild_id, frozen)
+    }
+
+    #[instrument(skip(self))]
+    fn auto_grant(&self, guild_id: &u64) -> Result<bool> {
+        auto_grant(&self.db, guild_id)
+    }
+
+    #[instrument(skip(self))]
+    fn set_auto_grant(&mut self, guild_id: u64, enabled: bool) -> Result<()> {
+        set_auto_grant(&self.db, guild_id, enabled)
+    }
 }
 
 /// The default sled storage backend which persists data to disk and encrypts
 /// the wallet addresses of users
 #[derive(Debug)]
 pub struct SledEncryptedStorage {
-    db: sled::Db,
+    db: Namespaced,
+    /// The database holding the users, the same as `db` unless the users are
+    /// configured to be stored in a separate directory
+    users: Namespaced,
+}
+
+impl SledEncryptedStorage {
+    /// Opens the database at the given path instead of the configured one
+    pub fn open(db_path: &Path) -> Self {
+        Self::open_with_users(db_path, db_path)
+    }
+
+    /// Opens the databases at the given paths instead of the configured ones,
+    /// the users are stored in the second one
+    pub fn open_with_users(db_path: &Path, user_db_path: &Path) -> Self {
+        Self::open_in_namespace(db_path, user_db_path, &namespace())
+    }
+
+    /// Opens the data of the given namespace in the databases at the given
+    /// paths, instead of the configured namespace
+    pub fn open_in_namespace(db_path: &Path, user_db_path: &Path, namespace: &str) -> Self {
+        let (db, users) = open_databases(db_path, user_db_path, namespace);
+        Self { db, users }
+    }
 }
 
 impl Storage for SledEncryptedStorage {
@@ -298,37 +771,31 @@ impl Storage for SledEncryptedStorage {
     type GuildIter = std::iter::FilterMap<std::vec::IntoIter<IVec>, fn(IVec) -> Option<u64>>;
 
     fn new() -> Self {
-        let db_path = &CONFIG.wait().storage.directory;
-        let db = sled::open(db_path).expect("Failed to open database");
-        Self { db }
+        let storage = &CONFIG.wait().storage;
+        Self::open_with_users(&storage.directory, storage.user_directory())
     }
 
     #[instrument(skip(self))]
     fn list_guilds(&self) -> Self::GuildIter {
         debug!("Listing guilds");
-        self.db.tree_names().into_iter().filter_map(|tree_name| {
-            if let Ok(bytes) = tree_name.to_vec().try_into() {
-                Some(u64::from_be_bytes(bytes))
-            } else {
-                error!(?tree_name, "Failed to deserialize tree name");
-                None
-            }
-        })
+        self.db
+            .tree_names()
+            .into_iter()
+            .filter_map(|tree_name| guild_id_from_tree_name(&tree_name))
     }
 
     #[instrument(skip(self))]
     fn remove_guild(&mut self, guild_id: u64) -> Result<()> {
         debug!("Removing guild");
-        let tree_name = guild_id.to_be_bytes().to_vec();
-        self.db.drop_tree(tree_name)?;
+        self.db.drop_tree(guild_tree_name(guild_id))?;
         Ok(())
     }
 
     #[instrument(skip(self))]
     fn add_gate(&mut self, guild_id: &u64, gate: Gate) -> Result<()> {
         debug!("Adding gate");
-        let tree = self.db.open_tree(guild_id.to_be_bytes())?;
-        let gate_bytes = bincode::serialize(&gate)?;
+        let tree = self.db.open_tree(guild_tree_name(*guild_id))?;
+        let gate_bytes = gate.to_bytes()?;
         let key = gate.identifier();
         tree.insert(key.to_be_bytes(), gate_bytes)?;
         Ok(())
@@ -337,7 +804,7 @@ impl Storage for SledEncryptedStorage {
     #[instrument(skip(self))]
     fn remove_gate(&mut self, guild_id: &u64, identifier: u128) -> Result<()> {
         debug!("Removing gate");
-        let tree = self.db.open_tree(guild_id.to_be_bytes())?;
+        let tree = self.db.open_tree(guild_tree_name(*guild_id))?;
         tree.remove(identifier.to_be_bytes())?;
         Ok(())
     }
@@ -345,10 +812,10 @@ impl Storage for SledEncryptedStorage {
     #[instrument(skip(self))]
     fn list_gates(&self, guild_id: &u64) -> Result<Self::GateIter> {
         debug!("Listing gates");
-        let tree = self.db.open_tree(guild_id.to_be_bytes())?;
+        let tree = self.db.open_tree(guild_tree_name(*guild_id))?;
         Ok(tree.iter().filter_map(|result| {
             if let Ok((_, v)) = result {
-                if let Ok(gate) = bincode::deserialize::<Gate>(&v) {
+                if let Ok(gate) = Gate::from_bytes(&v) {
                     Some(gate)
                 } else {
                     error!("Failed to deserialize gate");
@@ -361,10 +828,32 @@ impl Storage for SledEncryptedStorage {
         }))
     }
 
+    #[instrument(skip(self))]
+    fn add_global_gate(&mut self, gate: GlobalGate) -> Result<()> {
+        debug!("Adding global gate");
+        add_global_gate(&self.db, gate)
+    }
+
+    #[instrument(skip(self))]
+    fn list_global_gates(&self) -> Result<Vec<GlobalGate>> {
+        debug!("Listing global gates");
+        list_global_gates(&self.db)
+    }
+
+    #[instrument(skip(self))]
+    fn remove_global_gate(&mut self, identifier: u128) -> Result<()> {
+        debug!("Removing global gate");
+        let tree = self.db.open_tree(GLOBAL_GATES_TREE)?;
+        if tree.remove(identifier.to_be_bytes())?.is_none() {
+            bail!("Global gate {} not found", identifier);
+        }
+        Ok(())
+    }
+
     #[instrument(skip(self))]
     fn get_user(&self, user_id: &u64) -> Result<Vec<SecretString>> {
         debug!("Getting user");
-        let wallet = match self.db.get(user_id.to_be_bytes())? {
+        let wallet = match self.users.get(user_id.to_be_bytes())? {
             Some(wallet) => wallet,
             None => bail!("User {} not found", user_id),
         };
@@ -375,7 +864,7 @@ impl Storage for SledEncryptedStorage {
     #[instrument(skip(self))]
     fn list_users(&self) -> Result<Self::UserIter> {
         debug!("Listing users");
-        Ok(self.db.iter().filter_map(|result| {
+        Ok(self.users.iter().filter_map(|result| {
             if let Ok((user_id, wallet)) = result {
                 if let Ok(user_id) = user_id.to_vec().try_into() {
                     let user_id = u64::from_be_bytes(user_id);
@@ -406,23 +895,805 @@ impl Storage for SledEncryptedStorage {
     fn add_user(&mut self, user_id: u64, wallets: Vec<SecretString>) -> Result<()> {
         debug!("Adding user");
         let encrypted = EncryptionWrapper::new(wallets)?;
-        self.db
+        self.users
             .insert(user_id.to_be_bytes(), bincode::serialize(&encrypted)?)?;
+        clear_last_checked(&self.users, user_id)?;
+        set_tombstone(&self.users, user_id, None)?;
         Ok(())
     }
 
     #[instrument(skip(self))]
     fn contains_user(&self, user_id: &u64) -> bool {
         debug!("Checking if user exists");
-        self.db.contains_key(user_id.to_be_bytes()).unwrap_or(false)
+        self.users
+            .contains_key(user_id.to_be_bytes())
+            .unwrap_or(false)
     }
 
     #[instrument(skip(self))]
-    fn remove_user(&mut self, user_id: &u64) -> Result<()> {
+    fn remove_user(&mut self, user_id: &u64, soft: bool) -> Result<()> {
         debug!("Removing user");
-        self.db.remove(user_id.to_be_bytes())?;
+        let removed = self.users.remove(user_id.to_be_bytes())?.is_some();
+        // a soft deleted user only has their tombstone left, which a hard
+        // removal purges
+        match (removed, get_tombstone(&self.users, *user_id)?) {
+            (false, None) => bail!("user {} does not exist", user_id),
+            (false, Some(_)) if soft => return Ok(()),
+            _ => {}
+        }
+        clear_last_checked(&self.users, *user_id)?;
+        clear_first_failed(&self.users, *user_id)?;
+        set_tombstone(&self.users, *user_id, soft.then(unix_timestamp))?;
         Ok(())
     }
+
+    #[instrument(skip(self))]
+    fn removed_at(&self, user_id: &u64) -> Result<Option<u64>> {
+        get_tombstone(&self.users, *user_id)
+    }
+
+    #[instrument(skip(self))]
+    fn last_checked(&self, guild_id: &u64, user_id: &u64) -> Result<Option<u64>> {
+        get_last_checked(&self.users, guild_id, user_id)
+    }
+
+    #[instrument(skip(self))]
+    fn set_last_checked(&mut self, guild_id: &u64, user_id: &u64, timestamp: u64) -> Result<()> {
+        let tree = self.users.open_tree(LAST_CHECKED_TREE)?;
+        tree.insert(
+            last_checked_key(guild_id, user_id),
+            timestamp.to_be_bytes().to_vec(),
+        )?;
+        Ok(())
+    }
+
+    #[instrument(skip(self))]
+    fn first_failed(&self, guild_id: &u64, user_id: &u64, role_id: &u64) -> Result<Option<u64>> {
+        get_first_failed(&self.users, guild_id, user_id, role_id)
+    }
+
+    #[instrument(skip(self))]
+    fn set_first_failed(
+        &mut self,
+        guild_id: &u64,
+        user_id: &u64,
+        role_id: &u64,
+        timestamp: Option<u64>,
+    ) -> Result<()> {
+        set_first_failed(&self.users, guild_id, user_id, role_id, timestamp)
+    }
+
+    #[instrument(skip(self))]
+    fn registration_stats(&self) -> Result<RegistrationStats> {
+        get_registration_stats(&self.users)
+    }
+
+    #[instrument(skip(self))]
+    fn count_registration(&mut self) -> Result<()> {
+        increment_counter(&self.users, REGISTRATIONS_KEY)
+    }
+
+    #[instrument(skip(self))]
+    fn count_unregistration(&mut self) -> Result<()> {
+        increment_counter(&self.users, UNREGISTRATIONS_KEY)
+    }
+
+    #[instrument(skip(self))]
+    fn migrate_gates(&mut self) -> Result<usize> {
+        migrate_gate_trees(&self.db, self.list_guilds())
+    }
+
+    #[instrument(skip(self))]
+    fn is_frozen(&self, guild_id: &u64) -> Result<bool> {
+        is_frozen(&self.db, guild_id)
+    }
+
+    #[instrument(skip(self))]
+    fn set_frozen(&mut self, guild_id: Option<u64>, frozen: bool) -> Result<()> {
+        set_frozen(&self.db, guild_id, frozen)
+    }
+
+    #[instrument(skip(self))]
+    fn auto_grant(&self, guild_id: &u64) -> Result<bool> {
+        auto_grant(&self.db, guild_id)
+    }
+
+    #[instrument(skip(self))]
+    fn set_auto_grant(&mut self, guild_id: u64, enabled: bool) -> Result<()> {
+        set_auto_grant(&self.db, guild_id, enabled)
+    }
+}
+
+/// The stored layout of a global gate, the gate itself is stored with its
+/// versioned layout
+#[derive(Deserialize, Serialize)]
+struct StoredGlobalGate {
+    role_name: String,
+    gate: Vec<u8>,
+}
+
+fn add_global_gate(db: &Namespaced, gate: GlobalGate) -> Result<()> {
+    let tree = db.open_tree(GLOBAL_GATES_TREE)?;
+    let stored = StoredGlobalGate {
+        role_name: gate.role_name.clone(),
+        gate: gate.gate.to_bytes()?,
+    };
+    tree.insert(
+        gate.identifier().to_be_bytes(),
+        bincode::serialize(&stored)?,
+    )?;
+    Ok(())
+}
+
+fn list_global_gates(db: &Namespaced) -> Result<Vec<GlobalGate>> {
+    let tree = db.open_tree(GLOBAL_GATES_TREE)?;
+    let mut gates = Vec::new();
+    for entry in tree.iter() {
+        let (_, bytes) = entry?;
+        match bincode::deserialize::<StoredGlobalGate>(&bytes)
+            .map_err(anyhow::Error::from)
+            .and_then(|stored| {
+                Ok(GlobalGate {
+                    role_name: stored.role_name,
+                    gate: Gate::from_bytes(&stored.gate)?,
+                })
+            }) {
+            Ok(gate) => gates.push(gate),
+            Err(why) => error!("Failed to deserialize global gate: {}", why),
+        }
+    }
+    Ok(gates)
+}
+
+fn frozen_key(guild_id: Option<u64>) -> Vec<u8> {
+    match guild_id {
+        Some(guild_id) => guild_id.to_be_bytes().to_vec(),
+        None => FROZEN_ALL_KEY.to_vec(),
+    }
+}
+
+fn is_frozen(db: &Namespaced, guild_id: &u64) -> Result<bool> {
+    let tree = db.open_tree(FROZEN_TREE)?;
+    Ok(tree.contains_key(FROZEN_ALL_KEY)? || tree.contains_key(frozen_key(Some(*guild_id)))?)
+}
+
+fn set_frozen(db: &Namespaced, guild_id: Option<u64>, frozen: bool) -> Result<()> {
+    let tree = db.open_tree(FROZEN_TREE)?;
+    if frozen {
+        tree.insert(frozen_key(guild_id), vec![])?;
+    } else {
+        tree.remove(frozen_key(guild_id))?;
+    }
+    Ok(())
+}
+
+fn auto_grant(db: &Namespaced, guild_id: &u64) -> Result<bool> {
+    let tree = db.open_tree(AUTO_GRANT_TREE)?;
+    Ok(tree.contains_key(guild_id.to_be_bytes())?)
+}
+
+fn set_auto_grant(db: &Namespaced, guild_id: u64, enabled: bool) -> Result<()> {
+    let tree = db.open_tree(AUTO_GRANT_TREE)?;
+    if enabled {
+        tree.insert(guild_id.to_be_bytes(), vec![])?;
+    } else {
+        tree.remove(guild_id.to_be_bytes())?;
+    }
+    Ok(())
+}
+
+fn last_checked_key(guild_id: &u64, user_id: &u64) -> Vec<u8> {
+    let mut key = user_id.to_be_bytes().to_vec();
+    key.extend(guild_id.to_be_bytes());
+    key
+}
+
+fn get_last_checked(db: &Namespaced, guild_id: &u64, user_id: &u64) -> Result<Option<u64>> {
+    let tree = db.open_tree(LAST_CHECKED_TREE)?;
+    match tree.get(last_checked_key(guild_id, user_id))? {
+        Some(bytes) => Ok(Some(u64::from_be_bytes(bytes.as_ref().try_into()?))),
+        None => Ok(None),
+    }
+}
+
+/// Forgets when a user was checked, e.g. because the wallet changed and the
+/// next check must not be skipped
+fn clear_last_checked(db: &Namespaced, user_id: u64) -> Result<()> {
+    let tree = db.open_tree(LAST_CHECKED_TREE)?;
+    for entry in tree.scan_prefix(user_id.to_be_bytes()) {
+        let (key, _) = entry?;
+        tree.remove(key)?;
+    }
+    Ok(())
+}
+
+fn first_failed_key(guild_id: &u64, user_id: &u64, role_id: &u64) -> Vec<u8> {
+    let mut key = last_checked_key(guild_id, user_id);
+    key.extend(role_id.to_be_bytes());
+    key
+}
+
+fn get_first_failed(
+    db: &Namespaced,
+    guild_id: &u64,
+    user_id: &u64,
+    role_id: &u64,
+) -> Result<Option<u64>> {
+    let tree = db.open_tree(FIRST_FAILED_TREE)?;
+    match tree.get(first_failed_key(guild_id, user_id, role_id))? {
+        Some(bytes) => Ok(Some(u64::from_be_bytes(bytes.as_ref().try_into()?))),
+        None => Ok(None),
+    }
+}
+
+fn set_first_failed(
+    db: &Namespaced,
+    guild_id: &u64,
+    user_id: &u64,
+    role_id: &u64,
+    timestamp: Option<u64>,
+) -> Result<()> {
+    let tree = db.open_tree(FIRST_FAILED_TREE)?;
+    let key = first_failed_key(guild_id, user_id, role_id);
+    match timestamp {
+        Some(timestamp) => tree.insert(key, timestamp.to_be_bytes().to_vec())?,
+        None => tree.remove(key)?,
+    };
+    Ok(())
+}
+
+/// Forgets since when a removed user fails any gates
+fn clear_first_failed(db: &Namespaced, user_id: u64) -> Result<()> {
+    let tree = db.open_tree(FIRST_FAILED_TREE)?;
+    for entry in tree.scan_prefix(user_id.to_be_bytes()) {
+        let (key, _) = entry?;
+        tree.remove(key)?;
+    }
+    Ok(())
+}
+
+/// Stores the time the user was soft deleted, or removes the tombstone
+/// without a time, e.g. because the user registered again
+fn set_tombstone(db: &Namespaced, user_id: u64, removed_at: Option<u64>) -> Result<()> {
+    let tree = db.open_tree(REMOVED_USERS_TREE)?;
+    match removed_at {
+        Some(timestamp) => tree.insert(user_id.to_be_bytes(), timestamp.to_be_bytes().to_vec())?,
+        None => tree.remove(user_id.to_be_bytes())?,
+    };
+    Ok(())
+}
+
+fn get_tombstone(db: &Namespaced, user_id: u64) -> Result<Option<u64>> {
+    let tree = db.open_tree(REMOVED_USERS_TREE)?;
+    match tree.get(user_id.to_be_bytes())? {
+        Some(bytes) => Ok(Some(u64::from_be_bytes(bytes.as_ref().try_into()?))),
+        None => Ok(None),
+    }
+}
+
+/// Increments a counter of the stats tree atomically, a missing counter
+/// starts at zero
+fn increment_counter(db: &Namespaced, key: &[u8]) -> Result<()> {
+    let tree = db.open_tree(STATS_TREE)?;
+    tree.update_and_fetch(key, |count| {
+        let count = count
+            .and_then(|bytes| bytes.try_into().ok())
+            .map(u64::from_be_bytes)
+            .unwrap_or(0);
+        Some((count + 1).to_be_bytes().to_vec())
+    })?;
+    Ok(())
+}
+
+fn get_counter(tree: &sled::Tree, key: &[u8]) -> Result<u64> {
+    match tree.get(key)? {
+        Some(bytes) => Ok(u64::from_be_bytes(bytes.as_ref().try_into()?)),
+        None => Ok(0),
+    }
+}
+
+fn get_registration_stats(db: &Namespaced) -> Result<RegistrationStats> {
+    let tree = db.open_tree(STATS_TREE)?;
+    Ok(RegistrationStats {
+        registrations: get_counter(&tree, REGISTRATIONS_KEY)?,
+        unregistrations: get_counter(&tree, UNREGISTRATIONS_KEY)?,
+    })
+}
+
+fn guild_tree_name(guild_id: u64) -> String {
+    format!("{}{}", GUILD_TREE_PREFIX, guild_id)
+}
+
+/// The guild id of a guild tree, other trees are skipped silently
+fn guild_id_from_tree_name(tree_name: &[u8]) -> Option<u64> {
+    let guild_id = tree_name.strip_prefix(GUILD_TREE_PREFIX.as_bytes())?;
+    match std::str::from_utf8(guild_id).map(str::parse) {
+        Ok(Ok(guild_id)) => Some(guild_id),
+        _ => {
+            error!(?tree_name, "Failed to parse guild id from tree name");
+            None
+        }
+    }
+}
+
+/// Opens the database and the user database, which share the same sled
+/// database if both paths are the same. The migrations and the version check
+/// apply to the main database holding the gates
+fn open_databases(
+    db_path: &Path,
+    user_db_path: &Path,
+    namespace: &str,
+) -> (Namespaced, Namespaced) {
+    let db = sled::open(db_path).expect("Failed to open database");
+    let db = Namespaced::new(db, namespace).expect("Failed to open the storage namespace");
+    prepare_database(&db, env!("CARGO_PKG_VERSION"), force())
+        .expect("Refusing to open the database");
+    let users = if user_db_path == db_path {
+        db.clone()
+    } else {
+        check_user_directory(&db, user_db_path).expect("Refusing to open the database");
+        let users = sled::open(user_db_path).expect("Failed to open user database");
+        Namespaced::new(users, namespace).expect("Failed to open the storage namespace")
+    };
+    (db, users)
+}
+
+/// Fails if the gate database still holds users while they are expected in
+/// a separate directory, those users would be hidden and count as
+/// unregistered
+fn check_user_directory(db: &Namespaced, user_db_path: &Path) -> Result<()> {
+    for entry in db.iter() {
+        let (key, _) = entry?;
+        if key.len() == 8 {
+            bail!(
+                "The gate database still holds registered users, but users are \
+                 configured to be stored in {}. Move the users there or unset the \
+                 user directory",
+                user_db_path.display()
+            );
+        }
+    }
+    Ok(())
+}
+
+/// The configured storage namespace, empty if the config is not set up,
+/// e.g. in tests
+fn namespace() -> String {
+    CONFIG
+        .get()
+        .map(|cfg| cfg.storage.namespace.clone())
+        .unwrap_or_default()
+}
+
+/// A sled database seen through a storage namespace. The trees are opened
+/// with the namespace in front of their names and the default tree is
+/// replaced by a tree of the namespace, so bots sharing a directory don't see
+/// each other's data. The empty namespace is the database as it is, without
+/// the trees of the namespaces
+#[derive(Clone, Debug)]
+struct Namespaced {
+    db: sled::Db,
+    /// Put in front of the tree names, empty without a namespace
+    prefix: Vec<u8>,
+    default: sled::Tree,
+}
+
+impl Namespaced {
+    fn new(db: sled::Db, namespace: &str) -> sled::Result<Self> {
+        if namespace.is_empty() {
+            let default = sled::Tree::clone(&db);
+            return Ok(Self {
+                db,
+                prefix: Vec::new(),
+                default,
+            });
+        }
+        let prefix = [NAMESPACE_PREFIX, namespace.as_bytes(), b"/"].concat();
+        let default = db.open_tree([prefix.as_slice(), DEFAULT_TREE].concat())?;
+        Ok(Self {
+            db,
+            prefix,
+            default,
+        })
+    }
+
+    fn tree_name(&self, name: &[u8]) -> Vec<u8> {
+        [self.prefix.as_slice(), name].concat()
+    }
+
+    fn open_tree<V: AsRef<[u8]>>(&self, name: V) -> sled::Result<sled::Tree> {
+        self.db.open_tree(self.tree_name(name.as_ref()))
+    }
+
+    fn drop_tree<V: AsRef<[u8]>>(&self, name: V) -> sled::Result<bool> {
+        self.db.drop_tree(self.tree_name(name.as_ref()))
+    }
+
+    /// The names of the trees of the namespace, without the namespace
+    fn tree_names(&self) -> Vec<IVec> {
+        self.db
+            .tree_names()
+            .into_iter()
+            .filter_map(|name| {
+                if self.prefix.is_empty() {
+                    (!name.starts_with(NAMESPACE_PREFIX)).then_some(name)
+                } else {
+                    name.strip_prefix(self.prefix.as_slice()).map(IVec::from)
+                }
+            })
+            .collect()
+    }
+}
+
+/// The default tree of the namespace, which holds the users
+impl std::ops::Deref for Namespaced {
+    type Target = sled::Tree;
+
+    fn deref(&self) -> &sled::Tree {
+        &self.default
+    }
+}
+
+/// Whether the database may be opened even if it was written by a newer
+/// version, false if the config is not set up, e.g. in tests
+fn force() -> bool {
+    CONFIG.get().is_some_and(|cfg| cfg.force)
+}
+
+/// Checks the stored version before migrating the guild trees, so a database
+/// written by a newer version is refused before anything in it is changed
+fn prepare_database(db: &Namespaced, version: &str, force: bool) -> Result<()> {
+    check_stored_version(&db.db, version, force)?;
+    migrate_guild_tree_names(db).map_err(|why| why.context("Failed to migrate guild trees"))
+}
+
+/// Compares the version stored in the database with the running one. If the
+/// database was written by a newer version, it may hold data this version
+/// can not decode and silently drops, so this fails unless forced. Otherwise
+/// the running version is stored
+fn check_stored_version(db: &sled::Db, version: &str, force: bool) -> Result<()> {
+    let tree = db.open_tree(VERSION_TREE)?;
+    if let Some(stored) = tree.get(VERSION_KEY)? {
+        let stored = String::from_utf8_lossy(&stored).to_string();
+        if is_newer_version(&stored, version) {
+            if !force {
+                bail!(
+                    "The database was written by version {} of the bot, which is newer \
+                     than the running version {}. Start with --force to use it anyway, \
+                     data this version can not decode may get lost",
+                    stored,
+                    version
+                );
+            }
+            warn!(
+                %stored,
+                version,
+                "The database was written by a newer version of the bot, \
+                 data this version can not decode may get lost"
+            );
+            // keep the newer version, so the next start warns as well
+            return Ok(());
+        }
+    }
+    tree.insert(VERSION_KEY, version.as_bytes())?;
+    tree.flush()?;
+    Ok(())
+}
+
+/// Compares `major.minor.patch` versions, pre-release and build suffixes are
+/// ignored. Unparsable versions are never considered newer
+fn is_newer_version(version: &str, than: &str) -> bool {
+    let parse = |version: &str| -> Option<Vec<u64>> {
+        version
+            .split(['-', '+'])
+            .next()?
+            .split('.')
+            .map(|part| part.parse().ok())
+            .collect()
+    };
+    match (parse(version), parse(than)) {
+        (Some(version), Some(than)) => version > than,
+        _ => false,
+    }
+}
+
+/// Moves the gates of guild trees that are still named by the big endian
+/// bytes of the guild id to the prefixed trees. The old tree is only dropped
+/// after its gates have been copied, so an interrupted migration is picked up
+/// again on the next start. Once all trees are moved, this is recorded in the
+/// version tree and the migration is not run again. Other trees with eight
+/// byte names, e.g. `settings`, are only moved if all their entries are gates
+fn migrate_guild_tree_names(db: &Namespaced) -> Result<()> {
+    let version_tree = db.open_tree(VERSION_TREE)?;
+    if version_tree.contains_key(GUILD_TREES_MIGRATED_KEY)? {
+        return Ok(());
+    }
+    for tree_name in db.tree_names() {
+        // a short guild id with the prefix has eight bytes as well
+        if tree_name.starts_with(GUILD_TREE_PREFIX.as_bytes()) {
+            continue;
+        }
+        let guild_id = match <[u8; 8]>::try_from(tree_name.as_ref()) {
+            Ok(bytes) => u64::from_be_bytes(bytes),
+            Err(_) => continue,
+        };
+        let old_tree = db.open_tree(&tree_name)?;
+        if !holds_only_gates(&old_tree)? {
+            debug!(?tree_name, "Skipping tree that does not hold gates");
+            continue;
+        }
+        info!(guild_id, "Migrating guild tree to prefixed name");
+        let new_tree = db.open_tree(guild_tree_name(guild_id))?;
+        for entry in old_tree.iter() {
+            let (key, value) = entry?;
+            new_tree.insert(key, value)?;
+        }
+        new_tree.flush()?;
+        db.drop_tree(&tree_name)?;
+    }
+    version_tree.insert(GUILD_TREES_MIGRATED_KEY, &[])?;
+    version_tree.flush()?;
+    Ok(())
+}
+
+/// Whether a tree is a non empty legacy guild tree, whose entries are all
+/// gates stored under their identifier
+fn holds_only_gates(tree: &sled::Tree) -> Result<bool> {
+    if tree.is_empty() {
+        return Ok(false);
+    }
+    for entry in tree.iter() {
+        let (key, value) = entry?;
+        if key.len() != std::mem::size_of::<u128>() || Gate::from_bytes(&value).is_err() {
+            return Ok(false);
+        }
+    }
+    Ok(true)
+}
+
+/// Rewrites the gates of all guild trees that are not stored in the current
+/// layout. Entries that can't be decoded are left untouched and logged
+fn migrate_gate_trees(db: &Namespaced, guilds: impl Iterator<Item = u64>) -> Result<usize> {
+    debug!("Migrating gates");
+    let mut migrated = 0;
+    for guild_id in guilds {
+        let tree = db.open_tree(guild_tree_name(guild_id))?;
+        for entry in tree.iter() {
+            let (key, gate_bytes) = entry?;
+            if Gate::is_current(&gate_bytes) {
+                continue;
+            }
+            match Gate::from_bytes(&gate_bytes) {
+                Ok(gate) => {
+                    // gates from before the chain id are read with the default
+                    // chain, which is hashed into the identifier, so they are
+                    // moved to their new key
+                    let identifier = gate.identifier().to_be_bytes();
+                    if key.as_ref() != identifier {
+                        tree.remove(&key)?;
+                    }
+                    tree.insert(identifier, gate.to_bytes()?)?;
+                    migrated += 1;
+                }
+                Err(why) => error!(guild_id, "Failed to migrate gate: {}", why),
+            }
+        }
+    }
+    db.flush()?;
+    Ok(migrated)
+}
+
+/// The number of readable and unreadable records found by [`verify`]
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct VerifyReport {
+    /// The gates of all guilds and the global gates
+    pub gates: usize,
+    pub unreadable_gates: usize,
+    pub users: usize,
+    pub unreadable_users: usize,
+}
+
+impl VerifyReport {
+    pub fn is_ok(&self) -> bool {
+        self.unreadable_gates == 0 && self.unreadable_users == 0
+    }
+}
+
+/// Checks whether this version can read all gates and users of the
+/// databases. Sled can't open a database read only, so this works on a copy,
+/// which gets the same migrations a start of this version would apply, and
+/// leaves the databases untouched. The wallets of encrypted users are
+/// decrypted with the configured keys as well
+#[instrument]
+pub fn verify(db_path: &Path, user_db_path: &Path, encrypted: bool) -> Result<VerifyReport> {
+    debug!("Verifying databases");
+    if !db_path.exists() {
+        bail!("There is no database at {:?}", db_path);
+    }
+    let copy =
+        std::env::temp_dir().join(format!("discord-gating-bot-verify-{}", std::process::id()));
+    let _ = fs::remove_dir_all(&copy);
+    let report = copy_dir(db_path, &copy.join("db")).and_then(|_| {
+        let user_copy = if user_db_path == db_path {
+            copy.join("db")
+        } else {
+            copy_dir(user_db_path, &copy.join("users"))?;
+            copy.join("users")
+        };
+        verify_copy(&copy.join("db"), &user_copy, encrypted)
+    });
+    fs::remove_dir_all(&copy)?;
+    report
+}
+
+fn verify_copy(db_path: &Path, user_db_path: &Path, encrypted: bool) -> Result<VerifyReport> {
+    let db = Namespaced::new(sled::open(db_path)?, &namespace())?;
+    prepare_database(&db, env!("CARGO_PKG_VERSION"), force())?;
+    let users = if user_db_path == db_path {
+        db.clone()
+    } else {
+        Namespaced::new(sled::open(user_db_path)?, &namespace())?
+    };
+    let mut report = VerifyReport::default();
+    for guild_id in db
+        .tree_names()
+        .iter()
+        .filter_map(|tree_name| guild_id_from_tree_name(tree_name))
+    {
+        for entry in db.open_tree(guild_tree_name(guild_id))?.iter() {
+            let (_, gate_bytes) = entry?;
+            match Gate::from_bytes(&gate_bytes) {
+                Ok(_) => report.gates += 1,
+                Err(why) => {
+                    warn!(guild_id, "Unreadable gate: {:?}", why);
+                    report.unreadable_gates += 1;
+                }
+            }
+        }
+    }
+    for entry in db.open_tree(GLOBAL_GATES_TREE)?.iter() {
+        let (_, bytes) = entry?;
+        match bincode::deserialize::<StoredGlobalGate>(&bytes)
+            .map_err(anyhow::Error::from)
+            .and_then(|stored| Gate::from_bytes(&stored.gate))
+        {
+            Ok(_) => report.gates += 1,
+            Err(why) => {
+                warn!("Unreadable global gate: {:?}", why);
+                report.unreadable_gates += 1;
+            }
+        }
+    }
+    for entry in users.iter() {
+        let (user_id, wallets) = entry?;
+        let readable = <[u8; 8]>::try_from(user_id.as_ref())
+            .map_err(anyhow::Error::from)
+            .and_then(|_| {
+                if encrypted {
+                    bincode::deserialize::<EncryptionWrapper>(&wallets)?.decrypt()
+                } else {
+                    Ok(bincode::deserialize::<Vec<SecretString>>(&wallets)?)
+                }
+            });
+        match readable {
+            Ok(_) => report.users += 1,
+            Err(why) => {
+                warn!(?user_id, "Unreadable user: {:?}", why);
+                report.unreadable_users += 1;
+            }
+        }
+    }
+    Ok(report)
+}
+
+/// A line of an imported user file that was not stored
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ImportError {
+    /// The line of the entry, starting at 1
+    pub line: usize,
+    pub reason: String,
+}
+
+/// The outcome of importing users from a file
+#[derive(Debug, Default, PartialEq, Eq)]
+pub struct UserImport {
+    pub imported: usize,
+    /// Users that are registered already and were left as they are
+    pub existing: usize,
+    pub errors: Vec<ImportError>,
+}
+
+/// Imports users from a CSV file with a `user_id,wallet` line per user, e.g.
+/// exported from another gating bot. A header line is skipped. Invalid
+/// lines are reported with their line number and skipped, so a single typo
+/// doesn't fail a large import. Registered users are only replaced with
+/// `overwrite`. Imports are not counted as registrations
+pub fn import_users<S: Storage>(storage: &mut S, content: &str, overwrite: bool) -> UserImport {
+    let mut import = UserImport::default();
+    for (index, line) in content.lines().enumerate() {
+        let fields = line
+            .split(',')
+            .map(|value| value.trim().trim_matches('"').trim())
+            .collect::<Vec<_>>();
+        if fields.iter().all(|value| value.is_empty()) {
+            continue;
+        }
+        let error = |reason: String| ImportError {
+            line: index + 1,
+            reason,
+        };
+        let (user_id, wallet) = match fields.as_slice() {
+            [user_id, wallet] => (*user_id, *wallet),
+            _ => {
+                import
+                    .errors
+                    .push(error("Expected user_id,wallet".to_string()));
+                continue;
+            }
+        };
+        let user_id = match user_id.parse::<u64>() {
+            Ok(user_id) if user_id > 0 => user_id,
+            _ if index == 0 => {
+                debug!("Skipping the header line");
+                continue;
+            }
+            _ => {
+                import
+                    .errors
+                    .push(error(format!("Invalid user id {:?}", user_id)));
+                continue;
+            }
+        };
+        let wallet = match H160::from_str(wallet) {
+            Ok(wallet) => wallet,
+            Err(_) => {
+                import
+                    .errors
+                    .push(error(format!("Invalid wallet address {:?}", wallet)));
+                continue;
+            }
+        };
+        if !overwrite && storage.contains_user(&user_id) {
+            debug!(user_id, "User is registered already, skipping");
+            import.existing += 1;
+            continue;
+        }
+        match storage.add_user(user_id, vec![SecretString::new(format!("{:?}", wallet))]) {
+            Ok(()) => import.imported += 1,
+            Err(why) => import.errors.push(error(format!(
+                "Failed to store user {}: {:?}",
+                user_id, why
+            ))),
+        }
+    }
+    import
+}
+
+/// Copies the sled database directory to a new location. Sled only holds an
+/// advisory lock on the database, so this works while the bot is running.
+/// Sled flushes to disk periodically, so the most recent writes of a running
+/// bot might be missing from the copy.
+#[instrument]
+pub fn snapshot(source: &Path, target: &Path) -> Result<()> {
+    debug!("Creating snapshot");
+    if target.exists() {
+        bail!("Snapshot target {:?} already exists", target);
+    }
+    copy_dir(source, target)
+}
+
+fn copy_dir(source: &Path, target: &Path) -> Result<()> {
+    fs::create_dir_all(target)?;
+    for entry in fs::read_dir(source)? {
+        let entry = entry?;
+        let target = target.join(entry.file_name());
+        if entry.file_type()?.is_dir() {
+            copy_dir(&entry.path(), &target)?;
+        } else {
+            fs::copy(entry.path(), target)?;
+        }
+    }
+    Ok(())
 }
 
 /// A convinience wrapper around the stored user wallet addresses, that
@@ -434,13 +1705,15 @@ struct EncryptionWrapper {
 }
 
 impl EncryptionWrapper {
+    /// Encrypts the wallets with the current key
     #[instrument(skip(plaintexts))]
     fn new(plaintexts: Vec<SecretString>) -> Result<Self> {
+        Self::encrypt(plaintexts, &CONFIG.wait().storage.key)
+    }
+
+    fn encrypt(plaintexts: Vec<SecretString>, key: &SecretString) -> Result<Self> {
         debug!("Encrypting wallet");
-        let key_hex = &CONFIG.wait().storage.key.expose_secret();
-        let key_bytes = hex::decode(key_hex)?;
-        let key = GenericArray::from_slice(&key_bytes);
-        let cipher = ChaCha20Poly1305::new(key);
+        let cipher = cipher(key)?;
         let nonce = ChaCha20Poly1305::generate_nonce(&mut OsRng);
         let plain: Vec<String> = plaintexts
             .iter()
@@ -460,19 +1733,627 @@ impl EncryptionWrapper {
         })
     }
 
+    /// Decrypts the wallets with the current key or, if that fails, with
+    /// one of the legacy keys. Data decrypted with a legacy key is encrypted
+    /// with the current key the next time it is written
     #[instrument(skip(self))]
     fn decrypt(&self) -> Result<Vec<SecretString>> {
+        let storage = &CONFIG.wait().storage;
+        self.decrypt_with(std::iter::once(&storage.key).chain(storage.legacy_keys.iter()))
+    }
+
+    fn decrypt_with<'a>(
+        &self,
+        keys: impl IntoIterator<Item = &'a SecretString>,
+    ) -> Result<Vec<SecretString>> {
         debug!("Decrypting wallet");
-        let key_hex = &CONFIG.wait().storage.key.expose_secret();
-        let key_bytes = hex::decode(key_hex)?;
-        let key = GenericArray::from_slice(&key_bytes);
-        let cipher = ChaCha20Poly1305::new(key);
         let nonce = GenericArray::from_slice(&self.nonce);
         debug!(?nonce, "Using nonce");
-        let decrypted = cipher
-            .decrypt(nonce, self.ciphertext.as_ref())
-            .map_err(|e| anyhow!("{e}"))?;
-        Ok(bincode::deserialize::<Vec<SecretString>>(&decrypted)?)
-        // Ok(String::from_utf8(plaintext)?.into())
+        let mut error = anyhow!("No encryption key configured");
+        for (index, key) in keys.into_iter().enumerate() {
+            let decrypted = cipher(key).and_then(|cipher| {
+                cipher
+                    .decrypt(nonce, self.ciphertext.as_ref())
+                    .map_err(|e| anyhow!("{e}"))
+            });
+            match decrypted {
+                Ok(decrypted) => {
+                    if index > 0 {
+                        debug!(index, "Decrypted wallet with legacy key");
+                    }
+                    return Ok(bincode::deserialize::<Vec<SecretString>>(&decrypted)?);
+                }
+                Err(why) => error = why,
+            }
+        }
+        Err(error.context("Failed to decrypt wallet with any key"))
+    }
+}
+
+/// Creates the cipher from a hex encoded key
+fn cipher(key_hex: &SecretString) -> Result<ChaCha20Poly1305> {
+    let key_bytes = hex::decode(key_hex.expose_secret())?;
+    if key_bytes.len() != 32 {
+        bail!("The encryption key must be 32 bytes long");
+    }
+    Ok(ChaCha20Poly1305::new(GenericArray::from_slice(&key_bytes)))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::gate::{AllowlistGate, GateOptionValueType};
+    use colony_rs::H160;
+    use std::path::PathBuf;
+
+    fn temp_dir(name: &str) -> PathBuf {
+        let dir = std::env::temp_dir().join(format!(
+            "discord-gating-bot-{}-{}",
+            name,
+            std::process::id()
+        ));
+        let _ = fs::remove_dir_all(&dir);
+        dir
+    }
+
+    fn allowlist_gate(role_id: u64) -> Gate {
+        Gate {
+            role_id,
+            condition: Box::new(AllowlistGate {
+                wallets: vec![H160::zero()],
+            }),
+            enabled: true,
+            grant_message: None,
+        }
+    }
+
+    #[test]
+    fn test_migrate_unversioned_gates() {
+        let dir = temp_dir("migrate");
+        let mut storage = SledUnencryptedStorage::open(&dir);
+        let gate = Gate {
+            role_id: 3,
+            condition: Box::new(AllowlistGate {
+                wallets: vec![H160::zero()],
+            }),
+            enabled: false,
+            grant_message: None,
+        };
+        let tree = storage.db.open_tree(guild_tree_name(2)).unwrap();
+        tree.insert(
+            gate.identifier().to_be_bytes(),
+            bincode::serialize(&gate).unwrap(),
+        )
+        .unwrap();
+        assert_eq!(storage.migrate_gates().unwrap(), 1);
+        assert_eq!(storage.migrate_gates().unwrap(), 0);
+
+        let (_, gate_bytes) = tree.iter().next().unwrap().unwrap();
+        assert!(Gate::is_current(&gate_bytes));
+        let gates = storage.list_gates(&2).unwrap().collect::<Vec<_>>();
+        assert_eq!(gates.len(), 1);
+        assert_eq!(gates[0].identifier(), gate.identifier());
+        assert!(!gates[0].enabled);
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_migrate_pre_multichain_gates() {
+        #[derive(Serialize)]
+        struct PreMultichainTokenGateRef {
+            token_address: H160,
+            token_symbol: String,
+            token_decimals: u8,
+            amount: u64,
+        }
+        let dir = temp_dir("migrate-multichain");
+        let mut storage = SledUnencryptedStorage::open(&dir);
+        let condition = HashMap::from([(
+            "TokenGate",
+            PreMultichainTokenGateRef {
+                token_address: H160::zero(),
+                token_symbol: "CLNY".to_string(),
+                token_decimals: 18,
+                amount: 1,
+            },
+        )]);
+        let tree = storage.db.open_tree(guild_tree_name(2)).unwrap();
+        tree.insert(
+            42u64.to_be_bytes(),
+            bincode::serialize(&(3u64, condition)).unwrap(),
+        )
+        .unwrap();
+        assert_eq!(storage.migrate_gates().unwrap(), 1);
+        assert_eq!(storage.migrate_gates().unwrap(), 0);
+
+        let gates = storage.list_gates(&2).unwrap().collect::<Vec<_>>();
+        assert_eq!(gates.len(), 1);
+        assert!(matches!(
+            &gates[0].fields()[0].value,
+            GateOptionValueType::String(chain_id) if chain_id == "0x64"
+        ));
+        assert!(tree.get(42u64.to_be_bytes()).unwrap().is_none());
+        let gate_bytes = tree
+            .get(gates[0].identifier().to_be_bytes())
+            .unwrap()
+            .unwrap();
+        assert!(Gate::is_current(&gate_bytes));
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_last_checked_is_cleared_with_new_wallet() {
+        let dir = temp_dir("last-checked");
+        let mut storage = SledUnencryptedStorage::open(&dir);
+        let wallet = SecretString::new(format!("{:?}", H160::zero()));
+        storage.add_user(1, vec![wallet.clone()]).unwrap();
+        storage.set_last_checked(&2, &1, 1000).unwrap();
+        storage.set_last_checked(&3, &1, 2000).unwrap();
+        assert_eq!(storage.last_checked(&2, &1).unwrap(), Some(1000));
+        assert_eq!(storage.last_checked(&3, &1).unwrap(), Some(2000));
+        assert_eq!(storage.list_guilds().count(), 0);
+
+        storage.add_user(1, vec![wallet]).unwrap();
+        assert_eq!(storage.last_checked(&2, &1).unwrap(), None);
+        assert_eq!(storage.last_checked(&3, &1).unwrap(), None);
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_first_failed_is_cleared_with_removed_user() {
+        let dir = temp_dir("first-failed");
+        let mut storage = SledUnencryptedStorage::open(&dir);
+        let wallet = SecretString::new(format!("{:?}", H160::zero()));
+        storage.add_user(1, vec![wallet]).unwrap();
+        storage.set_first_failed(&2, &1, &10, Some(1000)).unwrap();
+        storage.set_first_failed(&2, &1, &11, Some(2000)).unwrap();
+        assert_eq!(storage.first_failed(&2, &1, &10).unwrap(), Some(1000));
+        assert_eq!(storage.first_failed(&2, &1, &11).unwrap(), Some(2000));
+
+        storage.set_first_failed(&2, &1, &10, None).unwrap();
+        assert_eq!(storage.first_failed(&2, &1, &10).unwrap(), None);
+        assert_eq!(storage.first_failed(&2, &1, &11).unwrap(), Some(2000));
+
+        storage.remove_user(&1, false).unwrap();
+        assert_eq!(storage.first_failed(&2, &1, &11).unwrap(), None);
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_non_guild_trees_are_not_listed() {
+        let dir = temp_dir("non-guild-trees");
+        let mut storage = SledUnencryptedStorage::open(&dir);
+        storage
+            .add_gate(&1234, allowlist_gate(1))
+            .expect("Failed to add gate");
+        storage.db.open_tree("audit").unwrap();
+        storage.db.open_tree("settings").unwrap();
+        storage.set_last_checked(&1234, &1, 100).unwrap();
+        assert_eq!(storage.list_guilds().collect::<Vec<_>>(), vec![1234]);
+        assert_eq!(guild_id_from_tree_name(b"__sled__default"), None);
+        assert_eq!(guild_id_from_tree_name(b"g:1234"), Some(1234));
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_migrate_guild_tree_names() {
+        let dir = temp_dir("migrate-tree-names");
+        let gate = allowlist_gate(1);
+        let identifier = gate.identifier();
+        {
+            let db = sled::open(&dir).unwrap();
+            let legacy = db.open_tree(1234u64.to_be_bytes()).unwrap();
+            legacy
+                .insert(identifier.to_be_bytes(), gate.to_bytes().unwrap())
+                .unwrap();
+            // an eight byte name that does not hold gates is left alone
+            let settings = db.open_tree("settings").unwrap();
+            settings.insert("color", "red").unwrap();
+            db.flush().unwrap();
+        }
+        let storage = SledUnencryptedStorage::open(&dir);
+        assert_eq!(storage.list_guilds().collect::<Vec<_>>(), vec![1234]);
+        let gates = storage.list_gates(&1234).unwrap().collect::<Vec<_>>();
+        assert_eq!(gates.len(), 1);
+        assert_eq!(gates[0].identifier(), identifier);
+        let tree_names = storage.db.tree_names();
+        assert!(!tree_names.contains(&IVec::from(&1234u64.to_be_bytes()[..])));
+        assert!(tree_names.contains(&IVec::from("settings")));
+
+        // the migration only runs once, later trees are not moved
+        let later = storage.db.open_tree(5678u64.to_be_bytes()).unwrap();
+        later
+            .insert(identifier.to_be_bytes(), gate.to_bytes().unwrap())
+            .unwrap();
+        migrate_guild_tree_names(&storage.db).unwrap();
+        assert!(storage
+            .db
+            .tree_names()
+            .contains(&IVec::from(&5678u64.to_be_bytes()[..])));
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_global_gates_are_not_guilds() {
+        let dir = temp_dir("global-gates");
+        let mut storage = SledUnencryptedStorage::open(&dir);
+        let gate = GlobalGate::new(
+            "Holder".to_string(),
+            Box::new(AllowlistGate {
+                wallets: vec![H160::zero()],
+            }),
+        );
+        let identifier = gate.identifier();
+        storage.add_global_gate(gate).unwrap();
+        assert_eq!(storage.list_guilds().count(), 0);
+        let gates = storage.list_global_gates().unwrap();
+        assert_eq!(gates.len(), 1);
+        assert_eq!(gates[0].role_name, "Holder");
+        assert_eq!(gates[0].identifier(), identifier);
+
+        storage.remove_global_gate(identifier).unwrap();
+        assert!(storage.list_global_gates().unwrap().is_empty());
+        // a mistyped identifier is reported
+        assert!(storage.remove_global_gate(identifier).is_err());
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_namespaces_do_not_share_data() {
+        let dir = temp_dir("namespaces");
+        {
+            let mut storage = SledUnencryptedStorage::open_in_namespace(&dir, &dir, "first");
+            storage.add_gate(&1234, allowlist_gate(1)).unwrap();
+            storage
+                .add_user(1, vec![SecretString::new("wallet".to_string())])
+                .unwrap();
+        }
+        {
+            let mut storage = SledUnencryptedStorage::open_in_namespace(&dir, &dir, "second");
+            assert_eq!(storage.list_guilds().count(), 0);
+            assert_eq!(storage.list_gates(&1234).unwrap().count(), 0);
+            assert!(!storage.contains_user(&1));
+            storage.add_gate(&1234, allowlist_gate(2)).unwrap();
+        }
+        let storage = SledUnencryptedStorage::open_in_namespace(&dir, &dir, "first");
+        assert_eq!(storage.list_guilds().collect::<Vec<_>>(), vec![1234]);
+        let gates = storage.list_gates(&1234).unwrap().collect::<Vec<_>>();
+        assert_eq!(gates.len(), 1);
+        assert_eq!(gates[0].role_id, 1);
+        assert!(storage.contains_user(&1));
+        drop(storage);
+        // the data stored without a namespace doesn't include the namespaces
+        let storage = SledUnencryptedStorage::open(&dir);
+        assert_eq!(storage.list_guilds().count(), 0);
+        assert_eq!(storage.list_users().unwrap().count(), 0);
+        drop(storage);
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_list_from_snapshot() {
+        let source = temp_dir("source");
+        let target = temp_dir("snapshot");
+        let mut storage = SledUnencryptedStorage::open(&source);
+        let wallet = format!("{:?}", H160::zero());
+        storage
+            .add_user(1, vec![SecretString::new(wallet.clone())])
+            .unwrap();
+        let gate = Gate {
+            role_id: 3,
+            condition: Box::new(AllowlistGate {
+                wallets: vec![H160::zero()],
+            }),
+            enabled: true,
+            grant_message: None,
+        };
+        storage.add_gate(&2, gate.clone()).unwrap();
+        storage.db.flush().unwrap();
+
+        // the source database is still open and locked at this point
+        snapshot(&source, &target).unwrap();
+        let copy = SledUnencryptedStorage::open(&target);
+        let users = copy.list_users().unwrap().collect::<Vec<_>>();
+        assert_eq!(users.len(), 1);
+        assert_eq!(users[0].0, 1);
+        assert_eq!(users[0].1[0].expose_secret(), &wallet);
+        assert_eq!(copy.list_guilds().collect::<Vec<_>>(), vec![2]);
+        let gates = copy.list_gates(&2).unwrap().collect::<Vec<_>>();
+        assert_eq!(gates, vec![gate]);
+        assert!(snapshot(&source, &target).is_err());
+
+        drop(copy);
+        drop(storage);
+        fs::remove_dir_all(source).unwrap();
+        fs::remove_dir_all(target).unwrap();
+    }
+
+    #[test]
+    fn test_decrypt_with_legacy_key() {
+        let key = |byte: u8| SecretString::new(hex::encode([byte; 32]));
+        let wallet = format!("{:?}", H160::repeat_byte(1));
+        let encrypted =
+            EncryptionWrapper::encrypt(vec![SecretString::new(wallet.clone())], &key(1)).unwrap();
+
+        let decrypted = encrypted.decrypt_with([&key(2), &key(1)]).unwrap();
+        assert_eq!(decrypted[0].expose_secret(), &wallet);
+        assert!(encrypted.decrypt_with([&key(2)]).is_err());
+        assert!(encrypted.decrypt_with([&key(2), &key(3)]).is_err());
+        // a malformed legacy key does not prevent trying the others
+        let malformed = SecretString::new("garbage".to_string());
+        let decrypted = encrypted.decrypt_with([&malformed, &key(1)]).unwrap();
+        assert_eq!(decrypted[0].expose_secret(), &wallet);
+    }
+
+    #[test]
+    fn test_newer_stored_version_refuses_to_open() {
+        let dir = temp_dir("version");
+        let db = sled::open(&dir).unwrap();
+        check_stored_version(&db, "0.2.0", false).unwrap();
+        let stored = || {
+            db.open_tree(VERSION_TREE)
+                .unwrap()
+                .get(VERSION_KEY)
+                .unwrap()
+        };
+        assert_eq!(stored().unwrap().as_ref(), b"0.2.0");
+
+        // a downgrade is refused unless forced and keeps the newer version
+        assert!(check_stored_version(&db, "0.1.0", false).is_err());
+        check_stored_version(&db, "0.1.0", true).unwrap();
+        assert_eq!(stored().unwrap().as_ref(), b"0.2.0");
+        // an upgrade updates the stored version
+        check_stored_version(&db, "0.10.0", false).unwrap();
+        assert_eq!(stored().unwrap().as_ref(), b"0.10.0");
+
+        assert!(is_newer_version("1.0.0", "0.9.9"));
+        assert!(!is_newer_version("0.2.0-rc.1", "0.2.0"));
+        assert!(!is_newer_version("garbage", "0.1.0"));
+        drop(db);
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_newer_database_is_not_migrated() {
+        let dir = temp_dir("newer-version");
+        let gate = allowlist_gate(1);
+        let db = Namespaced::new(sled::open(&dir).unwrap(), "").unwrap();
+        let legacy = db.open_tree(1234u64.to_be_bytes()).unwrap();
+        legacy
+            .insert(gate.identifier().to_be_bytes(), gate.to_bytes().unwrap())
+            .unwrap();
+        check_stored_version(&db.db, "0.2.0", false).unwrap();
+
+        assert!(prepare_database(&db, "0.1.0", false).is_err());
+        let tree_names = db.tree_names();
+        assert!(tree_names.contains(&IVec::from(&1234u64.to_be_bytes()[..])));
+        assert!(!tree_names.contains(&IVec::from(guild_tree_name(1234).as_bytes())));
+        assert!(!db
+            .open_tree(VERSION_TREE)
+            .unwrap()
+            .contains_key(GUILD_TREES_MIGRATED_KEY)
+            .unwrap());
+        drop(db);
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_soft_deleted_user_is_tombstoned() {
+        let dir = temp_dir("tombstone");
+        let mut storage = SledEncryptedStorage::open(&dir);
+        let wallet = SecretString::new(format!("{:?}", H160::zero()));
+        storage.add_user(1, vec![wallet.clone()]).unwrap();
+        storage.add_user(2, vec![wallet.clone()]).unwrap();
+
+        storage.remove_user(&1, true).unwrap();
+        assert!(!storage.contains_user(&1));
+        assert!(storage.get_user(&1).is_err());
+        assert!(storage.was_registered(&1));
+        assert!(storage.removed_at(&1).unwrap().is_some());
+        assert_eq!(
+            storage
+                .list_users()
+                .unwrap()
+                .map(|(id, _)| id)
+                .collect::<Vec<_>>(),
+            vec![2]
+        );
+
+        // a hard delete forgets the user entirely
+        storage.remove_user(&2, false).unwrap();
+        assert!(!storage.was_registered(&2));
+        // unknown users are not removed, nor tombstoned
+        assert!(storage.remove_user(&3, true).is_err());
+        assert!(!storage.was_registered(&3));
+        // registering again lifts the tombstone
+        storage.add_user(1, vec![wallet]).unwrap();
+        assert!(storage.contains_user(&1));
+        assert_eq!(storage.removed_at(&1).unwrap(), None);
+        drop(storage);
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_hard_remove_purges_tombstone() {
+        let dir = temp_dir("purge_tombstone");
+        let mut storage = SledEncryptedStorage::open(&dir);
+        let mut in_memory = InMemoryStorage::new();
+        let wallet = SecretString::new(format!("{:?}", H160::zero()));
+        storage.add_user(1, vec![wallet.clone()]).unwrap();
+        in_memory.add_user(1, vec![wallet]).unwrap();
+
+        storage.remove_user(&1, true).unwrap();
+        in_memory.remove_user(&1, true).unwrap();
+        let removed_at = storage.removed_at(&1).unwrap();
+        // removing the soft deleted user softly again keeps the tombstone
+        storage.remove_user(&1, true).unwrap();
+        in_memory.remove_user(&1, true).unwrap();
+        assert_eq!(storage.removed_at(&1).unwrap(), removed_at);
+        assert!(in_memory.was_registered(&1));
+
+        storage.remove_user(&1, false).unwrap();
+        in_memory.remove_user(&1, false).unwrap();
+        assert!(!storage.was_registered(&1));
+        assert!(!in_memory.was_registered(&1));
+        // once purged the user is unknown
+        assert!(storage.remove_user(&1, false).is_err());
+        assert!(in_memory.remove_user(&1, false).is_err());
+        drop(storage);
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_import_users() {
+        let dir = temp_dir("import");
+        let mut storage = SledEncryptedStorage::open(&dir);
+        let registered = SecretString::new(format!("{:?}", H160::zero()));
+        storage.add_user(3, vec![registered]).unwrap();
+        let csv = "user_id,wallet\n\
+                   1,0xcB313f361847e245954FD338Cb21b5F4225b17d1\n\
+                   \n\
+                   \"2\", \"0xc9B6218AffE8Aba68a13899Cbf7cF7f14DDd304C\"\n\
+                   3,0xc9B6218AffE8Aba68a13899Cbf7cF7f14DDd304C\n\
+                   four,0xc9B6218AffE8Aba68a13899Cbf7cF7f14DDd304C\n\
+                   5,0xc9B6218AffE8Aba68a13899Cbf7cF7f14DDd30\n\
+                   6\n";
+        let import = import_users(&mut storage, csv, false);
+        assert_eq!(import.imported, 2);
+        assert_eq!(import.existing, 1);
+        assert_eq!(
+            import.errors.iter().map(|e| e.line).collect::<Vec<_>>(),
+            vec![6, 7, 8]
+        );
+        assert_eq!(
+            storage.get_user(&1).unwrap()[0].expose_secret(),
+            "0xcb313f361847e245954fd338cb21b5f4225b17d1"
+        );
+        assert!(storage.contains_user(&2));
+        // the registered wallet is kept unless overwritten
+        assert_eq!(
+            storage.get_user(&3).unwrap()[0].expose_secret(),
+            &format!("{:?}", H160::zero())
+        );
+
+        let import = import_users(&mut storage, csv, true);
+        assert_eq!(import.imported, 3);
+        assert_eq!(import.existing, 0);
+        assert_eq!(
+            storage.get_user(&3).unwrap()[0].expose_secret(),
+            "0xc9b6218affe8aba68a13899cbf7cf7f14ddd304c"
+        );
+        // imports are not registrations
+        assert_eq!(
+            storage.registration_stats().unwrap(),
+            RegistrationStats::default()
+        );
+        drop(storage);
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_registration_stats_are_persisted() {
+        let dir = temp_dir("stats");
+        {
+            let mut storage = SledEncryptedStorage::open(&dir);
+            assert_eq!(
+                storage.registration_stats().unwrap(),
+                RegistrationStats::default()
+            );
+            storage.count_registration().unwrap();
+            storage.count_registration().unwrap();
+            storage.count_unregistration().unwrap();
+        }
+
+        let storage = SledEncryptedStorage::open(&dir);
+        assert_eq!(
+            storage.registration_stats().unwrap(),
+            RegistrationStats {
+                registrations: 2,
+                unregistrations: 1,
+            }
+        );
+        drop(storage);
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_users_in_separate_directory() {
+        let dir = temp_dir("split-gates");
+        let user_dir = temp_dir("split-users");
+        let wallet = format!("{:?}", H160::zero());
+        {
+            let mut storage = SledUnencryptedStorage::open_with_users(&dir, &user_dir);
+            storage
+                .add_user(1, vec![SecretString::new(wallet.clone())])
+                .unwrap();
+            storage.set_last_checked(&2, &1, 1000).unwrap();
+            storage.add_gate(&2, allowlist_gate(3)).unwrap();
+            assert!(storage.db.get(1u64.to_be_bytes()).unwrap().is_none());
+            assert!(storage
+                .users
+                .tree_names()
+                .iter()
+                .all(|name| guild_id_from_tree_name(name).is_none()));
+        }
+
+        let storage = SledUnencryptedStorage::open_with_users(&dir, &user_dir);
+        assert_eq!(storage.get_user(&1).unwrap()[0].expose_secret(), &wallet);
+        assert_eq!(storage.last_checked(&2, &1).unwrap(), Some(1000));
+        assert_eq!(storage.list_guilds().collect::<Vec<_>>(), vec![2]);
+        let gates = storage.list_gates(&2).unwrap().collect::<Vec<_>>();
+        assert_eq!(gates, vec![allowlist_gate(3)]);
+        drop(storage);
+        // the gate database alone has no users
+        let storage = SledUnencryptedStorage::open(&dir);
+        assert!(!storage.contains_user(&1));
+        drop(storage);
+        fs::remove_dir_all(&dir).unwrap();
+        fs::remove_dir_all(&user_dir).unwrap();
+    }
+
+    #[test]
+    fn test_users_left_in_gate_database_are_refused() {
+        let dir = temp_dir("left-gates");
+        let user_dir = temp_dir("left-users");
+        let mut storage = SledUnencryptedStorage::open(&dir);
+        check_user_directory(&storage.db, &user_dir).unwrap();
+        let wallet = SecretString::new(format!("{:?}", H160::zero()));
+        storage.add_user(1, vec![wallet]).unwrap();
+        assert!(check_user_directory(&storage.db, &user_dir).is_err());
+        drop(storage);
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_verify_reports_corrupt_gate() {
+        let dir = temp_dir("verify");
+        let mut storage = SledUnencryptedStorage::open(&dir);
+        storage.add_gate(&2, allowlist_gate(3)).unwrap();
+        storage.add_gate(&2, allowlist_gate(4)).unwrap();
+        let wallet = SecretString::new(format!("{:?}", H160::zero()));
+        storage.add_user(1, vec![wallet]).unwrap();
+        let tree = storage.db.open_tree(guild_tree_name(2)).unwrap();
+        let corrupt = [b"GATE".as_slice(), &[0xff; 16]].concat();
+        tree.insert(42u128.to_be_bytes(), corrupt.clone()).unwrap();
+        storage.db.flush().unwrap();
+
+        // the database is still open, verify works on a copy of it
+        let report = verify(&dir, &dir, false).unwrap();
+        assert_eq!(
+            report,
+            VerifyReport {
+                gates: 2,
+                unreadable_gates: 1,
+                users: 1,
+                unreadable_users: 0,
+            }
+        );
+        assert!(!report.is_ok());
+        // nothing was changed
+        assert_eq!(tree.get(42u128.to_be_bytes()).unwrap().unwrap(), corrupt);
+        assert!(verify(&temp_dir("verify-missing"), &dir, false).is_err());
+        drop(tree);
+        drop(storage);
+        fs::remove_dir_all(&dir).unwrap();
     }
 }
@@ -0,0 +1,12 @@
+//! Small helpers shared by the controller, the gates and the storage
+//!
+
+use std::time::{SystemTime, UNIX_EPOCH};
+
+/// The current time as seconds since the unix epoch
+pub(crate) fn unix_timestamp() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .expect("Failed to get system timestamp")
+        .as_secs()
+}
@@ -0,0 +1,81 @@
+//! Creates gates with the typed builder and compares them with the gates
+//! created from the options the discord command passes
+
+mod common;
+
+use colony_rs::H160;
+use common::MockClient;
+use discord_gating_bot::{
+    gate::{GateOptionValue, GateOptionValueType},
+    init_reputation_client, init_token_client, Gate, GateBuilder,
+};
+use std::sync::Arc;
+
+const ROLE_ID: u64 = 2;
+
+/// The clients are global, so all tests in here share the same mock
+fn inject_mock_client() {
+    let client = Arc::new(MockClient::default().with_domain_count(3));
+    let _ = init_reputation_client(client.clone());
+    let _ = init_token_client(client);
+}
+
+fn option(name: &str, value: GateOptionValueType) -> GateOptionValue {
+    GateOptionValue {
+        name: name.to_string(),
+        value,
+    }
+}
+
+#[tokio::test]
+async fn test_builder_matches_reputation_options() {
+    inject_mock_client();
+    let colony = H160::repeat_byte(0xc0);
+    let options = vec![
+        option(
+            "colony",
+            GateOptionValueType::String(format!("{:?}", colony)),
+        ),
+        option("domain", GateOptionValueType::I64(2)),
+        option("reputation", GateOptionValueType::F64(12.5)),
+    ];
+    let expected = Gate::new(ROLE_ID, "reputation", &options).await.unwrap();
+    let built = GateBuilder::reputation(colony, 2, 12.5)
+        .role(ROLE_ID)
+        .build()
+        .await
+        .unwrap();
+    assert_eq!(built, expected);
+
+    // the domain is still validated against the colony
+    assert!(GateBuilder::reputation(colony, 4, 12.5)
+        .role(ROLE_ID)
+        .build()
+        .await
+        .is_err());
+}
+
+#[tokio::test]
+async fn test_builder_matches_token_options() {
+    inject_mock_client();
+    let token = H160::repeat_byte(9);
+    let options = vec![
+        option(
+            "token_address",
+            GateOptionValueType::String(format!("{:?}", token)),
+        ),
+        option("amount", GateOptionValueType::I64(5)),
+    ];
+    let expected = Gate::new(ROLE_ID, "token", &options).await.unwrap();
+    let built = GateBuilder::token(token, 5)
+        .role(ROLE_ID)
+        .grant_message("Welcome, holder")
+        .build()
+        .await
+        .unwrap();
+    assert_eq!(built, expected);
+    assert_eq!(built.grant_message.as_deref(), Some("Welcome, holder"));
+
+    // a gate without a role can't be built
+    assert!(GateBuilder::token(token, 5).build().await.is_err());
+}
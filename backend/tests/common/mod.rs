@@ -0,0 +1,147 @@
+//! The mock client shared by the integration tests. Each test binary only
+//! uses some of the knobs, so unused ones are allowed here
+
+#![allow(dead_code)]
+
+use anyhow::{bail, Result};
+use async_trait::async_trait;
+use colony_rs::{H160, U256};
+use discord_gating_bot::{ReputationClient, TokenClient};
+use std::collections::HashMap;
+use std::sync::{
+    atomic::{AtomicUsize, Ordering},
+    Mutex,
+};
+
+/// Implements the reputation and the token client without a network. The
+/// lookups are counted, so tests can observe batching and caching
+pub struct MockClient {
+    /// The reputation of the wallets, the zero address holds the base
+    /// reputation. Looking up any other wallet fails
+    reputations: HashMap<H160, String>,
+    domain_count: u64,
+    /// Whether the reputations are also looked up in batches
+    batched_reputations: bool,
+    /// The token balance of the wallets, all others hold nothing
+    balances: HashMap<H160, U256>,
+    /// The total supply of every token, it is not mocked if unset
+    total_supply: Mutex<Option<U256>>,
+    pub single_lookups: AtomicUsize,
+    pub batched_lookups: AtomicUsize,
+}
+
+impl Default for MockClient {
+    fn default() -> Self {
+        Self {
+            reputations: HashMap::new(),
+            domain_count: 1,
+            batched_reputations: false,
+            balances: HashMap::new(),
+            total_supply: Mutex::new(None),
+            single_lookups: AtomicUsize::new(0),
+            batched_lookups: AtomicUsize::new(0),
+        }
+    }
+}
+
+impl MockClient {
+    pub fn with_reputation(mut self, wallet: H160, reputation: &str) -> Self {
+        self.reputations.insert(wallet, reputation.to_string());
+        self
+    }
+
+    pub fn with_domain_count(mut self, domain_count: u64) -> Self {
+        self.domain_count = domain_count;
+        self
+    }
+
+    pub fn with_batched_reputations(mut self) -> Self {
+        self.batched_reputations = true;
+        self
+    }
+
+    pub fn with_balance(mut self, wallet: H160, balance: U256) -> Self {
+        self.balances.insert(wallet, balance);
+        self
+    }
+
+    pub fn with_total_supply(self, supply: U256) -> Self {
+        self.set_total_supply(supply);
+        self
+    }
+
+    /// Changes the supply of an injected client, like a mint or a burn would
+    pub fn set_total_supply(&self, supply: U256) {
+        *self.total_supply.lock().unwrap() = Some(supply);
+    }
+
+    fn reputation(&self, wallet: &H160) -> Result<String> {
+        match self.reputations.get(wallet) {
+            Some(reputation) => Ok(reputation.clone()),
+            None => bail!("Unknown wallet"),
+        }
+    }
+}
+
+#[async_trait]
+impl ReputationClient for MockClient {
+    async fn get_reputation_in_domain(
+        &self,
+        _colony: &H160,
+        wallet: &H160,
+        _domain: u64,
+    ) -> Result<String> {
+        self.single_lookups.fetch_add(1, Ordering::SeqCst);
+        self.reputation(wallet)
+    }
+
+    async fn get_reputations_in_domain(
+        &self,
+        _colony: &H160,
+        wallets: &[H160],
+        _domain: u64,
+    ) -> Result<Vec<String>> {
+        if !self.batched_reputations {
+            bail!("Not mocked");
+        }
+        self.batched_lookups.fetch_add(1, Ordering::SeqCst);
+        wallets
+            .iter()
+            .map(|wallet| self.reputation(wallet))
+            .collect()
+    }
+
+    fn supports_batched_reputations(&self) -> bool {
+        self.batched_reputations
+    }
+
+    async fn get_domain_count(&self, _colony: H160) -> Result<u64> {
+        Ok(self.domain_count)
+    }
+
+    async fn get_colony_name(&self, _colony: H160) -> Result<String> {
+        Ok("mock".to_string())
+    }
+}
+
+#[async_trait]
+impl TokenClient for MockClient {
+    async fn balance_of(&self, _token: &H160, wallet: &H160) -> Result<U256> {
+        Ok(self.balances.get(wallet).copied().unwrap_or_default())
+    }
+
+    async fn get_token_decimals(&self, _token: H160) -> Result<u8> {
+        Ok(18)
+    }
+
+    async fn get_token_symbol(&self, _token: H160) -> Result<String> {
+        Ok("MOCK".to_string())
+    }
+
+    async fn get_total_supply(&self, _token: H160) -> Result<U256> {
+        match *self.total_supply.lock().unwrap() {
+            Some(supply) => Ok(supply),
+            None => bail!("Not mocked"),
+        }
+    }
+}
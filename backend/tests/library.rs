@@ -0,0 +1,150 @@
+//! Embeds the gating logic as a library with mock clients and checks a user
+//! end-to-end through the controller
+
+mod common;
+
+use colony_rs::{H160, U256};
+use common::MockClient;
+use confique::Config;
+use discord_gating_bot::{
+    controller::CheckResponse,
+    gate::{GateOptionValue, GateOptionValueType},
+    start_with,
+    storage::InMemoryStorage,
+    Gate, GlobalConfig, Message, Storage,
+};
+use secrecy::SecretString;
+use std::sync::Arc;
+use tokio::sync::oneshot;
+use tracing::Span;
+
+const GUILD_ID: u64 = 1;
+const ROLE_ID: u64 = 2;
+const HOLDER_ID: u64 = 3;
+const OTHER_ID: u64 = 4;
+const BRONZE_ID: u64 = 5;
+const SILVER_ID: u64 = 6;
+const GOLD_ID: u64 = 7;
+
+fn wallet(byte: u8) -> Vec<SecretString> {
+    vec![SecretString::new(format!("{:?}", H160::repeat_byte(byte)))]
+}
+
+async fn check(controller: &tokio::sync::mpsc::Sender<Message>, user_id: u64) -> Vec<u64> {
+    let (response_tx, response_rx) = oneshot::channel();
+    controller
+        .send(Message::Check {
+            guild_id: GUILD_ID,
+            user_id,
+            username: "user".to_string(),
+            response_tx,
+            span: Span::none(),
+        })
+        .await
+        .unwrap();
+    match response_rx.await.unwrap() {
+        CheckResponse::Grant(outcome) => outcome.granted,
+        response => panic!("Unexpected response {:?}", response),
+    }
+}
+
+#[tokio::test]
+async fn test_check_with_injected_clients() {
+    std::env::set_var("CLNY_DISCORD_TOKEN", "dummyToken");
+    std::env::set_var("CLNY_ENCRYPTION_KEY", "dummyKey");
+    let config = GlobalConfig::builder().env().load().unwrap();
+
+    let mut storage = InMemoryStorage::new();
+    storage.add_user(HOLDER_ID, wallet(1)).unwrap();
+    storage.add_user(OTHER_ID, wallet(2)).unwrap();
+    let client = Arc::new(
+        MockClient::default().with_balance(H160::repeat_byte(1), U256::from(5) * U256::exp10(18)),
+    );
+    let controller = start_with(storage, client.clone(), client, config)
+        .await
+        .unwrap();
+
+    // the gate is created with the injected token client as well
+    let gate = Gate::new(
+        ROLE_ID,
+        "token",
+        &[
+            GateOptionValue {
+                name: "token_address".to_string(),
+                value: GateOptionValueType::String(format!("{:?}", H160::repeat_byte(9))),
+            },
+            GateOptionValue {
+                name: "amount".to_string(),
+                value: GateOptionValueType::I64(5),
+            },
+        ],
+    )
+    .await
+    .unwrap();
+    let (gate_tx, gate_rx) = oneshot::channel();
+    controller
+        .send(Message::Gate {
+            guild_id: GUILD_ID,
+            gate,
+            response_tx: gate_tx,
+            span: Span::none(),
+        })
+        .await
+        .unwrap();
+    gate_rx.await.unwrap().unwrap();
+
+    assert_eq!(check(&controller, HOLDER_ID).await, vec![ROLE_ID]);
+    assert!(check(&controller, OTHER_ID).await.is_empty());
+
+    // the holder of 5 tokens only gets the silver tier of the ladder
+    let ladder = Gate::new(
+        BRONZE_ID,
+        "tiered_token",
+        &[
+            GateOptionValue {
+                name: "token_address".to_string(),
+                value: GateOptionValueType::String(format!("{:?}", H160::repeat_byte(9))),
+            },
+            GateOptionValue {
+                name: "amount".to_string(),
+                value: GateOptionValueType::I64(1),
+            },
+            GateOptionValue {
+                name: "tiers".to_string(),
+                value: GateOptionValueType::String(format!("3:{}, 10:{}", SILVER_ID, GOLD_ID)),
+            },
+        ],
+    )
+    .await
+    .unwrap();
+    let (gate_tx, gate_rx) = oneshot::channel();
+    controller
+        .send(Message::Gate {
+            guild_id: GUILD_ID,
+            gate: ladder,
+            response_tx: gate_tx,
+            span: Span::none(),
+        })
+        .await
+        .unwrap();
+    gate_rx.await.unwrap().unwrap();
+    assert_eq!(
+        check(&controller, HOLDER_ID).await,
+        vec![ROLE_ID, SILVER_ID]
+    );
+    assert!(check(&controller, OTHER_ID).await.is_empty());
+
+    // all tiers are managed, so the enforcement revokes the others
+    let (response, roles) = oneshot::channel();
+    controller
+        .send(Message::Roles {
+            guild_id: GUILD_ID,
+            response,
+            span: Span::none(),
+        })
+        .await
+        .unwrap();
+    let mut roles = roles.await.unwrap().into_iter().collect::<Vec<_>>();
+    roles.sort_unstable();
+    assert_eq!(roles, vec![ROLE_ID, BRONZE_ID, SILVER_ID, GOLD_ID]);
+}
@@ -0,0 +1,223 @@
+//! Walks through the wallet registration over http, from the link handed out
+//! by the controller to the stored user, with an in memory storage
+
+use actix_web::{http::StatusCode, test, App};
+use confique::Config;
+use discord_gating_bot::{
+    config,
+    controller::CheckResponse,
+    gate::{GateOptionValue, GateOptionValueType},
+    registration_routes,
+    storage::InMemoryStorage,
+    Controller, Gate, GlobalConfig, Message, Storage,
+};
+use ethers::signers::{LocalWallet, Signer};
+use secrecy::SecretString;
+use serde::{Deserialize, Serialize};
+use tokio::sync::{mpsc, oneshot};
+use tracing::Span;
+
+const GUILD_ID: u64 = 1;
+const ROLE_ID: u64 = 2;
+const USER_ID: u64 = 3;
+const USERNAME: &str = "alice";
+/// A user that unregistered before, remembered by a tombstone
+const UNREGISTERED_USER_ID: u64 = 4;
+
+#[derive(Deserialize)]
+struct Challenge {
+    message: String,
+}
+
+#[derive(Deserialize)]
+struct ChallengeError {
+    error: String,
+}
+
+#[derive(Serialize)]
+struct Registration {
+    signature: String,
+    address: String,
+}
+
+async fn check(controller: &mpsc::Sender<Message>, user_id: u64) -> CheckResponse {
+    let (response_tx, response_rx) = oneshot::channel();
+    controller
+        .send(Message::Check {
+            guild_id: GUILD_ID,
+            user_id,
+            username: USERNAME.to_string(),
+            response_tx,
+            span: Span::none(),
+        })
+        .await
+        .unwrap();
+    response_rx.await.unwrap()
+}
+
+async fn link(controller: &mpsc::Sender<Message>) -> String {
+    let (response_tx, response_rx) = oneshot::channel();
+    controller
+        .send(Message::Link {
+            user_id: USER_ID,
+            username: USERNAME.to_string(),
+            response_tx,
+            span: Span::none(),
+        })
+        .await
+        .unwrap();
+    response_rx.await.unwrap().unwrap()
+}
+
+/// The username and the session of the registration link
+fn session_of(url: &str) -> (String, String) {
+    let (_, query) = url.split_once('?').unwrap();
+    let mut username = None;
+    let mut session = None;
+    for pair in query.split('&') {
+        match pair.split_once('=').unwrap() {
+            ("username", value) => username = Some(urlencoding::decode(value).unwrap()),
+            ("session", value) => session = Some(value.to_string()),
+            _ => {}
+        }
+    }
+    (username.unwrap().into_owned(), session.unwrap())
+}
+
+#[actix_web::test]
+async fn test_registration_over_http() {
+    std::env::set_var("CLNY_DISCORD_TOKEN", "dummyToken");
+    std::env::set_var("CLNY_ENCRYPTION_KEY", "dummyKey");
+    config::set_config(GlobalConfig::builder().env().load().unwrap()).unwrap();
+
+    // a well known test key, never use it for anything else
+    let wallet = "4c0883a69102937d6231471b5dbb6204fe5129617082792ae468d01a3f362318"
+        .parse::<LocalWallet>()
+        .unwrap();
+    let address = format!("{:?}", wallet.address());
+    let mut storage = InMemoryStorage::new();
+    storage
+        .add_user(
+            UNREGISTERED_USER_ID,
+            vec![SecretString::new(address.clone())],
+        )
+        .unwrap();
+    storage.remove_user(&UNREGISTERED_USER_ID, true).unwrap();
+    let controller = Controller::with_storage(storage).start().await;
+    let gate = Gate::new_static(
+        ROLE_ID,
+        "allowlist",
+        &[GateOptionValue {
+            name: "wallets".to_string(),
+            value: GateOptionValueType::String(address.clone()),
+        }],
+    )
+    .await
+    .unwrap();
+    let (gate_tx, gate_rx) = oneshot::channel();
+    controller
+        .send(Message::Gate {
+            guild_id: GUILD_ID,
+            gate,
+            response_tx: gate_tx,
+            span: Span::none(),
+        })
+        .await
+        .unwrap();
+    gate_rx.await.unwrap().unwrap();
+    let app = test::init_service(App::new().configure(registration_routes)).await;
+
+    // a user that unregistered before is reminded instead of urged
+    assert!(matches!(
+        check(&controller, UNREGISTERED_USER_ID).await,
+        CheckResponse::Reregister(_)
+    ));
+
+    // the unregistered user gets a link with a session from the controller
+    let url = match check(&controller, USER_ID).await {
+        CheckResponse::Register(url) => url,
+        response => panic!("Unexpected response {:?}", response),
+    };
+    let (username, session) = session_of(&url);
+    assert_eq!(username, USERNAME);
+
+    // the registration page loads the message to sign for the session
+    let request = test::TestRequest::get()
+        .uri(&format!("/challenge/{}/{}", username, session))
+        .to_request();
+    let challenge: Challenge = test::call_and_read_body_json(&app, request).await;
+    assert!(challenge.message.contains(USERNAME));
+    assert!(challenge.message.contains(&session));
+    let signature = wallet.sign_message(&challenge.message).await.unwrap();
+
+    // the page gets the errors of the challenge as json
+    let request = test::TestRequest::get()
+        .uri(&format!("/challenge/{}/{}", username, "00.00"))
+        .to_request();
+    let response = test::call_service(&app, request).await;
+    assert_eq!(response.status(), StatusCode::BAD_REQUEST);
+    assert!(response.headers().contains_key("X-Error-Ref"));
+    let error: ChallengeError = test::read_body_json(response).await;
+    assert!(error.error.contains("Invalid session"), "{}", error.error);
+
+    // a session that was not issued by the controller is rejected
+    let request = test::TestRequest::post()
+        .uri(&format!("/register/{}/{}", username, "00.00"))
+        .set_json(Registration {
+            signature: signature.to_string(),
+            address: address.clone(),
+        })
+        .to_request();
+    let response = test::call_service(&app, request).await;
+    assert_eq!(response.status(), StatusCode::BAD_REQUEST);
+    let body = String::from_utf8(test::read_body(response).await.to_vec()).unwrap();
+    assert!(body.contains("Invalid session"), "{}", body);
+
+    // a signature of another message is rejected
+    let other_signature = wallet.sign_message("something else").await.unwrap();
+    let request = test::TestRequest::post()
+        .uri(&format!("/register/{}/{}", username, session))
+        .set_json(Registration {
+            signature: other_signature.to_string(),
+            address: address.clone(),
+        })
+        .to_request();
+    let response = test::call_service(&app, request).await;
+    assert_eq!(response.status(), StatusCode::BAD_REQUEST);
+    let body = String::from_utf8(test::read_body(response).await.to_vec()).unwrap();
+    assert!(body.contains("Invalid signature"), "{}", body);
+    assert!(matches!(
+        check(&controller, USER_ID).await,
+        CheckResponse::Register(_)
+    ));
+
+    // the valid signature registers the wallet of the user
+    let request = test::TestRequest::post()
+        .uri(&format!("/register/{}/{}", username, session))
+        .set_json(Registration {
+            signature: signature.to_string(),
+            address: address.clone(),
+        })
+        .to_request();
+    let response = test::call_service(&app, request).await;
+    assert_eq!(response.status(), StatusCode::OK);
+    let body = String::from_utf8(test::read_body(response).await.to_vec()).unwrap();
+    assert!(body.contains("Registration successful"), "{}", body);
+
+    // the stored wallet is on the allowlist
+    match check(&controller, USER_ID).await {
+        CheckResponse::Grant(outcome) => assert_eq!(outcome.granted, vec![ROLE_ID]),
+        response => panic!("Unexpected response {:?}", response),
+    }
+
+    // the registered user still gets a fresh link with a valid session, e.g.
+    // to link another wallet
+    let (username, new_session) = session_of(&link(&controller).await);
+    assert_eq!(username, USERNAME);
+    assert_ne!(new_session, session);
+    let request = test::TestRequest::get()
+        .uri(&format!("/challenge/{}/{}", username, new_session))
+        .to_request();
+    let challenge: Challenge = test::call_and_read_body_json(&app, request).await;
+    assert!(challenge.message.contains(&new_session));
+}
@@ -0,0 +1,62 @@
+//! Checks a user with several wallets against a reputation gate with a mock
+//! client that supports batched reputation lookups
+
+mod common;
+
+use colony_rs::H160;
+use common::MockClient;
+use discord_gating_bot::{controller::check_with_wallets, init_reputation_client, GateBuilder};
+use secrecy::SecretString;
+use std::sync::{atomic::Ordering, Arc};
+
+const ROLE_ID: u64 = 2;
+
+fn wallet(byte: u8) -> H160 {
+    H160::from_low_u64_be(byte as u64)
+}
+
+fn secret(wallet: H160) -> SecretString {
+    SecretString::new(format!("{:?}", wallet))
+}
+
+#[tokio::test]
+async fn test_wallets_are_looked_up_in_one_batch() {
+    // the zero address holds the base reputation of 1000
+    let client = Arc::new(
+        MockClient::default()
+            .with_reputation(wallet(0), "1000")
+            .with_reputation(wallet(1), "100")
+            .with_reputation(wallet(2), "300")
+            .with_reputation(wallet(3), "50")
+            .with_batched_reputations(),
+    );
+    assert!(init_reputation_client(client.clone()).is_ok());
+    let gate = GateBuilder::reputation(H160::repeat_byte(0xc0), 1, 20.0)
+        .role(ROLE_ID)
+        .build()
+        .await
+        .unwrap();
+
+    // only the second wallet has 30% of the reputation
+    let outcome = check_with_wallets(
+        vec![secret(wallet(1)), secret(wallet(2))],
+        vec![gate.clone()].into_iter(),
+    )
+    .await;
+    assert_eq!(outcome.granted, vec![ROLE_ID]);
+    assert_eq!(outcome.wallets[&ROLE_ID], wallet(2));
+    assert_eq!(client.batched_lookups.load(Ordering::SeqCst), 1);
+    assert_eq!(client.single_lookups.load(Ordering::SeqCst), 0);
+
+    // the cached wallets are not looked up again, so only the new wallet
+    // remains, which is looked up on its own
+    let outcome = check_with_wallets(
+        vec![secret(wallet(1)), secret(wallet(3))],
+        vec![gate].into_iter(),
+    )
+    .await;
+    assert!(outcome.granted.is_empty());
+    assert!(outcome.errored.is_empty());
+    assert_eq!(client.batched_lookups.load(Ordering::SeqCst), 1);
+    assert_eq!(client.single_lookups.load(Ordering::SeqCst), 1);
+}
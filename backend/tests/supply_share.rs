@@ -0,0 +1,96 @@
+//! Checks the token supply share gate against a mock token client whose
+//! supply can be changed, like it would be by a mint
+
+mod common;
+
+use colony_rs::{H160, U256};
+use common::MockClient;
+use discord_gating_bot::{init_token_client, GateBuilder};
+use std::sync::Arc;
+
+const ROLE_ID: u64 = 3;
+
+#[tokio::test]
+async fn test_supply_share_follows_the_supply() {
+    // the wallet holds 1% of the supply
+    let token = H160::repeat_byte(7);
+    let wallet = H160::repeat_byte(1);
+    let client = Arc::new(
+        MockClient::default()
+            .with_balance(wallet, U256::exp10(25))
+            .with_total_supply(U256::exp10(27)),
+    );
+    assert!(init_token_client(client.clone()).is_ok());
+
+    let one_percent = GateBuilder::token_supply_share(token, 1.0)
+        .role(ROLE_ID)
+        .build()
+        .await
+        .unwrap();
+    let half_percent = GateBuilder::token_supply_share(token, 0.5)
+        .role(ROLE_ID)
+        .build()
+        .await
+        .unwrap();
+    let two_percent = GateBuilder::token_supply_share(token, 2.0)
+        .role(ROLE_ID)
+        .build()
+        .await
+        .unwrap();
+    assert_eq!(
+        one_percent
+            .clone()
+            .check_condition(wallet, true)
+            .await
+            .unwrap(),
+        Some(ROLE_ID)
+    );
+    assert_eq!(
+        half_percent
+            .clone()
+            .check_condition(wallet, true)
+            .await
+            .unwrap(),
+        Some(ROLE_ID)
+    );
+    assert_eq!(
+        two_percent
+            .clone()
+            .check_condition(wallet, true)
+            .await
+            .unwrap(),
+        None
+    );
+
+    // minting dilutes the wallet to 0.5% of the supply
+    client.set_total_supply(U256::exp10(27) * 2);
+    assert_eq!(
+        one_percent.check_condition(wallet, true).await.unwrap(),
+        None
+    );
+    assert_eq!(
+        half_percent.check_condition(wallet, true).await.unwrap(),
+        Some(ROLE_ID)
+    );
+
+    // burning concentrates the wallet to 2% of the supply
+    client.set_total_supply(U256::exp10(27) / 2);
+    assert_eq!(
+        two_percent.check_condition(wallet, true).await.unwrap(),
+        Some(ROLE_ID)
+    );
+
+    // the share must be a percentage above zero
+    for share in [0.0, -1.0, 100.5, f64::NAN] {
+        assert!(GateBuilder::token_supply_share(token, share)
+            .role(ROLE_ID)
+            .build()
+            .await
+            .is_err());
+    }
+    assert!(GateBuilder::token_supply_share(token, 100.0)
+        .role(ROLE_ID)
+        .build()
+        .await
+        .is_ok());
+}
@@ -2,9 +2,19 @@ import { BrowserProvider } from 'ethers';
 
 window.addEventListener('load', () => {
   const connectButton = document.querySelector('#button-connect') as HTMLButtonElement;
+  const mobileLink = document.querySelector('#link-mobile') as HTMLAnchorElement;
   const disconnectButton = document.querySelector('#button-disconnect') as HTMLButtonElement;
   const errorText = document.querySelector('#text-error') as HTMLParagraphElement;
   const successText = document.querySelector('#text-success') as HTMLParagraphElement;
+  const errorRef = document.querySelector('#text-error-ref') as HTMLSpanElement | null;
+
+  // Failed requests carry a reference that users can quote when reporting issues
+  const showErrorRef = (response: Response) => {
+    const ref = response.headers.get('X-Error-Ref');
+    if (errorRef && ref) {
+      errorRef.innerText = `Error ref: ${ref}`;
+    }
+  };
 
   const urlParams = new URLSearchParams(window.location.search);
   const username = urlParams.get('username');
@@ -22,6 +32,32 @@ window.addEventListener('load', () => {
     connectButton.innerText = `Connect as ${username}`;
     connectButton.style.visibility = 'visible';
 
+    const challengeUrl = window.location.origin + '/challenge/' + encodeURIComponent(username) + '/' + sessionId;
+    // Errors of the challenge come back as json with a reference
+    const challenge = fetch(challengeUrl).then(async (response) => {
+      if (!response.ok) {
+        showErrorRef(response);
+        const { error } = await response.json().catch(() => ({ error: response.statusText }));
+        throw new Error(error);
+      }
+      return response.json();
+    });
+
+    challenge.then(({ mobile_deep_link }) => {
+      // Without an injected provider we are most likely on a mobile browser,
+      // so we offer to open this page in the browser of the wallet app
+      if (mobile_deep_link && mobileLink && !(window as any).ethereum) {
+        const page = window.location.host + window.location.pathname + window.location.search;
+        mobileLink.href = `https://metamask.app.link/dapp/${page}`;
+        mobileLink.style.visibility = 'visible';
+      }
+    }).catch((error) => {
+      // Without a message to sign the registration can't work
+      console.error('Failed to load the message to sign:', error);
+      connectButton.style.visibility = 'hidden';
+      errorText.style.visibility = 'visible';
+    });
+
     connectButton.addEventListener('click', async () => {
       connectButton.disabled = true;
 
@@ -34,10 +70,17 @@ window.addEventListener('load', () => {
       const signer = await provider.getSigner();
       const address = await signer.getAddress();
 
-      const signature = await signer.signMessage(`Please sign this message to connect your Discord username ${username} with your wallet address. Session ID: ${sessionId}`);
+      let message: string;
+      try {
+        ({ message } = await challenge);
+      } catch {
+        // The error is already shown when the challenge failed
+        return;
+      }
+      const signature = await signer.signMessage(message);
 
       // const response = await fetch(window.location.href, {
-      const response = await fetch(window.location.origin + '/register/' + username + '/' + sessionId, {
+      const response = await fetch(window.location.origin + '/register/' + encodeURIComponent(username) + '/' + sessionId, {
         method: 'POST',
         headers: {
           'Accept': 'application/json',
@@ -54,6 +97,7 @@ window.addEventListener('load', () => {
         successText.style.visibility = 'visible';
         errorText.style.visibility = 'hidden';
       } else {
+        showErrorRef(response);
         errorText.style.visibility = 'visible';
         connectButton.disabled = false;
       }
@@ -69,7 +113,7 @@ window.addEventListener('load', () => {
     disconnectButton.addEventListener('click', async () => {
       disconnectButton.disabled = true;
       // const response = await fetch(window.location.href, {
-      const response = await fetch(window.location.origin + '/unregister/' + username + '/' + sessionId, {
+      const response = await fetch(window.location.origin + '/unregister/' + encodeURIComponent(username) + '/' + sessionId, {
         method: 'POST'
       });
       if (response.ok) {
@@ -77,6 +121,7 @@ window.addEventListener('load', () => {
         successText.style.visibility = 'visible';
         errorText.style.visibility = 'hidden';
       } else {
+        showErrorRef(response);
         errorText.style.visibility = 'visible';
         disconnectButton.disabled = false;
       }